@@ -0,0 +1,124 @@
+use super::forth_compiler::SourceLocation;
+use super::stack_machine::Opcode;
+use std::fmt;
+
+/// Where a compiled word lives in the StackMachine's memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordInfo {
+    pub name: String,
+    pub address: usize,
+    // Number of opcodes in the word, including its RET
+    pub length: usize,
+}
+
+/// Everything the compiler produced for a compile, in a form that can be inspected by tools or
+/// dumped for people to read
+#[derive(Debug, Clone)]
+pub struct CompilationArtifact {
+    // The whole of the StackMachine's opcode memory
+    pub opcodes: Vec<Opcode>,
+    // The dictionary, sorted by address
+    pub words: Vec<WordInfo>,
+    // The source location each opcode was compiled from, the same length as opcodes
+    pub source_map: Vec<SourceLocation>,
+    // Opcodes from here on are the immediate mode code, everything before is the dictionary
+    pub immediate_code_start: usize,
+}
+
+impl CompilationArtifact {
+    /// Number of opcodes the named word compiled to
+    pub fn opcode_count(&self, word: &str) -> Option<usize> {
+        self.words.iter().find(|w| w.name == word).map(|w| w.length)
+    }
+
+    /// The word whose body contains the address
+    pub fn word_containing(&self, address: usize) -> Option<&WordInfo> {
+        self.words
+            .iter()
+            .find(|w| address >= w.address && address < w.address + w.length)
+    }
+
+    pub fn immediate_opcode_count(&self) -> usize {
+        self.opcodes.len() - self.immediate_code_start
+    }
+
+    pub fn largest_word(&self) -> Option<&WordInfo> {
+        self.words
+            .iter()
+            .max_by(|a, b| a.length.cmp(&b.length).then(b.address.cmp(&a.address)))
+    }
+
+    /// The opcodes of every word and of the immediate mode code, in address order and with
+    /// nothing that depends on source layout, so the text only changes when the compiled code
+    /// does. Meant for golden tests.
+    pub fn opcode_listing(&self) -> String {
+        let mut text = String::new();
+        for w in self.words.iter() {
+            text.push_str(&format!("{}:\n", w.name));
+            for address in w.address..w.address + w.length {
+                text.push_str(&format!("{:>6}  {:?}\n", address, self.opcodes[address]));
+            }
+        }
+        text.push_str("<immediate>:\n");
+        for address in self.immediate_code_start..self.opcodes.len() {
+            text.push_str(&format!("{:>6}  {:?}\n", address, self.opcodes[address]));
+        }
+        text
+    }
+}
+
+impl fmt::Display for CompilationArtifact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "; Words")?;
+        writeln!(f, ";   {:>8} {:>8}  name", "address", "length")?;
+        for w in self.words.iter() {
+            writeln!(f, ";   {:>8} {:>8}  {}", w.address, w.length, w.name)?;
+        }
+
+        writeln!(f, "; Listing")?;
+        // Label each run of opcodes with the word it belongs to, words that have been redefined
+        // leave their old bodies behind in memory
+        let mut current_label = None;
+        for (address, opcode) in self.opcodes.iter().enumerate() {
+            let label = if address >= self.immediate_code_start {
+                "<immediate>"
+            } else {
+                match self.word_containing(address) {
+                    Some(w) => w.name.as_str(),
+                    None => "<superseded>",
+                }
+            };
+            if current_label != Some(label) || self.words.iter().any(|w| w.address == address) {
+                writeln!(f, "{}:", label)?;
+                current_label = Some(label);
+            }
+            let opcode_text = format!("{:?}", opcode);
+            match self.source_map.get(address) {
+                Some(l) => writeln!(
+                    f,
+                    "    {:>8}  {:<16} ; {}:{}",
+                    address, opcode_text, l.line, l.column
+                )?,
+                None => writeln!(f, "    {:>8}  {}", address, opcode_text)?,
+            }
+        }
+
+        writeln!(f, "; Statistics")?;
+        writeln!(f, ";   words              {}", self.words.len())?;
+        writeln!(f, ";   total opcodes      {}", self.opcodes.len())?;
+        writeln!(f, ";   dictionary opcodes {}", self.immediate_code_start)?;
+        writeln!(
+            f,
+            ";   immediate opcodes  {}",
+            self.immediate_opcode_count()
+        )?;
+        if let Some(w) = self.largest_word() {
+            writeln!(
+                f,
+                ";   largest word       {} ({} opcodes)",
+                w.name, w.length
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An arbitrary precision signed integer, just enough of one to let the stack machine carry on
+/// when 64 bit arithmetic would overflow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    // Little endian base 2^32 digits, with no trailing zero digits (so zero is an empty vector)
+    magnitude: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> BigInt {
+        BigInt {
+            negative: false,
+            magnitude: Vec::new(),
+        }
+    }
+
+    pub fn from_i64(n: i64) -> BigInt {
+        let m = n.unsigned_abs();
+        BigInt::from_parts(n < 0, vec![m as u32, (m >> 32) as u32])
+    }
+
+    fn from_parts(negative: bool, mut magnitude: Vec<u32>) -> BigInt {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        BigInt {
+            // There is no such thing as negative zero
+            negative: negative && !magnitude.is_empty(),
+            magnitude,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// How many 32 bit digits the magnitude takes
+    pub fn digit_count(&self) -> usize {
+        self.magnitude.len()
+    }
+
+    /// Returns the value as an i64 if it fits in one
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+        let m = self
+            .magnitude
+            .iter()
+            .rev()
+            .fold(0_u64, |acc, d| (acc << 32) | u64::from(*d));
+        if self.negative {
+            if m <= i64::MIN.unsigned_abs() {
+                Some(0_i64.wrapping_sub(m as i64))
+            } else {
+                None
+            }
+        } else if m <= i64::MAX as u64 {
+            Some(m as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn negate(&self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.magnitude.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(
+                self.negative,
+                add_magnitudes(&self.magnitude, &other.magnitude),
+            );
+        }
+        // Signs differ, so take the smaller magnitude away from the larger one
+        match compare_magnitudes(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInt::from_parts(
+                other.negative,
+                sub_magnitudes(&other.magnitude, &self.magnitude),
+            ),
+            _ => BigInt::from_parts(
+                self.negative,
+                sub_magnitudes(&self.magnitude, &other.magnitude),
+            ),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.negate())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        let mut product = vec![0_u32; self.magnitude.len() + other.magnitude.len()];
+        for (i, a) in self.magnitude.iter().enumerate() {
+            let mut carry = 0_u64;
+            for (j, b) in other.magnitude.iter().enumerate() {
+                let t = u64::from(*a) * u64::from(*b) + u64::from(product[i + j]) + carry;
+                product[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            product[i + other.magnitude.len()] = carry as u32;
+        }
+        BigInt::from_parts(self.negative != other.negative, product)
+    }
+
+    /// Division truncating towards zero, the same as Rust's integer division.
+    /// Returns None if dividing by zero.
+    pub fn div(&self, other: &BigInt) -> Option<BigInt> {
+        if other.is_zero() {
+            return None;
+        }
+        // Simple shift and subtract long division, one bit at a time
+        let mut quotient = vec![0_u32; self.magnitude.len()];
+        let mut remainder: Vec<u32> = Vec::new();
+        for bit in (0..self.magnitude.len() * 32).rev() {
+            remainder = shift_left_one(&remainder, (self.magnitude[bit / 32] >> (bit % 32)) & 1);
+            if compare_magnitudes(&remainder, &other.magnitude) != Ordering::Less {
+                remainder = sub_magnitudes(&remainder, &other.magnitude);
+                quotient[bit / 32] |= 1 << (bit % 32);
+            }
+        }
+        Some(BigInt::from_parts(
+            self.negative != other.negative,
+            quotient,
+        ))
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::from_parts(false, self.magnitude.clone())
+    }
+
+    /// The greatest common divisor, never negative
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let (mut a, mut b) = (self.abs(), other.abs());
+        while let Some(quotient) = a.div(&b) {
+            let remainder = a.sub(&quotient.mul(&b));
+            a = b;
+            b = remainder;
+        }
+        a
+    }
+
+    /// Raise to the power by repeated squaring. Returns None if the answer would have more
+    /// than max_digits digits.
+    pub fn pow(&self, mut exponent: u32, max_digits: usize) -> Option<BigInt> {
+        // The answer has at least (bits - 1) * exponent + 1 bits, so give up before squaring
+        if let Some(top) = self.magnitude.last() {
+            let bits = (self.magnitude.len() * 32) as u64 - u64::from(top.leading_zeros());
+            if (bits - 1) * u64::from(exponent) > (max_digits * 32) as u64 {
+                return None;
+            }
+        }
+        let mut result = BigInt::from_i64(1);
+        let mut square = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.mul(&square);
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                square = square.mul(&square);
+            }
+            if result.digit_count() > max_digits || square.digit_count() > max_digits {
+                return None;
+            }
+        }
+        Some(result)
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // Peel off base 10^9 chunks from the bottom
+        let mut chunks = Vec::new();
+        let mut m = self.magnitude.clone();
+        while !m.is_empty() {
+            let mut remainder = 0_u64;
+            for d in m.iter_mut().rev() {
+                let t = (remainder << 32) | u64::from(*d);
+                *d = (t / 1_000_000_000) as u32;
+                remainder = t % 1_000_000_000;
+            }
+            while m.last() == Some(&0) {
+                m.pop();
+            }
+            chunks.push(remainder);
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        if let Some(first) = chunks.next() {
+            write!(f, "{}", first)?;
+        }
+        for c in chunks {
+            write!(f, "{:09}", c)?;
+        }
+        Ok(())
+    }
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0_u64;
+    for i in 0..a.len().max(b.len()) {
+        let t = u64::from(*a.get(i).unwrap_or(&0)) + u64::from(*b.get(i).unwrap_or(&0)) + carry;
+        sum.push(t as u32);
+        carry = t >> 32;
+    }
+    sum.push(carry as u32);
+    sum
+}
+
+// Requires a >= b
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0_i64;
+    for (i, d) in a.iter().enumerate() {
+        let mut t = i64::from(*d) - i64::from(*b.get(i).unwrap_or(&0)) - borrow;
+        borrow = 0;
+        if t < 0 {
+            t += 1 << 32;
+            borrow = 1;
+        }
+        difference.push(t as u32);
+    }
+    while difference.last() == Some(&0) {
+        difference.pop();
+    }
+    difference
+}
+
+fn shift_left_one(a: &[u32], low_bit: u32) -> Vec<u32> {
+    let mut shifted = Vec::with_capacity(a.len() + 1);
+    let mut carry = low_bit;
+    for d in a.iter() {
+        shifted.push((d << 1) | carry);
+        carry = d >> 31;
+    }
+    shifted.push(carry);
+    while shifted.last() == Some(&0) {
+        shifted.pop();
+    }
+    shifted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_i64() {
+        for n in &[0, 1, -1, 123_456_789_012, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from_i64(*n).to_i64(), Some(*n));
+            assert_eq!(BigInt::from_i64(*n).to_string(), n.to_string());
+        }
+    }
+
+    #[test]
+    fn test_overflowing_arithmetic() {
+        let max = BigInt::from_i64(i64::MAX);
+        let one = BigInt::from_i64(1);
+
+        let sum = max.add(&one);
+        assert_eq!(sum.to_i64(), None);
+        assert_eq!(sum.to_string(), "9223372036854775808");
+        assert_eq!(sum.sub(&one), max);
+
+        let square = max.mul(&max);
+        assert_eq!(square.to_string(), "85070591730234615847396907784232501249");
+        assert_eq!(square.div(&max), Some(max.clone()));
+        assert_eq!(square.negate().div(&max), Some(max.negate()));
+        assert_eq!(max.div(&BigInt::zero()), None);
+    }
+
+    #[test]
+    fn test_gcd_and_pow() {
+        let max = BigInt::from_i64(i64::MAX);
+        let square = max.mul(&max);
+
+        assert_eq!(square.gcd(&max.negate()), max);
+        assert_eq!(
+            BigInt::from_i64(-12).gcd(&BigInt::from_i64(18)).to_i64(),
+            Some(6)
+        );
+        assert_eq!(BigInt::zero().gcd(&BigInt::from_i64(-5)).to_i64(), Some(5));
+
+        assert_eq!(max.pow(2, 4), Some(square));
+        assert_eq!(
+            BigInt::from_i64(-2).pow(65, 4).unwrap().to_string(),
+            "-36893488147419103232"
+        );
+        assert_eq!(
+            BigInt::from_i64(-1).pow(u32::MAX, 1).unwrap().to_i64(),
+            Some(-1)
+        );
+        assert_eq!(BigInt::from_i64(7).pow(0, 1).unwrap().to_i64(), Some(1));
+        assert_eq!(BigInt::from_i64(2).pow(1000, 4), None);
+    }
+}
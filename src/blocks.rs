@@ -0,0 +1,140 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const BLOCK_SIZE: usize = 1024;
+pub const LINES_PER_BLOCK: usize = 16;
+pub const LINE_LENGTH: usize = 64;
+
+/// Forth source kept in traditional 1024 byte blocks, each shown as 16 lines of 64 characters.
+/// Blocks live in memory and are written back to their file, if they have one, by save.
+#[derive(Debug, Clone, Default)]
+pub struct BlockStore {
+    path: Option<PathBuf>,
+    data: Vec<u8>,
+}
+
+impl BlockStore {
+    /// A store that only lives in memory
+    pub fn new() -> BlockStore {
+        BlockStore::default()
+    }
+
+    /// Open a block file, a file that doesn't exist yet is an empty store that save will create
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<BlockStore> {
+        let path = path.into();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(BlockStore {
+            path: Some(path),
+            data,
+        })
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.data.len().div_ceil(BLOCK_SIZE)
+    }
+
+    /// The contents of a block, blocks past the end of the store are blank
+    pub fn block(&self, n: usize) -> Vec<u8> {
+        let mut block = vec![b' '; BLOCK_SIZE];
+        let start = n.saturating_mul(BLOCK_SIZE);
+        if start < self.data.len() {
+            let end = self.data.len().min(start + BLOCK_SIZE);
+            block[..end - start].copy_from_slice(&self.data[start..end]);
+        }
+        block
+    }
+
+    /// Replace a block, short contents are padded with blanks and the store grows as needed
+    pub fn set_block(&mut self, n: usize, contents: &[u8]) {
+        let start = n * BLOCK_SIZE;
+        if self.data.len() < start + BLOCK_SIZE {
+            self.data.resize(start + BLOCK_SIZE, b' ');
+        }
+        let length = contents.len().min(BLOCK_SIZE);
+        self.data[start..start + length].copy_from_slice(&contents[..length]);
+        for b in self.data[start + length..start + BLOCK_SIZE].iter_mut() {
+            *b = b' ';
+        }
+    }
+
+    /// The lines of a block with their trailing blanks removed
+    pub fn lines(&self, n: usize) -> Vec<String> {
+        self.block(n)
+            .chunks(LINE_LENGTH)
+            .map(|l| String::from_utf8_lossy(l).trim_end().to_owned())
+            .collect()
+    }
+
+    /// The block as Forth source text, one line per line of the block
+    pub fn source(&self, n: usize) -> String {
+        self.lines(n).join("\n")
+    }
+
+    /// The traditional LIST display of a block, its number followed by its numbered lines
+    pub fn list(&self, n: usize) -> String {
+        let mut text = format!("Screen {}\n", n);
+        for (i, line) in self.lines(n).iter().enumerate() {
+            text.push_str(format!("{:>2} {}", i, line).trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Write the store back to its file, stores that only live in memory have nothing to do
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => fs::write(path, &self.data),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let mut store = BlockStore::new();
+        assert_eq!(store.block_count(), 0);
+        assert_eq!(store.block(3), vec![b' '; BLOCK_SIZE]);
+
+        let mut contents = b": Double DUP ADD ;".to_vec();
+        contents.resize(LINE_LENGTH, b' ');
+        contents.extend_from_slice(b"2 Double");
+        store.set_block(1, &contents);
+
+        assert_eq!(store.block_count(), 2);
+        assert_eq!(store.source(0).trim(), "");
+        assert_eq!(
+            store.source(1),
+            format!(": Double DUP ADD ;\n2 Double{}", "\n".repeat(14))
+        );
+        assert!(store
+            .list(1)
+            .starts_with("Screen 1\n 0 : Double DUP ADD ;\n 1 2 Double\n 2\n"));
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let path =
+            std::env::temp_dir().join(format!("rust_forth_blocks_{}.fb", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = BlockStore::open(&path).unwrap();
+        assert_eq!(store.block_count(), 0);
+        store.set_block(0, b"1 2 ADD");
+        store.save().unwrap();
+
+        let reopened = BlockStore::open(&path).unwrap();
+        assert_eq!(reopened.block_count(), 1);
+        assert_eq!(reopened.lines(0)[0], "1 2 ADD");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+/// The checksums the CHECKSUM opcode can work out over a range of bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checksum {
+    /// CRC-16/CCITT-FALSE, polynomial 0x1021 starting from 0xFFFF
+    Crc16,
+    /// The CRC-32 used by zlib, Ethernet and PNG
+    Crc32,
+    /// The bytes added up, modulo 256
+    Sum8,
+    /// The bytes exclusive ored together
+    Xor8,
+}
+
+impl Checksum {
+    pub fn compute(&self, bytes: &[u8]) -> u32 {
+        match self {
+            Checksum::Crc16 => u32::from(crc16(bytes)),
+            Checksum::Crc32 => crc32(bytes),
+            Checksum::Sum8 => u32::from(bytes.iter().fold(0_u8, |s, b| s.wrapping_add(*b))),
+            Checksum::Xor8 => u32::from(bytes.iter().fold(0_u8, |s, b| s ^ b)),
+        }
+    }
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    // Bit reflected, so the polynomial is 0x04C11DB7 backwards
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_values() {
+        // The standard check input for CRC catalogues
+        let bytes = b"123456789";
+
+        assert_eq!(Checksum::Crc16.compute(bytes), 0x29B1);
+        assert_eq!(Checksum::Crc32.compute(bytes), 0xCBF4_3926);
+        assert_eq!(Checksum::Sum8.compute(bytes), 0xDD);
+        assert_eq!(Checksum::Xor8.compute(bytes), 0x31);
+        assert_eq!(Checksum::Crc32.compute(&[]), 0);
+    }
+}
@@ -0,0 +1,215 @@
+use super::blocks::BlockStore;
+use super::blocks::{BLOCK_SIZE, LINES_PER_BLOCK, LINE_LENGTH};
+use super::tui;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use std::io;
+use std::io::Write;
+
+/// What the editor should do after a key press
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditorAction {
+    Continue,
+    Save,
+    SaveAndQuit,
+    Quit,
+}
+
+/// A full screen editor for one block, typing overwrites like the traditional Forth screen
+/// editors. Arrows move, Enter goes to the start of the next line, Ctrl-S saves, Esc saves and
+/// quits and Ctrl-Q quits without saving.
+pub struct ScreenEditor {
+    block: Vec<u8>,
+    row: usize,
+    column: usize,
+    modified: bool,
+}
+
+impl ScreenEditor {
+    pub fn new(block: Vec<u8>) -> ScreenEditor {
+        let mut block = block;
+        block.resize(BLOCK_SIZE, b' ');
+        ScreenEditor {
+            block,
+            row: 0,
+            column: 0,
+            modified: false,
+        }
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.block
+    }
+
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> EditorAction {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('s') if control => {
+                self.modified = false;
+                return EditorAction::Save;
+            }
+            KeyCode::Char('q') if control => return EditorAction::Quit,
+            KeyCode::Esc => {
+                return if self.modified {
+                    EditorAction::SaveAndQuit
+                } else {
+                    EditorAction::Quit
+                }
+            }
+            KeyCode::Char(c) if c.is_ascii() && !c.is_ascii_control() => {
+                self.block[self.row * LINE_LENGTH + self.column] = c as u8;
+                self.modified = true;
+                self.advance();
+            }
+            KeyCode::Backspace if self.column > 0 => {
+                self.column -= 1;
+                self.block[self.row * LINE_LENGTH + self.column] = b' ';
+                self.modified = true;
+            }
+            KeyCode::Enter => {
+                self.column = 0;
+                self.row = (self.row + 1).min(LINES_PER_BLOCK - 1);
+            }
+            KeyCode::Left => self.column = self.column.saturating_sub(1),
+            KeyCode::Right => self.column = (self.column + 1).min(LINE_LENGTH - 1),
+            KeyCode::Up => self.row = self.row.saturating_sub(1),
+            KeyCode::Down => self.row = (self.row + 1).min(LINES_PER_BLOCK - 1),
+            KeyCode::Home => self.column = 0,
+            KeyCode::End => self.column = LINE_LENGTH - 1,
+            _ => (),
+        }
+        EditorAction::Continue
+    }
+
+    // Move along after typing a character, wrapping onto the next line
+    fn advance(&mut self) {
+        if self.column + 1 < LINE_LENGTH {
+            self.column += 1;
+        } else if self.row + 1 < LINES_PER_BLOCK {
+            self.column = 0;
+            self.row += 1;
+        }
+    }
+
+    /// Draw the whole screen, the block's lines under a title with the cursor left where typing goes
+    pub fn render(&self, output: &mut dyn Write, block_number: usize) -> io::Result<()> {
+        tui::page(output)?;
+        write!(
+            output,
+            "Screen {}{}   Esc save and quit, Ctrl-S save, Ctrl-Q quit",
+            block_number,
+            if self.modified { " (modified)" } else { "" }
+        )?;
+        for (i, line) in self.block.chunks(LINE_LENGTH).enumerate() {
+            tui::at_xy(output, 0, i as i64 + 1)?;
+            write!(output, "{:>2} {}", i, String::from_utf8_lossy(line))?;
+        }
+        tui::at_xy(output, self.column as i64 + 3, self.row as i64 + 1)
+    }
+}
+
+/// Edit a block interactively on the terminal, saving the store whenever the block is saved
+pub fn edit(store: &mut BlockStore, block_number: usize, output: &mut dyn Write) -> io::Result<()> {
+    let mut editor = ScreenEditor::new(store.block(block_number));
+
+    terminal::enable_raw_mode()?;
+    let result = (|| loop {
+        editor.render(output, block_number)?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            let action = editor.handle_key(key);
+            if action == EditorAction::Save || action == EditorAction::SaveAndQuit {
+                store.set_block(block_number, editor.contents());
+                store.save()?;
+            }
+            if action == EditorAction::Quit || action == EditorAction::SaveAndQuit {
+                return Ok(());
+            }
+        }
+    })();
+    terminal::disable_raw_mode()?;
+    tui::page(output)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_editing() {
+        let mut editor = ScreenEditor::new(b"1 2 ADD".to_vec());
+
+        for c in "3".chars() {
+            editor.handle_key(key(KeyCode::Char(c)));
+        }
+        editor.handle_key(key(KeyCode::End));
+        editor.handle_key(key(KeyCode::Char('X')));
+        assert_eq!(editor.cursor(), (0, 1));
+        editor.handle_key(key(KeyCode::Char('4')));
+        editor.handle_key(key(KeyCode::Char('5')));
+        editor.handle_key(key(KeyCode::Backspace));
+        editor.handle_key(key(KeyCode::Enter));
+        editor.handle_key(key(KeyCode::Down));
+        editor.handle_key(key(KeyCode::Char('.')));
+
+        assert!(editor.modified());
+        let mut store = BlockStore::new();
+        store.set_block(0, editor.contents());
+        let lines = store.lines(0);
+        assert_eq!(lines[0], format!("3 2 ADD{}X", " ".repeat(56)));
+        assert_eq!(lines[1], "4");
+        assert_eq!(lines[2], "");
+        assert_eq!(lines[3], ".");
+    }
+
+    #[test]
+    fn test_editor_actions() {
+        let mut editor = ScreenEditor::new(Vec::new());
+
+        assert_eq!(editor.handle_key(key(KeyCode::Esc)), EditorAction::Quit);
+        editor.handle_key(key(KeyCode::Char('1')));
+        assert_eq!(
+            editor.handle_key(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL)),
+            EditorAction::Save
+        );
+        assert!(!editor.modified());
+        editor.handle_key(key(KeyCode::Char('2')));
+        assert_eq!(
+            editor.handle_key(key(KeyCode::Esc)),
+            EditorAction::SaveAndQuit
+        );
+        assert_eq!(
+            editor.handle_key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL)),
+            EditorAction::Quit
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let editor = ScreenEditor::new(b"1 2 ADD".to_vec());
+        let mut output = Vec::new();
+
+        editor.render(&mut output, 7).unwrap();
+
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.starts_with("\x1b[2J\x1b[1;1HScreen 7   Esc"));
+        assert!(text.contains("\x1b[2;1H 0 1 2 ADD   "));
+        assert!(text.ends_with("\x1b[2;4H"));
+    }
+}
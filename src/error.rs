@@ -1,3 +1,6 @@
+use super::forth_compiler::SourceLocation;
+use super::quota::Quota;
+use super::stack_machine::IntegrityViolation;
 use super::stack_machine::StackMachineError;
 
 /// This Enum lists the errors that the Forth Interpreter might return
@@ -11,6 +14,40 @@ pub enum ForthError {
     Io(std::io::Error),
     UnhandledTrap,
     RanOutOfGas,
+    AssertionFailed(Option<SourceLocation>),
+    DivisionByZero,
+    TooManyBigNumbers,
+    UnknownSystemCall(u16),
+    DictionaryFull,
+    TrapNotPermitted(i64),
+    InvalidBlockNumber(i64),
+    InvalidExecutionToken(i64),
+    IntegrityViolation(Box<IntegrityViolation>),
+    OutOfDomain(i64),
+    InvalidAddress(i64),
+    DataSpaceFull,
+    IndexOutOfBounds(i64),
+    HostNotPermitted(String),
+    QuotaExceeded(Quota),
+    MemoryLimitExceeded(usize),
+    DefinitionFailed(Box<DefinitionError>),
+    LoopStackUnderflow,
+    InvalidCodeAddress(i64),
+    UnknownExtOpcode(u16),
+    NestingTooDeep(usize),
+    UnansweredRequest(i64),
+    UnresolvedExterns(Vec<String>),
+    SyntaxErrors(Vec<(SourceLocation, String)>),
+}
+
+/// A word definition in a source string that failed to compile
+#[derive(Debug)]
+pub struct DefinitionError {
+    pub word: String,
+    // How many definitions before it in the same source compiled, whether or not they were kept
+    // depends on the compiler's DefinitionFailureMode
+    pub compiled: usize,
+    pub error: ForthError,
 }
 
 /// Convert io::Errors to a ForthError so our Interpreter functions can
@@ -30,6 +67,21 @@ impl From<StackMachineError> for ForthError {
             StackMachineError::UnkownError => ForthError::UnknownError,
             StackMachineError::UnhandledTrap => ForthError::UnhandledTrap,
             StackMachineError::RanOutOfGas => ForthError::RanOutOfGas,
+            StackMachineError::AssertionFailed(_) => ForthError::AssertionFailed(None),
+            StackMachineError::Io(e) => ForthError::Io(e),
+            StackMachineError::DivisionByZero => ForthError::DivisionByZero,
+            StackMachineError::TooManyBigNumbers => ForthError::TooManyBigNumbers,
+            StackMachineError::TrapNotPermitted(id) => ForthError::TrapNotPermitted(id),
+            StackMachineError::IntegrityViolation(v) => ForthError::IntegrityViolation(v),
+            StackMachineError::OutOfDomain(n) => ForthError::OutOfDomain(n),
+            StackMachineError::InvalidAddress(a) => ForthError::InvalidAddress(a),
+            StackMachineError::DataSpaceFull => ForthError::DataSpaceFull,
+            StackMachineError::IndexOutOfBounds(i) => ForthError::IndexOutOfBounds(i),
+            StackMachineError::HostNotPermitted(h) => ForthError::HostNotPermitted(h),
+            StackMachineError::MemoryLimitExceeded(n) => ForthError::MemoryLimitExceeded(n),
+            StackMachineError::LoopStackUnderflow => ForthError::LoopStackUnderflow,
+            StackMachineError::InvalidCodeAddress(a) => ForthError::InvalidCodeAddress(a),
+            StackMachineError::UnknownExtOpcode(id) => ForthError::UnknownExtOpcode(id),
         }
     }
 }
@@ -46,6 +98,30 @@ impl From<ForthError> for i32 {
             ForthError::Io(_) => 7,
             ForthError::UnhandledTrap => 8,
             ForthError::RanOutOfGas => 9,
+            ForthError::AssertionFailed(_) => 10,
+            ForthError::DivisionByZero => 11,
+            ForthError::TooManyBigNumbers => 12,
+            ForthError::UnknownSystemCall(_) => 13,
+            ForthError::DictionaryFull => 14,
+            ForthError::TrapNotPermitted(_) => 15,
+            ForthError::InvalidBlockNumber(_) => 16,
+            ForthError::InvalidExecutionToken(_) => 17,
+            ForthError::IntegrityViolation(_) => 18,
+            ForthError::OutOfDomain(_) => 19,
+            ForthError::InvalidAddress(_) => 20,
+            ForthError::DataSpaceFull => 21,
+            ForthError::IndexOutOfBounds(_) => 22,
+            ForthError::HostNotPermitted(_) => 23,
+            ForthError::QuotaExceeded(_) => 24,
+            ForthError::MemoryLimitExceeded(_) => 25,
+            ForthError::DefinitionFailed(_) => 26,
+            ForthError::LoopStackUnderflow => 27,
+            ForthError::InvalidCodeAddress(_) => 28,
+            ForthError::UnknownExtOpcode(_) => 29,
+            ForthError::NestingTooDeep(_) => 30,
+            ForthError::UnansweredRequest(_) => 31,
+            ForthError::UnresolvedExterns(_) => 32,
+            ForthError::SyntaxErrors(_) => 33,
         }
     }
 }
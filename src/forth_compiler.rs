@@ -1,27 +1,254 @@
-use super::error::ForthError;
+use super::artifact::CompilationArtifact;
+use super::artifact::WordInfo;
+use super::blocks::BlockStore;
+use super::checksum::Checksum;
+use super::error::{DefinitionError, ForthError};
+use super::handlers;
+use super::handlers::HandlerPack;
+use super::host::{
+    Clock, FileKeyValueStore, FileSystem, IoBus, KeyValueStore, NullIoBus, OsFileSystem,
+    SystemClock,
+};
+use super::metrics::Metrics;
+use super::quota::{is_output_quota_error, MeteredOutput, Quota, QuotaUsage, Quotas};
+use super::sandbox::SandboxPolicy;
+use super::saved_session::SavedSession;
+use super::session::{update_definitions, Definition, DefinitionUpdate};
+use super::stack_machine::ExecutionStatus;
 use super::stack_machine::GasLimit;
+use super::stack_machine::HostRequest;
 use super::stack_machine::Opcode;
 use super::stack_machine::StackMachine;
+use super::stack_machine::TrapGuard;
+use super::stack_machine::CELL_SIZE;
+use super::stack_machine::{Profiler, WordProfile};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
 
+pub use super::stack_machine::ArithmeticMode;
+pub use super::stack_machine::CycleModel;
+pub use super::stack_machine::DivisionMode;
+pub use super::stack_machine::ExtOpcodeHandler;
 pub use super::stack_machine::HandleTrap;
+pub use super::stack_machine::IntegrityChecks;
+pub use super::stack_machine::SharedBuffer;
 pub use super::stack_machine::StackMachineError;
 pub use super::stack_machine::TrapHandled;
 pub use super::stack_machine::TrapHandler;
+pub use super::stack_machine::Truthiness;
+pub use super::stack_machine::Watchdog;
+pub use super::stack_machine::WordTrace;
+
+/// Where in the source text a token was found, lines and columns start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+}
 
 /// This Enum lists the token types that are used by the Forth interpreter
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Number(i64),
     Command(String),
     Colon(String),
     SemiColon,
+    Assert(SourceLocation),
+    // ON-EVENT with the event name and the word that handles it
+    OnEvent(String, String),
+    // ' with the word whose execution token is wanted
+    Tick(String),
+    // ARRAY with the name of the array it defines
+    Array(String),
+    // VARIABLE with the name of the variable it defines
+    Variable(String),
+    // CREATE outside a definition, with the name of the word it makes
+    Create(String),
+    // REQUIRE with the file it needs loaded
+    Require(String),
+    // EXTERN with the name of the host word it stands for
+    Extern(String),
+    // ." with the text it prints
+    DotQuote(String),
+    // S" with the text it gives the address and length of
+    SQuote(String),
+    // TRAP: with the name of the word it defines, the trap the word raises and the stack effect
+    // declared for it
+    TrapWord(String, i64, (usize, usize)),
     End,
     Error(String),
 }
 
+/// What happens to the words a source string defined before one of its definitions failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefinitionFailureMode {
+    /// They stay in the dictionary
+    KeepSuccessful,
+    /// The dictionary goes back to how it was before the source was compiled
+    AbortAll,
+}
+
+/// How much ForthCompiler::reset takes back. The host's own setup, like intrinsic words, aliases,
+/// handlers, quotas and where files and output go, is kept at every level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetLevel {
+    /// The stacks, big integers and any suspended run, keeping the words and data space
+    Stacks,
+    /// Back to how it was once the prelude was loaded, see mark_prelude
+    UserWords,
+    /// Back to an empty dictionary and data space, prelude and all
+    Everything,
+}
+
+/// The words a run was nested inside when it failed, innermost first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Backtrace {
+    pub words: Vec<String>,
+}
+
+/// Reads "in SQUARE called from DRAW called from MAIN", or nothing when there are no words
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, word) in self.words.iter().enumerate() {
+            if i == 0 {
+                write!(f, "in {}", word)?;
+            } else {
+                write!(f, " called from {}", word)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size information about everything the compiler has built so far
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerStats {
+    pub words: usize,
+    // Opcodes in the dictionary, not counting immediate mode code
+    pub opcode_memory: usize,
+    pub data_space_bytes: usize,
+    pub string_space_bytes: usize,
+    // The number and return stacks
+    pub stack_bytes: usize,
+    // Every opcode, immediate mode code included
+    pub dictionary_bytes: usize,
+    // Everything the StackMachine's state is using, what ForthCompiler::set_memory_limit limits
+    pub memory_bytes: usize,
+    // The name and opcode count of the biggest word
+    pub largest_word: Option<(String, usize)>,
+}
+
+impl fmt::Display for CompilerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "words: {}", self.words)?;
+        writeln!(f, "opcode memory: {}", self.opcode_memory)?;
+        writeln!(f, "data space bytes: {}", self.data_space_bytes)?;
+        writeln!(f, "string space bytes: {}", self.string_space_bytes)?;
+        writeln!(f, "stack bytes: {}", self.stack_bytes)?;
+        writeln!(f, "dictionary bytes: {}", self.dictionary_bytes)?;
+        writeln!(f, "memory bytes: {}", self.memory_bytes)?;
+        match &self.largest_word {
+            Some((name, length)) => writeln!(f, "largest word: {} ({} opcodes)", name, length),
+            None => writeln!(f, "largest word: none"),
+        }
+    }
+}
+
+// What compiling a source string can change in the dictionary, to put back when it fails
+struct DictionarySnapshot {
+    last_function: usize,
+    word_addresses: HashMap<String, usize>,
+    word_lengths: HashMap<String, usize>,
+    arrays: HashMap<String, usize>,
+    event_handlers: HashMap<String, String>,
+    externs: HashMap<String, usize>,
+    data_words: HashSet<usize>,
+    defining_words: HashSet<String>,
+    declared_effects: HashMap<String, (usize, usize)>,
+    assertions: usize,
+    here: i64,
+    string_space_bytes: usize,
+}
+
+/// The state of a ForthCompiler to go back to with restore_checkpoint, such as once its prelude
+/// is loaded. The host's own setup, like intrinsic words, handlers and quotas, isn't part of it
+/// and is kept as it is, apart from the TRAPs exposed to its words, which go with the words.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    session: SavedSession,
+    trap_guard: TrapGuard,
+    source_map: Vec<SourceLocation>,
+    required_files: HashSet<String>,
+    reloaded_files: HashMap<String, Vec<Definition>>,
+}
+
+// A word scheduled to run once the host's clock reaches a time
+#[derive(Debug, Clone)]
+struct Timer {
+    due: u64,
+    // EVERY timers run again this many milliseconds later
+    interval: Option<u64>,
+    address: usize,
+}
+
+// Where a run starts, new code at an address or the suspended run with the host's answer
+#[derive(Debug, Clone, Copy)]
+enum RunStart<'a> {
+    At(usize),
+    Resume(&'a [i64]),
+}
+
+// Services that compiled code asks the compiler for with a SYSCALL opcode
+const SYSCALL_PRINT_STATS: u16 = 1;
+#[cfg(feature = "tui")]
+const SYSCALL_AT_XY: u16 = 2;
+#[cfg(feature = "tui")]
+const SYSCALL_PAGE: u16 = 3;
+#[cfg(feature = "tui")]
+const SYSCALL_KEY_QUESTION: u16 = 4;
+const SYSCALL_LIST: u16 = 5;
+#[cfg(feature = "editor")]
+const SYSCALL_EDIT: u16 = 6;
+const SYSCALL_AFTER: u16 = 7;
+const SYSCALL_EVERY: u16 = 8;
+const SYSCALL_MS_FETCH: u16 = 9;
+const SYSCALL_IN: u16 = 10;
+const SYSCALL_OUT: u16 = 11;
+const SYSCALL_ARRAY: u16 = 12;
+const SYSCALL_KV_STORE: u16 = 13;
+const SYSCALL_KV_FETCH: u16 = 14;
+const SYSCALL_CREATED_WORD: u16 = 15;
+const SYSCALL_CREATE: u16 = 16;
+const SYSCALL_DOES: u16 = 17;
+const SYSCALL_EVALUATE: u16 = 18;
+const SYSCALL_UNBOUND_EXTERN: u16 = 19;
+const SYSCALL_PROFILE_ON: u16 = 20;
+const SYSCALL_PROFILE_OFF: u16 = 21;
+const SYSCALL_PROFILE_REPORT: u16 = 22;
+
+/// How many opcodes the stub an EXTERN word is compiled to takes up
+pub(crate) const EXTERN_STUB_LENGTH: usize = 3;
+
+// What set_definition_hook keeps
+type DefinitionHook = Box<dyn FnMut(&WordInfo, bool)>;
+
+/// How deeply EVALUATE may run code inside code that is already running, unless the host sets
+/// another limit
+pub const DEFAULT_MAX_NESTING: usize = 16;
+
+/// The file KV! and KV@ use unless the host gives the compiler another KeyValueStore
+pub const DEFAULT_KEY_VALUE_FILE: &str = "rust_forth.kv";
+
 // This macro lets you statically initialize a hashmap
 macro_rules! hashmap {
     ($( $key: expr => $val: expr ),*) => {{
@@ -35,36 +262,687 @@ pub struct ForthCompiler {
     // This is the Stack Machine processor that runs the compiled Forth instructions
     pub sm: StackMachine,
     // These are the words that we know how to work with regardless, things like POP, MUL, etc
-    intrinsic_words: HashMap<&'static str, Vec<Opcode>>,
+    intrinsic_words: HashMap<String, Vec<Opcode>>,
     // This is where we remember where we put compiled words in the *memory* of the StackMachine
     // We run the interactive opcodes after these compiled words, and then erase the memory after
     // the compiled words again for the next batch of interactive opcodes.
     word_addresses: HashMap<String, usize>,
+    // Other names for words, looked up before anything else so the host can present its own dialect
+    aliases: HashMap<String, String>,
+    // How many opcodes each compiled word takes up, including its RET
+    word_lengths: HashMap<String, usize>,
     // This is the location in memory that points to the location after the last compiled opcode
     // So its an ideal place to run interactive compiled opcodes
     last_function: usize,
+    // The source location each opcode in memory was compiled from, kept the same length as the opcodes
+    source_map: Vec<SourceLocation>,
+    // The most opcodes the StackMachine's memory may hold, including immediate mode code
+    opcode_memory_limit: Option<usize>,
+    // What scripts are allowed to do
+    sandbox_policy: SandboxPolicy,
+    // When true, a CompilationArtifact is built after every compile
+    artifacts_enabled: bool,
+    last_artifact: Option<CompilationArtifact>,
+    // When false, ASSERT( ... ) blocks are compiled to nothing
+    assertions_enabled: bool,
+    // The source location of every compiled ASSERT( ... ), indexed by the assertion id the
+    // ASSERT opcode reports when it fails
+    assertion_locations: Vec<SourceLocation>,
+    // Where the block words find their blocks
+    block_store: BlockStore,
+    // The word that handles each event, registered with ON-EVENT
+    event_handlers: HashMap<String, String>,
+    // Events the host has posted that haven't been handled yet, with their payloads
+    event_queue: VecDeque<(String, Vec<i64>)>,
+    // Words scheduled with AFTER and EVERY
+    timers: Vec<Timer>,
+    // The time the host last gave tick, in milliseconds
+    current_time: u64,
+    // Where MS@ gets the time
+    clock: Box<dyn Clock>,
+    // The ports IN and OUT talk to
+    io_bus: Box<dyn IoBus>,
+    // Where source files are read from
+    file_system: Box<dyn FileSystem>,
+    // Where KV! and KV@ keep values
+    key_value_store: Box<dyn KeyValueStore>,
+    // The address of the word each ARRAY defined
+    arrays: HashMap<String, usize>,
+    quotas: Quotas,
+    quota_usage: QuotaUsage,
+    definition_failure_mode: DefinitionFailureMode,
+    // How many cells of the stack the word trace shows, no trace when None
+    word_trace_cells: Option<usize>,
+    last_backtrace: Backtrace,
+    // The definitions each file given to reload_file had the last time it was read
+    reloaded_files: HashMap<String, Vec<Definition>>,
+    // The files already loaded, so REQUIRE only loads each one once
+    required_files: HashSet<String>,
+    // Words whose definitions use CREATE, each use of one is followed by the name of the word
+    // it makes
+    defining_words: HashSet<String>,
+    // The stack effects TRAP: declared, by word, until the word is redefined
+    declared_effects: HashMap<String, (usize, usize)>,
+    // The word the next CREATE gives its data address, set just before a defining word runs
+    word_to_create: Option<usize>,
+    // The word CREATE last made, which DOES> gives its action
+    last_created: Option<usize>,
+    // How many runs EVALUATE has started inside other runs that haven't finished yet
+    nesting: usize,
+    max_nesting: usize,
+    // A run stopped by YIELD is waiting to be resumed
+    suspended: bool,
+    // The data space holding the text of the immediate mode code's string literals, given back
+    // once the code has run
+    scratch_data: Range<i64>,
+    // The ids of the immediate mode code's assertions, forgotten once the code has run
+    scratch_assertions: Range<usize>,
+    // How much of the data space S" and ." have taken for their text
+    string_space_bytes: usize,
+    // The address of the stub each EXTERN word was compiled to, bound to the intrinsic of the
+    // same name whenever there is one
+    externs: HashMap<String, usize>,
+    // The LDIs in the dictionary that push a data space address, at the start of each word made
+    // by VARIABLE or CREATE and in words with string literals
+    data_words: HashSet<usize>,
+    // How the compiler was once the prelude was loaded, what reset goes back to for UserWords
+    prelude: Option<Checkpoint>,
+    // Told about every word put into the dictionary, see set_definition_hook
+    definition_hook: Option<DefinitionHook>,
+    // The counters metrics reports, the gauges are read when it is called
+    metrics: Metrics,
 }
 
 impl ForthCompiler {
     pub fn new() -> ForthCompiler {
         ForthCompiler {
             sm: StackMachine::new(),
-            intrinsic_words: hashmap![
+            intrinsic_words: ForthCompiler::default_intrinsic_words(),
+            word_addresses: HashMap::new(),
+            word_lengths: HashMap::new(),
+            aliases: HashMap::new(),
+            last_function: 0,
+            source_map: Vec::new(),
+            opcode_memory_limit: None,
+            sandbox_policy: SandboxPolicy::unrestricted(),
+            artifacts_enabled: false,
+            last_artifact: None,
+            assertions_enabled: true,
+            assertion_locations: Vec::new(),
+            block_store: BlockStore::new(),
+            event_handlers: HashMap::new(),
+            event_queue: VecDeque::new(),
+            timers: Vec::new(),
+            current_time: 0,
+            clock: Box::new(SystemClock::new()),
+            io_bus: Box::new(NullIoBus),
+            file_system: Box::new(OsFileSystem),
+            key_value_store: Box::new(FileKeyValueStore::new(DEFAULT_KEY_VALUE_FILE)),
+            arrays: HashMap::new(),
+            quotas: Quotas::unlimited(),
+            quota_usage: QuotaUsage::default(),
+            definition_failure_mode: DefinitionFailureMode::KeepSuccessful,
+            word_trace_cells: None,
+            last_backtrace: Backtrace::default(),
+            reloaded_files: HashMap::new(),
+            required_files: HashSet::new(),
+            defining_words: HashSet::new(),
+            declared_effects: HashMap::new(),
+            word_to_create: None,
+            last_created: None,
+            nesting: 0,
+            max_nesting: DEFAULT_MAX_NESTING,
+            suspended: false,
+            scratch_data: 0..0,
+            scratch_assertions: 0..0,
+            string_space_bytes: 0,
+            externs: HashMap::new(),
+            data_words: HashSet::new(),
+            prelude: None,
+            definition_hook: None,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// The intrinsic words a new ForthCompiler starts with
+    pub fn default_intrinsic_words() -> HashMap<String, Vec<Opcode>> {
+        let words: HashMap<&str, Vec<Opcode>> = hashmap![
             "POP" => vec![Opcode::POP],
+            "DROP" => vec![Opcode::POP],
+            "NIP" => vec![Opcode::SWAP, Opcode::POP],
+            "TUCK" => vec![Opcode::SWAP, Opcode::OVER],
             "SWAP" => vec![Opcode::SWAP],
+            "OVER" => vec![Opcode::OVER],
+            "2DUP" => vec![Opcode::OVER, Opcode::OVER],
+            "2DROP" => vec![Opcode::POP, Opcode::POP],
+            "2SWAP" => vec![Opcode::ROLL(3), Opcode::ROLL(3)],
+            "2OVER" => vec![Opcode::PICK(3), Opcode::PICK(3)],
             "ADD" => vec![Opcode::ADD],
             "SUB" => vec![Opcode::SUB],
             "MUL" => vec![Opcode::MUL],
             "DIV" => vec![Opcode::DIV],
+            "SM/REM" => vec![Opcode::SMREM],
+            "FM/MOD" => vec![Opcode::FMMOD],
+            "/MOD" => vec![Opcode::DIVMOD],
+            "MOD" => vec![Opcode::DIVMOD, Opcode::POP],
             "DUP" => vec![Opcode::DUP],
+            "NEGATE" => vec![Opcode::NEGATE],
+            "ABS" => vec![Opcode::ABS],
+            "MIN" => vec![Opcode::MIN],
+            "MAX" => vec![Opcode::MAX],
+            "AND" => vec![Opcode::AND],
+            "OR" => vec![Opcode::OR],
+            "XOR" => vec![Opcode::XOR],
+            "INVERT" => vec![Opcode::NOT],
+            "LSHIFT" => vec![Opcode::LSHIFT],
+            "RSHIFT" => vec![Opcode::RSHIFT],
             "TRAP" => vec![Opcode::TRAP],
             "INC" => vec![Opcode::LDI(1),Opcode::ADD],
-            "DEC" => vec![Opcode::LDI(-1),Opcode::ADD]
-            ],
-            word_addresses: HashMap::new(),
-            last_function: 0,
+            "DEC" => vec![Opcode::LDI(-1),Opcode::ADD],
+            "U." => vec![Opcode::UDOT],
+            "." => vec![Opcode::DOT],
+            ".S" => vec![Opcode::DOTS],
+            "TYPE" => vec![Opcode::TYPE],
+            "U<" => vec![Opcode::ULT],
+            "U>" => vec![Opcode::UGT],
+            "=" => vec![Opcode::EQ],
+            "<>" => vec![Opcode::EQ,Opcode::NOT],
+            "<" => vec![Opcode::LT],
+            ">" => vec![Opcode::GT],
+            "<=" => vec![Opcode::GT,Opcode::NOT],
+            ">=" => vec![Opcode::LT,Opcode::NOT],
+            "0=" => vec![Opcode::LDI(0),Opcode::EQ],
+            "0<" => vec![Opcode::LDI(0),Opcode::LT],
+            "0>" => vec![Opcode::LDI(0),Opcode::GT],
+            "UM*" => vec![Opcode::UMMUL],
+            "SQRT" => vec![Opcode::SQRT],
+            "GCD" => vec![Opcode::GCD],
+            "LCM" => vec![Opcode::LCM],
+            "POW" => vec![Opcode::POW],
+            "LOG2" => vec![Opcode::LOG2],
+            "+SAT" => vec![Opcode::ADDSAT],
+            "*SAT" => vec![Opcode::MULSAT],
+            "+OV" => vec![Opcode::ADDOV],
+            "[]@" => vec![Opcode::ARRAYFETCH],
+            "[]!" => vec![Opcode::ARRAYSTORE],
+            "@" => vec![Opcode::FETCH],
+            "!" => vec![Opcode::STORE],
+            "C@" => vec![Opcode::CFETCH],
+            "C!" => vec![Opcode::CSTORE],
+            "CMOVE" => vec![Opcode::CMOVE],
+            "CELLS" => vec![Opcode::LDI(CELL_SIZE),Opcode::MUL],
+            "CELL+" => vec![Opcode::LDI(CELL_SIZE),Opcode::ADD],
+            "HERE" => vec![Opcode::HERE],
+            "ALLOT" => vec![Opcode::ALLOT],
+            "RDEPTH" => vec![Opcode::RDEPTH],
+            "GAS-USED" => vec![Opcode::GASUSED],
+            "GAS-REMAINING" => vec![Opcode::GASLEFT],
+            "YIELD" => vec![Opcode::YIELD],
+            "REQUEST" => vec![Opcode::REQUEST],
+            "CRC16" => vec![Opcode::CHECKSUM(Checksum::Crc16)],
+            "CRC32" => vec![Opcode::CHECKSUM(Checksum::Crc32)],
+            "CHECKSUM8" => vec![Opcode::CHECKSUM(Checksum::Sum8)],
+            "XOR8" => vec![Opcode::CHECKSUM(Checksum::Xor8)],
+            "I" => vec![Opcode::INDEX(0)],
+            "J" => vec![Opcode::INDEX(1)],
+            "UNLOOP" => vec![Opcode::UNLOOP],
+            "LE16@" => vec![Opcode::FETCHLE(2)],
+            "LE32@" => vec![Opcode::FETCHLE(4)],
+            "LE64@" => vec![Opcode::FETCHLE(8)],
+            "BE16@" => vec![Opcode::FETCHBE(2)],
+            "BE32@" => vec![Opcode::FETCHBE(4)],
+            "BE64@" => vec![Opcode::FETCHBE(8)],
+            "LE16!" => vec![Opcode::STORELE(2)],
+            "LE32!" => vec![Opcode::STORELE(4)],
+            "LE64!" => vec![Opcode::STORELE(8)],
+            "BE16!" => vec![Opcode::STOREBE(2)],
+            "BE32!" => vec![Opcode::STOREBE(4)],
+            "BE64!" => vec![Opcode::STOREBE(8)],
+            ".STATS" => vec![Opcode::SYSCALL(SYSCALL_PRINT_STATS)],
+            ".VS" => vec![Opcode::DOTVS],
+            "LIST" => vec![Opcode::SYSCALL(SYSCALL_LIST)],
+            "KV!" => vec![Opcode::SYSCALL(SYSCALL_KV_STORE)],
+            "KV@" => vec![Opcode::SYSCALL(SYSCALL_KV_FETCH)],
+            "CREATE" => vec![Opcode::SYSCALL(SYSCALL_CREATE)],
+            "EVALUATE" => vec![Opcode::SYSCALL(SYSCALL_EVALUATE)],
+            "PROFILE-ON" => vec![Opcode::SYSCALL(SYSCALL_PROFILE_ON)],
+            "PROFILE-OFF" => vec![Opcode::SYSCALL(SYSCALL_PROFILE_OFF)],
+            "PROFILE-REPORT" => vec![Opcode::SYSCALL(SYSCALL_PROFILE_REPORT)],
+            "AFTER" => vec![Opcode::SYSCALL(SYSCALL_AFTER)],
+            "EVERY" => vec![Opcode::SYSCALL(SYSCALL_EVERY)],
+            "MS@" => vec![Opcode::SYSCALL(SYSCALL_MS_FETCH)],
+            "IN" => vec![Opcode::SYSCALL(SYSCALL_IN)],
+            "OUT" => vec![Opcode::SYSCALL(SYSCALL_OUT)]
+        ];
+        // Terminal control, for full screen programs
+        #[cfg(feature = "tui")]
+        let words = {
+            let mut words = words;
+            words.extend(vec![
+                ("AT-XY", vec![Opcode::SYSCALL(SYSCALL_AT_XY)]),
+                ("PAGE", vec![Opcode::SYSCALL(SYSCALL_PAGE)]),
+                ("KEY?", vec![Opcode::SYSCALL(SYSCALL_KEY_QUESTION)]),
+            ]);
+            words
+        };
+        #[cfg(feature = "editor")]
+        let words = {
+            let mut words = words;
+            words.insert("EDIT", vec![Opcode::SYSCALL(SYSCALL_EDIT)]);
+            words
+        };
+        words
+            .into_iter()
+            .map(|(name, opcodes)| (name.to_owned(), opcodes))
+            .collect()
+    }
+
+    /// Replace the whole intrinsic word set, for example to build a compiler without DIV or TRAP
+    pub fn with_intrinsics<I>(mut self, words: I) -> ForthCompiler
+    where
+        I: IntoIterator<Item = (String, Vec<Opcode>)>,
+    {
+        self.intrinsic_words = words.into_iter().collect();
+        self
+    }
+
+    /// Add intrinsic words, each one compiles inline to its list of opcodes. Words with the
+    /// same name as an existing intrinsic replace it.
+    pub fn with_additional_intrinsics<I>(mut self, words: I) -> ForthCompiler
+    where
+        I: IntoIterator<Item = (String, Vec<Opcode>)>,
+    {
+        self.intrinsic_words.extend(words);
+        self
+    }
+
+    /// Remove intrinsic words so that scripts can't use them
+    pub fn without_intrinsics<'a, I>(mut self, names: I) -> ForthCompiler
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for name in names {
+            self.intrinsic_words.remove(name);
+        }
+        self
+    }
+
+    /// Add a trap handler. Builder calls take effect in the order they are written, so add the
+    /// host's handlers and the words that reach them before with_prelude runs source that uses
+    /// them.
+    pub fn with_trap_handler<H>(mut self, handler: H) -> ForthCompiler
+    where
+        H: HandleTrap + 'static,
+    {
+        self.sm.trap_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Add a word that compiles to the host's own EXT opcode, along with the handler that runs it.
+    /// Words that need to be fast, such as fixed point DSP primitives, skip the TRAP protocol.
+    pub fn with_ext_opcode<H>(mut self, word: &str, id: u16, handler: H) -> ForthCompiler
+    where
+        H: ExtOpcodeHandler + 'static,
+    {
+        self.sm.ext_opcodes.insert(id, Box::new(handler));
+        self.intrinsic_words
+            .insert(word.to_owned(), vec![Opcode::EXT(id)]);
+        self
+    }
+
+    /// Add a handler pack's words and its trap handler, like handlers::install
+    pub fn with_handler_pack<P>(self, pack: P) -> ForthCompiler
+    where
+        P: HandlerPack + 'static,
+    {
+        handlers::install(self, pack)
+    }
+
+    /// Run prelude source such as init.forth, with everything the builder calls before it
+    /// already in place, so the prelude can define words that wrap the host's traps
+    pub fn with_prelude(
+        mut self,
+        source: &str,
+        gas_limit: GasLimit,
+    ) -> Result<ForthCompiler, ForthError> {
+        self.execute_string(source, gas_limit)?;
+        self.mark_prelude();
+        Ok(self)
+    }
+
+    /// Read prelude source through the compiler's FileSystem and run it like with_prelude
+    pub fn with_prelude_file(
+        mut self,
+        path: &str,
+        gas_limit: GasLimit,
+    ) -> Result<ForthCompiler, ForthError> {
+        self.include_file(path, gas_limit)?;
+        self.mark_prelude();
+        Ok(self)
+    }
+
+    /// Make alias another name for word, for example "+" for ADD. The word can be an intrinsic,
+    /// a compiled word or a control word like IF. Aliases take priority over words with the
+    /// same name, and only affect code compiled after they are added.
+    pub fn add_alias(&mut self, alias: &str, word: &str) {
+        self.aliases.insert(alias.to_owned(), word.to_owned());
+    }
+
+    /// Remove an alias, returning the word it stood for
+    pub fn remove_alias(&mut self, alias: &str) -> Option<String> {
+        self.aliases.remove(alias)
+    }
+
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    /// Whether the word is in the dictionary or is an intrinsic, looking through aliases
+    pub fn knows_word(&self, word: &str) -> bool {
+        let word = self.aliases.get(word).map(|w| w.as_str()).unwrap_or(word);
+        self.word_addresses.contains_key(word) || self.intrinsic_words.contains_key(word)
+    }
+
+    /// True for words whose definitions use CREATE, which take the name of the word they make
+    /// from after them
+    pub fn is_defining_word(&self, word: &str) -> bool {
+        let word = self.aliases.get(word).map(|w| w.as_str()).unwrap_or(word);
+        self.defining_words.contains(word)
+    }
+
+    /// Every word the compiler knows, from the dictionary, the intrinsics and the aliases, sorted
+    pub fn word_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .word_addresses
+            .keys()
+            .chain(self.intrinsic_words.keys())
+            .chain(self.aliases.keys())
+            .map(|w| w.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// The stack effect TRAP: declared for a word, as how many cells it takes and leaves
+    pub fn declared_stack_effect(&self, word: &str) -> Option<(usize, usize)> {
+        let word = self.aliases.get(word).map(|w| w.as_str()).unwrap_or(word);
+        self.declared_effects.get(word).copied()
+    }
+
+    /// The opcodes a word compiles to, one per line, for people to read. Dictionary words show
+    /// the address of each opcode, intrinsics are copied in wherever they are used so have none.
+    pub fn word_listing(&self, word: &str) -> Option<String> {
+        let word = self.aliases.get(word).map(|w| w.as_str()).unwrap_or(word);
+        let mut text = String::new();
+        if let Some(&address) = self.word_addresses.get(word) {
+            let length = self.word_lengths.get(word).copied().unwrap_or_default();
+            text.push_str(&format!("{}:\n", word));
+            for (offset, opcode) in self.sm.st.opcodes[address..address + length]
+                .iter()
+                .enumerate()
+            {
+                text.push_str(&format!("{:>6}  {:?}\n", address + offset, opcode));
+            }
+        } else {
+            text.push_str(&format!("{}: intrinsic\n", word));
+            for opcode in self.intrinsic_words.get(word)? {
+                text.push_str(&format!("        {:?}\n", opcode));
+            }
+        }
+        Some(text)
+    }
+
+    /// How many cells an intrinsic word takes off the number stack and how many it leaves, when
+    /// that can be worked out from the opcodes it compiles to
+    pub fn intrinsic_stack_effect(&self, word: &str) -> Option<(usize, usize)> {
+        let word = self.aliases.get(word).map(|w| w.as_str()).unwrap_or(word);
+        let mut depth: i64 = 0;
+        let mut lowest: i64 = 0;
+        for opcode in self.intrinsic_words.get(word)? {
+            let (inputs, outputs) = match opcode {
+                Opcode::SYSCALL(id) => ForthCompiler::system_call_stack_effect(*id)?,
+                _ => opcode.stack_effect()?,
+            };
+            depth -= inputs as i64;
+            lowest = lowest.min(depth);
+            depth += outputs as i64;
+        }
+        Some(((-lowest) as usize, (depth - lowest) as usize))
+    }
+
+    /// Turn compilation of ASSERT( ... ) blocks on or off, words that are already compiled keep
+    /// whatever they were compiled with
+    pub fn set_assertions_enabled(&mut self, enabled: bool) {
+        self.assertions_enabled = enabled;
+    }
+
+    pub fn assertions_enabled(&self) -> bool {
+        self.assertions_enabled
+    }
+
+    /// Turn on or off building a CompilationArtifact after every compile
+    pub fn set_artifacts_enabled(&mut self, enabled: bool) {
+        self.artifacts_enabled = enabled;
+        if !enabled {
+            self.last_artifact = None;
+        }
+    }
+
+    /// The artifact from the most recent compile, if artifacts are enabled
+    pub fn artifacts_enabled(&self) -> bool {
+        self.artifacts_enabled
+    }
+
+    pub fn last_artifact(&self) -> Option<&CompilationArtifact> {
+        self.last_artifact.as_ref()
+    }
+
+    /// Print a line to the output as each word is entered and left, showing this many cells from
+    /// the top of the stack, see WordTrace. None turns the trace off.
+    pub fn set_word_trace(&mut self, cells: Option<usize>) {
+        self.word_trace_cells = cells;
+    }
+
+    pub fn word_trace(&self) -> Option<usize> {
+        self.word_trace_cells
+    }
+
+    /// Choose whether the words a source string defined before one of its definitions failed are
+    /// kept, they are unless told otherwise
+    pub fn set_definition_failure_mode(&mut self, mode: DefinitionFailureMode) {
+        self.definition_failure_mode = mode;
+    }
+
+    pub fn definition_failure_mode(&self) -> DefinitionFailureMode {
+        self.definition_failure_mode
+    }
+
+    /// Make running fail with MemoryLimitExceeded once the stacks, data space and opcodes
+    /// between them use more than this many bytes, see CompilerStats::memory_bytes
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.sm.memory_limit = limit;
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.sm.memory_limit
+    }
+
+    /// Limit how many opcodes can be compiled into the StackMachine's memory, compiling past
+    /// the limit fails with ForthError::DictionaryFull. None means no limit.
+    pub fn set_opcode_memory_limit(&mut self, limit: Option<usize>) {
+        self.opcode_memory_limit = limit;
+    }
+
+    pub fn opcode_memory_limit(&self) -> Option<usize> {
+        self.opcode_memory_limit
+    }
+
+    pub fn set_block_store(&mut self, store: BlockStore) {
+        self.block_store = store;
+    }
+
+    pub fn block_store(&self) -> &BlockStore {
+        &self.block_store
+    }
+
+    pub fn block_store_mut(&mut self) -> &mut BlockStore {
+        &mut self.block_store
+    }
+
+    /// Have hook called with each word as it is put into the dictionary, and whether it replaces
+    /// a word of the same name, so host tooling can keep up with the dictionary. Colon
+    /// definitions, VARIABLE, ARRAY, CREATE, EXTERN, patch_word and link_session all call it.
+    /// Loading a whole session with restore_session doesn't, and words taken back because their
+    /// source failed under DefinitionFailureMode::AbortAll will already have been reported.
+    pub fn set_definition_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&WordInfo, bool) + 'static,
+    {
+        self.definition_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_definition_hook(&mut self) {
+        self.definition_hook = None;
+    }
+
+    /// Send what programs print, with . and .S and the like, to this sink instead of stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.sm.st.output = output;
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn set_io_bus(&mut self, io_bus: Box<dyn IoBus>) {
+        self.io_bus = io_bus;
+    }
+
+    pub fn set_file_system(&mut self, file_system: Box<dyn FileSystem>) {
+        self.file_system = file_system;
+    }
+
+    pub fn set_key_value_store(&mut self, key_value_store: Box<dyn KeyValueStore>) {
+        self.key_value_store = key_value_store;
+    }
+
+    /// Limits that apply across runs from now on, what has been used so far still counts
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    pub fn quota_usage(&self) -> &QuotaUsage {
+        &self.quota_usage
+    }
+
+    /// Start counting usage from nothing, for example at the start of a new billing period
+    pub fn reset_quota_usage(&mut self) {
+        self.quota_usage = QuotaUsage::default();
+    }
+
+    pub fn set_sandbox_policy(&mut self, policy: SandboxPolicy) {
+        self.sm.trap_guard.enabled = policy.guard_traps;
+        self.sandbox_policy = policy;
+    }
+
+    pub fn sandbox_policy(&self) -> &SandboxPolicy {
+        &self.sandbox_policy
+    }
+
+    /// Allow the word, as it is defined right now, to execute the TRAP when the sandbox policy
+    /// guards traps. Redefining the word later doesn't pass the permission on to the new definition.
+    pub fn expose_trap(&mut self, trap_id: i64, word: &str) -> Result<(), ForthError> {
+        let address = *self
+            .word_addresses
+            .get(word)
+            .ok_or_else(|| ForthError::UnknownToken(word.to_owned()))?;
+        let length = self.word_lengths.get(word).copied().unwrap_or(0);
+        self.sm.trap_guard.allow(trap_id, address..address + length);
+        Ok(())
+    }
+
+    /// The number stack rendered as an aligned table, for host UIs and error messages
+    pub fn format_stack(&self) -> String {
+        self.sm.st.format_stack()
+    }
+
+    /// What the profiler PROFILE-ON started has counted for each word, the words that used the
+    /// most gas themselves first. Code that isn't a word, such as a DOES> action, is named by its
+    /// address.
+    pub fn profile(&self) -> Vec<(String, WordProfile)> {
+        let profiler = match &self.sm.profiler {
+            Some(profiler) => profiler,
+            None => return Vec::new(),
+        };
+        let mut words: Vec<(String, WordProfile)> = profiler
+            .words()
+            .iter()
+            .map(|(address, word)| {
+                let name = self
+                    .word_addresses
+                    .iter()
+                    .filter(|(_, a)| *a == address)
+                    .map(|(name, _)| name.clone())
+                    .min()
+                    .unwrap_or_else(|| format!("@{}", address));
+                (name, *word)
+            })
+            .collect();
+        words.sort_by(|(a_name, a), (b_name, b)| {
+            b.self_gas.cmp(&a.self_gas).then(a_name.cmp(b_name))
+        });
+        words
+    }
+
+    /// Counters for the runs so far and gauges for the dictionary and stacks as they are now
+    pub fn metrics(&self) -> Metrics {
+        let (number_stack, return_stack) = self.sm.st.high_water();
+        Metrics {
+            number_stack_high_water: number_stack,
+            return_stack_high_water: return_stack,
+            dictionary_words: self.word_addresses.len(),
+            dictionary_opcodes: self.last_function,
+            ..self.metrics.clone()
         }
     }
+
+    /// Start the counters and stack high water marks again from zero
+    pub fn reset_metrics(&mut self) {
+        self.metrics = Metrics::default();
+        self.sm.st.reset_high_water();
+    }
+
+    pub fn stats(&self) -> CompilerStats {
+        let memory = self.sm.st.memory_usage();
+        CompilerStats {
+            words: self.word_addresses.len(),
+            opcode_memory: self.last_function,
+            data_space_bytes: memory.data_space_bytes,
+            string_space_bytes: self.string_space_bytes,
+            stack_bytes: memory.stack_bytes,
+            dictionary_bytes: memory.dictionary_bytes,
+            memory_bytes: memory.total(),
+            largest_word: self
+                .word_lengths
+                .iter()
+                .filter(|(name, _)| self.word_addresses.contains_key(*name))
+                .max_by(|(a_name, a_len), (b_name, b_len)| {
+                    a_len.cmp(b_len).then(b_name.cmp(a_name))
+                })
+                .map(|(name, length)| (name.clone(), *length)),
+        }
+    }
+}
+
+impl Default for ForthCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// This Enum determines whether the Forth interpreter is in Interpreting mode or Compiling mode
@@ -84,63 +962,437 @@ struct DeferredIfStatement {
 impl DeferredIfStatement {
     pub fn new(if_location: usize) -> DeferredIfStatement {
         DeferredIfStatement {
-            if_location: if_location,
+            if_location,
             else_location: None,
         }
     }
 }
 
-impl ForthCompiler {
-    // Take a string containing Forth words and turn it into a list of Forth tokens
-    fn tokenize_string(&self, s: &str) -> Result<Vec<Token>, ForthError> {
-        let mut tv = Vec::new();
+// This struct tracks information for Forth DO and BEGIN loops, they are deferred until the
+// Forth word that closes the loop jumps back to the start of the body
+#[derive(Debug)]
+struct DeferredLoop {
+    // True for DO loops, which have a loop frame LEAVE has to drop
+    counted: bool,
+    body_location: usize,
+    // Where a ?DO's jump over the loop is, it can't be filled in until the loop's end is known
+    skip_location: Option<usize>,
+    // Where each LEAVE's jump out of the loop is
+    leave_locations: Vec<usize>,
+}
 
-        let mut string_iter = s.split_whitespace();
+impl DeferredLoop {
+    pub fn new(body_location: usize, counted: bool) -> DeferredLoop {
+        DeferredLoop {
+            counted,
+            body_location,
+            skip_location: None,
+            leave_locations: Vec::new(),
+        }
+    }
 
-        loop {
-            match string_iter.next() {
-                // If no more text in the string, then return what we have tokenized
-                None => return Ok(tv),
-                // If we have some text to process, then process it
-                Some(string_token) => {
-                    // Try to convert it to a number
-                    tv.push(match string_token.parse::<i64>() {
-                        // We found a number, then return it as a number token
-                        Ok(n) => Token::Number(n),
-                        // Wasn't a number, treat it as a *word*
-                        Err(_) => match string_token {
+    // Now the loop is closed, point the jumps out of it at the opcode after its end
+    fn patch_exits(&self, tv: &mut [Opcode]) {
+        let end = tv.len();
+        for location in self.skip_location.iter().chain(self.leave_locations.iter()) {
+            tv[*location] = Opcode::LDI(end as i64 - (location + 1) as i64);
+        }
+    }
+}
+
+// A token along with where it came from in the source text
+type LocatedToken = (Token, SourceLocation);
+
+// Opcodes along with the source location each one was compiled from
+#[derive(Debug, Default)]
+struct CompiledCode {
+    opcodes: Vec<Opcode>,
+    locations: Vec<SourceLocation>,
+    // The LDIs RECURSE and DOES> compiled, waiting for addresses in the word being defined, as
+    // where each LDI is and how far into the word its address is
+    relocations: Vec<(usize, usize)>,
+    // Where each LDI that pushes an address in the data space is, such as a string literal's
+    data_references: Vec<usize>,
+    // The LDIs string literals compiled, waiting for their text to be put in the data space, as
+    // where each LDI is and the text
+    strings: Vec<(usize, Vec<u8>)>,
+    // The LDIs ASSERT( compiled, waiting for the id of the assertion's location, as where each
+    // LDI is and the location
+    assertions: Vec<(usize, SourceLocation)>,
+}
+
+impl CompiledCode {
+    fn push(&mut self, opcode: Opcode, location: SourceLocation) {
+        self.opcodes.push(opcode);
+        self.locations.push(location);
+    }
+
+    fn append(&mut self, other: &mut CompiledCode) {
+        let offset = self.len();
+        self.strings
+            .extend(other.strings.drain(..).map(|(i, text)| (offset + i, text)));
+        self.assertions.extend(
+            other
+                .assertions
+                .drain(..)
+                .map(|(i, location)| (offset + i, location)),
+        );
+        self.opcodes.append(&mut other.opcodes);
+        self.locations.append(&mut other.locations);
+    }
+
+    fn len(&self) -> usize {
+        self.opcodes.len()
+    }
+
+    // Fill in the addresses RECURSE and DOES> need, once the word's address is known
+    fn link_relocations(&mut self, address: usize) {
+        for (i, offset) in self.relocations.drain(..) {
+            self.opcodes[i] = Opcode::LDI((address + offset) as i64);
+        }
+    }
+
+    // Where the LDIs pushing data space addresses will be once the code is at address
+    fn data_references_at(&self, address: usize) -> impl Iterator<Item = usize> + '_ {
+        self.data_references.iter().map(move |i| address + i)
+    }
+}
+
+/// Where a token is in the source text, as a byte range along with its line and column
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub location: SourceLocation,
+}
+
+/// A token along with the span of source text it was made from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+// Words that take the text after them, spaces and all, up to a closing quote
+const QUOTING_WORDS: [&str; 2] = [".\"", "S\""];
+
+// Split a string on whitespace, remembering where each piece of text is. The text after a quoting
+// word is one piece, starting after the space that ends the word and taking in the closing quote
+// if there is one.
+fn split_with_spans(s: &str) -> Vec<(&str, Span)> {
+    let mut words = Vec::new();
+    let mut word_start: Option<(usize, SourceLocation)> = None;
+    let mut in_quote = false;
+    let mut quote_next = false;
+    let mut line = 1;
+    let mut column = 1;
+
+    for (byte_index, c) in s.char_indices() {
+        match (in_quote, c.is_whitespace(), word_start) {
+            (true, _, Some((start, location))) if c == '"' => {
+                words.push((
+                    &s[start..=byte_index],
+                    Span {
+                        start,
+                        end: byte_index + 1,
+                        location,
+                    },
+                ));
+                word_start = None;
+                in_quote = false;
+            }
+            (true, _, _) => (),
+            (false, true, Some((start, location))) => {
+                let word = &s[start..byte_index];
+                words.push((
+                    word,
+                    Span {
+                        start,
+                        end: byte_index,
+                        location,
+                    },
+                ));
+                word_start = None;
+                quote_next = QUOTING_WORDS.contains(&word);
+            }
+            (false, false, None) => {
+                word_start = Some((byte_index, SourceLocation { line, column }))
+            }
+            _ => (),
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+        // The quoted text starts with whatever follows the space after the quoting word
+        if quote_next {
+            let start = byte_index + c.len_utf8();
+            word_start = Some((start, SourceLocation { line, column }));
+            in_quote = true;
+            quote_next = false;
+        }
+    }
+    if let Some((start, location)) = word_start {
+        words.push((
+            &s[start..],
+            Span {
+                start,
+                end: s.len(),
+                location,
+            },
+        ));
+    }
+
+    words
+}
+
+/// Turn a string of Forth source into tokens, using exactly the lexical rules the compiler does.
+/// Each token carries the span of text it came from, a colon definition's span covers both the
+/// colon and the name of the word being defined. If any of the source can't be tokenized, the
+/// error lists every problem along with where it is.
+pub fn tokenize_string(s: &str) -> Result<Vec<SpannedToken>, ForthError> {
+    let tokens = tokenize_with_errors(s);
+    let errors: Vec<(SourceLocation, String)> = tokens
+        .iter()
+        .filter_map(|t| match &t.token {
+            Token::Error(message) => Some((t.span.location, message.clone())),
+            _ => None,
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(ForthError::SyntaxErrors(errors))
+    }
+}
+
+/// Like tokenize_string, but a problem becomes a Token::Error with the span of the text it is
+/// about and tokenizing carries on after it, for tools that show every problem where it is
+pub fn tokenize_with_errors(s: &str) -> Vec<SpannedToken> {
+    let mut tv = Vec::new();
+
+    let mut string_iter = split_with_spans(s).into_iter();
+    // CREATE takes the name after it outside a definition, inside one the name comes when the
+    // defining word is used
+    let mut in_definition = false;
+
+    loop {
+        match string_iter.next() {
+            // If no more text in the string, then return what we have tokenized
+            None => return tv,
+            // If we have some text to process, then process it
+            Some((string_token, mut span)) => {
+                // Try to convert it to a number
+                let token = match string_token.parse::<i64>() {
+                    // We found a number, then return it as a number token
+                    Ok(n) => Token::Number(n),
+                    // Wasn't a number, treat it as a *word*
+                    Err(_) => {
+                        match string_token {
                             // If its a colon, create a colon token
                             ":" => match &string_iter.next() {
                                 // If we found a token, then we need to grab the next bit of text so we know what Forth word is being compiled
-                                Some(next_token) => Token::Colon(next_token.to_string()),
-                                // There has to be something after the colon, so this is an error since we didn't find anything
-                                None => {
-                                    return Err(ForthError::InvalidSyntax(String::from(
-                                        "No token after :, but one needed to compile",
-                                    )))
+                                Some((next_token, next_span)) => {
+                                    span.end = next_span.end;
+                                    in_definition = true;
+                                    Token::Colon(next_token.to_string())
                                 }
+                                // There has to be something after the colon, so this is an error since we didn't find anything
+                                None => Token::Error(String::from(
+                                    "No token after :, but one needed to compile",
+                                )),
                             },
                             // Create a semicolon token
-                            ";" => Token::SemiColon,
+                            ";" => {
+                                in_definition = false;
+                                Token::SemiColon
+                            }
+                            // Execution tokens are taken from the word after the tick
+                            "'" => match string_iter.next() {
+                                Some((word, word_span)) => {
+                                    span.end = word_span.end;
+                                    Token::Tick(word.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No word after ', but one needed for its execution token",
+                                )),
+                            },
+                            "CREATE" if !in_definition => match string_iter.next() {
+                                Some((name, name_span)) => {
+                                    span.end = name_span.end;
+                                    Token::Create(name.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No name after CREATE, but one needed for the word it makes",
+                                )),
+                            },
+                            // Arrays are named by the word after ARRAY
+                            "ARRAY" => match string_iter.next() {
+                                Some((name, name_span)) => {
+                                    span.end = name_span.end;
+                                    Token::Array(name.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No name after ARRAY, but one needed for the array",
+                                )),
+                            },
+                            // Variables are named by the word after VARIABLE
+                            "VARIABLE" => match string_iter.next() {
+                                Some((name, name_span)) => {
+                                    span.end = name_span.end;
+                                    Token::Variable(name.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No name after VARIABLE, but one needed for the variable",
+                                )),
+                            },
+                            // The host word is named by the word after EXTERN
+                            "EXTERN" => match string_iter.next() {
+                                Some((name, name_span)) => {
+                                    span.end = name_span.end;
+                                    Token::Extern(name.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No name after EXTERN, but one needed for the host word",
+                                )),
+                            },
+                            "TRAP:" => trap_word_token(&mut string_iter, &mut span),
+                            // The text runs up to the closing quote
+                            ".\"" | "S\"" => match string_iter.next() {
+                                Some((text, text_span)) => {
+                                    span.end = text_span.end;
+                                    match text.strip_suffix('"') {
+                                        Some(text) if string_token == "S\"" => {
+                                            Token::SQuote(text.to_owned())
+                                        }
+                                        Some(text) => Token::DotQuote(text.to_owned()),
+                                        None => Token::Error(format!(
+                                            "No closing \" after {}, so the text never ends",
+                                            string_token
+                                        )),
+                                    }
+                                }
+                                None => Token::Error(format!(
+                                    "No text after {}, but some is needed",
+                                    string_token
+                                )),
+                            },
+                            // The file to load is the word after REQUIRE
+                            "REQUIRE" => match string_iter.next() {
+                                Some((path, path_span)) => {
+                                    span.end = path_span.end;
+                                    Token::Require(path.to_owned())
+                                }
+                                None => Token::Error(String::from(
+                                    "No file after REQUIRE, but one needed to load",
+                                )),
+                            },
+                            // Registering an event handler needs the event name and the handler word after it
+                            "ON-EVENT" => match (string_iter.next(), string_iter.next()) {
+                                (Some((event, _)), Some((word, word_span))) => {
+                                    span.end = word_span.end;
+                                    Token::OnEvent(event.to_owned(), word.to_owned())
+                                }
+                                _ => Token::Error(String::from(
+                                    "ON-EVENT needs an event name and a handler word",
+                                )),
+                            },
+                            // Assertions remember where they are so that a failure can be reported usefully
+                            "ASSERT(" => Token::Assert(span.location),
+                            // Digits that didn't make a number are too many for a cell
+                            _ if is_decimal(string_token) => {
+                                Token::Error(format!("{} is too big for a cell", string_token))
+                            }
                             // Whatever else, assume its a Forth word
                             _ => Token::Command(string_token.to_owned()),
-                        },
-                    });
-                }
+                        }
+                    }
+                };
+                // A word named by a number could never be used, the number would be pushed instead
+                let token = match token {
+                    Token::Colon(name)
+                    | Token::Variable(name)
+                    | Token::Array(name)
+                    | Token::Create(name)
+                    | Token::Extern(name)
+                    | Token::TrapWord(name, _, _)
+                        if is_decimal(&name) =>
+                    {
+                        Token::Error(format!("{} is a number, so it can't name a word", name))
+                    }
+                    token => token,
+                };
+                tv.push(SpannedToken { token, span });
+            }
+        }
+    }
+}
+
+// The rest of a TRAP: definition, NAME ID ( INPUTS -- OUTPUTS ), with the span taken up to the
+// closing parenthesis
+fn trap_word_token<'a>(
+    words: &mut impl Iterator<Item = (&'a str, Span)>,
+    span: &mut Span,
+) -> Token {
+    let (name, id) = match (words.next(), words.next(), words.next()) {
+        (Some((name, _)), Some((id, _)), Some(("(", _))) => (name, id),
+        _ => {
+            return Token::Error(String::from(
+                "TRAP: needs a name, a trap id and a stack effect in parentheses",
+            ))
+        }
+    };
+    let id = match id.parse::<i64>() {
+        Ok(id) => id,
+        Err(_) => return Token::Error(format!("{} isn't a trap id", id)),
+    };
+
+    let mut inputs = 0;
+    let mut outputs = None;
+    for (word, word_span) in words {
+        match (word, outputs.as_mut()) {
+            (")", Some(outputs)) => {
+                span.end = word_span.end;
+                return Token::TrapWord(name.to_owned(), id, (inputs, *outputs));
             }
+            (")", None) => return Token::Error(format!("The stack effect of {} has no --", name)),
+            ("--", None) => outputs = Some(0),
+            (_, Some(outputs)) => *outputs += 1,
+            (_, None) => inputs += 1,
         }
     }
+    Token::Error(format!("No ) closing the stack effect of {}", name))
+}
+
+// True for text that is written like an integer, whether or not it fits in a cell
+fn is_decimal(text: &str) -> bool {
+    let digits = text.strip_prefix(|c| c == '-' || c == '+').unwrap_or(text);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+// The tokens of a string in the form the compiler works with
+fn located_tokens(s: &str) -> Result<Vec<LocatedToken>, ForthError> {
+    Ok(tokenize_string(s)?
+        .into_iter()
+        .map(|t| (t.token, t.span.location))
+        .collect())
+}
 
+impl ForthCompiler {
     fn compile_token_vector_compile_and_remove_word_definitions(
         &mut self,
-        token_vector: &[Token],
-    ) -> Result<Vec<Opcode>, ForthError> {
+        token_vector: &[LocatedToken],
+    ) -> Result<CompiledCode, ForthError> {
         // This is the interactive compiled token list
-        let mut tvi = Vec::new();
+        let mut tvi = CompiledCode::default();
         // This tracks whethere we are interpreting or compiling right now
         let mut mode = Mode::Interpreting;
         // This is where we start compiling the latest segment of word/interactive tokens
         let mut starting_position = 0;
+        // How many words this source has defined so far, for reporting a definition that fails
+        let mut compiled_words = 0;
 
         //println!(
         //    "compile_token_vector_compile_and_remove_word_definitions Compiling Forth tokens {:?}",
@@ -148,7 +1400,8 @@ impl ForthCompiler {
         //);
         // So, for every token we have been passed, check what it is...
         for i in 0..token_vector.len() {
-            match &token_vector[i] {
+            let (token, location) = &token_vector[i];
+            match token {
                 Token::Colon(s) => {
                     // Found Colon, so the user wants to compile a word presumably
                     match mode {
@@ -158,11 +1411,9 @@ impl ForthCompiler {
                             if i > starting_position {
                                 // We end before the current token
                                 // Compile whatever appeared before this compile statement
-                                tvi.append(
-                                    &mut self.compile_token_vector(
-                                        &token_vector[starting_position..i],
-                                    )?,
-                                );
+                                tvi.append(&mut self.compile_immediate_tokens(
+                                    &token_vector[starting_position..i],
+                                )?);
                             }
                             // Start compiling again after this token
                             starting_position = i + 1;
@@ -187,31 +1438,48 @@ impl ForthCompiler {
                         }
                         // We have found the end of the word definition, so compile to opcodes and put into memory...
                         Mode::Compiling(s) => {
-                            // Remove anything extraneous from the end of the opcode array (*processor memory*),
-                            // typically previous immediate mode tokens
-                            self.sm.st.opcodes.resize(self.last_function, Opcode::NOP);
-
                             // Get the compiled assembler from the token vector
                             // stop compiling before the ending token
-                            let mut compiled =
-                                self.compile_token_vector(&token_vector[starting_position..i])?;
+                            let failed = |error| {
+                                ForthError::DefinitionFailed(Box::new(DefinitionError {
+                                    word: s.clone(),
+                                    compiled: compiled_words,
+                                    error,
+                                }))
+                            };
+                            let mut compiled = self
+                                .compile_token_vector(&token_vector[starting_position..i])
+                                .map_err(failed)?;
                             // Put the return OpCode onto the end
-                            compiled.push(Opcode::RET);
+                            compiled.push(Opcode::RET, *location);
                             // The current function start is the end of the last function
                             let function_start = self.last_function;
+                            let function_length = compiled.len();
+                            compiled.link_relocations(function_start);
+                            self.place_strings(&mut compiled).map_err(failed)?;
+                            self.place_assertions(&mut compiled);
+                            self.data_words
+                                .extend(compiled.data_references_at(function_start));
+                            // Uses of a defining word are compiled differently, so remember which words are
+                            if token_vector[starting_position..i].iter().any(|(t, _)| {
+                                matches!(t, Token::Command(c) if self.aliases.get(c).unwrap_or(c) == "CREATE")
+                            }) {
+                                self.defining_words.insert(s.clone());
+                            } else {
+                                self.defining_words.remove(&s);
+                            }
+                            // Add the function to the opcode memory, removing anything extraneous from the end
+                            // of the opcode array (*processor memory*), typically previous immediate mode tokens
+                            self.place_code(function_start, compiled).map_err(failed)?;
                             // Move last function pointer
-                            self.last_function += compiled.len();
-                            // Add the function to the opcode memory
-                            self.sm.st.opcodes.append(&mut compiled);
-                            // Remember where to find it...
-                            self.word_addresses.insert(s, function_start);
+                            self.last_function += function_length;
+                            // Remember where to find it and how big it is
+                            self.add_word(&s, function_start, function_length);
+                            compiled_words += 1;
                             // start compiling again after this token
                             starting_position = i + 1;
                             // Switch back to interpreting mode
                             mode = Mode::Interpreting;
-                            //println!("Token Memory {:?}", self.sm.st.opcodes);
-                            //println!("Word Addresses {:?}", self.word_addresses);
-                            //println!("Last function {}", self.last_function);
                         }
                     }
                 }
@@ -226,24 +1494,78 @@ impl ForthCompiler {
         }
 
         // Compile any tokens that remain after processing
-        let mut compiled = self.compile_token_vector(&token_vector[starting_position..])?;
+        let mut compiled = self.compile_immediate_tokens(&token_vector[starting_position..])?;
         tvi.append(&mut compiled);
         // We need to return after running the interactive opcodes, so put the return in now
-        tvi.push(Opcode::RET);
+        let end_location = token_vector
+            .last()
+            .map(|(_, location)| *location)
+            .unwrap_or_default();
+        tvi.push(Opcode::RET, end_location);
 
         // Return the interactive tokens, the compiled ones are already in memory
-        return Ok(tvi);
+        Ok(tvi)
+    }
+
+    // Compile tokens that aren't part of a definition, where there is no word for RECURSE to call
+    // or DOES> to end
+    fn compile_immediate_tokens(
+        &mut self,
+        token_vector: &[LocatedToken],
+    ) -> Result<CompiledCode, ForthError> {
+        let compiled = self.compile_token_vector(token_vector)?;
+        if !compiled.relocations.is_empty() {
+            return Err(ForthError::InvalidSyntax(
+                "RECURSE or DOES> outside a definition".to_owned(),
+            ));
+        }
+        Ok(compiled)
     }
 
-    fn compile_token_vector(&mut self, token_vector: &[Token]) -> Result<Vec<Opcode>, ForthError> {
+    fn compile_token_vector(
+        &mut self,
+        token_vector: &[LocatedToken],
+    ) -> Result<CompiledCode, ForthError> {
         // Stack of if statements, they are deferred until the THEN Forth word
         let mut deferred_if_statements = Vec::new();
+        // Stack of DO loops, they are deferred until the LOOP or +LOOP Forth word
+        let mut deferred_loops: Vec<DeferredLoop> = Vec::new();
         // List of compiled processor opcodes that we are building up
         let mut tv: Vec<Opcode> = Vec::new();
+        // Where each of those opcodes came from
+        let mut locations: Vec<SourceLocation> = Vec::new();
+        // Stack of ASSERT( statements waiting for their closing )
+        let mut pending_assertions: Vec<SourceLocation> = Vec::new();
+        // How deeply nested we are inside ASSERT( ... ) blocks that are being compiled to nothing
+        let mut skipped_assertion_depth = 0;
+        // The LDIs that will hold addresses in the word being defined
+        let mut relocations: Vec<(usize, usize)> = Vec::new();
+        // The LDIs that will push string literals' addresses in the data space
+        let mut strings: Vec<(usize, Vec<u8>)> = Vec::new();
+        // The LDIs that will push the ids of the ASSERT( statements' locations
+        let mut assertions: Vec<(usize, SourceLocation)> = Vec::new();
+
+        // The location of the token that the latest opcodes were compiled from
+        let mut previous_location = SourceLocation::default();
 
         // Go through all the Forth tokens and turn them into processor Opcodes (for our StackMachine emulated processor)
-        for t in token_vector.iter() {
-            match t {
+        let mut tokens = token_vector.iter();
+        while let Some((t, token_location)) = tokens.next() {
+            // Everything compiled since the last token came from that token
+            locations.resize(tv.len(), previous_location);
+            previous_location = *token_location;
+
+            // Assertions are disabled, so throw away everything up to the matching )
+            if skipped_assertion_depth > 0 {
+                match t {
+                    Token::Assert(_) => skipped_assertion_depth += 1,
+                    Token::Command(s) if s == ")" => skipped_assertion_depth -= 1,
+                    _ => (),
+                }
+                continue;
+            }
+
+            match t {
                 Token::Number(n) => {
                     // Numbers get pushed as a LDI opcode
                     tv.push(Opcode::LDI(*n));
@@ -251,8 +1573,18 @@ impl ForthCompiler {
                 Token::Command(s) => {
                     // Remember where we are in the list of opcodes in case we hit a IF statement, LOOP etc...
                     let current_instruction = tv.len();
+                    // Aliases are just other names for words, so look up what they stand for
+                    let s = self.aliases.get(s).unwrap_or(s);
 
                     match s.as_ref() {
+                        ")" if !pending_assertions.is_empty() => {
+                            // The condition has been computed, so check it and report where the assertion came from if it failed
+                            if let Some(location) = pending_assertions.pop() {
+                                assertions.push((tv.len(), location));
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::ASSERT);
+                            }
+                        }
                         "IF" => {
                             deferred_if_statements
                                 .push(DeferredIfStatement::new(current_instruction));
@@ -283,9 +1615,10 @@ impl ForthCompiler {
                                         - (x.if_location + 1) as u64)
                                         .try_into()
                                         .unwrap(),
-                                    Some(el) => (current_instruction as u64 - el as u64 + 1)
-                                        .try_into()
-                                        .unwrap(),
+                                    // Jump over the IF branch and the ELSE's jump, to the start of the ELSE branch
+                                    Some(el) => {
+                                        (el as u64 - x.if_location as u64 + 1).try_into().unwrap()
+                                    }
                                 };
                                 let (else_jump_location, else_jump_offset): (
                                     Option<usize>,
@@ -315,6 +1648,101 @@ impl ForthCompiler {
                                 ));
                             }
                         }
+                        "DO" => {
+                            tv.push(Opcode::DO);
+                            deferred_loops.push(DeferredLoop::new(current_instruction + 1, true));
+                        }
+                        "?DO" => {
+                            // QDO leaves true rather than starting a loop that wouldn't run, so
+                            // jump past the end of the loop
+                            tv.push(Opcode::QDO);
+                            tv.push(Opcode::LDI(0));
+                            tv.push(Opcode::JRNZ);
+                            let mut x = DeferredLoop::new(tv.len(), true);
+                            x.skip_location = Some(current_instruction + 1);
+                            deferred_loops.push(x);
+                        }
+                        "LOOP" | "+LOOP" => {
+                            if let Some(x) = deferred_loops.pop().filter(|x| x.counted) {
+                                // LOOP is +LOOP with a step of 1
+                                if s == "LOOP" {
+                                    tv.push(Opcode::LDI(1));
+                                }
+                                tv.push(Opcode::PLUSLOOP);
+                                // Jump back to the start of the body until PLUSLOOP says it's done
+                                let jump_location = tv.len() + 1;
+                                tv.push(Opcode::LDI(x.body_location as i64 - jump_location as i64));
+                                tv.push(Opcode::JRZ);
+                                x.patch_exits(&mut tv);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(format!("{} without DO", s)));
+                            }
+                        }
+                        "BEGIN" => {
+                            deferred_loops.push(DeferredLoop::new(current_instruction, false));
+                        }
+                        "AGAIN" => {
+                            if let Some(x) = deferred_loops.pop().filter(|x| !x.counted) {
+                                let jump_location = tv.len() + 1;
+                                tv.push(Opcode::LDI(x.body_location as i64 - jump_location as i64));
+                                tv.push(Opcode::JR);
+                                x.patch_exits(&mut tv);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "AGAIN without BEGIN".to_owned(),
+                                ));
+                            }
+                        }
+                        "LEAVE" => {
+                            if let Some(x) = deferred_loops.last_mut() {
+                                // A DO loop's frame has to go before jumping out of it
+                                if x.counted {
+                                    tv.push(Opcode::UNLOOP);
+                                }
+                                x.leave_locations.push(tv.len());
+                                tv.push(Opcode::LDI(0));
+                                tv.push(Opcode::JR);
+                            } else {
+                                return Err(ForthError::InvalidSyntax(
+                                    "LEAVE outside a loop".to_owned(),
+                                ));
+                            }
+                        }
+                        "RECURSE" => {
+                            // The word isn't in the dictionary until its ; so its address is filled in then
+                            relocations.push((current_instruction, 0));
+                            tv.push(Opcode::LDI(0));
+                            tv.push(Opcode::CALL);
+                        }
+                        "DOES>" => {
+                            // The defining word stops here, after giving the word it made the
+                            // code after its RET to run
+                            relocations.push((current_instruction, current_instruction + 3));
+                            tv.push(Opcode::LDI(0));
+                            tv.push(Opcode::SYSCALL(SYSCALL_DOES));
+                            tv.push(Opcode::RET);
+                        }
+                        _ if self.defining_words.contains(s)
+                            && self.word_addresses.contains_key(s) =>
+                        {
+                            // The word is made now so that the code after can use it, the defining
+                            // word fills it in when it runs
+                            let defining_word = self.word_addresses[s];
+                            let name = match tokens.next() {
+                                Some((Token::Command(name), _)) => name,
+                                _ => {
+                                    return Err(ForthError::InvalidSyntax(format!(
+                                        "No name after {}, but one needed for the word it makes",
+                                        s
+                                    )))
+                                }
+                            };
+                            let created = self.define_created_word(name, *token_location)?;
+                            tv.push(Opcode::LDI(created as i64));
+                            tv.push(Opcode::SYSCALL(SYSCALL_CREATED_WORD));
+                            tv.push(Opcode::LDI(defining_word as i64));
+                            tv.push(Opcode::CALL);
+                        }
                         _ => {
                             if let Some(offset) = self.word_addresses.get(s) {
                                 tv.push(Opcode::LDI(*offset as i64));
@@ -329,6 +1757,74 @@ impl ForthCompiler {
                         }
                     }
                 }
+                Token::Assert(location) => {
+                    if self.assertions_enabled {
+                        pending_assertions.push(*location);
+                    } else {
+                        skipped_assertion_depth += 1;
+                    }
+                }
+                Token::Tick(word) => {
+                    // A word's execution token is its address, so only compiled words have one
+                    let word = self.aliases.get(word).unwrap_or(word);
+                    match self.word_addresses.get(word) {
+                        Some(address) => tv.push(Opcode::LDI(*address as i64)),
+                        None => return Err(ForthError::UnknownToken(word.to_string())),
+                    }
+                }
+                Token::Array(name) => {
+                    // The array's word goes in the dictionary now, pushing 0 until the ARRAY
+                    // system call reserves the array and writes its address in
+                    let address = self.define_constant(name, 0, *token_location)?;
+                    self.arrays.insert(name.clone(), address);
+
+                    tv.push(Opcode::LDI(address as i64));
+                    tv.push(Opcode::SYSCALL(SYSCALL_ARRAY));
+                }
+                Token::Variable(name) => {
+                    // The cell is reserved now, so the variable's word only has to push its address
+                    let address = self.sm.st.allocate(1)?;
+                    let word = self.define_constant(name, address, *token_location)?;
+                    self.data_words.insert(word);
+                }
+                Token::Extern(name) => {
+                    self.define_extern(name, *token_location)?;
+                }
+                Token::TrapWord(name, id, effect) => {
+                    let body = [Opcode::LDI(*id), Opcode::TRAP];
+                    self.define_code(name, &body, *token_location)?;
+                    self.declared_effects.insert(name.clone(), *effect);
+                }
+                Token::SQuote(text) | Token::DotQuote(text) => {
+                    // The text goes in the data space once it is known whether the code is a
+                    // definition's or the scratch immediate mode code's
+                    strings.push((tv.len(), text.as_bytes().to_vec()));
+                    tv.push(Opcode::LDI(0));
+                    tv.push(Opcode::LDI(text.len() as i64));
+                    if let Token::DotQuote(_) = t {
+                        tv.push(Opcode::TYPE);
+                    }
+                }
+                Token::Create(name) => {
+                    let created = self.define_created_word(name, *token_location)?;
+                    tv.push(Opcode::LDI(created as i64));
+                    tv.push(Opcode::SYSCALL(SYSCALL_CREATED_WORD));
+                    tv.push(Opcode::SYSCALL(SYSCALL_CREATE));
+                }
+                Token::OnEvent(event, word) => {
+                    // Handlers are registered when they are compiled, like definitions
+                    let word = self.aliases.get(word).unwrap_or(word);
+                    if !self.knows_word(word) {
+                        return Err(ForthError::UnknownToken(word.to_string()));
+                    }
+                    self.event_handlers.insert(event.clone(), word.clone());
+                }
+                // Files are loaded before anything is compiled, so this one was inside a definition
+                Token::Require(_) => {
+                    return Err(ForthError::InvalidSyntax(
+                        "REQUIRE inside a definition".to_owned(),
+                    ));
+                }
                 Token::Colon(_) => {
                     panic!("Colon should never reach this function");
                 }
@@ -338,338 +1834,3699 @@ impl ForthCompiler {
                 Token::End => {
                     panic!("Token::End not coded yet");
                 }
-                Token::Error(_) => {
-                    panic!("Token::Error not coded yet");
+                // tokenize_string doesn't give the compiler any of these
+                Token::Error(message) => {
+                    return Err(ForthError::InvalidSyntax(message.clone()));
                 }
             }
         }
 
-        //println!("Compiled Codes {:?}", tv);
-        //println!("Total size of Codes {:?}", tv.len());
-        return Ok(tv);
-    }
+        locations.resize(tv.len(), previous_location);
 
-    fn execute_token_vector(
-        &mut self,
-        token_vector: &[Token],
-        gas_limit: GasLimit,
-    ) -> Result<(), ForthError> {
-        let mut ol = self.compile_token_vector_compile_and_remove_word_definitions(token_vector)?;
-        //println!("Compiled Opcodes: {:?}", ol);
-        self.sm.st.opcodes.resize(self.last_function, Opcode::NOP);
-        self.sm.st.opcodes.append(&mut ol);
-        self.sm.execute(self.last_function, gas_limit)?;
-        println!("Total opcodes defined: {}", self.sm.st.opcodes.len());
-        println!("Total opcodes executed: {}", self.sm.st.gas_used());
+        if !pending_assertions.is_empty() || skipped_assertion_depth > 0 {
+            return Err(ForthError::InvalidSyntax(
+                "ASSERT( without matching )".to_owned(),
+            ));
+        }
 
-        Ok(())
+        if !deferred_loops.is_empty() {
+            return Err(ForthError::InvalidSyntax(
+                "DO or BEGIN without its LOOP or AGAIN".to_owned(),
+            ));
+        }
+
+        Ok(CompiledCode {
+            opcodes: tv,
+            locations,
+            relocations,
+            data_references: Vec::new(),
+            strings,
+            assertions,
+        })
     }
 
-    pub fn execute_string(&mut self, s: &str, gas_limit: GasLimit) -> Result<(), ForthError> {
-        let tv = self.tokenize_string(s)?;
-        self.execute_token_vector(&tv, gas_limit)?;
-        Ok(())
+    // Put a word that pushes the value into the dictionary, returning its address. The LDI is
+    // the first opcode so the value can be changed later.
+    fn define_constant(
+        &mut self,
+        name: &str,
+        value: i64,
+        location: SourceLocation,
+    ) -> Result<usize, ForthError> {
+        self.define_code(name, &[Opcode::LDI(value)], location)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Put a word made of the opcodes and a RET at the end of the dictionary, returning its address
+    fn define_code(
+        &mut self,
+        name: &str,
+        opcodes: &[Opcode],
+        location: SourceLocation,
+    ) -> Result<usize, ForthError> {
+        let address = self.last_function;
+        let mut body = CompiledCode::default();
+        for opcode in opcodes {
+            body.push(opcode.clone(), location);
+        }
+        body.push(Opcode::RET, location);
+        let length = body.len();
+        self.place_code(address, body)?;
+        self.last_function += length;
+        self.add_word(name, address, length);
+        Ok(address)
+    }
 
-    #[test]
-    fn test_execute_intrinsics_1() {
-        let mut fc = ForthCompiler::new();
+    // Make name refer to the code at address, telling the definition hook
+    fn add_word(&mut self, name: &str, address: usize, length: usize) {
+        let redefined = self
+            .word_addresses
+            .insert(name.to_owned(), address)
+            .is_some();
+        self.word_lengths.insert(name.to_owned(), length);
+        self.declared_effects.remove(name);
+        if let Some(hook) = self.definition_hook.as_mut() {
+            let word = WordInfo {
+                name: name.to_owned(),
+                address,
+                length,
+            };
+            hook(&word, redefined);
+        }
+    }
 
-        fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))
-            .unwrap();
+    // Put a word made by CREATE into the dictionary, returning its address. It pushes 0 until
+    // CREATE runs and writes its data address into the LDI, then DOES> can replace the NOPs with
+    // a jump to the action its defining word gave it.
+    fn define_created_word(
+        &mut self,
+        name: &str,
+        location: SourceLocation,
+    ) -> Result<usize, ForthError> {
+        let address = self.last_function;
+        let mut body = CompiledCode::default();
+        for op in [Opcode::LDI(0), Opcode::NOP, Opcode::NOP, Opcode::RET] {
+            body.push(op, location);
+        }
+        let length = body.len();
+        self.place_code(address, body)?;
+        self.last_function += length;
+        self.add_word(name, address, length);
+        Ok(address)
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+    // Put a stub for a host word into the dictionary, which fails with UnresolvedExterns until
+    // it is bound, and bind it straight away if the host already has the word
+    fn define_extern(&mut self, name: &str, location: SourceLocation) -> Result<(), ForthError> {
+        let address = self.last_function;
+        let mut body = CompiledCode::default();
+        for op in ForthCompiler::extern_stub(address) {
+            body.push(op, location);
+        }
+        self.place_code(address, body)?;
+        self.last_function += EXTERN_STUB_LENGTH;
+        self.add_word(name, address, EXTERN_STUB_LENGTH);
+        self.externs.insert(name.to_owned(), address);
+        if self.intrinsic_words.contains_key(name) {
+            self.bind_extern(name, address)?;
+        }
+        Ok(())
+    }
 
-        fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))
-            .unwrap();
+    fn extern_stub(address: usize) -> [Opcode; EXTERN_STUB_LENGTH] {
+        [
+            Opcode::LDI(address as i64),
+            Opcode::SYSCALL(SYSCALL_UNBOUND_EXTERN),
+            Opcode::RET,
+        ]
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888]);
+    // Point the stub at the host's word, putting its opcodes at the end of the dictionary, or
+    // failing that at the word of the same name in the dictionary
+    fn bind_extern(&mut self, name: &str, address: usize) -> Result<(), ForthError> {
+        if !self.intrinsic_words.contains_key(name) {
+            let word = self
+                .word_addresses
+                .get(name)
+                .copied()
+                .filter(|w| *w != address)
+                .ok_or_else(|| ForthError::UnresolvedExterns(vec![name.to_owned()]))?;
+            self.sm.st.opcodes[address] = Opcode::LDI(word as i64);
+            self.sm.st.opcodes[address + 1] = Opcode::JMP;
+            return Ok(());
+        }
+        let location = self.source_map[address];
+        let target = self.last_function;
+        let mut body = CompiledCode::default();
+        for op in self.intrinsic_words[name].iter() {
+            body.push(op.clone(), location);
+        }
+        body.push(Opcode::RET, location);
+        let length = body.len();
+        self.place_code(target, body)?;
+        self.last_function += length;
+        self.sm.st.opcodes[address] = Opcode::LDI(target as i64);
+        self.sm.st.opcodes[address + 1] = Opcode::JMP;
+        Ok(())
     }
 
-    #[test]
-    fn test_compile_1() {
-        let mut fc = ForthCompiler::new();
+    fn dictionary_snapshot(&self) -> DictionarySnapshot {
+        DictionarySnapshot {
+            last_function: self.last_function,
+            word_addresses: self.word_addresses.clone(),
+            word_lengths: self.word_lengths.clone(),
+            arrays: self.arrays.clone(),
+            event_handlers: self.event_handlers.clone(),
+            externs: self.externs.clone(),
+            data_words: self.data_words.clone(),
+            defining_words: self.defining_words.clone(),
+            declared_effects: self.declared_effects.clone(),
+            assertions: self.assertion_locations.len(),
+            here: self.sm.st.here(),
+            string_space_bytes: self.string_space_bytes,
+        }
+    }
 
-        fc.execute_string(
-            ": RickTest 123 321 ADD 2 MUL ; RickTest",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
+    // Forget everything compiled since the snapshot, the words it knew were never overwritten
+    // because new code only goes after last_function
+    fn restore_dictionary(&mut self, snapshot: DictionarySnapshot) {
+        self.last_function = snapshot.last_function;
+        self.sm.trap_guard.forget_from(self.last_function);
+        self.word_addresses = snapshot.word_addresses;
+        self.word_lengths = snapshot.word_lengths;
+        self.arrays = snapshot.arrays;
+        self.event_handlers = snapshot.event_handlers;
+        self.externs = snapshot.externs;
+        self.data_words = snapshot.data_words;
+        self.defining_words = snapshot.defining_words;
+        self.declared_effects = snapshot.declared_effects;
+        self.assertion_locations.truncate(snapshot.assertions);
+        self.sm.st.release_data(snapshot.here);
+        self.string_space_bytes = snapshot.string_space_bytes;
+        self.sm.st.opcodes.truncate(self.last_function);
+        self.source_map.truncate(self.last_function);
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+    // Put compiled code into the StackMachine's memory at the given address, throwing away anything after it
+    fn place_code(&mut self, address: usize, mut code: CompiledCode) -> Result<(), ForthError> {
+        if let Some(limit) = self.opcode_memory_limit {
+            if address + code.len() > limit {
+                return Err(ForthError::DictionaryFull);
+            }
+        }
 
-        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
-            .unwrap();
+        self.sm.st.opcodes.truncate(address);
+        self.sm.st.opcodes.append(&mut code.opcodes);
+        self.source_map.truncate(address);
+        self.source_map.append(&mut code.locations);
+        Ok(())
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+    // Put the text of the code's string literals in the data space and fill in the addresses
+    // its LDIs push, giving back where the text starts
+    fn place_strings(&mut self, code: &mut CompiledCode) -> Result<i64, ForthError> {
+        let start = self.sm.st.here();
+        for (i, text) in code.strings.drain(..) {
+            let cells = text.len().div_ceil(CELL_SIZE as usize);
+            let address = self.sm.st.allocate(cells)?;
+            self.sm.st.store_bytes(address, &text)?;
+            self.string_space_bytes += cells * CELL_SIZE as usize;
+            code.opcodes[i] = Opcode::LDI(address);
+            code.data_references.push(i);
+        }
+        Ok(start)
     }
 
-    #[test]
-    fn test_compile_2() {
-        let mut fc = ForthCompiler::new();
+    // Give the code's assertions ids for where they came from, giving back the first id
+    fn place_assertions(&mut self, code: &mut CompiledCode) -> usize {
+        let start = self.assertion_locations.len();
+        for (i, location) in code.assertions.drain(..) {
+            code.opcodes[i] = Opcode::LDI(self.assertion_locations.len() as i64);
+            self.assertion_locations.push(location);
+        }
+        start
+    }
 
-        fc.execute_string(
-            ": RickTest 123 321 ADD 2 MUL ; RickTest : RickTestB 123 321 ADD 2 MUL ;",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
+    // Give back the data space of the immediate mode code's strings and forget its assertions,
+    // unless the run reserved more data space or compiled more assertions after them, which
+    // have to stay where they are
+    fn reclaim_scratch(&mut self) {
+        let scratch = mem::replace(&mut self.scratch_data, 0..0);
+        if self.sm.st.here() == scratch.end {
+            self.sm.st.release_data(scratch.start);
+            self.string_space_bytes -= (scratch.end - scratch.start) as usize;
+        }
+        let scratch = mem::replace(&mut self.scratch_assertions, 0..0);
+        if self.assertion_locations.len() == scratch.end {
+            self.assertion_locations.truncate(scratch.start);
+        }
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+    // Compile the tokens, putting words into the dictionary and the immediate mode code after them.
+    // Everything after the last word is scratch space that each line's immediate mode code
+    // overwrites, so running line after line doesn't grow the opcode memory.
+    fn compile_tokens(&mut self, token_vector: &[LocatedToken]) -> Result<(), ForthError> {
+        // The new code goes where a suspended run's immediate mode code is
+        self.suspended = false;
+        if self.nesting == 0 {
+            self.reclaim_scratch();
+        }
+        let snapshot = match self.definition_failure_mode {
+            DefinitionFailureMode::AbortAll => Some(self.dictionary_snapshot()),
+            DefinitionFailureMode::KeepSuccessful => None,
+        };
+        let mut loaded = Vec::new();
+        let token_vector = self.load_required_files(token_vector, &mut loaded)?;
+        let mut ol =
+            match self.compile_token_vector_compile_and_remove_word_definitions(&token_vector) {
+                Ok(ol) => ol,
+                Err(e) => {
+                    if let Some(snapshot) = snapshot {
+                        self.restore_dictionary(snapshot);
+                    }
+                    return Err(e);
+                }
+            };
+        let start = self.place_strings(&mut ol)?;
+        self.scratch_data = start..self.sm.st.here();
+        let start = self.place_assertions(&mut ol);
+        self.scratch_assertions = start..self.assertion_locations.len();
+        self.place_code(self.last_function, ol)?;
+        // Files that failed to compile are loaded again by the next REQUIRE of them
+        self.required_files.extend(loaded);
 
-        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
-            .unwrap();
+        if self.artifacts_enabled {
+            self.last_artifact = Some(self.build_artifact());
+        }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+        Ok(())
     }
 
-    #[test]
-    fn test_compile_3() {
-        let mut fc = ForthCompiler::new();
-
-        fc.execute_string(
-            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; RickTest : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB POP",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
-
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+    // Replace each REQUIRE with the tokens of its file, unless the file has already been loaded,
+    // adding the files read to loaded. The files' own REQUIREs are followed, each file's
+    // tokens coming after those of the files it needs.
+    fn load_required_files(
+        &self,
+        token_vector: &[LocatedToken],
+        loaded: &mut Vec<String>,
+    ) -> Result<Vec<LocatedToken>, ForthError> {
+        let mut tokens = Vec::with_capacity(token_vector.len());
+        let mut in_definition = false;
+        for (token, location) in token_vector.iter() {
+            match token {
+                Token::Colon(_) => in_definition = true,
+                Token::SemiColon => in_definition = false,
+                Token::Require(_) if in_definition => {
+                    return Err(ForthError::InvalidSyntax(
+                        "REQUIRE inside a definition".to_owned(),
+                    ));
+                }
+                Token::Require(path) => {
+                    if !self.required_files.contains(path) && !loaded.contains(path) {
+                        loaded.push(path.clone());
+                        let source = self.file_system.read_to_string(path)?;
+                        tokens.append(
+                            &mut self.load_required_files(&located_tokens(&source)?, loaded)?,
+                        );
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+            tokens.push((token.clone(), *location));
+        }
+        Ok(tokens)
+    }
 
-        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
-            .unwrap();
+    fn build_artifact(&self) -> CompilationArtifact {
+        let mut words: Vec<WordInfo> = self
+            .word_addresses
+            .iter()
+            .map(|(name, address)| WordInfo {
+                name: name.clone(),
+                address: *address,
+                length: self.word_lengths.get(name).copied().unwrap_or(0),
+            })
+            .collect();
+        words.sort_by(|a, b| a.address.cmp(&b.address).then(a.name.cmp(&b.name)));
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+        CompilationArtifact {
+            opcodes: self.sm.st.opcodes.clone(),
+            words,
+            source_map: self.source_map.clone(),
+            immediate_code_start: self.last_function,
+        }
     }
 
-    #[test]
-    fn test_compile_4() {
-        let mut fc = ForthCompiler::new();
+    fn execute_token_vector(
+        &mut self,
+        token_vector: &[LocatedToken],
+        gas_limit: GasLimit,
+    ) -> Result<(), ForthError> {
+        if let Err(e) = self.compile_tokens(token_vector) {
+            self.metrics.record_error(&e);
+            return Err(e);
+        }
+        self.run_code(RunStart::At(self.last_function), gas_limit)
+    }
 
-        fc.execute_string(
-            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
+    // Run code until it returns or yields, counting the run in the metrics
+    fn run_code(&mut self, start: RunStart, gas_limit: GasLimit) -> Result<(), ForthError> {
+        self.metrics.executions += 1;
+        let result = self.run_metered(start, gas_limit);
+        if let Err(e) = &result {
+            self.metrics.record_error(e);
+        }
+        if !self.suspended {
+            self.reclaim_scratch();
+        }
+        result
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+    // Run code until it returns or yields, counting what it uses against the quotas
+    fn run_metered(&mut self, start: RunStart, gas_limit: GasLimit) -> Result<(), ForthError> {
+        let now = self.clock.now_millis();
+        let (gas_limit, gas_capped) = self
+            .quota_usage
+            .start_run(&self.quotas, now, gas_limit)
+            .map_err(ForthError::QuotaExceeded)?;
 
-        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
-            .unwrap();
+        // Meter the output for the length of the run, then give the host's output back
+        let inner = Rc::new(RefCell::new(mem::replace(
+            &mut self.sm.st.output,
+            Box::new(io::sink()),
+        )));
+        let written = Rc::new(Cell::new(self.quota_usage.output_bytes));
+        self.sm.st.output = Box::new(MeteredOutput {
+            inner: Rc::clone(&inner),
+            written: Rc::clone(&written),
+            limit: self.quotas.max_output_bytes,
+        });
+        let result = self.run_unmetered(start, gas_limit);
+        self.sm.st.output = Box::new(io::sink());
+        self.sm.st.output = match Rc::try_unwrap(inner) {
+            Ok(output) => output.into_inner(),
+            Err(_) => unreachable!("the metered output has been dropped"),
+        };
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 888, 888]);
+        self.quota_usage.gas_this_hour += self.sm.st.gas_used();
+        self.metrics.gas_used += self.sm.st.gas_used();
+        self.quota_usage.output_bytes = written.get();
+        result.map_err(|e| match e {
+            ForthError::RanOutOfGas if gas_capped => ForthError::QuotaExceeded(Quota::GasPerHour),
+            ForthError::Io(e) if is_output_quota_error(&e) => {
+                ForthError::QuotaExceeded(Quota::OutputBytes)
+            }
+            e => e,
+        })
     }
 
-    #[test]
-    fn test_compile_fail_1() {
-        let mut fc = ForthCompiler::new();
-
-        match fc.execute_string(
-            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
-            GasLimit::Limited(100),
-        ) {
-            Err(ForthError::UnknownToken(ref x)) if x == "RickTestB" => (),
-            r => panic!("Incorrect error type returned {:?}", r),
+    // Run code until it returns or yields, servicing any system calls it makes
+    fn run_unmetered(&mut self, start: RunStart, gas_limit: GasLimit) -> Result<(), ForthError> {
+        self.last_backtrace = Backtrace::default();
+        self.suspended = false;
+        let result = match start {
+            RunStart::At(address) => {
+                // The names change as words are defined, so the trace gets them afresh for each run
+                self.sm.word_trace = self.word_trace_cells.map(|cells| {
+                    let names = self
+                        .word_addresses
+                        .iter()
+                        .map(|(name, address)| (*address, name.clone()))
+                        .collect();
+                    WordTrace::new(names, cells)
+                });
+                self.sm.st.clear_return_stack();
+                // Calls left by a run that failed will never return
+                if let Some(profiler) = self.sm.profiler.as_mut() {
+                    if self.nesting == 0 {
+                        profiler.abandon_calls();
+                    }
+                }
+                self.sm.execute(address, gas_limit)
+            }
+            RunStart::Resume(values) => self.sm.resume_with(values, gas_limit),
+        };
+        let mut status = self.check_status(result)?;
+        // Keep servicing system calls until the code finishes
+        loop {
+            match status {
+                ExecutionStatus::SystemCall(id) => {
+                    if let Err(e) = self.system_call(id, gas_limit) {
+                        self.last_backtrace = self.backtrace();
+                        return Err(e);
+                    }
+                }
+                // A run EVALUATE started can't give control back halfway through the outer
+                // one, so it carries straight on, unless it needs an answer from the host
+                ExecutionStatus::Suspended if self.nesting > 0 => {
+                    if let Some(request) = self.sm.st.request() {
+                        return Err(ForthError::UnansweredRequest(request.code));
+                    }
+                }
+                ExecutionStatus::Suspended => {
+                    self.suspended = true;
+                    return Ok(());
+                }
+                ExecutionStatus::Finished => return Ok(()),
+            }
+            let result = self.sm.resume(gas_limit);
+            status = self.check_status(result)?;
         }
     }
 
-    #[test]
-    fn test_compile_fail_2() {
-        let mut fc = ForthCompiler::new();
+    /// True when YIELD or REQUEST stopped the last run, which resume carries on with
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
 
-        match fc.execute_string(
-            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; ; : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
-            GasLimit::Limited(100),
-        ) {
-            Err(ForthError::InvalidSyntax(_)) => (),
-            r => panic!("Incorrect error type returned {:?}", r),
+    /// What the suspended run asked the host for, None when it stopped with YIELD
+    pub fn pending_request(&self) -> Option<&HostRequest> {
+        if !self.suspended {
+            return None;
         }
+        self.sm.st.request()
     }
 
-    #[test]
-    fn test_compile_fail_3() {
-        let mut fc = ForthCompiler::new();
+    /// Carry on with a run YIELD stopped, until it finishes or yields again. The gas limit is for
+    /// this part of the run alone. Does nothing when no run is suspended, and compiling or running
+    /// anything else abandons the suspended run.
+    pub fn resume(&mut self, gas_limit: GasLimit) -> Result<(), ForthError> {
+        self.resume_with(&[], gas_limit)
+    }
 
-        match fc.execute_string(
-            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : RickTestB 123 321 ADD 2 MUL ; : ERROR 3 3 SUB",
-            GasLimit::Limited(100),
-        ) {
-            Err(ForthError::MissingSemicolonAfterColon) => (),
-            r => panic!("Incorrect error type returned {:?}", r),
+    /// Answer the pending request by pushing the values, last on top, and resume the run
+    pub fn resume_with(&mut self, values: &[i64], gas_limit: GasLimit) -> Result<(), ForthError> {
+        if !self.suspended {
+            return Ok(());
         }
+        self.run_code(RunStart::Resume(values), gas_limit)
     }
 
-    #[test]
-    fn test_if_else_1() {
-        let mut fc = ForthCompiler::new();
+    // Compile and run source in the middle of a run, which shares the run's gas limit. The run's
+    // immediate mode code is in the scratch space after the dictionary, where the source's words
+    // and immediate mode code go, so it is kept aside and put back after them once the source
+    // has run.
+    fn evaluate_nested(&mut self, source: &str, gas_limit: GasLimit) -> Result<(), ForthError> {
+        if self.nesting >= self.max_nesting {
+            return Err(ForthError::NestingTooDeep(self.max_nesting));
+        }
+        let tv = located_tokens(source)?;
 
-        fc.execute_string(
-            "1 2 3 POP POP POP 0 IF 1 2 ADD ELSE 3 4 ADD THEN",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
+        let scratch_start = self.last_function;
+        let mut scratch = CompiledCode {
+            opcodes: self.sm.st.opcodes.split_off(scratch_start),
+            locations: self.source_map.split_off(scratch_start),
+            relocations: Vec::new(),
+            data_references: Vec::new(),
+            strings: Vec::new(),
+            assertions: Vec::new(),
+        };
+        let scratch_range = scratch_start..scratch_start + scratch.len();
+        let mut context = self.sm.st.save_context();
+        let gas_left = match gas_limit {
+            GasLimit::Limited(gas) => GasLimit::Limited(gas.saturating_sub(context.gas_used())),
+            GasLimit::Unlimited => GasLimit::Unlimited,
+        };
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+        let scratch_data = mem::replace(&mut self.scratch_data, 0..0);
+        let scratch_assertions = mem::replace(&mut self.scratch_assertions, 0..0);
+        self.nesting += 1;
+        let result = self
+            .compile_tokens(&tv)
+            .and_then(|_| self.run_unmetered(RunStart::At(self.last_function), gas_left));
+        self.nesting -= 1;
+        self.reclaim_scratch();
+        self.scratch_data = scratch_data;
+        self.scratch_assertions = scratch_assertions;
+
+        self.sm.st.opcodes.truncate(self.last_function);
+        self.source_map.truncate(self.last_function);
+        context.relocate(scratch_range, self.last_function);
+        self.sm.st.opcodes.append(&mut scratch.opcodes);
+        self.source_map.append(&mut scratch.locations);
+        self.sm.st.restore_context(context);
+        result
     }
 
-    #[test]
-    fn test_if_else_2() {
-        let mut fc = ForthCompiler::new();
+    /// Limit how deeply EVALUATE may run code inside code that is already running, deeper
+    /// runs fail with NestingTooDeep
+    pub fn set_max_nesting(&mut self, depth: usize) {
+        self.max_nesting = depth;
+    }
 
-        fc.execute_string(
-            "1 2 3 POP POP POP 1 IF 1 2 ADD ELSE 3 4 ADD THEN",
-            GasLimit::Limited(100),
-        )
-        .unwrap();
+    fn check_status(
+        &mut self,
+        result: Result<ExecutionStatus, StackMachineError>,
+    ) -> Result<ExecutionStatus, ForthError> {
+        result.map_err(|e| {
+            self.last_backtrace = self.backtrace();
+            self.translate_error(e)
+        })
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64]);
+    // The words the machine is in, from the one it stopped in out through the ones that called it
+    fn backtrace(&self) -> Backtrace {
+        // Each return address is just after the CALL that went into the next word in
+        let calls = self.sm.st.return_stack().iter().rev().map(|a| a - 1);
+        Backtrace {
+            words: std::iter::once(self.sm.st.pc())
+                .chain(calls)
+                .filter_map(|pc| self.word_at(pc))
+                .collect(),
+        }
     }
 
-    #[test]
-    fn test_if_else_3() {
-        let mut fc = ForthCompiler::new();
+    // The word whose body the address is in
+    fn word_at(&self, pc: usize) -> Option<String> {
+        self.word_addresses
+            .iter()
+            .find(|(name, address)| {
+                pc >= **address
+                    && pc < **address + self.word_lengths.get(*name).copied().unwrap_or(0)
+            })
+            .map(|(name, _)| name.clone())
+    }
 
-        fc.execute_string("0 IF 1 2 ADD ELSE 3 4 ADD THEN", GasLimit::Limited(100))
-            .unwrap();
+    /// The words the last run that failed was inside when it did, empty when it failed outside
+    /// any word or the last run didn't fail
+    pub fn last_backtrace(&self) -> &Backtrace {
+        &self.last_backtrace
+    }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+    // Turn a StackMachine error into a ForthError, filling in anything the compiler knows about it
+    fn translate_error(&self, e: StackMachineError) -> ForthError {
+        match e {
+            StackMachineError::AssertionFailed(id) => ForthError::AssertionFailed(
+                usize::try_from(id)
+                    .ok()
+                    .and_then(|id| self.assertion_locations.get(id))
+                    .copied(),
+            ),
+            StackMachineError::IntegrityViolation(mut v) => {
+                v.word = self.word_at(v.pc);
+                ForthError::IntegrityViolation(v)
+            }
+            e => ForthError::from(e),
+        }
     }
 
+    // Take a number off the stack for a system call
+    fn pop_number(&mut self) -> Result<i64, ForthError> {
+        self.sm
+            .st
+            .number_stack
+            .pop()
+            .ok_or(ForthError::PopOfEmptyStack)
+    }
+
+    // Take an address in opcode memory off the stack, such as the word a system call changes,
+    // failing rather than letting a bad one index past the end
+    fn pop_code_address(&mut self) -> Result<usize, ForthError> {
+        let address = self.pop_number()?;
+        Ok(self.sm.st.code_address(address)?)
+    }
+
+    // Take an address and length off the stack and read the string they point at
+    fn pop_string(&mut self) -> Result<String, ForthError> {
+        let length = self.pop_number()?;
+        let address = self.pop_number()?;
+        let bytes = self.sm.st.fetch_bytes(address, length)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn pop_block_number(&mut self) -> Result<usize, ForthError> {
+        let n = self.pop_number()?;
+        usize::try_from(n).map_err(|_| ForthError::InvalidBlockNumber(n))
+    }
+
+    // What each system call does to the number stack
+    fn system_call_stack_effect(id: u16) -> Option<(usize, usize)> {
+        match id {
+            SYSCALL_PRINT_STATS => Some((0, 0)),
+            SYSCALL_LIST => Some((1, 0)),
+            SYSCALL_AFTER | SYSCALL_EVERY => Some((2, 0)),
+            SYSCALL_MS_FETCH => Some((0, 1)),
+            SYSCALL_IN => Some((1, 1)),
+            SYSCALL_OUT => Some((2, 0)),
+            SYSCALL_ARRAY => Some((2, 0)),
+            SYSCALL_CREATED_WORD | SYSCALL_DOES => Some((1, 0)),
+            SYSCALL_CREATE => Some((0, 0)),
+            SYSCALL_EVALUATE => None,
+            SYSCALL_UNBOUND_EXTERN => Some((1, 0)),
+            SYSCALL_PROFILE_ON | SYSCALL_PROFILE_OFF | SYSCALL_PROFILE_REPORT => Some((0, 0)),
+            SYSCALL_KV_STORE => Some((3, 0)),
+            SYSCALL_KV_FETCH => Some((2, 2)),
+            #[cfg(feature = "editor")]
+            SYSCALL_EDIT => Some((1, 0)),
+            #[cfg(feature = "tui")]
+            SYSCALL_AT_XY => Some((2, 0)),
+            #[cfg(feature = "tui")]
+            SYSCALL_PAGE => Some((0, 0)),
+            #[cfg(feature = "tui")]
+            SYSCALL_KEY_QUESTION => Some((0, 1)),
+            _ => None,
+        }
+    }
+
+    fn system_call(&mut self, id: u16, gas_limit: GasLimit) -> Result<(), ForthError> {
+        match id {
+            SYSCALL_PRINT_STATS => {
+                let stats = self.stats();
+                write!(self.sm.st.output, "{}", stats)?;
+            }
+            SYSCALL_AFTER | SYSCALL_EVERY => {
+                let xt = self.pop_number()?;
+                let ms = self.pop_number()?;
+                let address = usize::try_from(xt)
+                    .ok()
+                    .filter(|a| self.word_addresses.values().any(|w| w == a))
+                    .ok_or(ForthError::InvalidExecutionToken(xt))?;
+                // Negative delays are already due
+                let ms = u64::try_from(ms).unwrap_or(0);
+                self.timers.push(Timer {
+                    due: self.current_time.saturating_add(ms),
+                    interval: if id == SYSCALL_EVERY { Some(ms) } else { None },
+                    address,
+                });
+            }
+            SYSCALL_MS_FETCH => {
+                let now = self.clock.now_millis();
+                self.sm.st.number_stack.push(now as i64);
+            }
+            SYSCALL_IN => {
+                let port = self.pop_number()?;
+                let value = self.io_bus.read_port(port)?;
+                self.sm.st.number_stack.push(value);
+            }
+            SYSCALL_OUT => {
+                let port = self.pop_number()?;
+                let value = self.pop_number()?;
+                self.io_bus.write_port(port, value)?;
+            }
+            SYSCALL_ARRAY => {
+                let word = self.pop_code_address()?;
+                let length = self.pop_number()?;
+                let length =
+                    usize::try_from(length).map_err(|_| ForthError::OutOfDomain(length))?;
+                let address = self.sm.st.allocate_array(length)?;
+                self.sm.st.opcodes[word] = Opcode::LDI(address);
+            }
+            SYSCALL_CREATED_WORD => {
+                self.word_to_create = Some(self.pop_code_address()?);
+            }
+            SYSCALL_CREATE => {
+                let word = self.word_to_create.take().ok_or_else(|| {
+                    ForthError::InvalidSyntax(
+                        "CREATE without a name for the word it makes".to_owned(),
+                    )
+                })?;
+                let word = self.sm.st.code_address(word as i64)?;
+                self.sm.st.opcodes[word] = Opcode::LDI(self.sm.st.here());
+                self.last_created = Some(word);
+                self.data_words.insert(word);
+            }
+            SYSCALL_DOES => {
+                let action = self.pop_number()?;
+                let word = self.last_created.ok_or_else(|| {
+                    ForthError::InvalidSyntax("DOES> without a word made by CREATE".to_owned())
+                })?;
+                // The action goes in the two opcodes after the created word's LDI
+                self.sm.st.code_address(word as i64 + 2)?;
+                self.sm.st.opcodes[word + 1] = Opcode::LDI(action);
+                self.sm.st.opcodes[word + 2] = Opcode::JMP;
+            }
+            SYSCALL_EVALUATE => {
+                let source = self.pop_string()?;
+                self.evaluate_nested(&source, gas_limit)?;
+            }
+            SYSCALL_UNBOUND_EXTERN => {
+                let address = self.pop_number()?;
+                let name = self
+                    .externs
+                    .iter()
+                    .find(|(_, a)| **a as i64 == address)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_default();
+                return Err(ForthError::UnresolvedExterns(vec![name]));
+            }
+            SYSCALL_PROFILE_ON => self.sm.profiler = Some(Profiler::new()),
+            SYSCALL_PROFILE_OFF => self.sm.profiler = None,
+            SYSCALL_PROFILE_REPORT => {
+                writeln!(
+                    self.sm.st.output,
+                    "{:<24} {:>10} {:>12} {:>12}",
+                    "word", "calls", "self gas", "total gas"
+                )?;
+                for (name, word) in self.profile() {
+                    writeln!(
+                        self.sm.st.output,
+                        "{:<24} {:>10} {:>12} {:>12}",
+                        name, word.calls, word.self_gas, word.total_gas
+                    )?;
+                }
+            }
+            SYSCALL_KV_STORE => {
+                let key = self.pop_string()?;
+                let value = self.pop_number()?;
+                self.key_value_store.set(&key, value)?;
+            }
+            SYSCALL_KV_FETCH => {
+                // The value and a flag saying whether it was there, 0 and false when it wasn't
+                let key = self.pop_string()?;
+                let (value, found) = match self.key_value_store.get(&key)? {
+                    Some(value) => (value, -1),
+                    None => (0, 0),
+                };
+                self.sm.st.number_stack.extend_from_slice(&[value, found]);
+            }
+            SYSCALL_LIST => {
+                let n = self.pop_block_number()?;
+                let listing = self.block_store.list(n);
+                write!(self.sm.st.output, "{}", listing)?;
+            }
+            #[cfg(feature = "editor")]
+            SYSCALL_EDIT => {
+                let n = self.pop_block_number()?;
+                super::editor::edit(&mut self.block_store, n, &mut self.sm.st.output)?;
+            }
+            #[cfg(feature = "tui")]
+            SYSCALL_AT_XY => {
+                let y = self.pop_number()?;
+                let x = self.pop_number()?;
+                super::tui::at_xy(&mut self.sm.st.output, x, y)?;
+            }
+            #[cfg(feature = "tui")]
+            SYSCALL_PAGE => super::tui::page(&mut self.sm.st.output)?,
+            #[cfg(feature = "tui")]
+            SYSCALL_KEY_QUESTION => {
+                let flag = if super::tui::key_available()? { -1 } else { 0 };
+                self.sm.st.number_stack.push(flag);
+            }
+            _ => return Err(ForthError::UnknownSystemCall(id)),
+        }
+        Ok(())
+    }
+
+    pub fn execute_string(&mut self, s: &str, gas_limit: GasLimit) -> Result<(), ForthError> {
+        let tv = located_tokens(s)?;
+        // Blank lines leave everything as it was
+        if tv.is_empty() {
+            return Ok(());
+        }
+        self.execute_token_vector(&tv, gas_limit)?;
+        Ok(())
+    }
+
+    /// Queue an event for the word registered with ON-EVENT to handle, the payload is pushed onto
+    /// the number stack, last value on top, before the handler runs
+    pub fn post_event(&mut self, event: &str, payload: &[i64]) {
+        self.event_queue
+            .push_back((event.to_owned(), payload.to_vec()));
+    }
+
+    pub fn pending_events(&self) -> usize {
+        self.event_queue.len()
+    }
+
+    /// Handle queued events, meant to be called once per tick of the host's loop. All the handlers
+    /// share the gas limit, if it runs out the events whose handlers haven't started stay queued
+    /// for the next tick. Events without a handler are dropped. Returns how many events were handled.
+    pub fn process_events(&mut self, gas_limit: GasLimit) -> Result<usize, ForthError> {
+        let mut handled = 0;
+        let mut gas_left = gas_limit;
+
+        // Once the gas is used up exactly the next event stays queued rather than failing to start
+        while gas_left != GasLimit::Limited(0) {
+            let (event, payload) = match self.event_queue.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            let word = match self.event_handlers.get(&event) {
+                Some(word) => word.clone(),
+                None => continue,
+            };
+            self.sm.st.number_stack.extend_from_slice(&payload);
+            self.execute_token_vector(
+                &[(Token::Command(word), SourceLocation::default())],
+                gas_left,
+            )?;
+            handled += 1;
+
+            if let GasLimit::Limited(gas) = gas_left {
+                gas_left = GasLimit::Limited(gas.saturating_sub(self.sm.st.gas_used()));
+            }
+        }
+
+        Ok(handled)
+    }
+
+    /// Run the words scheduled with AFTER and EVERY that are due by now, the host's clock in
+    /// milliseconds, earliest first. All the handlers share the gas limit, if it runs out the
+    /// ones that haven't started are still due next tick. An EVERY timer runs at most once a tick
+    /// however far behind it is. Returns how many handlers ran.
+    pub fn tick(&mut self, now: u64, gas_limit: GasLimit) -> Result<usize, ForthError> {
+        self.current_time = now;
+        let mut ran = 0;
+        let mut gas_left = gas_limit;
+
+        loop {
+            let next = self
+                .timers
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| t.due <= now)
+                .min_by_key(|(_, t)| t.due)
+                .map(|(i, _)| i);
+            let timer = match next {
+                Some(i) => self.timers.remove(i),
+                None => break,
+            };
+            // A timer whose word is no longer there is dropped rather than run
+            let address = self.sm.st.code_address(timer.address as i64)?;
+            if let Some(interval) = timer.interval {
+                let due = timer
+                    .due
+                    .saturating_add(interval)
+                    .max(now.saturating_add(1));
+                // Once the clock can go no further there is no later tick for it to run in
+                if due > now {
+                    self.timers.push(Timer {
+                        due,
+                        ..timer.clone()
+                    });
+                }
+            }
+
+            self.run_code(RunStart::At(address), gas_left)?;
+            ran += 1;
+
+            if let GasLimit::Limited(gas) = gas_left {
+                gas_left = GasLimit::Limited(gas.saturating_sub(self.sm.st.gas_used()));
+            }
+        }
+
+        Ok(ran)
+    }
+
+    /// Recompile a word from the source of its definition, `: NAME ... ;`, writing the new body
+    /// over the old one so that every word that already calls it picks up the change. Returns
+    /// false, leaving everything as it was, when the word isn't in the dictionary yet or its new
+    /// body is bigger than the old one.
+    pub fn patch_word(&mut self, definition: &str) -> Result<bool, ForthError> {
+        let tv = located_tokens(definition)?;
+        let (name, end_location) = match (tv.first(), tv.last()) {
+            (Some((Token::Colon(name), _)), Some((Token::SemiColon, location)))
+                if tv.len() >= 2 =>
+            {
+                (name.clone(), *location)
+            }
+            _ => {
+                return Err(ForthError::InvalidSyntax(
+                    "A patch must be exactly one colon definition".to_string(),
+                ))
+            }
+        };
+        let body = &tv[1..tv.len() - 1];
+        if body
+            .iter()
+            .any(|(t, _)| matches!(t, Token::Colon(_) | Token::SemiColon))
+        {
+            return Err(ForthError::InvalidSyntax(
+                "A patch must be exactly one colon definition".to_string(),
+            ));
+        }
+
+        let (address, length) = match (self.word_addresses.get(&name), self.word_lengths.get(&name))
+        {
+            (Some(address), Some(length)) => (*address, *length),
+            _ => return Ok(false),
+        };
+
+        let mut compiled = self.compile_token_vector(body)?;
+        compiled.push(Opcode::RET, end_location);
+        compiled.link_relocations(address);
+        if compiled.len() > length {
+            return Ok(false);
+        }
+        self.place_strings(&mut compiled)?;
+        self.place_assertions(&mut compiled);
+        self.data_words.extend(compiled.data_references_at(address));
+        // Anything left of the old body is never reached, but NOPs keep listings tidy
+        while compiled.len() < length {
+            compiled.push(Opcode::NOP, end_location);
+        }
+
+        self.sm.st.opcodes[address..address + length].clone_from_slice(&compiled.opcodes);
+        self.source_map[address..address + length].copy_from_slice(&compiled.locations);
+        self.add_word(&name, address, length);
+        Ok(true)
+    }
+
+    /// The elements of an array made with ARRAY, None if the word isn't an array or ARRAY hasn't
+    /// run yet
+    pub fn array(&self, name: &str) -> Option<&[i64]> {
+        self.sm.st.array(self.array_address(name)?)
+    }
+
+    pub fn array_mut(&mut self, name: &str) -> Option<&mut [i64]> {
+        let address = self.array_address(name)?;
+        self.sm.st.array_mut(address)
+    }
+
+    /// Push cells onto the number stack for a script to work on, the last one on top. For hosts
+    /// handing over a frame of samples at a time.
+    pub fn push_slice(&mut self, cells: &[i64]) {
+        self.sm.st.push_slice(cells);
+    }
+
+    /// Pop the cells a script left into the buffer, what was on top last
+    pub fn pop_n_into(&mut self, buffer: &mut [i64]) -> Result<(), ForthError> {
+        Ok(self.sm.st.pop_n_into(buffer)?)
+    }
+
+    /// Take everything on the number stack, bottom first
+    pub fn drain_stack(&mut self) -> Vec<i64> {
+        self.sm.st.drain_stack()
+    }
+
+    // The address the array's word pushes
+    fn array_address(&self, name: &str) -> Option<i64> {
+        match self.sm.st.opcodes.get(*self.arrays.get(name)?)? {
+            Opcode::LDI(address) if self.word_addresses.get(name) == self.arrays.get(name) => {
+                Some(*address)
+            }
+            _ => None,
+        }
+    }
+
+    /// Map a host buffer into the data space and define a word with the buffer's name that
+    /// pushes its address, so programs can fill it with []! while the host reads it through its
+    /// own clone. Returns the buffer's address.
+    pub fn map_buffer(&mut self, name: &str, buffer: SharedBuffer) -> Result<i64, ForthError> {
+        let address = self.sm.st.map_buffer(buffer)?;
+        self.define_constant(name, address, SourceLocation::default())?;
+        Ok(address)
+    }
+
+    /// Read a source file through the compiler's FileSystem and run it. REQUIRE FILE in the
+    /// source loads another file first unless it has already been loaded, by REQUIRE or by
+    /// include_file, so a project's files can each name what they need.
+    pub fn include_file(&mut self, path: &str, gas_limit: GasLimit) -> Result<(), ForthError> {
+        let source = self.file_system.read_to_string(path)?;
+        // Marked first so the file's REQUIREs don't load it again, a file that fails isn't loaded
+        self.required_files.insert(path.to_owned());
+        let result = self.execute_string(&source, gas_limit);
+        if result.is_err() {
+            self.required_files.remove(path);
+        }
+        result
+    }
+
+    /// Capture the dictionary, aliases, number stack and data space along with the history the
+    /// host kept, so an interactive session can be picked up again in another run
+    pub fn saved_session(&self, history: &[String]) -> SavedSession {
+        let mut arrays: Vec<(String, usize)> = self
+            .arrays
+            .iter()
+            .map(|(name, address)| (name.clone(), *address))
+            .collect();
+        arrays.sort();
+        let mut aliases: Vec<(String, String)> = self
+            .aliases
+            .iter()
+            .map(|(alias, word)| (alias.clone(), word.clone()))
+            .collect();
+        aliases.sort();
+        let mut event_handlers: Vec<(String, String)> = self
+            .event_handlers
+            .iter()
+            .map(|(event, word)| (event.clone(), word.clone()))
+            .collect();
+        event_handlers.sort();
+        let mut defining_words: Vec<String> = self.defining_words.iter().cloned().collect();
+        defining_words.sort();
+        let mut declared_effects: Vec<(String, (usize, usize))> = self
+            .declared_effects
+            .iter()
+            .map(|(word, effect)| (word.clone(), *effect))
+            .collect();
+        declared_effects.sort();
+        let mut externs: Vec<(String, usize)> = self
+            .externs
+            .iter()
+            .map(|(name, address)| (name.clone(), *address))
+            .collect();
+        externs.sort();
+        let mut data_words: Vec<usize> = self.data_words.iter().copied().collect();
+        data_words.sort();
+
+        // Externs are saved unbound, the host loading the session binds them to its own words
+        let mut opcodes = self.sm.st.opcodes[..self.last_function].to_vec();
+        for (_, address) in externs.iter() {
+            opcodes.splice(
+                *address..*address + EXTERN_STUB_LENGTH,
+                ForthCompiler::extern_stub(*address).iter().cloned(),
+            );
+        }
+
+        SavedSession {
+            opcodes,
+            words: self.build_artifact().words,
+            arrays,
+            aliases,
+            defining_words,
+            declared_effects,
+            event_handlers,
+            externs,
+            data_words,
+            string_space_bytes: self.string_space_bytes,
+            assertion_locations: self.assertion_locations.clone(),
+            number_stack: self.sm.st.number_stack.clone(),
+            data: self.sm.st.data().to_vec(),
+            history: history.to_vec(),
+        }
+    }
+
+    /// Replace the dictionary, aliases, number stack and data space with a saved session's,
+    /// giving back its history. Intrinsics and the host's settings are left as they are. Each
+    /// EXTERN word is bound to the intrinsic of the same name, and if any are missing nothing is
+    /// restored and the error lists them. Timers and queued events go with the old dictionary.
+    pub fn restore_session(&mut self, session: SavedSession) -> Result<Vec<String>, ForthError> {
+        if let Some(limit) = self.opcode_memory_limit {
+            if session.opcodes.len() > limit {
+                return Err(ForthError::DictionaryFull);
+            }
+        }
+        let unresolved: Vec<String> = session
+            .externs
+            .iter()
+            .filter(|(name, stub)| {
+                !self.intrinsic_words.contains_key(name)
+                    && !session
+                        .words
+                        .iter()
+                        .any(|w| &w.name == name && w.address != *stub)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !unresolved.is_empty() {
+            return Err(ForthError::UnresolvedExterns(unresolved));
+        }
+        self.sm.st.restore_data(session.data)?;
+        self.sm.st.number_stack = session.number_stack;
+        self.sm.st.clear_return_stack();
+        self.suspended = false;
+        self.scratch_data = 0..0;
+        self.scratch_assertions = 0..0;
+        self.string_space_bytes = session.string_space_bytes;
+        // Whatever the exposed words, timers and queued events ran, the session's code now has
+        // their addresses
+        self.sm.trap_guard.forget_from(0);
+        self.timers.clear();
+        self.event_queue.clear();
+
+        self.last_function = session.opcodes.len();
+        self.source_map = vec![SourceLocation::default(); self.last_function];
+        self.sm.st.opcodes = session.opcodes;
+        self.word_addresses.clear();
+        self.word_lengths.clear();
+        for w in session.words {
+            self.word_addresses.insert(w.name.clone(), w.address);
+            self.word_lengths.insert(w.name, w.length);
+        }
+        self.arrays = session.arrays.into_iter().collect();
+        self.aliases = session.aliases.into_iter().collect();
+        self.defining_words = session.defining_words.into_iter().collect();
+        self.declared_effects = session.declared_effects.into_iter().collect();
+        self.last_created = None;
+        self.event_handlers = session.event_handlers.into_iter().collect();
+        self.assertion_locations = session.assertion_locations;
+        self.externs = session.externs.iter().cloned().collect();
+        self.data_words = session.data_words.into_iter().collect();
+        for (name, address) in session.externs {
+            self.bind_extern(&name, address)?;
+        }
+
+        Ok(session.history)
+    }
+
+    /// Add a saved session's words and data after those already here, so an application can be
+    /// built from images compiled separately, such as a shared prelude, a vendor library and the
+    /// application itself. Calls and the words' data addresses are relocated, though execution
+    /// tokens kept as plain numbers are not. The image's EXTERN words are bound to the host's
+    /// word of the same name, or else to a word already in the dictionary. If any can't be,
+    /// nothing is linked and the error lists them. The image's own words replace any with the
+    /// same name, as a later definition would, but its stack and history are left out.
+    pub fn link_session(&mut self, session: SavedSession) -> Result<(), ForthError> {
+        let code_base = self.last_function;
+        let data_base = self.sm.st.here();
+        let assertion_base = self.assertion_locations.len();
+        if let Some(limit) = self.opcode_memory_limit {
+            if code_base + session.opcodes.len() > limit {
+                return Err(ForthError::DictionaryFull);
+            }
+        }
+        let unresolved: Vec<String> = session
+            .externs
+            .iter()
+            .filter(|(name, stub)| {
+                !self.intrinsic_words.contains_key(name)
+                    && !self.word_addresses.contains_key(name)
+                    && !session
+                        .words
+                        .iter()
+                        .any(|w| &w.name == name && w.address != *stub)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !unresolved.is_empty() {
+            return Err(ForthError::UnresolvedExterns(unresolved));
+        }
+        let mut data = self.sm.st.data().to_vec();
+        data.extend_from_slice(&session.data);
+        self.sm.st.restore_data(data)?;
+        // The image's code goes where the immediate mode code was, which exposes nothing
+        self.sm.trap_guard.forget_from(code_base);
+
+        let mut opcodes = session.opcodes;
+        ForthCompiler::relocate_code(&mut opcodes, code_base, assertion_base);
+        let data_words = session
+            .data_words
+            .iter()
+            .chain(session.arrays.iter().map(|(_, address)| address));
+        for address in data_words {
+            match opcodes[*address] {
+                // An array that hasn't been reserved yet still pushes 0
+                Opcode::LDI(0) if session.arrays.iter().any(|(_, a)| a == address) => (),
+                Opcode::LDI(a) => opcodes[*address] = Opcode::LDI(a + data_base),
+                _ => (),
+            }
+        }
+        self.last_function += opcodes.len();
+        self.source_map.truncate(code_base);
+        self.source_map
+            .resize(self.last_function, SourceLocation::default());
+        self.sm.st.opcodes.truncate(code_base);
+        self.sm.st.opcodes.append(&mut opcodes);
+
+        for w in session.words {
+            // A stub doesn't hide the word it is going to be bound to
+            let stub = session.externs.contains(&(w.name.clone(), w.address));
+            if stub && self.word_addresses.contains_key(&w.name) {
+                continue;
+            }
+            self.add_word(&w.name, w.address + code_base, w.length);
+        }
+        self.arrays.extend(
+            session
+                .arrays
+                .into_iter()
+                .map(|(name, address)| (name, address + code_base)),
+        );
+        self.data_words
+            .extend(session.data_words.into_iter().map(|a| a + code_base));
+        self.aliases.extend(session.aliases);
+        self.defining_words.extend(session.defining_words);
+        self.declared_effects.extend(session.declared_effects);
+        self.event_handlers.extend(session.event_handlers);
+        self.assertion_locations.extend(session.assertion_locations);
+        self.string_space_bytes += session.string_space_bytes;
+        for (name, address) in session.externs {
+            self.externs.insert(name.clone(), address + code_base);
+            self.bind_extern(&name, address + code_base)?;
+        }
+        Ok(())
+    }
+
+    // Move an image's code to code_base, adjusting the code addresses its LDIs push for calls,
+    // jumps and the system calls that take a word, and the ids its assertions report
+    fn relocate_code(opcodes: &mut [Opcode], code_base: usize, assertion_base: usize) {
+        for i in 1..opcodes.len() {
+            let offset = match opcodes[i] {
+                Opcode::CALL
+                | Opcode::JMP
+                | Opcode::SYSCALL(SYSCALL_ARRAY)
+                | Opcode::SYSCALL(SYSCALL_CREATED_WORD)
+                | Opcode::SYSCALL(SYSCALL_DOES)
+                | Opcode::SYSCALL(SYSCALL_UNBOUND_EXTERN) => code_base,
+                Opcode::ASSERT => assertion_base,
+                _ => continue,
+            };
+            if let Opcode::LDI(a) = opcodes[i - 1] {
+                opcodes[i - 1] = Opcode::LDI(a + offset as i64);
+            }
+        }
+    }
+
+    /// Remember the dictionary, stacks and data space as they are now, along with the files
+    /// REQUIRE has loaded
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            session: self.saved_session(&[]),
+            trap_guard: self.sm.trap_guard.clone(),
+            source_map: self.source_map[..self.last_function].to_vec(),
+            required_files: self.required_files.clone(),
+            reloaded_files: self.reloaded_files.clone(),
+        }
+    }
+
+    /// Go back to a checkpoint, forgetting everything since: words, stacks, data, big integers,
+    /// queued events, timers, a suspended run and the quota used
+    pub fn restore_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), ForthError> {
+        self.sm.st.clear_run_state();
+        self.restore_session(checkpoint.session.clone())?;
+        // Binding the externs may have put code after the dictionary the checkpoint knew
+        let known = checkpoint.source_map.len();
+        self.source_map[..known].clone_from_slice(&checkpoint.source_map);
+        self.required_files = checkpoint.required_files.clone();
+        self.reloaded_files = checkpoint.reloaded_files.clone();
+        // Whether traps are guarded is the sandbox policy's to say, not the checkpoint's
+        let enabled = self.sm.trap_guard.enabled;
+        self.sm.trap_guard = checkpoint.trap_guard.clone();
+        self.sm.trap_guard.enabled = enabled;
+        self.quota_usage = QuotaUsage::default();
+        self.last_artifact = None;
+        self.last_backtrace = Backtrace::default();
+        self.word_to_create = None;
+        Ok(())
+    }
+
+    /// Treat everything defined so far as the prelude, which reset(ResetLevel::UserWords) keeps.
+    /// with_prelude and with_prelude_file call this once the prelude has run.
+    pub fn mark_prelude(&mut self) {
+        self.prelude = Some(self.checkpoint());
+    }
+
+    /// Put the compiler back to a clean state without making a new one and reloading its
+    /// prelude, so nothing one run leaves behind can affect the next. Without a prelude,
+    /// UserWords resets everything.
+    pub fn reset(&mut self, level: ResetLevel) -> Result<(), ForthError> {
+        if level == ResetLevel::Stacks {
+            self.sm.st.clear_run_state();
+            self.suspended = false;
+            self.last_backtrace = Backtrace::default();
+            return Ok(());
+        }
+        // Once everything is gone there is no prelude for a later reset to keep
+        let target = match level {
+            ResetLevel::UserWords => self.prelude.clone().unwrap_or_default(),
+            _ => {
+                self.prelude = None;
+                Checkpoint::default()
+            }
+        };
+        let aliases = self.aliases.clone();
+        let result = self.restore_checkpoint(&target);
+        self.aliases = aliases;
+        result
+    }
+
+    /// Write saved_session to a file through the compiler's FileSystem
+    pub fn save_session(&mut self, path: &str, history: &[String]) -> Result<(), ForthError> {
+        let text = self.saved_session(history).to_string();
+        self.file_system.write(path, &text)?;
+        Ok(())
+    }
+
+    /// Like save_session but the file is deflated, which load_session notices and undoes
+    #[cfg(feature = "compression")]
+    pub fn save_session_compressed(
+        &mut self,
+        path: &str,
+        history: &[String],
+    ) -> Result<(), ForthError> {
+        let bytes = self.saved_session(history).compressed();
+        self.file_system.write_bytes(path, &bytes)?;
+        Ok(())
+    }
+
+    /// Read a file written by save_session, compressed or not, through the compiler's
+    /// FileSystem and restore it, giving back its history
+    pub fn load_session(&mut self, path: &str) -> Result<Vec<String>, ForthError> {
+        let session = SavedSession::from_bytes(&self.file_system.read(path)?)?;
+        self.restore_session(session)
+    }
+
+    /// Read a file written by save_session, compressed or not, through the compiler's
+    /// FileSystem and link it after the words already here, see link_session
+    pub fn link_session_file(&mut self, path: &str) -> Result<(), ForthError> {
+        let session = SavedSession::from_bytes(&self.file_system.read(path)?)?;
+        self.link_session(session)
+    }
+
+    /// True when the source stops partway through a colon definition, IF, loop or ASSERT( and
+    /// more lines should be read before running it. Source with an ending that has nothing to
+    /// close counts as complete, so running it reports the mistake.
+    pub fn is_incomplete(&self, source: &str) -> bool {
+        let tokens = match tokenize_string(source) {
+            Ok(tokens) => tokens,
+            Err(_) => return false,
+        };
+
+        let mut in_definition = false;
+        let mut open_control = 0;
+        let mut open_assertions = 0;
+        for t in tokens.iter() {
+            match &t.token {
+                Token::Colon(_) => in_definition = true,
+                Token::SemiColon if !in_definition => return false,
+                Token::SemiColon => in_definition = false,
+                Token::Assert(_) => open_assertions += 1,
+                Token::Command(word) => {
+                    let word = self.aliases.get(word).unwrap_or(word);
+                    match word.as_str() {
+                        ")" if open_assertions > 0 => open_assertions -= 1,
+                        "IF" | "DO" | "?DO" | "BEGIN" => open_control += 1,
+                        "THEN" | "LOOP" | "+LOOP" | "AGAIN" if open_control == 0 => return false,
+                        "THEN" | "LOOP" | "+LOOP" | "AGAIN" => open_control -= 1,
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+        in_definition || open_control > 0 || open_assertions > 0
+    }
+
+    /// Read a source file through the compiler's FileSystem and recompile the definitions in it
+    /// that changed since the last reload, writing them over the old ones where they fit so
+    /// running words pick them up. Code outside definitions isn't run again, and words from
+    /// other files are left alone. Returns every definition in the file with what happened to
+    /// it, the first reload of a file counts every definition as changed.
+    pub fn reload_file(&mut self, path: &str) -> Result<Vec<DefinitionUpdate>, ForthError> {
+        let source = self.file_system.read_to_string(path)?;
+        let previous = self.reloaded_files.remove(path).unwrap_or_default();
+        let (definitions, changes) = match update_definitions(self, &previous, &source) {
+            Ok(updated) => updated,
+            Err(e) => {
+                self.reloaded_files.insert(path.to_owned(), previous);
+                return Err(e);
+            }
+        };
+        self.reloaded_files.insert(path.to_owned(), definitions);
+        Ok(changes)
+    }
+
+    /// Compile a string without running it, words are added to the dictionary and the immediate
+    /// mode code is left in memory after them
+    pub fn compile_string(&mut self, s: &str) -> Result<(), ForthError> {
+        let tv = located_tokens(s)?;
+        self.compile_tokens(&tv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::MemoryKeyValueStore;
+    use crate::quota::QUOTA_WINDOW_MILLIS;
+
+    #[test]
+    fn test_prelude_uses_host_words() {
+        let host_word = vec![("ANSWER".to_owned(), vec![Opcode::LDI(100), Opcode::TRAP])];
+        let answer = |_, st: &mut crate::stack_machine::StackMachineState| {
+            st.number_stack.push(42);
+            Ok(TrapHandled::Handled)
+        };
+        let prelude = ": Answer+1 ANSWER INC ; Answer+1";
+
+        let fc = ForthCompiler::new()
+            .with_additional_intrinsics(host_word.clone())
+            .with_trap_handler(TrapHandler::new(100, answer))
+            .with_handler_pack(crate::handlers::RandomHandlers::new(7))
+            .with_prelude(prelude, GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![43_i64]);
+
+        // A prelude run before the host's words are added can't see them
+        match ForthCompiler::new().with_prelude(prelude, GasLimit::Limited(100)) {
+            Err(ForthError::DefinitionFailed(e)) => match e.error {
+                ForthError::UnknownToken(word) => assert_eq!(word, "ANSWER"),
+                r => panic!("Incorrect error type returned {:?}", r),
+            },
+            r => panic!("Incorrect result {:?}", r.map(|fc| fc.sm.st.number_stack)),
+        }
+    }
+
+    #[test]
+    fn test_execute_intrinsics_1() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+
+        fc.execute_string("123 321 ADD 2 MUL", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888]);
+    }
+
+    #[test]
+    fn test_compile_1() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": RickTest 123 321 ADD 2 MUL ; RickTest",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+
+        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+    }
+
+    #[test]
+    fn test_compile_2() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": RickTest 123 321 ADD 2 MUL ; RickTest : RickTestB 123 321 ADD 2 MUL ;",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+
+        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+    }
+
+    #[test]
+    fn test_compile_3() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; RickTest : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB POP",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64]);
+
+        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![888_i64, 888, 888]);
+    }
+
+    #[test]
+    fn test_compile_4() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+
+        fc.execute_string("123 321 ADD 2 MUL RickTest", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 888, 888]);
+    }
+
+    #[test]
+    fn test_compile_fail_1() {
+        let mut fc = ForthCompiler::new();
+
+        match fc.execute_string(
+            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::DefinitionFailed(d)) => match d.error {
+                ForthError::UnknownToken(ref x) if x == "RickTestB" => {
+                    assert_eq!((d.word.as_str(), d.compiled), (":", 1))
+                }
+                e => panic!("Incorrect error type returned {:?}", e),
+            },
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_compile_fail_2() {
+        let mut fc = ForthCompiler::new();
+
+        match fc.execute_string(
+            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; ; : RickTestB 123 321 ADD 2 MUL ; 3 3 SUB",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::InvalidSyntax(_)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_compile_fail_3() {
+        let mut fc = ForthCompiler::new();
+
+        match fc.execute_string(
+            "2 2 SUB POP : RickTest 123 321 ADD 2 MUL ; : RickTestB 123 321 ADD 2 MUL ; : ERROR 3 3 SUB",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::MissingSemicolonAfterColon) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_definition_failure_modes() {
+        let mut fc = ForthCompiler::new();
+        let source = ": One 1 ; : Two 2 ; : Bad MISSING ; : Three 3 ;";
+
+        match fc.execute_string(source, GasLimit::Limited(100)) {
+            Err(ForthError::DefinitionFailed(d)) => {
+                assert_eq!((d.word.as_str(), d.compiled), ("Bad", 2));
+                assert!(matches!(d.error, ForthError::UnknownToken(ref x) if x == "MISSING"));
+            }
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert!(fc.knows_word("Two"));
+        assert!(!fc.knows_word("Three"));
+
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Zero 0 ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.set_definition_failure_mode(DefinitionFailureMode::AbortAll);
+        match fc.execute_string(source, GasLimit::Limited(100)) {
+            Err(ForthError::DefinitionFailed(d)) => assert_eq!(d.compiled, 2),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert!(!fc.knows_word("One"));
+        assert_eq!(fc.stats().opcode_memory, 2);
+
+        fc.execute_string("Zero", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+
+        // The data space, strings, assertions and what's known about words go back too
+        let data = fc.sm.st.data().to_vec();
+        let stats = fc.stats();
+        match fc.execute_string(
+            "VARIABLE Count 2 ARRAY Table : Greet S\" hello\" ASSERT( 1 ) ; : Maker CREATE ; \
+             TRAP: IO-IN 100 ( port -- value ) : Broken MISSING ;",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::DefinitionFailed(_)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(fc.sm.st.data(), &data[..]);
+        assert_eq!(fc.stats().string_space_bytes, stats.string_space_bytes);
+        assert_eq!(fc.assertion_locations.len(), 0);
+        assert!(!fc.is_defining_word("Maker"));
+        assert_eq!(fc.declared_stack_effect("IO-IN"), None);
+    }
+
+    #[test]
+    fn test_if_else_1() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "1 2 3 POP POP POP 0 IF 1 2 ADD ELSE 3 4 ADD THEN",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+    }
+
+    #[test]
+    fn test_if_else_2() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "1 2 3 POP POP POP 1 IF 1 2 ADD ELSE 3 4 ADD THEN",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64]);
+    }
+
+    #[test]
+    fn test_if_else_3() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string("0 IF 1 2 ADD ELSE 3 4 ADD THEN", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+    }
+
+    #[test]
+    fn test_if_else_4() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string("1 IF 1 2 ADD ELSE 3 4 ADD THEN", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64]);
+    }
+
+    #[test]
+    fn test_if_else_truthiness() {
+        // IF runs its ELSE branch for a true flag, and only -1 is true with AllBitsSet
+        for (truthiness, results) in [
+            (Truthiness::NonZero, vec![3_i64, 7, 7]),
+            (Truthiness::AllBitsSet, vec![3_i64, 3, 7]),
+        ] {
+            let mut fc = ForthCompiler::new();
+            fc.sm.st.truthiness = truthiness;
+
+            fc.execute_string(
+                ": Pick IF 3 ELSE 7 THEN ; 0 Pick 5 Pick -1 Pick",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+
+            assert_eq!(&fc.sm.st.number_stack, &results);
+        }
+    }
+
+    #[test]
+    fn test_if_else_uneven_branches() {
+        let mut fc = ForthCompiler::new();
+        fc.sm.integrity_checks = Some(IntegrityChecks::default());
+
+        fc.execute_string(
+            ": Pick IF 1 2 3 ELSE 4 THEN ; 0 Pick 1 Pick",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_integrity_violation() {
+        let mut fc = ForthCompiler::new();
+        fc.sm.integrity_checks = Some(IntegrityChecks {
+            max_number_stack: 3,
+            max_return_stack: 8,
+        });
+
+        match fc.execute_string(": Fill 1 2 3 4 ; Fill", GasLimit::Limited(100)) {
+            Err(ForthError::IntegrityViolation(v)) => {
+                assert_eq!(v.description, "number stack depth 4 is over the limit of 3");
+                assert_eq!(v.pc, 3);
+                assert_eq!(v.word, Some("Fill".to_owned()));
+                assert_eq!(v.number_stack, vec![1, 2, 3, 4]);
+                assert_eq!(v.return_stack, vec![7]);
+            }
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_trap_1() {
+        let mut fc = ForthCompiler::new();
+
+        // Simulate a IO OUT command, at TRAP(100)
+        fc.sm
+            .trap_handlers
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                let io_port = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                let io_value = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                println!(
+                    "Simulated IO OUT command to Port: {} and Value: {}",
+                    io_port, io_value
+                );
+                Ok(TrapHandled::Handled)
+            })));
+
+        fc.execute_string(
+            ": IO_OUT 100 TRAP ; 123456 1000 IO_OUT",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // Nothing left over
+        assert_eq!(&fc.sm.st.number_stack, &Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_trap_2() {
+        let mut fc = ForthCompiler::new();
+
+        // Simulate a IO IN command, at TRAP(101)
+        fc.sm
+            .trap_handlers
+            .push(Box::from(TrapHandler::new(101, |_trap_id, st| {
+                let io_port = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                let io_value = 654321_i64;
+                println!(
+                    "Simulated IO IN command from Port: {} and Value: {}",
+                    io_port, io_value
+                );
+                st.number_stack.push(io_value);
+                Ok(TrapHandled::Handled)
+            })));
+
+        fc.execute_string(": IO_IN 101 TRAP ; 1000 IO_IN", GasLimit::Limited(100))
+            .unwrap();
+
+        // Value from IO port on stack
+        assert_eq!(&fc.sm.st.number_stack, &vec![654321]);
+    }
+
+    #[test]
+    fn test_trap_3() {
+        let mut fc = ForthCompiler::new();
+
+        // Simulate a IO OUT command, at TRAP(100), but define the port number inside a Forth Word as well
+        fc.sm
+            .trap_handlers
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                let io_port = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                let io_value = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                println!(
+                    "Simulated IO OUT command to Port: {} and Value: {}",
+                    io_port, io_value
+                );
+                Ok(TrapHandled::Handled)
+            })));
+
+        fc.execute_string(
+            ": IO_OUT 100 TRAP ; : OUT_DISPLAY 1000 IO_OUT ; 123456 OUT_DISPLAY",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // Nothing left over
+        assert_eq!(&fc.sm.st.number_stack, &Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_trap_word() {
+        let mut fc = ForthCompiler::new().with_trap_handler(TrapHandler::new(100, |_, st| {
+            let port = st.number_stack.pop().unwrap_or_default();
+            st.number_stack.push(port * 10);
+            Ok(TrapHandled::Handled)
+        }));
+
+        fc.execute_string(
+            "TRAP: IO-IN 100 ( port -- value ) TRAP: IO-OUT 101 ( value port -- ) 7 IO-IN",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![70_i64]);
+        assert_eq!(fc.declared_stack_effect("IO-IN"), Some((1, 1)));
+        assert_eq!(fc.declared_stack_effect("IO-OUT"), Some((2, 0)));
+        assert_eq!(
+            fc.word_listing("IO-OUT").unwrap(),
+            "IO-OUT:\n     3  LDI(101)\n     4  TRAP\n     5  RET\n"
+        );
+
+        // The declared effects are kept by saved sessions, but not by a redefinition
+        let mut restored = ForthCompiler::new();
+        let text = fc.saved_session(&[]).to_string();
+        restored
+            .restore_session(SavedSession::parse(&text).unwrap())
+            .unwrap();
+        assert_eq!(restored.declared_stack_effect("IO-IN"), Some((1, 1)));
+        fc.execute_string(": IO-IN 1 ;", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(fc.declared_stack_effect("IO-IN"), None);
+
+        for source in ["TRAP: Beep", "TRAP: Beep X ( -- )", "TRAP: Beep 9 ( n )"] {
+            match fc.execute_string(source, GasLimit::Limited(100)) {
+                Err(ForthError::SyntaxErrors(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_trap_4() {
+        let mut fc = ForthCompiler::new();
+
+        // Simulate a IO IN command, at TRAP(101), but define the port number inside a Forth word as well
+        fc.sm
+            .trap_handlers
+            .push(Box::from(TrapHandler::new(101, |_trap_id, st| {
+                let io_port = st
+                    .number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                let io_value = 654321_i64;
+                println!(
+                    "Simulated IO IN command from Port: {} and Value: {}",
+                    io_port, io_value
+                );
+                st.number_stack.push(io_value);
+                Ok(TrapHandled::Handled)
+            })));
+
+        fc.execute_string(
+            ": IO_IN 101 TRAP ; : IN_KEYBOARD 1000 IO_IN ; IN_KEYBOARD",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // Value from IO port on stack
+        assert_eq!(&fc.sm.st.number_stack, &vec![654321]);
+    }
+
+    #[test]
+    fn test_tokenize_string() {
+        let tokens = tokenize_string("1 DUP\n  : Double DUP ADD ;").unwrap();
+
+        let span = |start, end, line, column| Span {
+            start,
+            end,
+            location: SourceLocation { line, column },
+        };
+        assert_eq!(
+            tokens,
+            vec![
+                SpannedToken {
+                    token: Token::Number(1),
+                    span: span(0, 1, 1, 1)
+                },
+                SpannedToken {
+                    token: Token::Command("DUP".to_owned()),
+                    span: span(2, 5, 1, 3)
+                },
+                SpannedToken {
+                    token: Token::Colon("Double".to_owned()),
+                    span: span(8, 16, 2, 3)
+                },
+                SpannedToken {
+                    token: Token::Command("DUP".to_owned()),
+                    span: span(17, 20, 2, 12)
+                },
+                SpannedToken {
+                    token: Token::Command("ADD".to_owned()),
+                    span: span(21, 24, 2, 16)
+                },
+                SpannedToken {
+                    token: Token::SemiColon,
+                    span: span(25, 26, 2, 20)
+                },
+            ]
+        );
+
+        match tokenize_string("1 :") {
+            Err(ForthError::SyntaxErrors(errors)) => assert_eq!(errors.len(), 1),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+
+        // Tokenizing carries on after a problem, so every one is reported at once
+        let source = "VARIABLE 12\n: Double DUP ADD ;\n99999999999999999999 1 ARRAY";
+        let tokens = tokenize_with_errors(source);
+        let errors: Vec<(usize, usize, &str)> = tokens
+            .iter()
+            .filter_map(|t| match &t.token {
+                Token::Error(message) => Some((
+                    t.span.location.line,
+                    t.span.location.column,
+                    message.as_str(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, 1, "12 is a number, so it can't name a word"),
+                (3, 1, "99999999999999999999 is too big for a cell"),
+                (3, 24, "No name after ARRAY, but one needed for the array"),
+            ]
+        );
+        match ForthCompiler::new().execute_string(source, GasLimit::Limited(100)) {
+            Err(ForthError::SyntaxErrors(errors)) => assert_eq!(errors.len(), 3),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_assert_passes() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": Checked ASSERT( DUP ) 2 MUL ; 21 Checked",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![42_i64]);
+    }
+
+    #[test]
+    fn test_assert_fails_with_location() {
+        let mut fc = ForthCompiler::new();
+
+        match fc.execute_string(
+            ": Checked\n  ASSERT( DUP ) 2 MUL ;\n0 Checked",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::AssertionFailed(Some(SourceLocation { line: 2, column: 3 }))) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_immediate_assertions_are_forgotten() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Checked ASSERT( DUP ) ;", GasLimit::Limited(100))
+            .unwrap();
+
+        for _ in 0..100 {
+            fc.execute_string(
+                "ASSERT( 1 ) : Again ASSERT( 1 ) ; 1 Checked DROP",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+        }
+        // Checked's and each definition of Again's, the runs' own are gone
+        assert_eq!(fc.assertion_locations.len(), 101);
+        assert_eq!(fc.saved_session(&[]).assertion_locations.len(), 101);
+
+        // A failed immediate mode assertion still knows where it was
+        match fc.execute_string("1\n  ASSERT( 0 )", GasLimit::Limited(100)) {
+            Err(ForthError::AssertionFailed(Some(SourceLocation { line: 2, column: 3 }))) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(fc.assertion_locations.len(), 101);
+    }
+
+    #[test]
+    fn test_assert_disabled() {
+        let mut fc = ForthCompiler::new();
+        fc.set_assertions_enabled(false);
+
+        fc.execute_string(
+            ": Checked ASSERT( DUP ASSERT( 1 ) ) 2 MUL ; 0 Checked",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+        // Nothing is compiled for the assertion, just the call and the MUL
+        assert_eq!(fc.sm.st.opcodes.len(), 7);
+    }
+
+    #[test]
+    fn test_assert_unterminated() {
+        let mut fc = ForthCompiler::new();
+
+        match fc.execute_string("1 ASSERT( DUP", GasLimit::Limited(100)) {
+            Err(ForthError::InvalidSyntax(_)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_unsigned_words() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string("-1 1 U< 1 -1 U< -1 2 UM*", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, -1, -2, 1]);
+    }
+
+    #[test]
+    fn test_comparison_words() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "2 2 = 2 3 = 2 3 <> 3 3 <> -1 0 < 0 -1 > 3 3 <= 4 3 <= 3 3 >= 3 4 >=",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![-1_i64, 0, -1, 0, -1, -1, -1, 0, -1, 0]
+        );
+
+        fc.sm.st.number_stack.clear();
+        fc.execute_string("0 0= 5 0= -5 0< 5 0< 5 0> 0 0>", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, 0, -1, 0, -1, 0]);
+    }
+
+    #[test]
+    fn test_negate_abs_min_max() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "5 NEGATE -5 NEGATE -7 ABS 7 ABS -3 4 MIN -3 4 MAX 0 NEGATE",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![-5_i64, 5, 7, 7, -3, 4, 0]);
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "1 2 3 DROP 4 5 NIP 6 7 TUCK 8 9 OVER",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2, 5, 7, 6, 7, 8, 9, 8]);
+
+        fc.sm.st.number_stack.clear();
+        fc.execute_string(
+            "1 2 2DUP 3 4 5 6 2SWAP 7 8 2DROP 2OVER",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![1_i64, 2, 1, 2, 5, 6, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn test_bitwise_words() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "12 10 AND 12 10 OR 12 10 XOR 0 INVERT 1 2 < 3 4 < AND",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![8_i64, 14, 6, -1, -1]);
+
+        fc.sm.st.number_stack.clear();
+        fc.execute_string(
+            "1 10 LSHIFT 1024 3 RSHIFT 5 64 LSHIFT",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![1024_i64, 128, 0]);
+    }
+
+    #[test]
+    fn test_promoting_arithmetic() {
+        let mut fc = ForthCompiler::new();
+        fc.sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+
+        fc.execute_string(
+            ": Square DUP MUL ; 4294967296 Square Square 2 SWAP DIV",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        let n = fc.sm.st.number_stack.pop().unwrap();
+        assert_eq!(
+            fc.sm.st.format_cell(n),
+            "170141183460469231731687303715884105728"
+        );
+
+        // The comparison words see the big integers, not their handles
+        fc.execute_string(
+            "9223372036854775807 1 ADD 5 > 10 5 > 4294967296 Square DUP 1 ADD -1 ADD = \
+             4294967296 Square NEGATE 0<",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, -1, -1, -1]);
+    }
+
+    #[test]
+    fn test_compilation_artifact() {
+        let mut fc = ForthCompiler::new();
+        fc.set_artifacts_enabled(true);
+
+        fc.compile_string(": Double DUP ADD ;\n: Quad Double Double ;\n5 Quad")
+            .unwrap();
+
+        let artifact = fc.last_artifact().unwrap();
+        assert_eq!(
+            artifact.words,
+            vec![
+                WordInfo {
+                    name: "Double".to_owned(),
+                    address: 0,
+                    length: 3
+                },
+                WordInfo {
+                    name: "Quad".to_owned(),
+                    address: 3,
+                    length: 5
+                }
+            ]
+        );
+        assert_eq!(artifact.opcode_count("Quad"), Some(5));
+        assert_eq!(artifact.immediate_code_start, 8);
+        assert_eq!(artifact.immediate_opcode_count(), 4);
+        assert_eq!(artifact.source_map.len(), artifact.opcodes.len());
+        // The CALL for the second Double in Quad
+        assert_eq!(
+            artifact.source_map[6],
+            SourceLocation {
+                line: 2,
+                column: 15
+            }
+        );
+        assert_eq!(artifact.word_containing(6).unwrap().name, "Quad");
+
+        // Nothing was run
+        assert_eq!(&fc.sm.st.number_stack, &Vec::<i64>::new());
+
+        let listing = artifact.to_string();
+        assert!(listing.contains("Quad:"));
+        assert!(listing.contains("<immediate>:"));
+        assert!(listing.contains("largest word       Quad (5 opcodes)"));
+    }
+
+    #[test]
+    fn test_artifacts_disabled_by_default() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(": Double DUP ADD ; 5 Double", GasLimit::Limited(100))
+            .unwrap();
+
+        assert!(fc.last_artifact().is_none());
+        assert_eq!(&fc.sm.st.number_stack, &vec![10_i64]);
+    }
+
+    // Lets tests look at what a program printed after the StackMachine has taken ownership of the output
+    #[derive(Clone, Default)]
+    struct CapturedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for CapturedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_word_trace() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+        fc.set_word_trace(Some(2));
+
+        fc.execute_string(
+            ": Double DUP ADD ; : Quad Double Double ; 1 2 3 Quad",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            captured.contents(),
+            "> Quad 2 3\n  > Double 2 3\n  < Double 2 6\n  > Double 2 6\n  < Double 2 12\n< Quad 2 12\n"
+        );
+
+        captured.0.borrow_mut().clear();
+        fc.set_word_trace(None);
+        fc.execute_string("Quad", GasLimit::Limited(100)).unwrap();
+        assert_eq!(captured.contents(), "");
+    }
+
+    #[test]
+    fn test_print_words() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.set_output(Box::new(captured.clone()));
+
+        fc.execute_string(": Show DUP . ; 1 -2 .S Show .S", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(captured.contents(), "<2> 1 -2 -2 <2> 1 -2 ");
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, -2]);
+    }
+
+    #[test]
+    fn test_word_listing() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Double DUP ADD ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.add_alias("Twice", "Double");
+
+        assert_eq!(
+            fc.word_listing("Twice").unwrap(),
+            "Double:\n     0  DUP\n     1  ADD\n     2  RET\n"
+        );
+        assert_eq!(
+            fc.word_listing("NIP").unwrap(),
+            "NIP: intrinsic\n        SWAP\n        POP\n"
+        );
+        assert_eq!(fc.word_listing("Frob"), None);
+    }
+
+    #[test]
+    fn test_division_words() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            "-7 2 /MOD -7 2 MOD 7 -2 FM/MOD 7 -2 SM/REM",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.drain_stack(), &vec![-1_i64, -3, -1, -1, -4, 1, -3]);
+
+        fc.sm.st.division_mode = DivisionMode::Floored;
+        fc.execute_string("-7 2 /MOD -7 2 MOD", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.drain_stack(), &vec![1_i64, -4, 1]);
+    }
+
+    #[test]
+    fn test_dot_quote() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.set_output(Box::new(captured.clone()));
+
+        fc.execute_string(
+            ": Greet .\" hello,  world\" 1 . ; .\" say \" Greet .\" \"",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(captured.contents(), "say hello,  world1 ");
+
+        // The text follows the word when its session is linked after another's data
+        let mut other = ForthCompiler::new();
+        other
+            .execute_string("VARIABLE Count .\" padding\"", GasLimit::Limited(100))
+            .unwrap();
+        let mut linked = ForthCompiler::new();
+        linked.set_output(Box::new(captured.clone()));
+        linked.link_session(other.saved_session(&[])).unwrap();
+        linked.link_session(fc.saved_session(&[])).unwrap();
+        captured.0.borrow_mut().clear();
+        linked
+            .execute_string("Greet", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(captured.contents(), "hello,  world1 ");
+
+        assert_eq!(
+            tokenize_string("1 .\" a\nb\"c").unwrap()[1],
+            SpannedToken {
+                token: Token::DotQuote("a\nb".to_owned()),
+                span: Span {
+                    start: 2,
+                    end: 9,
+                    location: SourceLocation { line: 1, column: 3 }
+                }
+            }
+        );
+        match fc.execute_string(".\" unfinished", GasLimit::Limited(100)) {
+            Err(ForthError::SyntaxErrors(errors)) => assert_eq!(errors.len(), 1),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_immediate_strings_reuse_data_space() {
+        let mut fc = ForthCompiler::new();
+        fc.set_output(Box::new(io::sink()));
+        fc.execute_string(": Greet .\" hello\" ;", GasLimit::Limited(100))
+            .unwrap();
+        let here = fc.sm.st.here();
+        assert_eq!(here, 8);
+
+        for _ in 0..100 {
+            fc.execute_string(".\" hello world\" Greet", GasLimit::Limited(100))
+                .unwrap();
+        }
+        assert_eq!(fc.sm.st.here(), here);
+
+        // What the run reserves after its strings stays, so they stay with it
+        fc.execute_string(".\" hi\" 8 ALLOT", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(fc.sm.st.here(), here + 16);
+        fc.execute_string("VARIABLE Count S\" abc\" 2DROP", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(fc.sm.st.here(), here + 24);
+    }
+
+    #[test]
+    fn test_s_quote() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.set_output(Box::new(captured.clone()));
+
+        fc.execute_string(
+            ": Greeting S\" hi there\" ; Greeting 2DUP TYPE S\" \"",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(captured.contents(), "hi there");
+        let stack = fc.drain_stack();
+        assert_eq!(stack[1], 8);
+        assert_eq!(stack[3], 0);
+        assert_eq!(fc.sm.st.fetch_bytes(stack[0], 8).unwrap(), b"hi there");
+
+        // The string follows the word when its session is linked after another's data
+        let mut other = ForthCompiler::new();
+        other
+            .execute_string("VARIABLE Count 2 ARRAY Table", GasLimit::Limited(100))
+            .unwrap();
+        let mut linked = ForthCompiler::new();
+        linked.set_output(Box::new(captured.clone()));
+        linked.link_session(other.saved_session(&[])).unwrap();
+        linked.link_session(fc.saved_session(&[])).unwrap();
+        captured.0.borrow_mut().clear();
+        linked
+            .execute_string("Greeting TYPE", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(captured.contents(), "hi there");
+
+        match fc.execute_string("S\"", GasLimit::Limited(100)) {
+            Err(ForthError::SyntaxErrors(errors)) => assert_eq!(errors.len(), 1),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            ": SQUARE DUP MUL ; : DRAW SQUARE ; : MAIN 2 DRAW ; MAIN",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(fc.last_backtrace(), &Backtrace::default());
+
+        // DRAW is missing the number MAIN gives it
+        fc.sm.st.number_stack.clear();
+        match fc.execute_string("DRAW", GasLimit::Limited(100)) {
+            Err(ForthError::PopOfEmptyStack) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(
+            fc.last_backtrace().to_string(),
+            "in SQUARE called from DRAW"
+        );
+
+        // A run that fails outside any word has no backtrace, and the calls the failed run was
+        // in don't get in the way of the next one
+        match fc.execute_string("ADD", GasLimit::Limited(100)) {
+            Err(ForthError::PopOfEmptyStack) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(fc.last_backtrace(), &Backtrace::default());
+        fc.execute_string("MAIN 5", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![4_i64, 5]);
+    }
+
+    #[test]
+    fn test_do_loop() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": Squares 4 1 DO I I MUL LOOP ; : Down 0 10 DO I -3 +LOOP ; Squares Down",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 4, 9, 10, 7, 4, 1]);
+
+        // J is the index of the loop around the innermost one
+        fc.sm.st.number_stack.clear();
+        fc.execute_string(
+            "3 1 DO 2 0 DO J 10 MUL I ADD LOOP LOOP",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![10_i64, 11, 20, 21]);
+    }
+
+    #[test]
+    fn test_question_do() {
+        let mut fc = ForthCompiler::new();
+
+        // With the limit equal to the start DO would go all the way round, ?DO skips the body
+        fc.execute_string(
+            ": Count 0 ?DO I LOOP 100 ; 3 Count 0 Count 0 5 ?DO I -2 +LOOP",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![0_i64, 1, 2, 100, 100, 5, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_begin_again_leave() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": UpTo5 0 BEGIN 1 ADD DUP 5 SUB IF LEAVE THEN AGAIN ; UpTo5",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+
+        // Leaving the inner loop drops its frame, so I is the outer loop's index again
+        fc.sm.st.number_stack.clear();
+        fc.execute_string(
+            "3 0 DO 10 0 DO I 1 SUB IF LEAVE THEN LOOP I LOOP",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2]);
+
+        for source in ["AGAIN", "BEGIN 1", "BEGIN LOOP", "1 0 DO AGAIN", "LEAVE"] {
+            match fc.execute_string(source, GasLimit::Limited(100)) {
+                Err(ForthError::InvalidSyntax(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_recurse() {
+        let mut fc = ForthCompiler::new();
+
+        // 0 IF runs the IF branch, so the recursion stops at 0
+        fc.execute_string(
+            ": Triangle DUP IF ELSE DUP 1 SWAP SUB RECURSE ADD THEN ; 4 Triangle 0 Triangle",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![10_i64, 0]);
+
+        // A patched body calls itself at the address it was patched into
+        assert!(fc
+            .patch_word(": Triangle DUP IF ELSE DUP 1 SWAP SUB RECURSE MUL THEN ;")
+            .unwrap());
+        fc.sm.st.number_stack.clear();
+        fc.execute_string("3 Triangle", GasLimit::Limited(500))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+
+        match fc.execute_string("1 RECURSE", GasLimit::Limited(100)) {
+            Err(ForthError::InvalidSyntax(message)) => {
+                assert_eq!(message, "RECURSE or DOES> outside a definition")
+            }
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[cfg(all(feature = "testing", feature = "compression"))]
     #[test]
-    fn test_if_else_4() {
+    fn test_compressed_session() {
+        use crate::testing::MemoryFileSystem;
+
+        let file_system = MemoryFileSystem::new();
+        let mut fc = ForthCompiler::new();
+        fc.set_file_system(Box::new(file_system.clone()));
+        fc.execute_string(": Double DUP ADD ; 5 Double", GasLimit::Limited(100))
+            .unwrap();
+        let history = vec!["5 Double".to_owned()];
+        fc.save_session_compressed("small.session", &history)
+            .unwrap();
+        fc.save_session("plain.session", &history).unwrap();
+        assert!(file_system.file("small.session").is_none());
+
+        for path in ["small.session", "plain.session"] {
+            let mut restored = ForthCompiler::new();
+            restored.set_file_system(Box::new(file_system.clone()));
+            assert_eq!(restored.load_session(path).unwrap(), history);
+            restored
+                .execute_string("Double", GasLimit::Limited(100))
+                .unwrap();
+            assert_eq!(&restored.sm.st.number_stack, &vec![20_i64]);
+        }
+    }
+
+    #[test]
+    fn test_link_sessions() {
+        // A prelude with data, a library that needs the prelude and an application needing both
+        let mut prelude = ForthCompiler::new();
+        prelude
+            .execute_string(
+                "VARIABLE Count 3 ARRAY Table 7 2 Table []! \
+                 : Tally 1 Count @ ADD Count ! ; : Square DUP MUL ;",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+        let mut library = ForthCompiler::new();
+        library
+            .execute_string(
+                "EXTERN Square EXTERN Tally VARIABLE Last \
+                 : Quad Tally Square Square DUP Last ! ; : Triple DUP DUP ADD ADD ;",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+        let mut application = ForthCompiler::new();
+        application
+            .execute_string(
+                "EXTERN Quad EXTERN Triple : Main 2 Quad 3 Triple ADD ;",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+
+        let mut fc = ForthCompiler::new();
+        fc.link_session(prelude.saved_session(&[])).unwrap();
+        fc.link_session(library.saved_session(&[])).unwrap();
+        fc.link_session(application.saved_session(&[])).unwrap();
+        fc.execute_string("Main Count @ Last @ 2 Table []@", GasLimit::Limited(500))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![25_i64, 1, 16, 7]);
+
+        // The linked dictionary saves and loads like any other
+        let mut restored = ForthCompiler::new();
+        restored.restore_session(fc.saved_session(&[])).unwrap();
+        restored
+            .execute_string("Main Count @", GasLimit::Limited(500))
+            .unwrap();
+        assert_eq!(&restored.sm.st.number_stack, &vec![25_i64, 1, 16, 7, 25, 2]);
+
+        // Without the library the application's externs can't be bound
+        let mut missing = ForthCompiler::new();
+        missing.link_session(prelude.saved_session(&[])).unwrap();
+        match missing.link_session(application.saved_session(&[])) {
+            Err(ForthError::UnresolvedExterns(names)) => assert_eq!(names, vec!["Quad", "Triple"]),
+            r => panic!("expected unresolved externs, got {:?}", r),
+        }
+        assert!(!missing.knows_word("Main"));
+    }
+
+    #[test]
+    fn test_definition_hook() {
+        let defined = Rc::new(RefCell::new(Vec::new()));
+        let mut fc = ForthCompiler::new();
+        let log = defined.clone();
+        fc.set_definition_hook(move |word: &WordInfo, redefined| {
+            log.borrow_mut()
+                .push((word.name.clone(), word.address, word.length, redefined))
+        });
+
+        fc.execute_string(
+            ": Double DUP ADD ; VARIABLE Count : Double 2 MUL ;",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert!(fc.patch_word(": Double 3 MUL ;").unwrap());
+        assert_eq!(
+            *defined.borrow(),
+            vec![
+                ("Double".to_owned(), 0, 3, false),
+                ("Count".to_owned(), 3, 2, false),
+                ("Double".to_owned(), 5, 3, true),
+                ("Double".to_owned(), 5, 3, true),
+            ]
+        );
+
+        fc.clear_definition_hook();
+        fc.execute_string(": Triple 3 MUL ;", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(defined.borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_profile_words() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+        fc.execute_string(
+            ": Square DUP MUL ; : Quad Square Square ; 2 Quad",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert!(fc.profile().is_empty());
+
+        fc.execute_string("PROFILE-ON 2 Quad 3 Square", GasLimit::Limited(100))
+            .unwrap();
+        let self_gas = |word: &WordProfile| word.self_gas;
+        let profile = fc.profile();
+        assert_eq!(
+            profile
+                .iter()
+                .map(|(name, word)| (name.as_str(), word.calls))
+                .collect::<Vec<_>>(),
+            vec![("Square", 3), ("Quad", 1)]
+        );
+        // A CALL counts against the word called and a RET against the word returned to, so
+        // Square has its CALL, DUP and MUL each time and Quad its CALL, two LDIs and two RETs
+        assert_eq!(self_gas(&profile[0].1), 9);
+        assert_eq!(self_gas(&profile[1].1), 5);
+        assert_eq!(profile[1].1.total_gas, 11);
+
+        fc.execute_string("PROFILE-REPORT PROFILE-OFF", GasLimit::Limited(100))
+            .unwrap();
+        assert!(fc.profile().is_empty());
+        let report: Vec<Vec<String>> = captured
+            .contents()
+            .lines()
+            .map(|line| line.split_whitespace().map(str::to_owned).collect())
+            .collect();
+        assert_eq!(
+            report,
+            vec![
+                vec!["word", "calls", "self", "gas", "total", "gas"],
+                vec!["Square", "3", "9", "9"],
+                vec!["Quad", "1", "5", "11"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extern() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            "EXTERN SENSOR-READ : Twice SENSOR-READ DUP ADD ;",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        match fc.execute_string("Twice", GasLimit::Limited(100)) {
+            Err(ForthError::UnresolvedExterns(names)) => assert_eq!(names, vec!["SENSOR-READ"]),
+            r => panic!("expected an unbound extern, got {:?}", r),
+        }
+        let session = fc.saved_session(&[]);
+
+        // The host's word is bound in when the session is loaded
+        let mut host = ForthCompiler::new()
+            .with_additional_intrinsics(vec![("SENSOR-READ".to_owned(), vec![Opcode::LDI(21)])]);
+        host.restore_session(session.clone()).unwrap();
+        host.execute_string("Twice", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&host.sm.st.number_stack, &vec![42_i64]);
+
+        // Saved again it is unbound, so it can be bound to another host's word
+        let resaved = host.saved_session(&[]);
+        assert_eq!(resaved.externs, session.externs);
+        let mut other = ForthCompiler::new()
+            .with_additional_intrinsics(vec![("SENSOR-READ".to_owned(), vec![Opcode::LDI(5)])]);
+        other.restore_session(resaved).unwrap();
+        other
+            .execute_string("Twice", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&other.sm.st.number_stack, &vec![42_i64, 10]);
+
+        // Loading without the host's word fails and changes nothing
+        let mut missing = ForthCompiler::new();
+        missing
+            .execute_string(": Kept 1 ;", GasLimit::Limited(100))
+            .unwrap();
+        match missing.restore_session(session) {
+            Err(ForthError::UnresolvedExterns(names)) => assert_eq!(names, vec!["SENSOR-READ"]),
+            r => panic!("expected unresolved externs, got {:?}", r),
+        }
+        assert!(missing.knows_word("Kept"));
+        assert!(!missing.knows_word("Twice"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_require() {
+        use crate::testing::MemoryFileSystem;
+
+        let file_system = MemoryFileSystem::new();
+        let mut fc = ForthCompiler::new();
+        fc.set_file_system(Box::new(file_system.clone()));
+        file_system.add_file("util.fs", ": Double DUP ADD ; 1");
+        file_system.add_file("math.fs", "REQUIRE util.fs : Quad Double Double ;");
+        file_system.add_file("app.fs", "REQUIRE util.fs REQUIRE math.fs 3 Quad");
+
+        // util.fs is only loaded the first time, so its 1 is only pushed once
+        fc.include_file("app.fs", GasLimit::Limited(100)).unwrap();
+        fc.execute_string("REQUIRE math.fs REQUIRE app.fs", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 12]);
+
+        // Files that need each other are each loaded once, the one needed first
+        fc.sm.st.number_stack.clear();
+        file_system.add_file("a.fs", "REQUIRE b.fs 2");
+        file_system.add_file("b.fs", "REQUIRE a.fs 3");
+        fc.execute_string("REQUIRE a.fs", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 2]);
+
+        // A file that couldn't be loaded is tried again next time
+        match fc.execute_string("REQUIRE late.fs", GasLimit::Limited(100)) {
+            Err(ForthError::Io(_)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        file_system.add_file("late.fs", "4");
+        fc.execute_string("REQUIRE late.fs", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64, 2, 4]);
+
+        for source in [": Foo REQUIRE util.fs ;", "REQUIRE"] {
+            match fc.execute_string(source, GasLimit::Limited(100)) {
+                Err(ForthError::InvalidSyntax(_)) | Err(ForthError::SyntaxErrors(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ext_opcode() {
+        let mut calls = 0;
+        let square = move |_, st: &mut crate::stack_machine::StackMachineState| {
+            calls += 1;
+            let x = st.number_stack.pop().unwrap_or_default();
+            st.number_stack.extend_from_slice(&[x * x, calls]);
+            Ok(())
+        };
+        let mut fc = ForthCompiler::new().with_ext_opcode("SQUARE", 1, square);
+
+        fc.execute_string(
+            ": Twice SQUARE POP SQUARE ; 3 Twice",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![81_i64, 2]);
+    }
+
+    #[test]
+    fn test_do_loop_errors() {
+        let mut fc = ForthCompiler::new();
+
+        for source in ["1 0 DO I", "1 0 ?DO I", "I LOOP"] {
+            match fc.execute_string(source, GasLimit::Limited(100)) {
+                Err(ForthError::InvalidSyntax(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+        match fc.execute_string(": Foo +LOOP ;", GasLimit::Limited(100)) {
+            Err(ForthError::DefinitionFailed(e)) => match e.error {
+                ForthError::InvalidSyntax(message) => assert_eq!(message, "+LOOP without DO"),
+                r => panic!("Incorrect error type returned {:?}", r),
+            },
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        match fc.execute_string("I", GasLimit::Limited(100)) {
+            Err(ForthError::LoopStackUnderflow) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": Double DUP ADD ; : Quad Double Double ; : Double 2 MUL ;",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fc.stats(),
+            CompilerStats {
+                words: 2,
+                opcode_memory: 11,
+                data_space_bytes: 0,
+                string_space_bytes: 0,
+                stack_bytes: 0,
+                // The immediate mode code is still there after it has run
+                dictionary_bytes: 12 * mem::size_of::<Opcode>(),
+                memory_bytes: 12 * mem::size_of::<Opcode>(),
+                largest_word: Some(("Quad".to_owned(), 5)),
+            }
+        );
+
+        // Only the definitions' strings are kept once the line has run
+        fc.set_output(Box::new(io::sink()));
+        fc.execute_string(
+            ": Greet .\" hello, world\" ; .\" scratch\"",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(fc.stats().string_space_bytes, 16);
+        assert_eq!(fc.stats().data_space_bytes, 16);
+        let mut restored = ForthCompiler::new();
+        restored
+            .restore_session(SavedSession::parse(&fc.saved_session(&[]).to_string()).unwrap())
+            .unwrap();
+        assert_eq!(restored.stats().string_space_bytes, 16);
+    }
+
+    #[test]
+    fn test_print_stats() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+
+        fc.execute_string(": Double DUP ADD ; 1 .STATS 2", GasLimit::Limited(100))
+            .unwrap();
+
+        // The stack has the 1 on it, the opcodes are the word and all the immediate mode code
+        let opcodes = 7 * mem::size_of::<Opcode>();
+        assert_eq!(
+            captured.contents(),
+            format!(
+                "words: 1\nopcode memory: 3\ndata space bytes: 0\nstring space bytes: 0\nstack bytes: 8\ndictionary bytes: {}\nmemory bytes: {}\nlargest word: Double (3 opcodes)\n",
+                opcodes,
+                opcodes + 8
+            )
+        );
+        // Execution carries on after the stats are printed
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 2]);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedClock(std::rc::Rc<std::cell::Cell<u64>>);
+
+    impl Clock for SharedClock {
+        fn now_millis(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_quotas() {
+        let mut fc = ForthCompiler::new();
+        let clock = SharedClock::default();
+        fc.set_clock(Box::new(clock.clone()));
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+        fc.set_quotas(Quotas {
+            gas_per_hour: Some(10),
+            max_output_bytes: Some(5),
+            ..Quotas::unlimited()
+        });
+
+        // Each U. prints three bytes, so the second is cut short
+        match fc.execute_string("12 U. 34 U.", GasLimit::Limited(100)) {
+            Err(ForthError::QuotaExceeded(Quota::OutputBytes)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(captured.contents(), "12 34");
+        assert_eq!(fc.quota_usage().output_bytes, 5);
+
+        // The run above used some of the hour's gas, leaving too little for nine more
+        fc.set_quotas(Quotas {
+            gas_per_hour: Some(10),
+            max_executions: Some(3),
+            ..Quotas::unlimited()
+        });
+        match fc.execute_string("1 2 3 4 5 6 7 8 9", GasLimit::Limited(100)) {
+            Err(ForthError::QuotaExceeded(Quota::GasPerHour)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        clock.0.set(QUOTA_WINDOW_MILLIS);
+        fc.execute_string("1 2 3 4 5 6 7 8 9", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(fc.quota_usage().executions, 3);
+        match fc.execute_string("1", GasLimit::Limited(100)) {
+            Err(ForthError::QuotaExceeded(Quota::Executions)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+
+        // The output goes back to the host between runs
+        fc.reset_quota_usage();
+        fc.execute_string("7 U.", GasLimit::Limited(100)).unwrap();
+        assert_eq!(captured.contents(), "12 347 ");
+    }
+
+    #[test]
+    fn test_execute_empty_string() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Double DUP ADD ;", GasLimit::Limited(100))
+            .unwrap();
+        let opcodes = fc.sm.st.opcodes.len();
+
+        for s in ["", "   \n\t"] {
+            fc.execute_string(s, GasLimit::Limited(100)).unwrap();
+        }
+
+        assert_eq!(fc.sm.st.opcodes.len(), opcodes);
+        assert_eq!(fc.quota_usage().executions, 1);
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        let mut fc = ForthCompiler::new();
+        fc.add_alias("SI", "IF");
+
+        for source in [
+            ": Double",
+            ": Abs DUP 0 U<\n SI",
+            "10 0 DO I",
+            "BEGIN",
+            "ASSERT( 1",
+        ] {
+            assert!(fc.is_incomplete(source), "{}", source);
+        }
+        for source in [
+            "",
+            ": Double DUP ADD ;",
+            "0 IF 1 THEN",
+            "THEN 0 IF",
+            "; : Foo",
+        ] {
+            assert!(!fc.is_incomplete(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_immediate_code_reuses_scratch_space() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Double DUP ADD ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.execute_string("1 1 Double MUL", GasLimit::Limited(100))
+            .unwrap();
+        let opcodes = fc.sm.st.opcodes.len();
+
+        for _ in 0..1000 {
+            fc.execute_string("1 Double MUL", GasLimit::Limited(100))
+                .unwrap();
+        }
+
+        // Each line's code went where the one before it was
+        assert_eq!(fc.sm.st.opcodes.len(), opcodes - 1);
+        assert_eq!(fc.stats().opcode_memory, 3);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut fc = ForthCompiler::new();
+        // Room for the three opcodes of each line below and two cells on the stack
+        fc.set_memory_limit(Some(3 * mem::size_of::<Opcode>() + 16));
+
+        fc.execute_string("1 2", GasLimit::Limited(100)).unwrap();
+        assert_eq!(fc.stats().stack_bytes, 16);
+        match fc.execute_string("3 4", GasLimit::Limited(100)) {
+            Err(ForthError::MemoryLimitExceeded(_)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_array() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "3 ARRAY Table : Fill 10 0 Table []! 20 2 Table []! ; Fill 2 Table []@",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![20_i64]);
+        assert_eq!(fc.array("Table"), Some(&[10_i64, 0, 20][..]));
+        // The host can change the array in place
+        fc.array_mut("Table").unwrap()[1] = 15;
+        fc.execute_string("1 Table []@", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![20_i64, 15]);
+        assert_eq!(fc.stats().data_space_bytes, 32);
+        assert_eq!(fc.array("Fill"), None);
+
+        match fc.execute_string("3 Table []@", GasLimit::Limited(100)) {
+            Err(ForthError::IndexOutOfBounds(3)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("-1 ARRAY Broken", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(-1)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_variable() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "VARIABLE Count VARIABLE Total : Bump Count @ INC Count ! ; \
+             Bump Bump 7 Total ! Count @ Total @",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // Each variable has a cell of its own
+        assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 7]);
+        assert_eq!(fc.stats().data_space_bytes, 16);
+
+        match fc.execute_string("VARIABLE", GasLimit::Limited(100)) {
+            Err(ForthError::SyntaxErrors(_)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_array_address() {
+        let mut fc = ForthCompiler::new();
+        let session = SavedSession::parse(
+            "rust_forth session 1\n\
+             opcode LDI(5)\nopcode LDI(99999)\nopcode SYSCALL(12)\nopcode RET\n\
+             word Bad 0 4\n",
+        )
+        .unwrap();
+        fc.restore_session(session).unwrap();
+
+        match fc.execute_string("Bad", GasLimit::Limited(100)) {
+            Err(ForthError::InvalidCodeAddress(99999)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_created_word_address() {
+        for opcodes in [
+            "opcode LDI(99999)\nopcode SYSCALL(15)\nopcode SYSCALL(16)\nopcode RET\n",
+            // Opcode 8 is the last of the code calling Bad, so DOES> has nowhere to go after it
+            "opcode LDI(8)\nopcode SYSCALL(15)\nopcode SYSCALL(16)\nopcode LDI(0)\n\
+             opcode SYSCALL(17)\nopcode RET\n",
+        ] {
+            let mut fc = ForthCompiler::new();
+            let length = opcodes.lines().count();
+            let text = format!("rust_forth session 1\n{}word Bad 0 {}\n", opcodes, length);
+            fc.restore_session(SavedSession::parse(&text).unwrap())
+                .unwrap();
+
+            match fc.execute_string("Bad", GasLimit::Limited(100)) {
+                Err(ForthError::InvalidCodeAddress(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_create_does() {
+        let mut fc = ForthCompiler::new();
+
+        // A created word pushes the address of the data space after what was reserved before it
+        fc.execute_string("CREATE Start Start", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.allocate(2).unwrap();
+        fc.execute_string(
+            ": Offset CREATE DOES> 1000 ADD ; Offset A A \
+             : Pair CREATE 5 DOES> ADD ; Pair P P",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1016, 21]);
+        assert!(fc.is_defining_word("Offset"));
+        assert!(!fc.is_defining_word("A"));
+
+        // Each use of a defining word inside a definition makes its word again when it runs
+        fc.sm.st.number_stack.clear();
+        fc.execute_string(": Make Offset B ; Make B", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![1016_i64]);
+
+        for source in ["Offset", "Offset 5", "DOES> 1", "CREATE"] {
+            match fc.execute_string(source, GasLimit::Limited(100)) {
+                Err(ForthError::InvalidSyntax(_)) | Err(ForthError::SyntaxErrors(_)) => (),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_allot() {
+        let mut fc = ForthCompiler::new();
+
+        // A created word's data is whatever ALLOT reserves after it
+        fc.execute_string(
+            "CREATE Table 24 ALLOT CREATE Next HERE \
+             7 Table 16 ADD ! Table 16 ADD @ Next",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![24_i64, 7, 24]);
+        assert_eq!(fc.stats().data_space_bytes, 24);
+    }
+
+    #[test]
+    fn test_cells() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            "CREATE Pair 2 CELLS ALLOT 5 Pair CELL+ ! Pair CELL+ @ 3 CELLS",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 3 * CELL_SIZE]);
+        assert_eq!(fc.stats().data_space_bytes, 2 * CELL_SIZE as usize);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut fc = ForthCompiler::new()
+            .with_prelude(": Double DUP ADD ; VARIABLE Seed", GasLimit::Limited(100))
+            .unwrap();
+        fc.add_alias("+", "ADD");
+        fc.execute_string(": Extra 1 ; 5 Seed ! 4 Double", GasLimit::Limited(100))
+            .unwrap();
+
+        fc.reset(ResetLevel::Stacks).unwrap();
+        assert!(fc.sm.st.number_stack.is_empty());
+        fc.execute_string("Extra Seed @", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 5]);
+
+        fc.reset(ResetLevel::UserWords).unwrap();
+        assert!(fc.sm.st.number_stack.is_empty());
+        assert!(fc.execute_string("Extra", GasLimit::Limited(100)).is_err());
+        fc.execute_string("Seed @ 3 Double", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 6]);
+
+        // Aliases are the host's and are kept
+        fc.reset(ResetLevel::Everything).unwrap();
+        assert!(fc
+            .execute_string("3 Double", GasLimit::Limited(100))
+            .is_err());
+        assert!(fc.sm.st.data().is_empty());
+        fc.reset(ResetLevel::Stacks).unwrap();
+        fc.execute_string("1 2 +", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+    }
+
+    #[test]
+    fn test_yield() {
         let mut fc = ForthCompiler::new();
 
-        fc.execute_string("1 IF 1 2 ADD ELSE 3 4 ADD THEN", GasLimit::Limited(100))
+        fc.execute_string(
+            ": Count 3 0 DO I YIELD LOOP ; Count 9",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert!(fc.is_suspended());
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64]);
+
+        // Each resumption has the gas limit to itself
+        for _ in 0..3 {
+            fc.resume(GasLimit::Limited(10)).unwrap();
+        }
+        assert!(!fc.is_suspended());
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2, 9]);
+
+        // A request is answered with the values the host pushes
+        fc.execute_string(
+            ": Ask 3 4 2 100 REQUEST ; Ask 1 ADD",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(
+            fc.pending_request(),
+            Some(&HostRequest {
+                code: 100,
+                arguments: vec![3, 4]
+            })
+        );
+        fc.resume_with(&[12], GasLimit::Limited(10)).unwrap();
+        assert_eq!(fc.pending_request(), None);
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2, 9, 13]);
+        fc.sm.st.number_stack.truncate(4);
+
+        // EVALUATE can't wait for an answer
+        fc.execute_string("2 ARRAY Src", GasLimit::Limited(100))
             .unwrap();
+        fc.sm.st.store_bytes(8, b"0 5 REQUEST").unwrap();
+        match fc.execute_string("Src 11 EVALUATE", GasLimit::Limited(100)) {
+            Err(ForthError::UnansweredRequest(5)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
 
-        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64]);
+        // Running something else abandons the suspended run
+        fc.execute_string("Count", GasLimit::Limited(100)).unwrap();
+        fc.execute_string("7", GasLimit::Limited(100)).unwrap();
+        assert!(!fc.is_suspended());
+        fc.resume(GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 1, 2, 9, 0, 7]);
     }
 
     #[test]
-    fn test_trap_1() {
+    fn test_introspection_words() {
         let mut fc = ForthCompiler::new();
 
-        // Simulate a IO OUT command, at TRAP(100)
-        fc.sm
-            .trap_handlers
-            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
-                let io_port = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                let io_value = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                println!(
-                    "Simulated IO OUT command to Port: {} and Value: {}",
-                    io_port, io_value
-                );
-                Ok(TrapHandled::Handled)
-            })));
+        fc.execute_string(
+            ": Depth RDEPTH ; : Nested Depth ; RDEPTH Nested GAS-USED GAS-REMAINING",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        let left = fc.sm.st.number_stack.pop().unwrap();
+        let used = fc.sm.st.number_stack.pop().unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![0_i64, 2]);
+        assert_eq!(used + left, 99);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut fc = ForthCompiler::new();
 
         fc.execute_string(
-            ": IO_OUT 100 TRAP ; 123456 1000 IO_OUT",
+            "2 ARRAY Text 72 Text C! 105 Text INC C! Text Text 8 ADD 2 CMOVE Text 9 ADD C@",
             GasLimit::Limited(100),
         )
         .unwrap();
 
-        // Nothing left over
-        assert_eq!(&fc.sm.st.number_stack, &vec![]);
+        assert_eq!(&fc.sm.st.number_stack, &vec![105_i64]);
+        assert_eq!(fc.array("Text"), Some(&[0x6948_i64, 0x6948][..]));
     }
 
     #[test]
-    fn test_trap_2() {
+    fn test_checksums() {
         let mut fc = ForthCompiler::new();
 
-        // Simulate a IO IN command, at TRAP(101)
-        fc.sm
-            .trap_handlers
-            .push(Box::from(TrapHandler::new(101, |_trap_id, st| {
-                let io_port = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                let io_value = 654321_i64;
-                println!(
-                    "Simulated IO IN command from Port: {} and Value: {}",
-                    io_port, io_value
-                );
-                st.number_stack.push(io_value);
-                Ok(TrapHandled::Handled)
-            })));
+        // "123456789" packed into two cells, big endian so the bytes are in order
+        fc.execute_string(
+            "2 ARRAY Data 3544952156018063160 Data BE64! 57 Data 8 ADD C! \
+             Data 9 CRC16 Data 9 CRC32 Data 9 CHECKSUM8 Data 9 XOR8 Data 0 CRC32",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
 
-        fc.execute_string(": IO_IN 101 TRAP ; 1000 IO_IN", GasLimit::Limited(100))
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![0x29B1_i64, 0xCBF4_3926, 0xDD, 0x31, 0]
+        );
+    }
+
+    #[test]
+    fn test_key_value_store() {
+        let mut fc = ForthCompiler::new();
+        fc.set_key_value_store(Box::new(MemoryKeyValueStore::new()));
+
+        fc.execute_string("1 ARRAY Key", GasLimit::Limited(100))
             .unwrap();
+        fc.sm.st.store_bytes(8, b"runs").unwrap();
+        fc.execute_string(
+            "Key 4 KV@ POP INC Key 4 KV! Key 4 KV@ Key 3 KV@",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
 
-        // Value from IO port on stack
-        assert_eq!(&fc.sm.st.number_stack, &vec![654321]);
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, -1, 0, 0]);
     }
 
     #[test]
-    fn test_trap_3() {
+    fn test_evaluate() {
         let mut fc = ForthCompiler::new();
+        fc.execute_string("2 ARRAY Src", GasLimit::Limited(100))
+            .unwrap();
 
-        // Simulate a IO OUT command, at TRAP(100), but define the port number inside a Forth Word as well
-        fc.sm
-            .trap_handlers
-            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
-                let io_port = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                let io_value = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                println!(
-                    "Simulated IO OUT command to Port: {} and Value: {}",
-                    io_port, io_value
-                );
-                Ok(TrapHandled::Handled)
-            })));
+        // The words the source defines go in the dictionary, and the code after EVALUATE still
+        // runs even though they were put where it was
+        fc.sm.st.store_bytes(8, b": Sq DUP MUL ; 7").unwrap();
+        fc.execute_string("Src 16 EVALUATE 4 INC", GasLimit::Limited(100))
+            .unwrap();
+        fc.execute_string("3 Sq", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64, 5, 9]);
+
+        // Source that evaluates itself stops at the nesting limit
+        fc.set_max_nesting(3);
+        fc.sm.st.store_bytes(8, b"Src 15 EVALUATE ").unwrap();
+        match fc.execute_string("Src 15 EVALUATE", GasLimit::Limited(100)) {
+            Err(ForthError::NestingTooDeep(3)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+
+        // The gas limit covers the evaluated source too
+        fc.sm.st.store_bytes(8, b"BEGIN AGAIN     ").unwrap();
+        match fc.execute_string("Src 11 EVALUATE", GasLimit::Limited(100)) {
+            Err(ForthError::RanOutOfGas) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+
+        fc.sm.st.number_stack.clear();
+        fc.execute_string("1 2 ADD", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![3_i64]);
+    }
+
+    #[test]
+    fn test_map_buffer() {
+        let mut fc = ForthCompiler::new();
+        let framebuffer = SharedBuffer::new(4);
 
+        fc.map_buffer("framebuffer", framebuffer.clone()).unwrap();
         fc.execute_string(
-            ": IO_OUT 100 TRAP ; : OUT_DISPLAY 1000 IO_OUT ; 123456 OUT_DISPLAY",
+            ": Fill 4 0 framebuffer []! 5 3 framebuffer []! ; Fill 3 framebuffer []@",
             GasLimit::Limited(100),
         )
         .unwrap();
 
-        // Nothing left over
-        assert_eq!(&fc.sm.st.number_stack, &vec![]);
+        assert_eq!(*framebuffer.borrow(), vec![4, 0, 0, 5]);
+        assert_eq!(&fc.sm.st.number_stack, &vec![5_i64]);
+        // Nothing was copied into the data space
+        assert_eq!(fc.stats().data_space_bytes, 0);
+
+        match fc.execute_string("0 4 framebuffer []!", GasLimit::Limited(100)) {
+            Err(ForthError::IndexOutOfBounds(4)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
     }
 
     #[test]
-    fn test_trap_4() {
+    fn test_events() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": DO-CLICK 100 MUL ; ON-EVENT button DO-CLICK ON-EVENT key INC",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        fc.post_event("button", &[3]);
+        fc.post_event("unhandled", &[1, 2]);
+        fc.post_event("key", &[7]);
+        assert_eq!(fc.pending_events(), 3);
+
+        assert_eq!(fc.process_events(GasLimit::Limited(100)).unwrap(), 2);
+        assert_eq!(&fc.sm.st.number_stack, &vec![300_i64, 8]);
+        assert_eq!(fc.pending_events(), 0);
+
+        // The handlers share the gas, so the second one doesn't get to run
+        fc.post_event("button", &[1]);
+        fc.post_event("button", &[2]);
+        fc.post_event("button", &[3]);
+        match fc.process_events(GasLimit::Limited(8)) {
+            Err(ForthError::RanOutOfGas) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(fc.pending_events(), 1);
+
+        // Gas for exactly one handler runs it and leaves the next one queued
+        fc.sm.st.number_stack.clear();
+        fc.event_queue.clear();
+        fc.post_event("button", &[1]);
+        assert_eq!(fc.process_events(GasLimit::Limited(100)).unwrap(), 1);
+        let one_handler = fc.sm.st.gas_used();
+        fc.post_event("button", &[2]);
+        fc.post_event("button", &[3]);
+        assert_eq!(
+            fc.process_events(GasLimit::Limited(one_handler)).unwrap(),
+            1
+        );
+        assert_eq!(&fc.sm.st.number_stack, &vec![100_i64, 200]);
+        assert_eq!(fc.pending_events(), 1);
+        assert_eq!(fc.process_events(GasLimit::Limited(100)).unwrap(), 1);
+        assert_eq!(&fc.sm.st.number_stack, &vec![100_i64, 200, 300]);
+
+        match fc.execute_string("ON-EVENT button MISSING", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(word)) => assert_eq!(word, "MISSING"),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("ON-EVENT button", GasLimit::Limited(100)) {
+            Err(ForthError::SyntaxErrors(_)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut fc = ForthCompiler::new();
+
+        fc.execute_string(
+            ": Once 1 ; : Often 2 ; 100 ' Once AFTER 30 ' Often EVERY",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(fc.tick(20, GasLimit::Limited(100)).unwrap(), 0);
+        assert_eq!(fc.tick(30, GasLimit::Limited(100)).unwrap(), 1);
+        assert_eq!(&fc.sm.st.number_stack, &vec![2_i64]);
+        // Far behind, but EVERY only catches up once
+        assert_eq!(fc.tick(200, GasLimit::Limited(100)).unwrap(), 2);
+        assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 2, 1]);
+        assert_eq!(fc.tick(201, GasLimit::Limited(100)).unwrap(), 1);
+        assert_eq!(fc.tick(500, GasLimit::Limited(100)).unwrap(), 1);
+        assert_eq!(&fc.sm.st.number_stack, &vec![2_i64, 2, 1, 2, 2]);
+
+        // Loading a session forgets the timers and events, whose words are gone
+        let mut loaded = ForthCompiler::new();
+        loaded
+            .execute_string(": A 1 ; : B 2 ; 10 ' B AFTER", GasLimit::Limited(100))
+            .unwrap();
+        let mut image = ForthCompiler::new();
+        image
+            .execute_string(": C 3 ;", GasLimit::Limited(100))
+            .unwrap();
+        loaded.post_event("ping", &[]);
+        loaded.restore_session(image.saved_session(&[])).unwrap();
+        assert_eq!(loaded.tick(100, GasLimit::Limited(100)).unwrap(), 0);
+        assert_eq!(loaded.pending_events(), 0);
+        // One that is left pointing past the code anyway is reported once, then forgotten
+        loaded.timers.push(Timer {
+            due: 0,
+            interval: Some(10),
+            address: 40,
+        });
+        match loaded.tick(200, GasLimit::Limited(100)) {
+            Err(ForthError::InvalidCodeAddress(40)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(loaded.tick(300, GasLimit::Limited(100)).unwrap(), 0);
+
+        // A timer due at the end of time stays there rather than wrapping around
+        let mut late = ForthCompiler::new();
+        late.execute_string(": Often 2 ;", GasLimit::Limited(100))
+            .unwrap();
+        late.tick(u64::MAX - 10, GasLimit::Limited(100)).unwrap();
+        late.execute_string("20 ' Often EVERY", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(late.tick(u64::MAX, GasLimit::Limited(100)).unwrap(), 1);
+        assert_eq!(late.tick(u64::MAX, GasLimit::Limited(100)).unwrap(), 0);
+
+        match fc.execute_string("10 12345 AFTER", GasLimit::Limited(100)) {
+            Err(ForthError::InvalidExecutionToken(12345)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("' DUP", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(word)) => assert_eq!(word, "DUP"),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+        fc.block_store_mut().set_block(2, b"1 2 ADD");
+
+        fc.execute_string("2 LIST", GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(
+            captured.contents(),
+            format!(
+                "Screen 2\n 0 1 2 ADD\n{}",
+                (1..16).map(|i| format!("{:>2}\n", i)).collect::<String>()
+            )
+        );
+
+        match fc.execute_string("-1 LIST", GasLimit::Limited(100)) {
+            Err(ForthError::InvalidBlockNumber(-1)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn test_terminal_control() {
+        let mut fc = ForthCompiler::new();
+        let captured = CapturedOutput::default();
+        fc.sm.st.output = Box::new(captured.clone());
+
+        fc.execute_string("PAGE 10 3 AT-XY 7", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(captured.contents(), "\x1b[2J\x1b[1;1H\x1b[4;11H");
+        assert_eq!(&fc.sm.st.number_stack, &vec![7_i64]);
+
+        match fc.execute_string("POP 1 AT-XY", GasLimit::Limited(100)) {
+            Err(ForthError::PopOfEmptyStack) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_opcode_memory_limit() {
+        let mut fc = ForthCompiler::new();
+        fc.set_opcode_memory_limit(Some(11));
+
+        fc.execute_string(": Double DUP ADD ; 2 Double", GasLimit::Limited(100))
+            .unwrap();
+
+        match fc.execute_string(
+            ": Quad Double Double ; : Big 1 2 3 4 ;",
+            GasLimit::Limited(100),
+        ) {
+            Err(ForthError::DefinitionFailed(d)) if d.word == "Big" => {
+                assert!(matches!(d.error, ForthError::DictionaryFull))
+            }
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        // Quad fitted, Big didn't
+        assert_eq!(fc.stats().words, 2);
+        assert_eq!(fc.stats().opcode_memory, 8);
+
+        // Immediate mode code counts against the limit too
+        match fc.execute_string("1 2 3", GasLimit::Limited(100)) {
+            Err(ForthError::DictionaryFull) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        fc.execute_string("Quad", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![16_i64]);
+    }
+
+    #[test]
+    fn test_restricted_intrinsics() {
+        let mut fc = ForthCompiler::new().without_intrinsics(vec!["DIV", "TRAP"]);
+
+        match fc.execute_string("10 2 DIV", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(ref x)) if x == "DIV" => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        let mut fc = ForthCompiler::new().with_intrinsics(vec![
+            ("PLUS".to_owned(), vec![Opcode::ADD]),
+            ("DUP".to_owned(), vec![Opcode::DUP]),
+        ]);
+
+        fc.execute_string("1 DUP PLUS", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![2_i64]);
+
+        match fc.execute_string("1 2 ADD", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(ref x)) if x == "ADD" => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_additional_intrinsics() {
+        let mut fc = ForthCompiler::new().with_additional_intrinsics(vec![
+            ("SQUARE".to_owned(), vec![Opcode::DUP, Opcode::MUL]),
+            ("INC".to_owned(), vec![Opcode::LDI(10), Opcode::ADD]),
+        ]);
+
+        fc.execute_string("3 SQUARE INC", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![19_i64]);
+    }
+
+    #[test]
+    fn test_guarded_traps() {
         let mut fc = ForthCompiler::new();
 
-        // Simulate a IO IN command, at TRAP(101), but define the port number inside a Forth word as well
         fc.sm
             .trap_handlers
-            .push(Box::from(TrapHandler::new(101, |_trap_id, st| {
-                let io_port = st
-                    .number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                let io_value = 654321_i64;
-                println!(
-                    "Simulated IO IN command from Port: {} and Value: {}",
-                    io_port, io_value
-                );
-                st.number_stack.push(io_value);
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                st.number_stack.push(654321);
+                Ok(TrapHandled::Handled)
+            })));
+
+        // The prelude defines the word that is meant to reach the host
+        fc.execute_string(": IO_IN 100 TRAP ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.set_sandbox_policy(SandboxPolicy::restricted());
+        fc.expose_trap(100, "IO_IN").unwrap();
+
+        fc.execute_string("IO_IN 1", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![654321_i64, 1]);
+
+        // Going straight to the TRAP isn't allowed
+        match fc.execute_string("100 TRAP", GasLimit::Limited(100)) {
+            Err(ForthError::TrapNotPermitted(100)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        // Neither is redefining the exposed word
+        match fc.execute_string(": IO_IN 100 TRAP ; IO_IN", GasLimit::Limited(100)) {
+            Err(ForthError::TrapNotPermitted(100)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        match fc.expose_trap(100, "MISSING") {
+            Err(ForthError::UnknownToken(ref x)) if x == "MISSING" => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        // A word defined after a reset where the exposed one was doesn't get its permission,
+        // though one exposed before the prelude was marked keeps it
+        let mut fc = ForthCompiler::new();
+        fc.sm
+            .trap_handlers
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                st.number_stack.push(654321);
                 Ok(TrapHandled::Handled)
             })));
+        fc.execute_string(": Kept 100 TRAP ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.set_sandbox_policy(SandboxPolicy::restricted());
+        fc.expose_trap(100, "Kept").unwrap();
+        fc.mark_prelude();
+        fc.execute_string(": IO_IN 100 TRAP ;", GasLimit::Limited(100))
+            .unwrap();
+        fc.expose_trap(100, "IO_IN").unwrap();
+        fc.reset(ResetLevel::UserWords).unwrap();
+        match fc.execute_string(": Evil 100 TRAP ; Evil", GasLimit::Limited(100)) {
+            Err(ForthError::TrapNotPermitted(100)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        fc.reset(ResetLevel::Stacks).unwrap();
+        fc.execute_string("Kept", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![654321_i64]);
+
+        // Nor does code loaded from a session at the same address
+        let session = fc.saved_session(&[]);
+        fc.restore_session(session).unwrap();
+        match fc.execute_string("Kept", GasLimit::Limited(100)) {
+            Err(ForthError::TrapNotPermitted(100)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut fc = ForthCompiler::new();
+        fc.add_alias("+", "ADD");
+        fc.add_alias("*", "MUL");
+        fc.add_alias("SI", "IF");
+        fc.add_alias("ALORS", "THEN");
 
         fc.execute_string(
-            ": IO_IN 101 TRAP ; : IN_KEYBOARD 1000 IO_IN ; IN_KEYBOARD",
+            ": Carre DUP * ; 3 Carre 1 + 0 SI 5 + ALORS",
             GasLimit::Limited(100),
         )
         .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![15_i64]);
 
-        // Value from IO port on stack
-        assert_eq!(&fc.sm.st.number_stack, &vec![654321]);
+        fc.add_alias("Square", "Carre");
+        fc.execute_string("2 Square", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![15_i64, 4]);
+
+        assert_eq!(fc.remove_alias("+"), Some("ADD".to_owned()));
+        match fc.execute_string("1 2 +", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(ref x)) if x == "+" => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
     }
 }
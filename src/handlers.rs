@@ -0,0 +1,616 @@
+//! Ready made trap handlers for the things most hosts end up writing themselves. Each pack
+//! answers its own range of trap numbers and comes with words that reach them, so a host only
+//! has to pick the packs it wants:
+//!
+//! ```
+//! use rust_forth::forth_compiler::ForthCompiler;
+//! use rust_forth::handlers::{self, MathHandlers, RandomHandlers};
+//!
+//! let fc = handlers::install(ForthCompiler::new(), MathHandlers);
+//! let fc = handlers::install(fc, RandomHandlers::new(1234));
+//! ```
+//!
+//! A prelude that builds on a pack's words has to run after the pack is installed:
+//!
+//! ```
+//! use rust_forth::forth_compiler::ForthCompiler;
+//! use rust_forth::handlers::RandomHandlers;
+//! use rust_forth::stack_machine::GasLimit;
+//!
+//! let fc = ForthCompiler::new()
+//!     .with_handler_pack(RandomHandlers::new(1234))
+//!     .with_prelude(": TwoRandoms RANDOM RANDOM ;", GasLimit::Limited(100))
+//!     .unwrap();
+//! ```
+//!
+//! The words compile inline to a TRAP, so a sandbox policy that limits TRAPs has to allow the
+//! words that use them.
+
+use super::forth_compiler::ForthCompiler;
+use super::host::{Clock, UnixClock};
+use super::stack_machine::{
+    gcd, isqrt, HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled,
+};
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+/// First trap number of ConsoleHandlers
+pub const CONSOLE_TRAPS: i64 = 200;
+/// First trap number of MathHandlers
+pub const MATH_TRAPS: i64 = 210;
+/// First trap number of RandomHandlers
+pub const RANDOM_TRAPS: i64 = 220;
+/// First trap number of TimeHandlers
+pub const TIME_TRAPS: i64 = 230;
+/// First trap number of NetHandlers
+pub const NET_TRAPS: i64 = 240;
+/// First trap number of MqttHandlers
+pub const MQTT_TRAPS: i64 = 250;
+/// First trap number of JsonHandlers, which has sixteen words and so takes up to 275
+pub const JSON_TRAPS: i64 = 260;
+/// First trap number of MatchHandlers
+pub const MATCH_TRAPS: i64 = 280;
+
+/// A trap handler that also knows which words reach its traps
+pub trait HandlerPack: HandleTrap {
+    /// The words, as intrinsics to add to a ForthCompiler
+    fn words(&self) -> Vec<(String, Vec<Opcode>)>;
+}
+
+/// Add the pack's words to the compiler and the pack to its trap handlers
+pub fn install<P>(fc: ForthCompiler, pack: P) -> ForthCompiler
+where
+    P: HandlerPack + 'static,
+{
+    let mut fc = fc.with_additional_intrinsics(pack.words());
+    fc.sm.trap_handlers.push(Box::new(pack));
+    fc
+}
+
+// A word that runs a trap
+pub(crate) fn trap_word(name: &str, trap: i64) -> (String, Vec<Opcode>) {
+    (name.to_owned(), vec![Opcode::LDI(trap), Opcode::TRAP])
+}
+
+pub(crate) fn pop(st: &mut StackMachineState) -> Result<i64, StackMachineError> {
+    st.number_stack
+        .pop()
+        .ok_or(StackMachineError::NumberStackUnderflow)
+}
+
+/// Character I/O on the program's output and an input stream
+///
+/// | Word   | Stack effect | |
+/// |--------|--------------|-|
+/// | EMIT   | ( c -- )     | Print the character with code c |
+/// | CR     | ( -- )       | Print a newline |
+/// | SPACE  | ( -- )       | Print a space |
+/// | SPACES | ( n -- )     | Print n spaces |
+/// | KEY    | ( -- c )     | Read a byte from the input, -1 at the end of it |
+pub struct ConsoleHandlers {
+    input: Box<dyn Read>,
+}
+
+impl ConsoleHandlers {
+    /// Read keys from stdin
+    pub fn new() -> ConsoleHandlers {
+        ConsoleHandlers::with_input(Box::new(io::stdin()))
+    }
+
+    pub fn with_input(input: Box<dyn Read>) -> ConsoleHandlers {
+        ConsoleHandlers { input }
+    }
+}
+
+impl Default for ConsoleHandlers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandleTrap for ConsoleHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - CONSOLE_TRAPS {
+            0 => {
+                let c = pop(st)?;
+                let c = std::char::from_u32(c as u32)
+                    .filter(|_| c >= 0)
+                    .ok_or(StackMachineError::OutOfDomain(c))?;
+                write!(st.output, "{}", c).map_err(StackMachineError::Io)?;
+            }
+            1 => writeln!(st.output).map_err(StackMachineError::Io)?,
+            2 => write!(st.output, " ").map_err(StackMachineError::Io)?,
+            3 => {
+                // Copied rather than formatted, format widths stop at u16::MAX
+                let n = pop(st)?.max(0) as u64;
+                io::copy(&mut io::repeat(b' ').take(n), &mut st.output)
+                    .map_err(StackMachineError::Io)?;
+            }
+            4 => {
+                let mut key = [0_u8];
+                let c = match self.input.read(&mut key).map_err(StackMachineError::Io)? {
+                    0 => -1,
+                    _ => i64::from(key[0]),
+                };
+                st.number_stack.push(c);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for ConsoleHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("EMIT", CONSOLE_TRAPS),
+            trap_word("CR", CONSOLE_TRAPS + 1),
+            trap_word("SPACE", CONSOLE_TRAPS + 2),
+            trap_word("SPACES", CONSOLE_TRAPS + 3),
+            trap_word("KEY", CONSOLE_TRAPS + 4),
+        ]
+    }
+}
+
+/// Integer math that is slow to write in Forth. A ForthCompiler already has these words as
+/// intrinsics, the pack is for hosts that build their own word set with with_intrinsics().
+///
+/// | Word | Stack effect | |
+/// |------|--------------|-|
+/// | SQRT | ( n -- r )   | Square root of n rounded down, n can't be negative |
+/// | GCD  | ( a b -- g ) | Greatest common divisor of a and b, never negative |
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MathHandlers;
+
+impl HandleTrap for MathHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - MATH_TRAPS {
+            0 => {
+                let n = pop(st)?;
+                st.number_stack
+                    .push(isqrt(n).ok_or(StackMachineError::OutOfDomain(n))?);
+            }
+            1 => {
+                let b = pop(st)?;
+                let a = pop(st)?;
+                st.number_stack
+                    .push(gcd(a, b).ok_or(StackMachineError::OutOfDomain(a))?);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for MathHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("SQRT", MATH_TRAPS),
+            trap_word("GCD", MATH_TRAPS + 1),
+        ]
+    }
+}
+
+/// Pseudo random numbers from a xorshift generator, good enough for games and jitter but not for
+/// anything that needs to be unpredictable
+///
+/// | Word   | Stack effect | |
+/// |--------|--------------|-|
+/// | RANDOM | ( n -- r )   | A number from 0 to n-1, n has to be positive |
+/// | SEED   | ( n -- )     | Restart the sequence from a seed |
+#[derive(Debug, Clone, Copy)]
+pub struct RandomHandlers {
+    state: u64,
+}
+
+impl RandomHandlers {
+    /// The same seed always gives the same sequence
+    pub fn new(seed: u64) -> RandomHandlers {
+        let mut r = RandomHandlers { state: 0 };
+        r.seed(seed);
+        r
+    }
+
+    fn seed(&mut self, seed: u64) {
+        // xorshift gets stuck on 0
+        self.state = if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        };
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl HandleTrap for RandomHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - RANDOM_TRAPS {
+            0 => {
+                let n = pop(st)?;
+                if n <= 0 {
+                    return Err(StackMachineError::OutOfDomain(n));
+                }
+                let r = self.next() % n as u64;
+                st.number_stack.push(r as i64);
+            }
+            1 => {
+                let seed = pop(st)?;
+                self.seed(seed as u64);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for RandomHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("RANDOM", RANDOM_TRAPS),
+            trap_word("SEED", RANDOM_TRAPS + 1),
+        ]
+    }
+}
+
+/// The date and time of day, in UTC
+///
+/// | Word      | Stack effect                             | |
+/// |-----------|------------------------------------------|-|
+/// | TIME&DATE | ( -- second minute hour day month year ) | The current date and time |
+/// | EPOCH     | ( -- s )                                 | Seconds since 1970-01-01 |
+///
+/// and calendar arithmetic, with dates as ( day month year ) like TIME&DATE leaves them
+///
+/// | Word         | Stack effect                             | |
+/// |--------------|------------------------------------------|-|
+/// | DAYS         | ( day month year -- days )               | Days since 1970-01-01 |
+/// | DATE         | ( days -- day month year )               | The date that many days after 1970-01-01 |
+/// | DAY-OF-WEEK  | ( day month year -- n )                  | 1 for Monday through 7 for Sunday |
+/// | DAYS-BETWEEN | ( day month year day month year -- n )   | Days from the first date to the second |
+/// | ISO-DATE>    | ( addr len -- day month year )           | Parse a YYYY-MM-DD date |
+/// | >ISO-DATE    | ( day month year addr -- len )           | Write the date as YYYY-MM-DD |
+///
+/// Dates that don't exist, like the 30th of February, are out of domain.
+pub struct TimeHandlers {
+    // Milliseconds since the Unix epoch
+    clock: Box<dyn Clock>,
+}
+
+impl TimeHandlers {
+    /// Use the system's time
+    pub fn new() -> TimeHandlers {
+        TimeHandlers::with_clock(Box::new(UnixClock))
+    }
+
+    /// Use a clock that counts milliseconds since the Unix epoch
+    pub fn with_clock(clock: Box<dyn Clock>) -> TimeHandlers {
+        TimeHandlers { clock }
+    }
+}
+
+impl Default for TimeHandlers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The year, month and day of a count of days since 1970-01-01, from Howard Hinnant's
+// civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The inverse of civil_from_days
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Pop ( day month year ) and give the days since 1970-01-01, checking the date exists
+fn pop_date(st: &mut StackMachineState) -> Result<i64, StackMachineError> {
+    let year = pop(st)?;
+    let month = pop(st)?;
+    let day = pop(st)?;
+    checked_days(day, month, year)
+}
+
+fn checked_days(day: i64, month: i64, year: i64) -> Result<i64, StackMachineError> {
+    if !(1..=12).contains(&month) {
+        return Err(StackMachineError::OutOfDomain(month));
+    }
+    if !(1..=31).contains(&day) {
+        return Err(StackMachineError::OutOfDomain(day));
+    }
+    // Years this far out would overflow the arithmetic and aren't dates anyone schedules for
+    if year.abs() > 1 << 40 {
+        return Err(StackMachineError::OutOfDomain(year));
+    }
+    let days = days_from_civil(year, month, day);
+    // The 31st of a short month comes back as the start of the next one
+    if civil_from_days(days) != (year, month, day) {
+        return Err(StackMachineError::OutOfDomain(day));
+    }
+    Ok(days)
+}
+
+fn parse_iso_date(text: &[u8]) -> Option<(i64, i64, i64)> {
+    let text = std::str::from_utf8(text).ok()?;
+    let mut parts = text.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let digits = |s: &str| -> Option<i64> {
+        if s.bytes().all(|b| b.is_ascii_digit()) {
+            s.parse().ok()
+        } else {
+            None
+        }
+    };
+    Some((digits(day)?, digits(month)?, digits(year)?))
+}
+
+impl HandleTrap for TimeHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        let seconds = (self.clock.now_millis() / 1000) as i64;
+        match trap_id - TIME_TRAPS {
+            0 => {
+                let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+                let time = seconds.rem_euclid(86400);
+                st.number_stack.extend_from_slice(&[
+                    time % 60,
+                    time / 60 % 60,
+                    time / 3600,
+                    day,
+                    month,
+                    year,
+                ]);
+            }
+            1 => st.number_stack.push(seconds),
+            2 => {
+                let days = pop_date(st)?;
+                st.number_stack.push(days);
+            }
+            3 => {
+                let days = pop(st)?;
+                if days.abs() > 1 << 40 {
+                    return Err(StackMachineError::OutOfDomain(days));
+                }
+                let (year, month, day) = civil_from_days(days);
+                st.number_stack.extend_from_slice(&[day, month, year]);
+            }
+            4 => {
+                // 1970-01-01 was a Thursday
+                let days = pop_date(st)?;
+                st.number_stack.push((days + 3).rem_euclid(7) + 1);
+            }
+            5 => {
+                let to = pop_date(st)?;
+                let from = pop_date(st)?;
+                st.number_stack.push(to - from);
+            }
+            6 => {
+                let length = pop(st)?;
+                let address = pop(st)?;
+                let text = st.fetch_bytes(address, length)?;
+                let (day, month, year) = parse_iso_date(&text).ok_or_else(|| {
+                    StackMachineError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("not a YYYY-MM-DD date: {}", String::from_utf8_lossy(&text)),
+                    ))
+                })?;
+                checked_days(day, month, year)?;
+                st.number_stack.extend_from_slice(&[day, month, year]);
+            }
+            7 => {
+                let address = pop(st)?;
+                let (year, month, day) = civil_from_days(pop_date(st)?);
+                let text = format!("{:04}-{:02}-{:02}", year, month, day);
+                st.store_bytes(address, text.as_bytes())?;
+                st.number_stack.push(text.len() as i64);
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for TimeHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("TIME&DATE", TIME_TRAPS),
+            trap_word("EPOCH", TIME_TRAPS + 1),
+            trap_word("DAYS", TIME_TRAPS + 2),
+            trap_word("DATE", TIME_TRAPS + 3),
+            trap_word("DAY-OF-WEEK", TIME_TRAPS + 4),
+            trap_word("DAYS-BETWEEN", TIME_TRAPS + 5),
+            trap_word("ISO-DATE>", TIME_TRAPS + 6),
+            trap_word(">ISO-DATE", TIME_TRAPS + 7),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ForthError;
+    use crate::stack_machine::GasLimit;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now_millis(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_console() {
+        let output = SharedOutput::default();
+        let mut fc = install(
+            ForthCompiler::new(),
+            ConsoleHandlers::with_input(Box::new("ab".as_bytes())),
+        );
+        fc.sm.st.output = Box::new(output.clone());
+
+        fc.execute_string(
+            "72 EMIT 105 EMIT SPACE 3 SPACES CR KEY KEY KEY",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(&*output.0.borrow(), b"Hi    \n");
+        assert_eq!(&fc.sm.st.number_stack, &vec![97_i64, 98, -1]);
+
+        // More spaces than a format width can hold
+        output.0.borrow_mut().clear();
+        fc.execute_string("70000 SPACES", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(output.0.borrow().len(), 70000);
+        assert!(output.0.borrow().iter().all(|b| *b == b' '));
+    }
+
+    #[test]
+    fn test_math() {
+        let mut fc = install(ForthCompiler::new(), MathHandlers);
+
+        fc.execute_string(
+            "0 SQRT 15 SQRT 16 SQRT 9223372036854775807 SQRT 12 18 GCD -12 18 GCD 7 0 GCD",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![0_i64, 3, 4, 3037000499, 6, 6, 7]
+        );
+
+        match fc.execute_string("-1 SQRT", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(-1)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        let mut fc = install(ForthCompiler::new(), RandomHandlers::new(42));
+
+        fc.execute_string(
+            "10 RANDOM 10 RANDOM 42 SEED 10 RANDOM",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        let stack = fc.sm.st.number_stack.clone();
+        assert!(stack.iter().all(|r| (0..10).contains(r)));
+        // Seeding again starts the same sequence
+        assert_eq!(stack[0], stack[2]);
+
+        match fc.execute_string("0 RANDOM", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(0)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_time() {
+        // 2024-02-29 13:45:30 UTC
+        let clock = FixedClock(1_709_214_330_000);
+        let mut fc = install(
+            ForthCompiler::new(),
+            TimeHandlers::with_clock(Box::new(clock)),
+        );
+
+        fc.execute_string("TIME&DATE EPOCH", GasLimit::Limited(100))
+            .unwrap();
+
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![30_i64, 45, 13, 29, 2, 2024, 1_709_214_330]
+        );
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_calendar() {
+        let mut fc = install(ForthCompiler::new(), TimeHandlers::new());
+        fc.execute_string("2 ARRAY Text", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.store_bytes(8, b"2024-02-29").unwrap();
+
+        fc.execute_string(
+            "Text 10 ISO-DATE> DAYS DUP DATE DAY-OF-WEEK \
+             31 12 2023 1 3 2024 DAYS-BETWEEN 1 1 1970 DAYS -1 DATE \
+             1 3 2000 Text >ISO-DATE",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![19_782_i64, 4, 61, 0, 31, 12, 1969, 10]
+        );
+        assert_eq!(fc.sm.st.fetch_bytes(8, 10).unwrap(), b"2000-03-01");
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+
+        match fc.execute_string("29 2 2023 DAYS", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(29)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        fc.sm.st.store_bytes(8, b"2024-2-29").unwrap();
+        match fc.execute_string("Text 9 ISO-DATE>", GasLimit::Limited(100)) {
+            Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! The parts of the outside world the compiler reaches, behind traits so hosts and tests can
+//! supply their own
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Where MS@ gets the time from
+pub trait Clock {
+    /// Milliseconds since some fixed point, which never go backwards
+    fn now_millis(&self) -> u64;
+}
+
+/// Counts milliseconds from when it was made
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+/// Counts milliseconds since the Unix epoch, for words that need the date and time of day
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnixClock;
+
+impl Clock for UnixClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// The ports IN and OUT talk to
+pub trait IoBus {
+    fn read_port(&mut self, port: i64) -> io::Result<i64>;
+    fn write_port(&mut self, port: i64, value: i64) -> io::Result<()>;
+}
+
+/// A bus with nothing on it, reads give 0 and writes go nowhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullIoBus;
+
+impl IoBus for NullIoBus {
+    fn read_port(&mut self, _port: i64) -> io::Result<i64> {
+        Ok(0)
+    }
+
+    fn write_port(&mut self, _port: i64, _value: i64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where the compiler reads source files from
+pub trait FileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String>;
+    fn write(&mut self, path: &str, contents: &str) -> io::Result<()>;
+
+    /// Read a file that may not be text, such as a compressed session
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.read_to_string(path).map(String::into_bytes)
+    }
+
+    /// Write a file that may not be text. File systems that only hold text refuse anything
+    /// that isn't UTF-8.
+    fn write_bytes(&mut self, path: &str, contents: &[u8]) -> io::Result<()> {
+        let text = std::str::from_utf8(contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write(path, text)
+    }
+}
+
+/// The real file system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write_bytes(&mut self, path: &str, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+}
+
+/// Where KV! and KV@ keep values between runs
+pub trait KeyValueStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<i64>>;
+    fn set(&mut self, key: &str, value: i64) -> io::Result<()>;
+}
+
+/// Values kept in memory, gone when the store is dropped
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKeyValueStore {
+    values: HashMap<String, i64>,
+}
+
+impl MemoryKeyValueStore {
+    pub fn new() -> MemoryKeyValueStore {
+        MemoryKeyValueStore::default()
+    }
+}
+
+impl KeyValueStore for MemoryKeyValueStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<i64>> {
+        Ok(self.values.get(key).copied())
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> io::Result<()> {
+        self.values.insert(key.to_owned(), value);
+        Ok(())
+    }
+}
+
+/// Values kept in a text file, one `VALUE KEY` pair per line. The file is read the first time
+/// it is needed and written out again after every change.
+#[derive(Debug, Clone)]
+pub struct FileKeyValueStore {
+    path: PathBuf,
+    // None until the file has been read
+    values: Option<HashMap<String, i64>>,
+}
+
+impl FileKeyValueStore {
+    /// The file doesn't have to exist yet
+    pub fn new(path: impl Into<PathBuf>) -> FileKeyValueStore {
+        FileKeyValueStore {
+            path: path.into(),
+            values: None,
+        }
+    }
+
+    fn values(&mut self) -> io::Result<&mut HashMap<String, i64>> {
+        if self.values.is_none() {
+            let text = match fs::read_to_string(&self.path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e),
+            };
+            let mut values = HashMap::new();
+            for line in text.lines().filter(|l| !l.is_empty()) {
+                let (value, key) = line
+                    .split_once(' ')
+                    .and_then(|(v, k)| Some((v.parse::<i64>().ok()?, k)))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}: bad line {:?}", self.path.display(), line),
+                        )
+                    })?;
+                values.insert(key.to_owned(), value);
+            }
+            self.values = Some(values);
+        }
+        Ok(self.values.get_or_insert_with(HashMap::new))
+    }
+}
+
+impl KeyValueStore for FileKeyValueStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<i64>> {
+        Ok(self.values()?.get(key).copied())
+    }
+
+    fn set(&mut self, key: &str, value: i64) -> io::Result<()> {
+        if key.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keys can't contain newlines",
+            ));
+        }
+        let values = self.values()?;
+        values.insert(key.to_owned(), value);
+
+        let mut keys: Vec<&String> = values.keys().collect();
+        keys.sort();
+        let text: String = keys
+            .into_iter()
+            .map(|k| format!("{} {}\n", values[k], k))
+            .collect();
+        // Written next to the file and renamed over it, so a crash can't leave half a file
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_key_value_store() {
+        let path = std::env::temp_dir().join(format!("rust_forth_kv_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = FileKeyValueStore::new(&path);
+        assert_eq!(store.get("count").unwrap(), None);
+        store.set("count", 3).unwrap();
+        store.set("two words", -1).unwrap();
+        assert!(store.set("bad\nkey", 1).is_err());
+
+        // A new store sees what the last one saved
+        let mut store = FileKeyValueStore::new(&path);
+        assert_eq!(store.get("count").unwrap(), Some(3));
+        assert_eq!(store.get("two words").unwrap(), Some(-1));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "3 count\n-1 two words\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Comparing two compiled images, such as before and after a script update, word by word. Calls
+//! are compared by the word they go to rather than its address, so a word only counts as
+//! changed when its own code is different, not because something before it grew.
+
+use super::artifact::{CompilationArtifact, WordInfo};
+use super::stack_machine::Opcode;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A word that is in both images but compiled differently
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordDelta {
+    pub name: String,
+    pub opcodes_before: usize,
+    pub opcodes_after: usize,
+    // How many of the opcodes are different, counting the ones only the longer body has
+    pub opcodes_changed: usize,
+}
+
+/// What changed in the dictionary between two images, each list sorted by name. Immediate mode
+/// code isn't part of an image's dictionary, so it isn't compared.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageDiff {
+    // The words only the new image has, with how many opcodes each compiled to
+    pub added: Vec<(String, usize)>,
+    pub removed: Vec<String>,
+    pub changed: Vec<WordDelta>,
+    pub unchanged: usize,
+}
+
+impl ImageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// How many opcodes an update has to carry, the whole of every added or changed word, for
+    /// estimating how big an over the air update to a device will be
+    pub fn update_opcodes(&self) -> usize {
+        self.added.iter().map(|(_, length)| length).sum::<usize>()
+            + self.changed.iter().map(|d| d.opcodes_after).sum::<usize>()
+    }
+}
+
+/// One line per added, removed or changed word, then a summary
+impl fmt::Display for ImageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, length) in self.added.iter() {
+            writeln!(f, "+ {} ({} opcodes)", name, length)?;
+        }
+        for name in self.removed.iter() {
+            writeln!(f, "- {}", name)?;
+        }
+        for d in self.changed.iter() {
+            writeln!(
+                f,
+                "~ {} ({} -> {} opcodes, {} changed)",
+                d.name, d.opcodes_before, d.opcodes_after, d.opcodes_changed
+            )?;
+        }
+        writeln!(
+            f,
+            "{} added, {} removed, {} changed, {} unchanged, {} opcodes to update",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.unchanged,
+            self.update_opcodes()
+        )
+    }
+}
+
+// An opcode as it is compared, the address a call or jump goes to is replaced by where it is
+// in the word it goes to
+#[derive(Debug, PartialEq)]
+enum ComparedOpcode<'a> {
+    Opcode(&'a Opcode),
+    Target(&'a str, usize),
+}
+
+/// Compare the dictionaries of two images
+pub fn diff_images(before: &CompilationArtifact, after: &CompilationArtifact) -> ImageDiff {
+    let mut diff = ImageDiff::default();
+
+    for w in after.words.iter() {
+        let old = match before.words.iter().find(|b| b.name == w.name) {
+            Some(old) => old,
+            None => {
+                diff.added.push((w.name.clone(), w.length));
+                continue;
+            }
+        };
+        let (old_body, new_body) = (compared_body(before, old), compared_body(after, w));
+        if old_body == new_body {
+            diff.unchanged += 1;
+            continue;
+        }
+        let differing = old_body
+            .iter()
+            .zip(new_body.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        diff.changed.push(WordDelta {
+            name: w.name.clone(),
+            opcodes_before: old.length,
+            opcodes_after: w.length,
+            opcodes_changed: differing + old.length.abs_diff(w.length),
+        });
+    }
+    diff.removed = before
+        .words
+        .iter()
+        .filter(|b| !after.words.iter().any(|w| w.name == b.name))
+        .map(|b| b.name.clone())
+        .collect();
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort_by(|a, b| a.name.cmp(&b.name));
+    diff
+}
+
+fn compared_body<'a>(image: &'a CompilationArtifact, word: &WordInfo) -> Vec<ComparedOpcode<'a>> {
+    let body = &image.opcodes[word.address..word.address + word.length];
+    body.iter()
+        .enumerate()
+        .map(|(i, op)| match (op, body.get(i + 1)) {
+            (Opcode::LDI(address), Some(Opcode::CALL | Opcode::JMP)) => {
+                match usize::try_from(*address)
+                    .ok()
+                    .and_then(|a| image.word_containing(a).map(|target| (a, target)))
+                {
+                    Some((a, target)) => ComparedOpcode::Target(&target.name, a - target.address),
+                    None => ComparedOpcode::Opcode(op),
+                }
+            }
+            _ => ComparedOpcode::Opcode(op),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::stack_machine::GasLimit;
+
+    fn image(source: &str) -> CompilationArtifact {
+        let mut fc = ForthCompiler::new();
+        fc.set_artifacts_enabled(true);
+        fc.execute_string(source, GasLimit::Limited(100)).unwrap();
+        fc.last_artifact().unwrap().clone()
+    }
+
+    #[test]
+    fn test_diff_images() {
+        let before = image(": Double DUP ADD ; : Quad Double Double ; : Old 1 ; : Same 2 ;");
+        // Quad moves but calls the same word, so only Double and the new words differ
+        let after = image(": New 5 ; : Double 2 MUL ; : Quad Double Double ; : Same 3 MUL ;");
+        let diff = diff_images(&before, &after);
+
+        assert_eq!(diff.added, vec![("New".to_owned(), 2)]);
+        assert_eq!(diff.removed, vec!["Old".to_owned()]);
+        assert_eq!(
+            diff.changed,
+            vec![
+                WordDelta {
+                    name: "Double".to_owned(),
+                    opcodes_before: 3,
+                    opcodes_after: 3,
+                    opcodes_changed: 2,
+                },
+                WordDelta {
+                    name: "Same".to_owned(),
+                    opcodes_before: 2,
+                    opcodes_after: 3,
+                    opcodes_changed: 3,
+                }
+            ]
+        );
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.update_opcodes(), 8);
+        assert_eq!(
+            diff.to_string(),
+            "+ New (2 opcodes)\n- Old\n~ Double (3 -> 3 opcodes, 2 changed)\n\
+             ~ Same (2 -> 3 opcodes, 3 changed)\n\
+             1 added, 1 removed, 2 changed, 1 unchanged, 8 opcodes to update\n"
+        );
+
+        assert!(diff_images(&after, &after).is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! A report on what a saved image is made of, for trimming a program to fit a small target: how
+//! often each opcode is used, how big each word is, which words nothing refers to and how much
+//! data space the image carries.
+
+use super::saved_session::SavedSession;
+use super::stack_machine::{Opcode, CELL_SIZE};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageStats {
+    // Each opcode used in the dictionary, without its argument, most used first
+    pub opcode_counts: Vec<(String, usize)>,
+    // Largest first
+    pub word_sizes: Vec<(String, usize)>,
+    // Words no other word calls, jumps to or takes the execution token of, and no event is
+    // handled by. The words a host calls by name are among them.
+    pub unreferenced: Vec<String>,
+    pub dictionary_opcodes: usize,
+    pub data_cells: usize,
+    // The cells of the data space that are the elements of ARRAYs
+    pub array_cells: usize,
+    // The bytes of the data space that hold string literals' text
+    pub string_bytes: usize,
+}
+
+/// A table of opcodes, one of words and a summary
+impl fmt::Display for ImageStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Opcodes")?;
+        for (name, count) in self.opcode_counts.iter() {
+            writeln!(f, "  {:>8}  {}", count, name)?;
+        }
+        writeln!(f, "Words")?;
+        for (name, length) in self.word_sizes.iter() {
+            writeln!(f, "  {:>8}  {}", length, name)?;
+        }
+        writeln!(f, "Unreferenced words")?;
+        for name in self.unreferenced.iter() {
+            writeln!(f, "  {}", name)?;
+        }
+        writeln!(
+            f,
+            "{} words, {} dictionary opcodes, {} data bytes of which {} in arrays and {} in strings",
+            self.word_sizes.len(),
+            self.dictionary_opcodes,
+            self.data_cells * CELL_SIZE as usize,
+            self.array_cells * CELL_SIZE as usize,
+            self.string_bytes
+        )
+    }
+}
+
+pub fn image_stats(image: &SavedSession) -> ImageStats {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for op in image.opcodes.iter() {
+        *counts.entry(opcode_name(op)).or_insert(0) += 1;
+    }
+    let mut opcode_counts: Vec<(String, usize)> = counts.into_iter().collect();
+    opcode_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut word_sizes: Vec<(String, usize)> = image
+        .words
+        .iter()
+        .map(|w| (w.name.clone(), w.length))
+        .collect();
+    word_sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    // Any LDI of a word's address refers to it, as a call, a jump or an execution token, unless
+    // it is inside the word itself or pushes a data space address that happens to be the same
+    let mut referenced: HashSet<&str> = image
+        .event_handlers
+        .iter()
+        .map(|(_, word)| word.as_str())
+        .collect();
+    for (address, op) in image.opcodes.iter().enumerate() {
+        if image.data_words.contains(&address) {
+            continue;
+        }
+        if let Opcode::LDI(target) = op {
+            referenced.extend(
+                image
+                    .words
+                    .iter()
+                    .filter(|w| w.address as i64 == *target)
+                    .filter(|w| !(w.address..w.address + w.length).contains(&address))
+                    .map(|w| w.name.as_str()),
+            );
+        }
+    }
+    let mut unreferenced: Vec<String> = image
+        .words
+        .iter()
+        .filter(|w| !referenced.contains(w.name.as_str()))
+        .map(|w| w.name.clone())
+        .collect();
+    unreferenced.sort();
+
+    ImageStats {
+        opcode_counts,
+        word_sizes,
+        unreferenced,
+        dictionary_opcodes: image.opcodes.len(),
+        data_cells: image.data.len(),
+        array_cells: image
+            .arrays
+            .iter()
+            .filter_map(|(_, address)| match image.opcodes.get(*address) {
+                // An array's word pushes the address of its first element, the length is in
+                // the cell before
+                Some(Opcode::LDI(first)) => usize::try_from(first / CELL_SIZE - 1).ok(),
+                _ => None,
+            })
+            .filter_map(|index| image.data.get(index))
+            .map(|length| *length as usize)
+            .sum(),
+        string_bytes: image.string_space_bytes,
+    }
+}
+
+// An opcode's name without its argument, LDI for LDI(5)
+fn opcode_name(op: &Opcode) -> String {
+    let text = format!("{:?}", op);
+    match text.split_once('(') {
+        Some((name, _)) => name.to_owned(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_image_stats() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            ": Double DUP ADD ; : Quad Double Double ; : Loop RECURSE ; \
+             : Handler .\" welcome\" ; ON-EVENT ping Handler 3 ARRAY Table VARIABLE Count",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        let stats = image_stats(&fc.saved_session(&[]));
+
+        assert_eq!(stats.opcode_counts[0], ("LDI".to_owned(), 7));
+        assert_eq!(stats.opcode_counts[1], ("RET".to_owned(), 6));
+        assert_eq!(stats.word_sizes[0], ("Quad".to_owned(), 5));
+        // Loop only refers to itself
+        assert_eq!(stats.unreferenced, vec!["Count", "Loop", "Quad", "Table"]);
+        assert_eq!(stats.data_cells, 6);
+        assert_eq!(stats.array_cells, 3);
+        assert_eq!(stats.string_bytes, 8);
+        assert!(stats
+            .to_string()
+            .ends_with("6 words, 19 dictionary opcodes, 48 data bytes of which 24 in arrays and 8 in strings\n"));
+    }
+}
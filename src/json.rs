@@ -0,0 +1,339 @@
+//! Words for reading and building JSON, so scripts can work with structured data from the host
+//! without a trap for every field. Parsed and built values are kept by the pack and referred to
+//! by handles. Strings go in and out through the data space as address and length pairs.
+
+use super::handlers::{pop, trap_word, HandlerPack, JSON_TRAPS};
+use super::stack_machine::{HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+
+/// What JSON-TYPE gives for each kind of value
+pub const JSON_NULL: i64 = 0;
+pub const JSON_BOOLEAN: i64 = 1;
+pub const JSON_NUMBER: i64 = 2;
+pub const JSON_STRING: i64 = 3;
+pub const JSON_ARRAY: i64 = 4;
+pub const JSON_OBJECT: i64 = 5;
+
+/// Reading and building JSON
+///
+/// | Word             | Stack effect                       | |
+/// |------------------|------------------------------------|-|
+/// | JSON-PARSE       | ( addr len -- handle )             | Parse JSON text |
+/// | JSON-GET         | ( handle addr len -- handle )      | A member of an object, null if it isn't there |
+/// | JSON-NTH         | ( handle n -- handle )             | An element of an array, null if it isn't there |
+/// | JSON-TYPE        | ( handle -- type )                 | 0 null, 1 boolean, 2 number, 3 string, 4 array, 5 object |
+/// | JSON-LENGTH      | ( handle -- n )                    | Elements of an array, members of an object or bytes of a string |
+/// | JSON-NUMBER      | ( handle -- n )                    | A number, rounded toward zero, or a boolean as a flag |
+/// | JSON-STRING      | ( handle addr max -- len )         | Copy a string into memory, cut short to fit |
+/// | JSON-OBJECT      | ( -- handle )                      | A new empty object |
+/// | JSON-ARRAY       | ( -- handle )                      | A new empty array |
+/// | JSON-FROM-NUMBER | ( n -- handle )                    | A number value |
+/// | JSON-FROM-STRING | ( addr len -- handle )             | A string value |
+/// | JSON-PUT         | ( object addr len value -- )       | Set a member of an object to a copy of the value |
+/// | JSON-PUSH        | ( array value -- )                 | Add a copy of the value to the end of an array |
+/// | JSON-WRITE       | ( handle addr max -- len )         | Write the value as JSON text into memory, cut short to fit |
+/// | JSON.            | ( handle -- )                      | Print the value as JSON text |
+/// | JSON-CLEAR       | ( -- )                             | Forget every handle |
+#[derive(Debug, Default)]
+pub struct JsonHandlers {
+    values: Vec<Value>,
+}
+
+impl JsonHandlers {
+    pub fn new() -> JsonHandlers {
+        JsonHandlers::default()
+    }
+
+    fn add(&mut self, value: Value) -> i64 {
+        self.values.push(value);
+        (self.values.len() - 1) as i64
+    }
+
+    fn value(&self, handle: i64) -> Result<&Value, StackMachineError> {
+        usize::try_from(handle)
+            .ok()
+            .and_then(|h| self.values.get(h))
+            .ok_or(StackMachineError::OutOfDomain(handle))
+    }
+
+    fn value_mut(&mut self, handle: i64) -> Result<&mut Value, StackMachineError> {
+        let values = &mut self.values;
+        usize::try_from(handle)
+            .ok()
+            .and_then(move |h| values.get_mut(h))
+            .ok_or(StackMachineError::OutOfDomain(handle))
+    }
+
+    // Pop an address and length and read the string they point at
+    fn pop_string(st: &mut StackMachineState) -> Result<String, StackMachineError> {
+        let length = pop(st)?;
+        let address = pop(st)?;
+        Ok(String::from_utf8_lossy(&st.fetch_bytes(address, length)?).into_owned())
+    }
+
+    // Pop ( addr max ) and copy as much of the bytes as fits there, pushing how many were copied
+    fn copy_out(st: &mut StackMachineState, bytes: &[u8]) -> Result<(), StackMachineError> {
+        let max = pop(st)?;
+        let address = pop(st)?;
+        let bytes = &bytes[..bytes.len().min(max.max(0) as usize)];
+        st.store_bytes(address, bytes)?;
+        st.number_stack.push(bytes.len() as i64);
+        Ok(())
+    }
+}
+
+impl HandleTrap for JsonHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - JSON_TRAPS {
+            0 => {
+                let text = JsonHandlers::pop_string(st)?;
+                let value = serde_json::from_str(&text).map_err(|e| {
+                    StackMachineError::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+                })?;
+                st.number_stack.push(self.add(value));
+            }
+            1 => {
+                let key = JsonHandlers::pop_string(st)?;
+                let handle = pop(st)?;
+                let member = self.value(handle)?.get(&key).cloned();
+                st.number_stack
+                    .push(self.add(member.unwrap_or(Value::Null)));
+            }
+            2 => {
+                let n = pop(st)?;
+                let handle = pop(st)?;
+                let element = usize::try_from(n)
+                    .ok()
+                    .and_then(|n| self.value(handle).ok()?.as_array()?.get(n).cloned());
+                // Check the handle even when the index is bad
+                self.value(handle)?;
+                st.number_stack
+                    .push(self.add(element.unwrap_or(Value::Null)));
+            }
+            3 => {
+                let handle = pop(st)?;
+                let kind = match self.value(handle)? {
+                    Value::Null => JSON_NULL,
+                    Value::Bool(_) => JSON_BOOLEAN,
+                    Value::Number(_) => JSON_NUMBER,
+                    Value::String(_) => JSON_STRING,
+                    Value::Array(_) => JSON_ARRAY,
+                    Value::Object(_) => JSON_OBJECT,
+                };
+                st.number_stack.push(kind);
+            }
+            4 => {
+                let handle = pop(st)?;
+                let length = match self.value(handle)? {
+                    Value::Array(a) => a.len(),
+                    Value::Object(o) => o.len(),
+                    Value::String(s) => s.len(),
+                    _ => 0,
+                };
+                st.number_stack.push(length as i64);
+            }
+            5 => {
+                let handle = pop(st)?;
+                let n = match self.value(handle)? {
+                    Value::Number(n) => n
+                        .as_i64()
+                        .or_else(|| n.as_f64().map(|f| f as i64))
+                        .unwrap_or(0),
+                    Value::Bool(b) => {
+                        if *b {
+                            -1
+                        } else {
+                            0
+                        }
+                    }
+                    _ => return Err(StackMachineError::OutOfDomain(handle)),
+                };
+                st.number_stack.push(n);
+            }
+            6 => {
+                let max = pop(st)?;
+                let address = pop(st)?;
+                let handle = pop(st)?;
+                let s = match self.value(handle)? {
+                    Value::String(s) => s.clone(),
+                    _ => return Err(StackMachineError::OutOfDomain(handle)),
+                };
+                st.number_stack.extend_from_slice(&[address, max]);
+                JsonHandlers::copy_out(st, s.as_bytes())?;
+            }
+            7 => st.number_stack.push(self.add(Value::Object(Map::new()))),
+            8 => st.number_stack.push(self.add(Value::Array(Vec::new()))),
+            9 => {
+                let n = pop(st)?;
+                st.number_stack.push(self.add(Value::from(n)));
+            }
+            10 => {
+                let s = JsonHandlers::pop_string(st)?;
+                st.number_stack.push(self.add(Value::String(s)));
+            }
+            11 => {
+                let value = pop(st)?;
+                let value = self.value(value)?.clone();
+                let key = JsonHandlers::pop_string(st)?;
+                let handle = pop(st)?;
+                match self.value_mut(handle)? {
+                    Value::Object(o) => {
+                        o.insert(key, value);
+                    }
+                    _ => return Err(StackMachineError::OutOfDomain(handle)),
+                }
+            }
+            12 => {
+                let value = pop(st)?;
+                let value = self.value(value)?.clone();
+                let handle = pop(st)?;
+                match self.value_mut(handle)? {
+                    Value::Array(a) => a.push(value),
+                    _ => return Err(StackMachineError::OutOfDomain(handle)),
+                }
+            }
+            13 => {
+                let max = pop(st)?;
+                let address = pop(st)?;
+                let handle = pop(st)?;
+                let text = self.value(handle)?.to_string();
+                st.number_stack.extend_from_slice(&[address, max]);
+                JsonHandlers::copy_out(st, text.as_bytes())?;
+            }
+            14 => {
+                let handle = pop(st)?;
+                let text = self.value(handle)?.to_string();
+                write!(st.output, "{}", text).map_err(StackMachineError::Io)?;
+            }
+            15 => self.values.clear(),
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for JsonHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("JSON-PARSE", JSON_TRAPS),
+            trap_word("JSON-GET", JSON_TRAPS + 1),
+            trap_word("JSON-NTH", JSON_TRAPS + 2),
+            trap_word("JSON-TYPE", JSON_TRAPS + 3),
+            trap_word("JSON-LENGTH", JSON_TRAPS + 4),
+            trap_word("JSON-NUMBER", JSON_TRAPS + 5),
+            trap_word("JSON-STRING", JSON_TRAPS + 6),
+            trap_word("JSON-OBJECT", JSON_TRAPS + 7),
+            trap_word("JSON-ARRAY", JSON_TRAPS + 8),
+            trap_word("JSON-FROM-NUMBER", JSON_TRAPS + 9),
+            trap_word("JSON-FROM-STRING", JSON_TRAPS + 10),
+            trap_word("JSON-PUT", JSON_TRAPS + 11),
+            trap_word("JSON-PUSH", JSON_TRAPS + 12),
+            trap_word("JSON-WRITE", JSON_TRAPS + 13),
+            trap_word("JSON.", JSON_TRAPS + 14),
+            trap_word("JSON-CLEAR", JSON_TRAPS + 15),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ForthError;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::handlers::install;
+    use crate::stack_machine::GasLimit;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A compiler with the JSON words and the text in an array called Text
+    fn compiler(text: &[u8]) -> ForthCompiler {
+        let mut fc = install(ForthCompiler::new(), JsonHandlers::new());
+        fc.execute_string("8 ARRAY Text", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.store_bytes(8, text).unwrap();
+        fc
+    }
+
+    #[test]
+    fn test_read() {
+        let text = br#"{"a":[1,2.5,"xyz"],"b":true}"#;
+        let mut fc = compiler(text);
+
+        fc.execute_string(
+            &format!(
+                "Text {} JSON-PARSE DUP Text 20 ADD 1 JSON-GET JSON-NUMBER SWAP \
+                 Text 2 ADD 1 JSON-GET DUP JSON-LENGTH SWAP DUP 1 JSON-NTH JSON-NUMBER SWAP \
+                 2 JSON-NTH Text 40 ADD 2 JSON-STRING",
+                text.len()
+            ),
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // b, then the length of a, its second element and the bytes of its third that fit
+        assert_eq!(&fc.sm.st.number_stack, &vec![-1_i64, 3, 2, 2]);
+        assert_eq!(fc.sm.st.fetch_bytes(8 + 40, 2).unwrap(), b"xy");
+    }
+
+    #[test]
+    fn test_build() {
+        let output = SharedOutput::default();
+        let mut fc = compiler(b"name");
+        fc.sm.st.output = Box::new(output.clone());
+
+        fc.execute_string(
+            "JSON-OBJECT DUP Text 4 JSON-ARRAY DUP 7 JSON-FROM-NUMBER JSON-PUSH JSON-PUT \
+             DUP Text 1 Text 4 JSON-FROM-STRING JSON-PUT DUP JSON. Text 20 JSON-WRITE",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            r#"{"n":"name","name":[7]}"#
+        );
+        assert_eq!(&fc.sm.st.number_stack, &vec![20_i64]);
+        assert_eq!(
+            fc.sm.st.fetch_bytes(8, 20).unwrap(),
+            br#"{"n":"name","name":["#
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut fc = compiler(b"{");
+
+        match fc.execute_string("Text 1 JSON-PARSE", GasLimit::Limited(100)) {
+            Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("JSON-ARRAY JSON-NUMBER", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(0)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("JSON-CLEAR 0 JSON-TYPE", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(0)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
@@ -7,9 +7,41 @@
 pub use error::ForthError;
 pub use forth_compiler::Token;
 
+pub mod artifact;
+pub mod bignum;
+pub mod blocks;
+pub mod checksum;
+#[cfg(feature = "editor")]
+pub mod editor;
 pub mod error;
 pub mod forth_compiler;
+pub mod handlers;
+pub mod host;
+pub mod image_diff;
+pub mod image_stats;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod lint;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "matching")]
+pub mod matching;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod pool;
+pub mod protocol;
+pub mod quota;
+pub mod repl;
+pub mod sandbox;
+pub mod saved_session;
+pub mod session;
 pub mod stack_machine;
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod tui;
 
 pub enum Handled {
     Handled,
@@ -0,0 +1,658 @@
+use super::error::ForthError;
+use super::forth_compiler::{tokenize_with_errors, ForthCompiler, Span, Token};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+
+/// The kinds of mistake the linter looks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintKind {
+    InvalidSyntax,
+    UnknownWord,
+    UnbalancedControl,
+    UnusedDefinition,
+    InconsistentStackEffect,
+}
+
+impl LintKind {
+    /// A short stable name for the kind of mistake, for tools to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintKind::InvalidSyntax => "invalid-syntax",
+            LintKind::UnknownWord => "unknown-word",
+            LintKind::UnbalancedControl => "unbalanced-control",
+            LintKind::UnusedDefinition => "unused-definition",
+            LintKind::InconsistentStackEffect => "inconsistent-stack-effect",
+        }
+    }
+}
+
+/// One mistake the linter found
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintMessage {
+    pub kind: LintKind,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Written as `LINE:COLUMN: CODE: MESSAGE`, one message per line, for CI to parse
+impl fmt::Display for LintMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.span.location.line,
+            self.span.location.column,
+            self.kind.code(),
+            self.message
+        )
+    }
+}
+
+// Follows how deep the number stack is through straight line code, relative to where it started
+#[derive(Debug, Clone, Copy)]
+struct StackTracker {
+    depth: i64,
+    lowest: i64,
+    // False once something with an unknown stack effect has run
+    known: bool,
+}
+
+impl StackTracker {
+    fn new() -> StackTracker {
+        StackTracker {
+            depth: 0,
+            lowest: 0,
+            known: true,
+        }
+    }
+
+    fn apply(&mut self, effect: Option<(usize, usize)>) {
+        match effect {
+            Some((inputs, outputs)) => {
+                self.depth -= inputs as i64;
+                self.lowest = self.lowest.min(self.depth);
+                self.depth += outputs as i64;
+            }
+            None => self.known = false,
+        }
+    }
+
+    fn effect(&self) -> Option<(usize, usize)> {
+        if self.known {
+            Some(((-self.lowest) as usize, (self.depth - self.lowest) as usize))
+        } else {
+            None
+        }
+    }
+}
+
+// Which control structure an OpenControl is
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ControlKind {
+    If,
+    Do,
+    Begin,
+}
+
+impl ControlKind {
+    // What the structure's start and end words are, for messages
+    fn describe(&self) -> &'static str {
+        match self {
+            ControlKind::If => "IF without THEN",
+            ControlKind::Do => "DO without LOOP",
+            ControlKind::Begin => "BEGIN without AGAIN",
+        }
+    }
+}
+
+// An IF that hasn't reached its THEN yet, or a loop that hasn't reached its end
+struct OpenControl {
+    kind: ControlKind,
+    span: Span,
+    // Stack depth after the flag was taken, or at the start of the loop body
+    depth: i64,
+    // Stack depth at the end of the IF branch, once an ELSE is seen
+    else_depth: Option<i64>,
+}
+
+// A definition that hasn't reached its ; yet
+struct OpenDefinition {
+    name: String,
+    span: Span,
+    tracker: StackTracker,
+    control: Vec<OpenControl>,
+    // Whether it uses CREATE, making it a defining word
+    creates: bool,
+}
+
+/// Look for common mistakes in Forth source: words the compiler won't know, IF/ELSE/THEN and
+/// colon definitions that don't pair up, definitions nothing calls and IF branches that leave the
+/// stack at different depths. Words the compiler already knows count as defined.
+pub fn lint(fc: &ForthCompiler, source: &str) -> Result<Vec<LintMessage>, ForthError> {
+    Ok(analyse(fc, source)?.0)
+}
+
+/// The stack effects of the words defined in the source, as how many cells each takes and how
+/// many it leaves, or None when that couldn't be worked out
+pub fn stack_effects(
+    fc: &ForthCompiler,
+    source: &str,
+) -> Result<HashMap<String, Option<(usize, usize)>>, ForthError> {
+    Ok(analyse(fc, source)?.1)
+}
+
+type StackEffects = HashMap<String, Option<(usize, usize)>>;
+
+fn analyse(
+    fc: &ForthCompiler,
+    source: &str,
+) -> Result<(Vec<LintMessage>, StackEffects), ForthError> {
+    let tokens = tokenize_with_errors(source);
+    let mut messages = Vec::new();
+    let mut report = |kind, span, message: String| {
+        messages.push(LintMessage {
+            kind,
+            span,
+            message,
+        })
+    };
+
+    // Stack effects of the words defined so far, None when they couldn't be worked out
+    let mut defined: StackEffects = HashMap::new();
+    let mut definition_spans: Vec<(String, Span)> = Vec::new();
+    let mut used: HashSet<String> = HashSet::new();
+
+    let mut immediate = StackTracker::new();
+    let mut immediate_control: Vec<OpenControl> = Vec::new();
+    let mut definition: Option<OpenDefinition> = None;
+    let mut assertion: Option<Span> = None;
+    // Defining words in the source, and whether the last token was one so this one names the
+    // word it makes
+    let mut defining_words: HashSet<String> = HashSet::new();
+    let mut makes_word = false;
+
+    for t in tokens.iter() {
+        if mem::take(&mut makes_word) {
+            if let Token::Command(name) = &t.token {
+                // What the word does is up to its defining word's DOES>
+                defined.insert(name.clone(), None);
+                continue;
+            }
+        }
+        let in_definition = definition.is_some();
+        let mut creates = false;
+        let (tracker, control) = match &mut definition {
+            Some(d) => (&mut d.tracker, &mut d.control),
+            None => (&mut immediate, &mut immediate_control),
+        };
+
+        match &t.token {
+            Token::Number(_) => tracker.apply(Some((0, 1))),
+            Token::Colon(name) => {
+                if let Some(d) = &definition {
+                    report(
+                        LintKind::UnbalancedControl,
+                        t.span,
+                        format!(
+                            "definition of {} starts inside the definition of {}",
+                            name, d.name
+                        ),
+                    );
+                }
+                definition = Some(OpenDefinition {
+                    name: name.clone(),
+                    span: t.span,
+                    tracker: StackTracker::new(),
+                    control: Vec::new(),
+                    creates: false,
+                });
+            }
+            Token::SemiColon => match definition.take() {
+                Some(d) => {
+                    for open in d.control.iter() {
+                        report(
+                            LintKind::UnbalancedControl,
+                            open.span,
+                            format!("{} in the definition of {}", open.kind.describe(), d.name),
+                        );
+                    }
+                    let effect = if d.control.is_empty() {
+                        d.tracker.effect()
+                    } else {
+                        None
+                    };
+                    defined.insert(d.name.clone(), effect);
+                    if d.creates {
+                        defining_words.insert(d.name.clone());
+                    }
+                    definition_spans.push((d.name, d.span));
+                }
+                None => report(
+                    LintKind::UnbalancedControl,
+                    t.span,
+                    "; without a definition to end".to_owned(),
+                ),
+            },
+            Token::Assert(_) => {
+                if assertion.is_some() {
+                    report(
+                        LintKind::UnbalancedControl,
+                        t.span,
+                        "ASSERT( inside another ASSERT(".to_owned(),
+                    );
+                }
+                assertion = Some(t.span);
+            }
+            Token::Command(word) => {
+                let word = fc.aliases().get(word).unwrap_or(word);
+                match word.as_str() {
+                    ")" if assertion.is_some() => assertion = None,
+                    // Assertions don't change the stack, so what they do isn't followed
+                    _ if assertion.is_some() => {
+                        if !is_control_word(word) {
+                            check_word(fc, &defined, &mut used, word, t.span, &mut report);
+                        }
+                    }
+                    "IF" => {
+                        tracker.apply(Some((1, 0)));
+                        control.push(OpenControl {
+                            kind: ControlKind::If,
+                            span: t.span,
+                            depth: tracker.depth,
+                            else_depth: None,
+                        });
+                    }
+                    "ELSE" => match control.last_mut() {
+                        Some(open) if open.kind == ControlKind::If && open.else_depth.is_none() => {
+                            open.else_depth = Some(tracker.depth);
+                            tracker.depth = open.depth;
+                        }
+                        _ => report(
+                            LintKind::UnbalancedControl,
+                            t.span,
+                            "ELSE without IF".to_owned(),
+                        ),
+                    },
+                    "THEN" => match control.last() {
+                        Some(open) if open.kind == ControlKind::If => {
+                            let open = control.pop().unwrap();
+                            let other_depth = open.else_depth.unwrap_or(open.depth);
+                            if tracker.known && other_depth != tracker.depth {
+                                report(
+                                    LintKind::InconsistentStackEffect,
+                                    open.span,
+                                    format!(
+                                        "the branches of this IF leave the stack {} cells apart",
+                                        (other_depth - tracker.depth).abs()
+                                    ),
+                                );
+                                tracker.known = false;
+                            }
+                        }
+                        _ => report(
+                            LintKind::UnbalancedControl,
+                            t.span,
+                            "THEN without IF".to_owned(),
+                        ),
+                    },
+                    "DO" | "?DO" => {
+                        tracker.apply(Some((2, 0)));
+                        control.push(OpenControl {
+                            kind: ControlKind::Do,
+                            span: t.span,
+                            depth: tracker.depth,
+                            else_depth: None,
+                        });
+                    }
+                    "BEGIN" => control.push(OpenControl {
+                        kind: ControlKind::Begin,
+                        span: t.span,
+                        depth: tracker.depth,
+                        else_depth: None,
+                    }),
+                    "LEAVE" => {
+                        if control.iter().all(|open| open.kind == ControlKind::If) {
+                            report(
+                                LintKind::UnbalancedControl,
+                                t.span,
+                                "LEAVE outside a loop".to_owned(),
+                            );
+                        }
+                    }
+                    // The code after DOES> is run by the words the definition makes
+                    "DOES>" => {
+                        if !in_definition {
+                            report(
+                                LintKind::UnbalancedControl,
+                                t.span,
+                                "DOES> outside a definition".to_owned(),
+                            );
+                        }
+                        tracker.apply(None);
+                    }
+                    // What a word does to the stack isn't known until its ;
+                    "RECURSE" => {
+                        if !in_definition {
+                            report(
+                                LintKind::UnbalancedControl,
+                                t.span,
+                                "RECURSE outside a definition".to_owned(),
+                            );
+                        }
+                        tracker.apply(None);
+                    }
+                    "LOOP" | "+LOOP" | "AGAIN" => {
+                        let (kind, opener) = match word.as_str() {
+                            "AGAIN" => (ControlKind::Begin, "BEGIN"),
+                            _ => (ControlKind::Do, "DO"),
+                        };
+                        match control.last() {
+                            Some(open) if open.kind == kind => {
+                                let open = control.pop().unwrap();
+                                if word == "+LOOP" {
+                                    tracker.apply(Some((1, 0)));
+                                }
+                                // Each time round has to leave the stack as it found it
+                                if tracker.known && open.depth != tracker.depth {
+                                    report(
+                                        LintKind::InconsistentStackEffect,
+                                        open.span,
+                                        format!(
+                                            "each time round this loop changes the stack by {} cells",
+                                            tracker.depth - open.depth
+                                        ),
+                                    );
+                                    tracker.known = false;
+                                }
+                            }
+                            _ => report(
+                                LintKind::UnbalancedControl,
+                                t.span,
+                                format!("{} without {}", word, opener),
+                            ),
+                        }
+                    }
+                    _ => {
+                        let effect = check_word(fc, &defined, &mut used, word, t.span, &mut report);
+                        tracker.apply(effect);
+                        creates = word == "CREATE";
+                        makes_word = defining_words.contains(word) || fc.is_defining_word(word);
+                    }
+                }
+            }
+            Token::Tick(word) => {
+                let word = fc.aliases().get(word).unwrap_or(word);
+                check_word(fc, &defined, &mut used, word, t.span, &mut report);
+                tracker.apply(Some((0, 1)));
+            }
+            Token::Array(name) => {
+                tracker.apply(Some((1, 0)));
+                defined.insert(name.clone(), Some((0, 1)));
+            }
+            Token::TrapWord(name, _, effect) => {
+                defined.insert(name.clone(), Some(*effect));
+            }
+            Token::Variable(name) | Token::Create(name) => {
+                defined.insert(name.clone(), Some((0, 1)));
+            }
+            // The host word has the effect of the intrinsic it will be bound to, if it is known yet
+            Token::Extern(name) => {
+                defined.insert(name.clone(), fc.intrinsic_stack_effect(name));
+            }
+            Token::OnEvent(_, word) => {
+                let word = fc.aliases().get(word).unwrap_or(word);
+                check_word(fc, &defined, &mut used, word, t.span, &mut report);
+            }
+            Token::Error(message) => {
+                report(LintKind::InvalidSyntax, t.span, message.clone());
+            }
+            // The words a required file defines aren't followed, only those the compiler knows
+            Token::SQuote(_) => tracker.apply(Some((0, 2))),
+            Token::Require(_) | Token::DotQuote(_) | Token::End => (),
+        }
+
+        if creates {
+            if let Some(d) = &mut definition {
+                d.creates = true;
+            }
+        }
+    }
+
+    if let Some(d) = &definition {
+        report(
+            LintKind::UnbalancedControl,
+            d.span,
+            format!("definition of {} has no ;", d.name),
+        );
+    }
+    for open in immediate_control.iter() {
+        report(
+            LintKind::UnbalancedControl,
+            open.span,
+            open.kind.describe().to_owned(),
+        );
+    }
+    if let Some(span) = assertion {
+        report(
+            LintKind::UnbalancedControl,
+            span,
+            "ASSERT( without )".to_owned(),
+        );
+    }
+    for (name, span) in definition_spans.iter() {
+        if !used.contains(name) {
+            report(
+                LintKind::UnusedDefinition,
+                *span,
+                format!("{} is never called", name),
+            );
+        }
+    }
+
+    messages.sort_by_key(|m| m.span.start);
+    Ok((messages, defined))
+}
+
+fn is_control_word(word: &str) -> bool {
+    matches!(
+        word,
+        "IF" | "ELSE"
+            | "THEN"
+            | "DO"
+            | "?DO"
+            | "LOOP"
+            | "+LOOP"
+            | "BEGIN"
+            | "AGAIN"
+            | "LEAVE"
+            | "RECURSE"
+            | "DOES>"
+    )
+}
+
+// Note the use of a word, reporting it if it isn't known, and give back its stack effect
+fn check_word(
+    fc: &ForthCompiler,
+    defined: &StackEffects,
+    used: &mut HashSet<String>,
+    word: &str,
+    span: Span,
+    report: &mut impl FnMut(LintKind, Span, String),
+) -> Option<(usize, usize)> {
+    used.insert(word.to_owned());
+    if let Some(effect) = defined.get(word) {
+        return *effect;
+    }
+    if !fc.knows_word(word) {
+        report(
+            LintKind::UnknownWord,
+            span,
+            format!("{} is not a known word", word),
+        );
+    }
+    fc.declared_stack_effect(word)
+        .or_else(|| fc.intrinsic_stack_effect(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint_codes(source: &str) -> Vec<String> {
+        lint(&ForthCompiler::new(), source)
+            .unwrap()
+            .iter()
+            .map(|m| m.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_source() {
+        assert_eq!(
+            lint_codes(": Double DUP ADD ;\n: Quad Double Double ;\n1 Quad IF 2 ELSE 3 THEN"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_unknown_words_and_unused_definitions() {
+        assert_eq!(
+            lint_codes(": Double DUP ADD ;\n: Triple DUP Dobule ADD ;\n2 Tripple"),
+            vec![
+                "1:1: unused-definition: Double is never called",
+                "2:1: unused-definition: Triple is never called",
+                "2:14: unknown-word: Dobule is not a known word",
+                "3:3: unknown-word: Tripple is not a known word",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_control() {
+        assert_eq!(
+            lint_codes("THEN 1 IF 2 : Foo ELSE ; ;\n: Bar 1 ASSERT( 1"),
+            vec![
+                "1:1: unbalanced-control: THEN without IF",
+                "1:8: unbalanced-control: IF without THEN",
+                "1:13: unused-definition: Foo is never called",
+                "1:19: unbalanced-control: ELSE without IF",
+                "1:26: unbalanced-control: ; without a definition to end",
+                "2:1: unbalanced-control: definition of Bar has no ;",
+                "2:9: unbalanced-control: ASSERT( without )",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_syntax() {
+        assert_eq!(
+            lint_codes("VARIABLE 12\n-99999999999999999999 ON-EVENT Tick"),
+            vec![
+                "1:1: invalid-syntax: 12 is a number, so it can't name a word",
+                "2:1: invalid-syntax: -99999999999999999999 is too big for a cell",
+                "2:23: invalid-syntax: ON-EVENT needs an event name and a handler word",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inconsistent_stack_effects() {
+        assert_eq!(
+            lint_codes(
+                ": Good IF 1 ELSE 2 THEN ;\n: Bad IF 1 2 ELSE 3 THEN ;\n: Worse IF POP THEN ;\nGood Bad Worse"
+            ),
+            vec![
+                "2:7: inconsistent-stack-effect: the branches of this IF leave the stack 1 cells apart",
+                "3:9: inconsistent-stack-effect: the branches of this IF leave the stack 1 cells apart",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            lint_codes(
+                ": Sum 0 SWAP 0 DO I ADD LOOP ;\n: Leaky 10 0 DO I LOOP ;\n1 0 DO 2 +LOOP LOOP\nSum Leaky"
+            ),
+            vec![
+                "2:14: inconsistent-stack-effect: each time round this loop changes the stack by 1 cells",
+                "3:16: unbalanced-control: LOOP without DO",
+            ]
+        );
+        assert_eq!(
+            lint_codes("BEGIN 1 AGAIN LEAVE 1 0 DO BEGIN LEAVE LOOP"),
+            vec![
+                "1:1: inconsistent-stack-effect: each time round this loop changes the stack by 1 cells",
+                "1:15: unbalanced-control: LEAVE outside a loop",
+                "1:25: unbalanced-control: DO without LOOP",
+                "1:28: unbalanced-control: BEGIN without AGAIN",
+                "1:40: unbalanced-control: LOOP without DO",
+            ]
+        );
+        let effects =
+            stack_effects(&ForthCompiler::new(), ": Sum 0 SWAP 0 DO I ADD LOOP ;").unwrap();
+        assert_eq!(effects["Sum"], Some((1, 1)));
+    }
+
+    #[test]
+    fn test_recurse() {
+        assert_eq!(
+            lint_codes(": Countdown DUP IF ELSE 1 SWAP SUB RECURSE THEN ;\n3 Countdown RECURSE"),
+            vec!["2:13: unbalanced-control: RECURSE outside a definition"]
+        );
+        let effects = stack_effects(&ForthCompiler::new(), ": Forever RECURSE ;").unwrap();
+        assert_eq!(effects["Forever"], None);
+    }
+
+    #[test]
+    fn test_defining_words() {
+        assert_eq!(
+            lint_codes(": Offset CREATE DOES> ADD ;\nOffset Ten 5 Ten CREATE Table Table\nDOES>"),
+            vec!["3:1: unbalanced-control: DOES> outside a definition"]
+        );
+    }
+
+    #[test]
+    fn test_stack_effects() {
+        let effects = stack_effects(
+            &ForthCompiler::new(),
+            ": Double DUP ADD ; : Pair 1 2 ; : Mixed IF 1 THEN ; : Quad Double Double ;",
+        )
+        .unwrap();
+
+        assert_eq!(effects["Double"], Some((1, 1)));
+        assert_eq!(effects["Pair"], Some((0, 2)));
+        assert_eq!(effects["Mixed"], None);
+        assert_eq!(effects["Quad"], Some((1, 1)));
+
+        // Words TRAP: makes have the effect they declare, in the source or in the compiler
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            "TRAP: Send 100 ( value port -- )",
+            crate::stack_machine::GasLimit::Limited(100),
+        )
+        .unwrap();
+        let effects = stack_effects(
+            &fc,
+            "TRAP: Read 101 ( port -- value ) : Copy 1 Read 2 Send ;",
+        )
+        .unwrap();
+        assert_eq!(effects["Read"], Some((1, 1)));
+        assert_eq!(effects["Copy"], Some((0, 0)));
+    }
+
+    #[test]
+    fn test_known_words() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            ": Double DUP ADD ;",
+            crate::stack_machine::GasLimit::Limited(100),
+        )
+        .unwrap();
+        fc.add_alias("+", "ADD");
+
+        assert!(lint(&fc, "1 Double 2 +").unwrap().is_empty());
+    }
+}
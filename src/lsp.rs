@@ -0,0 +1,432 @@
+use super::forth_compiler::{tokenize_with_errors, ForthCompiler, SpannedToken, Token};
+use super::lint::{lint, stack_effects, LintKind};
+use super::session::Session;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+// The control words the compiler handles itself, so they aren't in the dictionary
+const CONTROL_WORDS: [&str; 12] = [
+    "IF", "ELSE", "THEN", "DO", "?DO", "LOOP", "+LOOP", "BEGIN", "AGAIN", "LEAVE", "RECURSE",
+    "DOES>",
+];
+
+/// A Language Server Protocol server for Forth source. Each open document is a chunk of a
+/// Session, so definitions are compiled as they are edited, and the lint pass provides the
+/// diagnostics. It offers go to definition, hover with stack effects and completion.
+pub struct LanguageServer {
+    session: Session,
+    documents: HashMap<String, String>,
+}
+
+impl LanguageServer {
+    pub fn new(fc: ForthCompiler) -> LanguageServer {
+        LanguageServer {
+            session: Session::new(fc),
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Read messages from the input and write replies to the output until the client exits
+    pub fn serve(&mut self, mut input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        loop {
+            // Headers, of which only the length matters, end with a blank line
+            let mut length = None;
+            loop {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+            let length = length.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Message without a Content-Length",
+                )
+            })?;
+            let mut body = vec![0; length];
+            input.read_exact(&mut body)?;
+            let message: Value = serde_json::from_slice(&body)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            if message["method"] == "exit" {
+                return Ok(());
+            }
+            for reply in self.handle_message(&message) {
+                let text = reply.to_string();
+                write!(output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+                output.flush()?;
+            }
+        }
+    }
+
+    /// Handle one message from the client, returning the messages to send back
+    pub fn handle_message(&mut self, message: &Value) -> Vec<Value> {
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let position = (
+            params["position"]["line"].as_u64().unwrap_or(0) as usize,
+            params["position"]["character"].as_u64().unwrap_or(0) as usize,
+        );
+
+        let result = match message["method"].as_str().unwrap_or("") {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "rust_forth" },
+            }),
+            "shutdown" => Value::Null,
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                return vec![self.update_document(uri, text)];
+            }
+            "textDocument/didChange" => {
+                // Only full document changes are asked for
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                    .unwrap_or("");
+                return vec![self.update_document(uri, text)];
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            "textDocument/definition" => self.definition(uri, position),
+            "textDocument/hover" => self.hover(uri, position),
+            "textDocument/completion" => self.completion(),
+            method => {
+                return match id {
+                    Some(id) => vec![json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32601, "message": format!("Unknown method {}", method) },
+                    })],
+                    // Notifications we don't care about, such as initialized
+                    None => Vec::new(),
+                };
+            }
+        };
+
+        match id {
+            Some(id) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            None => Vec::new(),
+        }
+    }
+
+    // Recompile the document and work out its diagnostics
+    fn update_document(&mut self, uri: &str, text: &str) -> Value {
+        self.documents.insert(uri.to_owned(), text.to_owned());
+        let compile_error = self.session.update_chunk(uri, text).err();
+
+        let mut diagnostics: Vec<Value> = match lint(self.session.compiler(), text) {
+            Ok(messages) => messages
+                .iter()
+                .map(|m| {
+                    let severity = match m.kind {
+                        LintKind::InvalidSyntax
+                        | LintKind::UnknownWord
+                        | LintKind::UnbalancedControl => 1,
+                        LintKind::InconsistentStackEffect => 2,
+                        LintKind::UnusedDefinition => 4,
+                    };
+                    json!({
+                        "range": range(text, &m.span),
+                        "severity": severity,
+                        "code": m.kind.code(),
+                        "source": "rust_forth",
+                        "message": m.message,
+                    })
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        // Report a compile failure the lint pass didn't explain at the top of the document
+        if let (Some(e), true) = (compile_error, diagnostics.is_empty()) {
+            diagnostics.push(json!({
+                "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } },
+                "severity": 1,
+                "source": "rust_forth",
+                "message": format!("{:?}", e),
+            }));
+        }
+
+        publish_diagnostics(uri, diagnostics)
+    }
+
+    // The word under the cursor
+    fn word_at(&self, uri: &str, (line, character): (usize, usize)) -> Option<String> {
+        let text = self.documents.get(uri)?;
+        let tokens = tokenize_with_errors(text);
+        let t = tokens.into_iter().find(|t| {
+            let start = t.span.location.column - 1;
+            let length = text[t.span.start..t.span.end].chars().count();
+            t.span.location.line == line + 1 && character >= start && character < start + length
+        })?;
+        match t.token {
+            Token::Command(word) | Token::Colon(word) => Some(
+                self.session
+                    .compiler()
+                    .aliases()
+                    .get(&word)
+                    .cloned()
+                    .unwrap_or(word),
+            ),
+            _ => None,
+        }
+    }
+
+    // The colon definition of the word, found through the chunk the session compiled it from
+    fn find_definition(&self, word: &str) -> Option<(&str, &str, SpannedToken)> {
+        let uri = self.session.chunk_of(word)?;
+        let text = self.documents.get(uri)?;
+        let colon = tokenize_with_errors(text)
+            .into_iter()
+            .find(|t| t.token == Token::Colon(word.to_owned()))?;
+        Some((uri, text, colon))
+    }
+
+    fn definition(&self, uri: &str, position: (usize, usize)) -> Value {
+        let word = match self.word_at(uri, position) {
+            Some(word) => word,
+            None => return Value::Null,
+        };
+        if !self.session.compiler().knows_word(&word) {
+            return Value::Null;
+        }
+        match self.find_definition(&word) {
+            Some((uri, text, colon)) => json!({ "uri": uri, "range": range(text, &colon.span) }),
+            None => Value::Null,
+        }
+    }
+
+    fn hover(&self, uri: &str, position: (usize, usize)) -> Value {
+        let word = match self.word_at(uri, position) {
+            Some(word) => word,
+            None => return Value::Null,
+        };
+        let fc = self.session.compiler();
+
+        let (effect, description) = match self.find_definition(&word) {
+            Some((_, text, colon)) => {
+                let effect = stack_effects(fc, text)
+                    .ok()
+                    .and_then(|effects| effects.get(&word).copied().flatten());
+                // The definition runs from its colon to the first semicolon after it
+                let source = text[colon.span.start..]
+                    .split_whitespace()
+                    .scan(false, |done, w| {
+                        if *done {
+                            return None;
+                        }
+                        *done = w == ";";
+                        Some(w)
+                    })
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+                (effect, format!("```forth\n{}\n```", source))
+            }
+            None if CONTROL_WORDS.contains(&word.as_str()) => (None, "control word".to_owned()),
+            None if fc.knows_word(&word) => {
+                (fc.intrinsic_stack_effect(&word), "intrinsic".to_owned())
+            }
+            None => return Value::Null,
+        };
+        let effect = match effect {
+            Some((inputs, outputs)) => format!("( {} -- {} )", inputs, outputs),
+            None => "( ? )".to_owned(),
+        };
+
+        json!({
+            "contents": {
+                "kind": "markdown",
+                "value": format!("**{}** {}\n\n{}", word, effect, description),
+            }
+        })
+    }
+
+    fn completion(&self) -> Value {
+        let words = self.session.compiler().word_names();
+        let items: Vec<Value> = CONTROL_WORDS
+            .iter()
+            .map(|w| json!({ "label": w, "kind": 14 }))
+            .chain(words.iter().map(|w| json!({ "label": w, "kind": 3 })))
+            .collect();
+        json!(items)
+    }
+}
+
+// The LSP range of a span, positions count characters from 0
+fn range(text: &str, span: &super::forth_compiler::Span) -> Value {
+    let line = span.location.line - 1;
+    let character = span.location.column - 1;
+    let length = text[span.start..span.end].chars().count();
+    json!({
+        "start": { "line": line, "character": character },
+        "end": { "line": line, "character": character + length },
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(server: &mut LanguageServer, uri: &str, text: &str) -> Vec<Value> {
+        server.handle_message(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "forth", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(
+        server: &mut LanguageServer,
+        method: &str,
+        uri: &str,
+        line: u64,
+        character: u64,
+    ) -> Value {
+        let replies = server.handle_message(&json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": { "textDocument": { "uri": uri }, "position": { "line": line, "character": character } },
+        }));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["id"], 7);
+        replies[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut server = LanguageServer::new(ForthCompiler::new());
+
+        let replies = open(&mut server, "file:///a.fs", ": Double DUP ADD ;\n1 Dobule");
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0]["code"], "unused-definition");
+        assert_eq!(diagnostics[0]["severity"], 4);
+        assert_eq!(diagnostics[1]["code"], "unknown-word");
+        assert_eq!(
+            diagnostics[1]["range"],
+            json!({ "start": { "line": 1, "character": 2 }, "end": { "line": 1, "character": 8 } })
+        );
+    }
+
+    #[test]
+    fn test_definition_hover_and_completion() {
+        let mut server = LanguageServer::new(ForthCompiler::new());
+        open(&mut server, "file:///lib.fs", "\n  : Double DUP ADD ;");
+        open(
+            &mut server,
+            "file:///main.fs",
+            ": Quad Double Double ;\n3 Quad",
+        );
+
+        assert_eq!(
+            request(
+                &mut server,
+                "textDocument/definition",
+                "file:///main.fs",
+                0,
+                9
+            ),
+            json!({
+                "uri": "file:///lib.fs",
+                "range": { "start": { "line": 1, "character": 2 }, "end": { "line": 1, "character": 10 } },
+            })
+        );
+        assert_eq!(
+            request(
+                &mut server,
+                "textDocument/definition",
+                "file:///main.fs",
+                1,
+                0
+            ),
+            Value::Null
+        );
+
+        assert_eq!(
+            request(&mut server, "textDocument/hover", "file:///main.fs", 0, 9)["contents"]
+                ["value"],
+            "**Double** ( 1 -- 1 )\n\n```forth\n: Double DUP ADD ;\n```"
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", "file:///lib.fs", 1, 12)["contents"]
+                ["value"],
+            "**DUP** ( 1 -- 2 )\n\nintrinsic"
+        );
+
+        let completion = request(
+            &mut server,
+            "textDocument/completion",
+            "file:///main.fs",
+            0,
+            0,
+        );
+        let labels: Vec<&str> = completion
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"IF"));
+        assert!(labels.contains(&"Double"));
+        assert!(labels.contains(&"Quad"));
+        assert!(labels.contains(&"SWAP"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut server = LanguageServer::new(ForthCompiler::new());
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+        let input: String = messages
+            .iter()
+            .map(|m| {
+                let text = m.to_string();
+                format!("Content-Length: {}\r\n\r\n{}", text.len(), text)
+            })
+            .collect();
+        let mut output = Vec::new();
+
+        server.serve(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let replies: Vec<&str> = output.split("Content-Length: ").skip(1).collect();
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].contains("\"definitionProvider\":true"));
+        assert!(replies[1].ends_with("{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":null}"));
+    }
+}
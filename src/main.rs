@@ -1,12 +1,239 @@
+use rust_forth::blocks::BlockStore;
 use rust_forth::forth_compiler::ForthCompiler;
+use rust_forth::image_stats::image_stats;
+use rust_forth::lint::lint;
+use rust_forth::protocol::LineProtocol;
+use rust_forth::repl::MetaCommands;
+use rust_forth::saved_session::SavedSession;
 use rust_forth::stack_machine::GasLimit;
 use rust_forth::ForthError;
 use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::mem;
 
 fn main() -> Result<(), ForthError> {
-    println!("Hello, world! This is rust_forth");
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|s| s.as_str()) {
+        Some("compile") => compile(&args[2..])?,
+        Some("lint") => lint_files(&args[2..])?,
+        Some("stats") => stats(&args[2..])?,
+        Some("link") => link(&args[2..])?,
+        #[cfg(feature = "lsp")]
+        Some("lsp") => {
+            let stdin = io::stdin();
+            rust_forth::lsp::LanguageServer::new(ForthCompiler::new())
+                .serve(stdin.lock(), &mut io::stdout())?
+        }
+        Some("repl") => repl(&args[2..])?,
+        Some("protocol") => {
+            let stdin = io::stdin();
+            LineProtocol::new(ForthCompiler::new(), GasLimit::Unlimited)
+                .serve(stdin.lock(), &mut io::stdout())?
+        }
+        _ => {
+            println!("Hello, world! This is rust_forth");
+
+            run()?;
+        }
+    }
+
+    Ok(())
+}
+
+// Compile the files without running them, and dump what the compiler produced
+fn compile(files: &[String]) -> Result<(), ForthError> {
+    if files.is_empty() {
+        eprintln!("Usage: rust_forth compile FILE...");
+        std::process::exit(2);
+    }
+
+    let mut fc = ForthCompiler::new();
+    fc.set_artifacts_enabled(true);
+
+    for f in files {
+        fc.compile_string(&fs::read_to_string(f)?)?;
+    }
+
+    if let Some(artifact) = fc.last_artifact() {
+        print!("{}", artifact);
+    }
+
+    Ok(())
+}
+
+// Lint the files, printing one FILE:LINE:COLUMN: CODE: MESSAGE line per problem and exiting with
+// 1 if there were any. Words defined by earlier files are known to later ones.
+fn lint_files(files: &[String]) -> Result<(), ForthError> {
+    if files.is_empty() {
+        eprintln!("Usage: rust_forth lint FILE...");
+        std::process::exit(2);
+    }
+
+    let mut fc = ForthCompiler::new();
+    let mut problems = 0;
+
+    for f in files {
+        let source = fs::read_to_string(f)?;
+        for message in lint(&fc, &source)? {
+            println!("{}:{}", f, message);
+            problems += 1;
+        }
+        // Keep going with whatever compiled, the lint messages say what didn't
+        let _ = fc.compile_string(&source);
+    }
+
+    if problems > 0 {
+        std::process::exit(1);
+    }
 
-    run()?;
+    Ok(())
+}
+
+// Print what a saved session is made of, see image_stats
+fn stats(files: &[String]) -> Result<(), ForthError> {
+    let file = match files {
+        [file] => file,
+        _ => {
+            eprintln!("Usage: rust_forth stats SESSION");
+            std::process::exit(2);
+        }
+    };
+
+    let image = SavedSession::from_bytes(&fs::read(file)?)?;
+    print!("{}", image_stats(&image));
+
+    Ok(())
+}
+
+// Link saved sessions into one, each image's externs bound to the words of those before it
+fn link(files: &[String]) -> Result<(), ForthError> {
+    let (output, images) = match files {
+        [output, images @ ..] if !images.is_empty() => (output, images),
+        _ => {
+            eprintln!("Usage: rust_forth link OUTPUT SESSION...");
+            std::process::exit(2);
+        }
+    };
+
+    let mut fc = ForthCompiler::new();
+    for image in images {
+        fc.link_session_file(image)?;
+    }
+    fc.save_session(output, &[])?;
+
+    Ok(())
+}
+
+// How the REPL's prompt looks, set by its command line flags
+struct PromptStyle {
+    // Color the prompt, unless --no-color was given or stderr isn't a terminal
+    color: bool,
+    // Show the top of the stack as well as its depth, with --show-top
+    show_top: bool,
+}
+
+impl PromptStyle {
+    // The prompt for a new line of input, such as [2] or [2: 15] with --show-top
+    fn prompt(&self, fc: &ForthCompiler) -> String {
+        let stack = &fc.sm.st.number_stack;
+        let text = match stack.last() {
+            Some(top) if self.show_top => {
+                format!("[{}: {}]", stack.len(), fc.sm.st.format_cell(*top))
+            }
+            _ => format!("[{}]", stack.len()),
+        };
+        self.paint(&text, "36")
+    }
+
+    // The prompt for the next line of a definition that isn't finished
+    fn continuation(&self) -> String {
+        self.paint("...", "33")
+    }
+
+    fn paint(&self, text: &str, color: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m ", color, text)
+        } else {
+            format!("{} ", text)
+        }
+    }
+}
+
+// Prompts go to stderr so the output of a piped session is only what the program printed
+fn show_prompt(prompt: &str) -> io::Result<()> {
+    eprint!("{}", prompt);
+    io::stderr().flush()
+}
+
+// Read lines from stdin and run them one at a time, using the block file if one is given. A line
+// that leaves a definition or control structure open is held until the lines that finish it
+// have been read. Lines such as :words and :save-session FILE are meta commands, see
+// MetaCommands, and :help lists them.
+fn repl(args: &[String]) -> Result<(), ForthError> {
+    let mut style = PromptStyle {
+        color: io::stderr().is_terminal(),
+        show_top: false,
+    };
+    let mut block_file = None;
+    for arg in args {
+        match arg.as_str() {
+            "--no-color" => style.color = false,
+            "--show-top" => style.show_top = true,
+            _ => block_file = Some(arg),
+        }
+    }
+
+    let mut fc = ForthCompiler::new();
+    if let Some(f) = block_file {
+        fc.set_block_store(BlockStore::open(f)?);
+    }
+    let mut commands = MetaCommands::new();
+    let mut history: Vec<String> = Vec::new();
+    // The lines read so far of source that isn't complete yet
+    let mut pending = String::new();
+
+    show_prompt(&style.prompt(&fc))?;
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let result = if let Some(result) = commands.dispatch(&mut fc, &mut history, &line) {
+            result
+        } else {
+            history.push(line.clone());
+            pending.push_str(&line);
+            pending.push('\n');
+            if fc.is_incomplete(&pending) {
+                show_prompt(&style.continuation())?;
+                continue;
+            }
+            // There is nothing else for the REPL to do while a run yields
+            fc.execute_string(&mem::take(&mut pending), GasLimit::Unlimited)
+                .and_then(|_| {
+                    while fc.is_suspended() {
+                        fc.resume(GasLimit::Unlimited)?;
+                    }
+                    Ok(())
+                })
+        };
+        match result {
+            Ok(()) => println!(" ok"),
+            Err(e) if fc.last_backtrace().words.is_empty() => println!(" error: {:?}", e),
+            Err(e) => println!(" error: {:?} {}", e, fc.last_backtrace()),
+        }
+        io::stdout().flush()?;
+        show_prompt(&style.prompt(&fc))?;
+    }
+
+    // Run whatever was left open when the input ended, so the mistake is reported
+    if !pending.is_empty() {
+        if let Err(e) = fc.execute_string(&pending, GasLimit::Unlimited) {
+            println!(" error: {:?}", e);
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,135 @@
+//! Regular expression words for scripts that pick through logs and other text. Both the text and
+//! the pattern live in the data space as address and length pairs, the text is matched as bytes
+//! so it doesn't have to be valid UTF-8. The syntax is the regex crate's.
+
+use super::handlers::{pop, trap_word, HandlerPack, MATCH_TRAPS};
+use super::stack_machine::{HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled};
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::io;
+
+/// Matching strings against regular expressions
+///
+/// | Word   | Stack effect                              | |
+/// |--------|-------------------------------------------|-|
+/// | MATCH  | ( addr len pattern len -- flag )          | True if the pattern matches anywhere in the string, anchor it with ^ and $ to match the whole string |
+/// | SEARCH | ( addr len pattern len -- addr len flag ) | The first part of the string the pattern matches, or the whole string and false if there isn't one |
+#[derive(Debug, Default)]
+pub struct MatchHandlers {
+    // Scripts tend to use the same few patterns over and over, so they're only compiled once
+    patterns: HashMap<String, Regex>,
+}
+
+impl MatchHandlers {
+    pub fn new() -> MatchHandlers {
+        MatchHandlers::default()
+    }
+
+    // Pop ( addr len pattern len ), giving the address and bytes of the string and the pattern
+    fn pop_operands(
+        &mut self,
+        st: &mut StackMachineState,
+    ) -> Result<(i64, Vec<u8>, &Regex), StackMachineError> {
+        let pattern_length = pop(st)?;
+        let pattern_address = pop(st)?;
+        let length = pop(st)?;
+        let address = pop(st)?;
+        let pattern =
+            String::from_utf8_lossy(&st.fetch_bytes(pattern_address, pattern_length)?).into_owned();
+        let text = st.fetch_bytes(address, length)?;
+
+        if !self.patterns.contains_key(&pattern) {
+            let regex = Regex::new(&pattern).map_err(|e| {
+                StackMachineError::Io(io::Error::new(io::ErrorKind::InvalidInput, e))
+            })?;
+            self.patterns.insert(pattern.clone(), regex);
+        }
+        Ok((address, text, &self.patterns[&pattern]))
+    }
+}
+
+impl HandleTrap for MatchHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - MATCH_TRAPS {
+            0 => {
+                let (_, text, regex) = self.pop_operands(st)?;
+                let flag = if regex.is_match(&text) { -1 } else { 0 };
+                st.number_stack.push(flag);
+            }
+            1 => {
+                let (address, text, regex) = self.pop_operands(st)?;
+                match regex.find(&text) {
+                    Some(m) => st.number_stack.extend_from_slice(&[
+                        address + m.start() as i64,
+                        m.len() as i64,
+                        -1,
+                    ]),
+                    None => st
+                        .number_stack
+                        .extend_from_slice(&[address, text.len() as i64, 0]),
+                }
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for MatchHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("MATCH", MATCH_TRAPS),
+            trap_word("SEARCH", MATCH_TRAPS + 1),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ForthError;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::handlers::install;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_match_search() {
+        let mut fc = install(ForthCompiler::new(), MatchHandlers::new());
+        // A log line, then the patterns "^ok$" and "[0-9]+ms", the line's own first bytes make "ER"
+        fc.execute_string("8 ARRAY Text", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm
+            .st
+            .store_bytes(8, b"ERROR took 125ms^ok$[0-9]+ms")
+            .unwrap();
+
+        fc.execute_string(
+            "Text 16 Text 2 MATCH Text 16 Text 16 ADD 4 MATCH \
+             Text 16 Text 20 ADD 8 SEARCH Text 5 Text 20 ADD 8 SEARCH",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            &fc.sm.st.number_stack,
+            &vec![-1_i64, 0, 8 + 11, 5, -1, 8, 5, 0]
+        );
+    }
+
+    #[test]
+    fn test_bad_pattern() {
+        let mut fc = install(ForthCompiler::new(), MatchHandlers::new());
+        fc.execute_string("1 ARRAY Text", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.store_bytes(8, b"a(").unwrap();
+
+        match fc.execute_string("Text 1 Text 2 MATCH", GasLimit::Limited(100)) {
+            Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Counters and gauges for operating fleets of services that run scripts, read with
+//! ForthCompiler::metrics and written in the Prometheus text exposition format by exposition.
+
+use super::error::ForthError;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Every metric's name starts with this
+const PREFIX: &str = "rust_forth";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    // Runs of code the host started, events, timers and resumed runs included
+    pub executions: u64,
+    // Compiles and runs that failed, by the name of the ForthError
+    pub errors: BTreeMap<String, u64>,
+    pub gas_used: u64,
+    // The deepest each stack has been, see StackMachineState::high_water
+    pub number_stack_high_water: usize,
+    pub return_stack_high_water: usize,
+    pub dictionary_words: usize,
+    pub dictionary_opcodes: usize,
+}
+
+impl Metrics {
+    /// Count an error against the name of its ForthError variant
+    pub fn record_error(&mut self, error: &ForthError) {
+        let debug = format!("{:?}", error);
+        let name = debug.split('(').next().unwrap_or_default();
+        *self.errors.entry(name.to_owned()).or_insert(0) += 1;
+    }
+
+    /// The metrics in the Prometheus text exposition format, ready to serve from a /metrics
+    /// endpoint
+    pub fn exposition(&self) -> String {
+        let mut text = String::new();
+        let counters = [
+            (
+                "executions_total",
+                "Runs of Forth code started",
+                self.executions,
+            ),
+            ("gas_used_total", "Gas used by all runs", self.gas_used),
+        ];
+        for (name, help, value) in counters {
+            metric_header(&mut text, name, help, "counter");
+            let _ = writeln!(text, "{}_{} {}", PREFIX, name, value);
+        }
+
+        metric_header(
+            &mut text,
+            "errors_total",
+            "Compiles and runs that failed, by error",
+            "counter",
+        );
+        for (error, count) in self.errors.iter() {
+            let _ = writeln!(
+                text,
+                "{}_errors_total{{error=\"{}\"}} {}",
+                PREFIX, error, count
+            );
+        }
+
+        let gauges = [
+            (
+                "number_stack_high_water",
+                "The most cells the number stack has held",
+                self.number_stack_high_water,
+            ),
+            (
+                "return_stack_high_water",
+                "The most return addresses the return stack has held",
+                self.return_stack_high_water,
+            ),
+            (
+                "dictionary_words",
+                "Words in the dictionary",
+                self.dictionary_words,
+            ),
+            (
+                "dictionary_opcodes",
+                "Opcodes in the dictionary",
+                self.dictionary_opcodes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            metric_header(&mut text, name, help, "gauge");
+            let _ = writeln!(text, "{}_{} {}", PREFIX, name, value);
+        }
+        text
+    }
+}
+
+fn metric_header(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(text, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forth_compiler::ForthCompiler;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_metrics() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(": Double DUP ADD ; 2 Double", GasLimit::Limited(100))
+            .unwrap();
+        let gas = fc.sm.st.gas_used();
+        fc.execute_string("Frob", GasLimit::Limited(100))
+            .unwrap_err();
+        fc.execute_string("0 1 DIV", GasLimit::Limited(100))
+            .unwrap_err();
+        let gas = gas + fc.sm.st.gas_used();
+        fc.execute_string("BEGIN AGAIN", GasLimit::Limited(10))
+            .unwrap_err();
+        let gas = gas + fc.sm.st.gas_used();
+
+        let metrics = fc.metrics();
+        assert_eq!(metrics.executions, 3);
+        assert_eq!(metrics.gas_used, gas);
+        assert_eq!(
+            metrics.errors.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("DivisionByZero".to_owned(), 1),
+                ("RanOutOfGas".to_owned(), 1),
+                ("UnknownToken".to_owned(), 1),
+            ]
+        );
+        assert_eq!(metrics.number_stack_high_water, 3);
+        assert_eq!(metrics.return_stack_high_water, 1);
+        assert_eq!(metrics.dictionary_words, 1);
+        assert_eq!(metrics.dictionary_opcodes, 3);
+
+        let text = fc.metrics().exposition();
+        assert!(text.contains(
+            "# TYPE rust_forth_executions_total counter\nrust_forth_executions_total 3\n"
+        ));
+        assert!(text.contains("rust_forth_errors_total{error=\"UnknownToken\"} 1\n"));
+        assert!(text
+            .contains("# TYPE rust_forth_dictionary_words gauge\nrust_forth_dictionary_words 1\n"));
+
+        fc.reset_metrics();
+        assert_eq!(fc.metrics().executions, 0);
+        assert_eq!(fc.metrics().dictionary_words, 1);
+    }
+}
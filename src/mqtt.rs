@@ -0,0 +1,313 @@
+//! MQTT publish and subscribe words, for scripts running on devices that report to a broker.
+//! This is a small MQTT 3.1.1 client that only does what the words need: one connection, clean
+//! sessions, QoS 0 and no keep alive pings.
+//!
+//! Topics and payloads live in the data space as address and length pairs, like the network
+//! words, and the broker has to be a host the sandbox policy allows.
+
+use super::handlers::{pop, trap_word, HandlerPack, MQTT_TRAPS};
+use super::net::{connect_tcp, pop_destination};
+use super::sandbox::SandboxPolicy;
+use super::stack_machine::{HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled};
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const DISCONNECT: u8 = 0xE0;
+
+// A packet's type and flags, and what follows its remaining length
+struct Packet {
+    header: u8,
+    body: Vec<u8>,
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn write_packet(stream: &mut impl Write, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec![header];
+    // The remaining length, seven bits at a time with the top bit meaning more follow
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet)
+}
+
+fn read_packet(stream: &mut impl Read) -> io::Result<Packet> {
+    let mut byte = [0_u8];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut length = 0_usize;
+    for shift in (0..28).step_by(7) {
+        stream.read_exact(&mut byte)?;
+        length |= usize::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body)?;
+            return Ok(Packet { header, body });
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "MQTT remaining length is too long",
+    ))
+}
+
+// The topic and payload of a PUBLISH packet's body
+fn split_publish(packet: &Packet) -> io::Result<(&[u8], &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "MQTT PUBLISH is too short");
+    let body = &packet.body;
+    let topic_length = usize::from(u16::from_be_bytes([
+        *body.first().ok_or_else(invalid)?,
+        *body.get(1).ok_or_else(invalid)?,
+    ]));
+    let topic = body.get(2..2 + topic_length).ok_or_else(invalid)?;
+    // QoS 1 and 2 messages have a packet identifier before the payload
+    let payload_start = if packet.header & 0x06 != 0 {
+        4 + topic_length
+    } else {
+        2 + topic_length
+    };
+    let payload = body.get(payload_start..).ok_or_else(invalid)?;
+    Ok((topic, payload))
+}
+
+/// Publish and subscribe to topics on an MQTT broker
+///
+/// | Word            | Stack effect                                   | |
+/// |-----------------|------------------------------------------------|-|
+/// | MQTT-CONNECT    | ( host len port -- )                           | Connect to the broker |
+/// | MQTT-PUBLISH    | ( topic len payload len -- )                   | Publish a message |
+/// | MQTT-SUBSCRIBE  | ( topic len -- )                               | Subscribe to a topic filter |
+/// | MQTT-RECV       | ( topic max payload max -- topic-len payload-len ) | Wait for a message, both lengths are -1 if none came in time. Anything longer than the buffers is cut short. |
+/// | MQTT-DISCONNECT | ( -- )                                         | Disconnect from the broker |
+pub struct MqttHandlers {
+    policy: SandboxPolicy,
+    client_id: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    next_packet_id: u16,
+}
+
+impl MqttHandlers {
+    /// Only brokers the policy allows can be reached, MQTT-RECV waits up to 5 seconds for a
+    /// message
+    pub fn new(policy: SandboxPolicy, client_id: &str) -> MqttHandlers {
+        MqttHandlers {
+            policy,
+            client_id: client_id.to_owned(),
+            timeout: Duration::from_secs(5),
+            stream: None,
+            next_packet_id: 1,
+        }
+    }
+
+    /// How long connecting and MQTT-RECV wait before giving up
+    pub fn with_timeout(mut self, timeout: Duration) -> MqttHandlers {
+        self.timeout = timeout;
+        self
+    }
+
+    fn stream(&mut self) -> Result<&mut TcpStream, StackMachineError> {
+        self.stream.as_mut().ok_or_else(|| {
+            StackMachineError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "MQTT-CONNECT hasn't been run",
+            ))
+        })
+    }
+
+    fn connect(&mut self, host: &str, port: u16) -> io::Result<()> {
+        let mut stream = connect_tcp(host, port, self.timeout)?;
+
+        let mut body = Vec::new();
+        encode_string(&mut body, b"MQTT");
+        // Protocol level 4 is 3.1.1, with a clean session and keep alive turned off
+        body.extend_from_slice(&[4, 0x02, 0, 0]);
+        encode_string(&mut body, self.client_id.as_bytes());
+        write_packet(&mut stream, CONNECT, &body)?;
+
+        let reply = read_packet(&mut stream)?;
+        if reply.header != CONNACK || reply.body.get(1) != Some(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("MQTT broker refused the connection: {:?}", reply.body),
+            ));
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    // Wait for the next PUBLISH, skipping acknowledgements, None if nothing came in time
+    fn receive(&mut self) -> Result<Option<Packet>, StackMachineError> {
+        let stream = self.stream()?;
+        loop {
+            match read_packet(stream) {
+                Ok(packet) if packet.header & 0xf0 == PUBLISH => return Ok(Some(packet)),
+                Ok(_) => (),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(StackMachineError::Io(e)),
+            }
+        }
+    }
+}
+
+impl HandleTrap for MqttHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - MQTT_TRAPS {
+            0 => {
+                let (host, port) = pop_destination(&self.policy, st)?;
+                self.connect(&host, port).map_err(StackMachineError::Io)?;
+            }
+            1 => {
+                let payload_length = pop(st)?;
+                let payload_address = pop(st)?;
+                let topic_length = pop(st)?;
+                let topic_address = pop(st)?;
+                let mut body = Vec::new();
+                encode_string(&mut body, &st.fetch_bytes(topic_address, topic_length)?);
+                body.extend_from_slice(&st.fetch_bytes(payload_address, payload_length)?);
+                write_packet(self.stream()?, PUBLISH, &body).map_err(StackMachineError::Io)?;
+            }
+            2 => {
+                let topic_length = pop(st)?;
+                let topic_address = pop(st)?;
+                let mut body = self.next_packet_id.to_be_bytes().to_vec();
+                self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+                encode_string(&mut body, &st.fetch_bytes(topic_address, topic_length)?);
+                // Ask for QoS 0
+                body.push(0);
+                write_packet(self.stream()?, SUBSCRIBE, &body).map_err(StackMachineError::Io)?;
+            }
+            3 => {
+                let payload_max = pop(st)?;
+                let payload_address = pop(st)?;
+                let topic_max = pop(st)?;
+                let topic_address = pop(st)?;
+                // Check the buffers are there before anything is read off the network
+                st.fetch_bytes(topic_address, topic_max)?;
+                st.fetch_bytes(payload_address, payload_max)?;
+                match self.receive()? {
+                    Some(packet) => {
+                        let (topic, payload) =
+                            split_publish(&packet).map_err(StackMachineError::Io)?;
+                        let topic = &topic[..topic.len().min(topic_max.max(0) as usize)];
+                        let payload = &payload[..payload.len().min(payload_max.max(0) as usize)];
+                        st.store_bytes(topic_address, topic)?;
+                        st.store_bytes(payload_address, payload)?;
+                        st.number_stack.push(topic.len() as i64);
+                        st.number_stack.push(payload.len() as i64);
+                    }
+                    None => st.number_stack.extend_from_slice(&[-1, -1]),
+                }
+            }
+            4 => {
+                if let Some(mut stream) = self.stream.take() {
+                    write_packet(&mut stream, DISCONNECT, &[]).map_err(StackMachineError::Io)?;
+                }
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for MqttHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("MQTT-CONNECT", MQTT_TRAPS),
+            trap_word("MQTT-PUBLISH", MQTT_TRAPS + 1),
+            trap_word("MQTT-SUBSCRIBE", MQTT_TRAPS + 2),
+            trap_word("MQTT-RECV", MQTT_TRAPS + 3),
+            trap_word("MQTT-DISCONNECT", MQTT_TRAPS + 4),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::handlers::install;
+    use crate::stack_machine::GasLimit;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_publish_subscribe() {
+        // A broker that accepts one client and sends its publish straight back
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = read_packet(&mut stream).unwrap();
+            assert_eq!(connect.header, CONNECT);
+            assert!(connect.body.ends_with(b"\x00\x04test"));
+            write_packet(&mut stream, CONNACK, &[0, 0]).unwrap();
+
+            let subscribe = read_packet(&mut stream).unwrap();
+            assert_eq!(subscribe.header, SUBSCRIBE);
+            assert_eq!(subscribe.body, b"\x00\x01\x00\x01t\x00");
+            write_packet(&mut stream, 0x90, &[0, 1, 0]).unwrap();
+
+            let publish = read_packet(&mut stream).unwrap();
+            write_packet(&mut stream, PUBLISH, &publish.body).unwrap();
+            assert_eq!(read_packet(&mut stream).unwrap().header, DISCONNECT);
+        });
+        let mut fc = install(
+            ForthCompiler::new(),
+            MqttHandlers::new(SandboxPolicy::restricted().allow_host("127.0.0.1"), "test"),
+        );
+        // "127.0.0.1", then the topic "t" and the payload "hello"
+        fc.execute_string("4 ARRAY Text", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.store_bytes(8, b"127.0.0.1thello").unwrap();
+
+        fc.execute_string(
+            &format!(
+                "Text 9 {} MQTT-CONNECT Text 9 ADD 1 MQTT-SUBSCRIBE \
+                 Text 9 ADD 1 Text 10 ADD 5 MQTT-PUBLISH \
+                 Text 16 ADD 4 Text 20 ADD 3 MQTT-RECV MQTT-DISCONNECT",
+                port
+            ),
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        broker.join().unwrap();
+
+        // The payload was cut short to fit the buffer
+        assert_eq!(&fc.sm.st.number_stack, &vec![1_i64, 3]);
+        assert_eq!(
+            fc.sm.st.fetch_bytes(8 + 16, 7).unwrap(),
+            b"t\x00\x00\x00hel"
+        );
+    }
+}
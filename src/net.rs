@@ -0,0 +1,255 @@
+//! TCP and UDP words for scripts that report to a collector or talk to devices on the network.
+//! Install them with handlers::install like any other pack, giving the pack the sandbox policy
+//! that decides which hosts scripts may reach.
+//!
+//! Host names and the data sent and received live in the data space as address and length
+//! pairs, connections are referred to by small integer handles.
+
+use super::handlers::{pop, trap_word, HandlerPack, NET_TRAPS};
+use super::sandbox::SandboxPolicy;
+use super::stack_machine::{HandleTrap, Opcode, StackMachineError, StackMachineState, TrapHandled};
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+// Pop ( host len port ) and check the policy allows the host
+pub(crate) fn pop_destination(
+    policy: &SandboxPolicy,
+    st: &mut StackMachineState,
+) -> Result<(String, u16), StackMachineError> {
+    let port = pop(st)?;
+    let length = pop(st)?;
+    let address = pop(st)?;
+    let host = String::from_utf8_lossy(&st.fetch_bytes(address, length)?).into_owned();
+    if !policy.allows_host(&host) {
+        return Err(StackMachineError::HostNotPermitted(host));
+    }
+    let port = u16::try_from(port).map_err(|_| StackMachineError::OutOfDomain(port))?;
+    Ok((host, port))
+}
+
+// Connect to the first of the host's addresses that answers, reads time out after the timeout
+pub(crate) fn connect_tcp(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, host.to_owned());
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+/// Network connections for scripts
+///
+/// | Word        | Stack effect                 | |
+/// |-------------|------------------------------|-|
+/// | NET-CONNECT | ( host len port -- handle )  | Open a TCP connection |
+/// | NET-UDP     | ( host len port -- handle )  | Open a UDP socket that sends to the host |
+/// | NET-SEND    | ( addr len handle -- sent )  | Send bytes, returning how many went |
+/// | NET-RECV    | ( addr len handle -- count ) | Receive up to len bytes, 0 at the end of a TCP stream and -1 when nothing arrived in time |
+/// | NET-CLOSE   | ( handle -- )                | Close the connection |
+pub struct NetHandlers {
+    policy: SandboxPolicy,
+    timeout: Duration,
+    // Indexed by handle, closed connections leave a gap
+    connections: Vec<Option<Connection>>,
+}
+
+impl NetHandlers {
+    /// Only hosts the policy allows can be reached, NET-RECV waits up to 5 seconds for data
+    pub fn new(policy: SandboxPolicy) -> NetHandlers {
+        NetHandlers {
+            policy,
+            timeout: Duration::from_secs(5),
+            connections: Vec::new(),
+        }
+    }
+
+    /// How long connecting and NET-RECV wait before giving up
+    pub fn with_timeout(mut self, timeout: Duration) -> NetHandlers {
+        self.timeout = timeout;
+        self
+    }
+
+    fn open(&mut self, connection: Connection) -> i64 {
+        self.connections.push(Some(connection));
+        (self.connections.len() - 1) as i64
+    }
+
+    fn connection(&mut self, handle: i64) -> Result<&mut Connection, StackMachineError> {
+        let connections = &mut self.connections;
+        usize::try_from(handle)
+            .ok()
+            .and_then(move |h| connections.get_mut(h))
+            .and_then(|c| c.as_mut())
+            .ok_or(StackMachineError::OutOfDomain(handle))
+    }
+
+    fn connect_udp(&self, host: &str, port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect((host, port))?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        Ok(socket)
+    }
+}
+
+impl HandleTrap for NetHandlers {
+    fn handle_trap(
+        &mut self,
+        trap_id: i64,
+        st: &mut StackMachineState,
+    ) -> Result<TrapHandled, StackMachineError> {
+        match trap_id - NET_TRAPS {
+            0 => {
+                let (host, port) = pop_destination(&self.policy, st)?;
+                let stream =
+                    connect_tcp(&host, port, self.timeout).map_err(StackMachineError::Io)?;
+                let handle = self.open(Connection::Tcp(stream));
+                st.number_stack.push(handle);
+            }
+            1 => {
+                let (host, port) = pop_destination(&self.policy, st)?;
+                let socket = self
+                    .connect_udp(&host, port)
+                    .map_err(StackMachineError::Io)?;
+                let handle = self.open(Connection::Udp(socket));
+                st.number_stack.push(handle);
+            }
+            2 => {
+                let handle = pop(st)?;
+                let length = pop(st)?;
+                let address = pop(st)?;
+                let bytes = st.fetch_bytes(address, length)?;
+                let sent = match self.connection(handle)? {
+                    Connection::Tcp(stream) => stream.write(&bytes),
+                    Connection::Udp(socket) => socket.send(&bytes),
+                }
+                .map_err(StackMachineError::Io)?;
+                st.number_stack.push(sent as i64);
+            }
+            3 => {
+                let handle = pop(st)?;
+                let length = pop(st)?;
+                let address = pop(st)?;
+                // Check the whole buffer is there before anything is read off the network
+                st.fetch_bytes(address, length)?;
+                let mut buffer = vec![0; length.max(0) as usize];
+                let received = match self.connection(handle)? {
+                    Connection::Tcp(stream) => stream.read(&mut buffer),
+                    Connection::Udp(socket) => socket.recv(&mut buffer),
+                };
+                let count = match received {
+                    Ok(count) => {
+                        st.store_bytes(address, &buffer[..count])?;
+                        count as i64
+                    }
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        -1
+                    }
+                    Err(e) => return Err(StackMachineError::Io(e)),
+                };
+                st.number_stack.push(count);
+            }
+            4 => {
+                let handle = pop(st)?;
+                self.connection(handle)?;
+                self.connections[handle as usize] = None;
+            }
+            _ => return Ok(TrapHandled::NotHandled),
+        }
+        Ok(TrapHandled::Handled)
+    }
+}
+
+impl HandlerPack for NetHandlers {
+    fn words(&self) -> Vec<(String, Vec<Opcode>)> {
+        vec![
+            trap_word("NET-CONNECT", NET_TRAPS),
+            trap_word("NET-UDP", NET_TRAPS + 1),
+            trap_word("NET-SEND", NET_TRAPS + 2),
+            trap_word("NET-RECV", NET_TRAPS + 3),
+            trap_word("NET-CLOSE", NET_TRAPS + 4),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ForthError;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::handlers::install;
+    use crate::stack_machine::GasLimit;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A compiler with the network words, "127.0.0.1" then "ping" in an array called Text and a
+    // cell called Handle to keep a connection in
+    fn compiler(policy: SandboxPolicy) -> ForthCompiler {
+        let mut fc = install(ForthCompiler::new(), NetHandlers::new(policy));
+        fc.execute_string("4 ARRAY Text 1 ARRAY Handle", GasLimit::Limited(100))
+            .unwrap();
+        fc.sm.st.store_bytes(8, b"127.0.0.1ping").unwrap();
+        fc
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(&buffer).unwrap();
+        });
+        let mut fc = compiler(SandboxPolicy::restricted().allow_host("127.0.0.1"));
+
+        fc.execute_string(
+            &format!(
+                "Text 9 {} NET-CONNECT 0 Handle []! \
+                 Text 9 ADD 4 0 Handle []@ NET-SEND \
+                 Text 24 ADD 4 0 Handle []@ NET-RECV \
+                 Text 24 ADD 4 0 Handle []@ NET-RECV \
+                 0 Handle []@ NET-CLOSE",
+                port
+            ),
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        echo.join().unwrap();
+
+        // Everything was sent, the echo came back and then the stream ended
+        assert_eq!(&fc.sm.st.number_stack, &vec![4_i64, 4, 0]);
+        assert_eq!(fc.sm.st.fetch_bytes(8 + 24, 4).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut fc = compiler(SandboxPolicy::restricted());
+
+        match fc.execute_string("Text 9 7 NET-CONNECT", GasLimit::Limited(100)) {
+            Err(ForthError::HostNotPermitted(host)) => assert_eq!(host, "127.0.0.1"),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        match fc.execute_string("0 NET-CLOSE", GasLimit::Limited(100)) {
+            Err(ForthError::OutOfDomain(0)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
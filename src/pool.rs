@@ -0,0 +1,199 @@
+//! A pool of ForthCompilers that are already set up, so a server running one script per request
+//! doesn't pay for loading the prelude and registering host words every time. A compiler is
+//! checked out, used, and checked back in, which puts it back the way it was when it was warmed.
+
+use super::error::ForthError;
+use super::forth_compiler::{Checkpoint, ForthCompiler};
+use std::ops::{Deref, DerefMut};
+
+/// A ForthCompiler checked out of a ForthPool, give it back with ForthPool::check_in
+pub struct PooledCompiler {
+    fc: ForthCompiler,
+    // How it was once warmed, what checking it in goes back to
+    checkpoint: Checkpoint,
+}
+
+impl Deref for PooledCompiler {
+    type Target = ForthCompiler;
+
+    fn deref(&self) -> &ForthCompiler {
+        &self.fc
+    }
+}
+
+impl DerefMut for PooledCompiler {
+    fn deref_mut(&mut self) -> &mut ForthCompiler {
+        &mut self.fc
+    }
+}
+
+/// Warm ForthCompilers made by a function that sets each one up, for example by loading a
+/// prelude and registering host words. Whatever a script does to a compiler is undone when it
+/// is checked in, see ForthCompiler::restore_checkpoint. Settings the host changes after
+/// checking one out, such as its output or quotas, are not undone.
+pub struct ForthPool {
+    warm: Box<dyn Fn() -> Result<ForthCompiler, ForthError>>,
+    idle: Vec<PooledCompiler>,
+    // The most idle compilers kept, more than that are dropped when checked in
+    capacity: usize,
+}
+
+impl ForthPool {
+    /// A pool with `capacity` compilers warmed up front
+    pub fn new<F>(capacity: usize, warm: F) -> Result<ForthPool, ForthError>
+    where
+        F: Fn() -> Result<ForthCompiler, ForthError> + 'static,
+    {
+        let mut pool = ForthPool {
+            warm: Box::new(warm),
+            idle: Vec::with_capacity(capacity),
+            capacity,
+        };
+        for _ in 0..capacity {
+            let compiler = pool.warm_compiler()?;
+            pool.idle.push(compiler);
+        }
+        Ok(pool)
+    }
+
+    /// An idle compiler, or a newly warmed one when they are all checked out
+    pub fn check_out(&mut self) -> Result<PooledCompiler, ForthError> {
+        match self.idle.pop() {
+            Some(compiler) => Ok(compiler),
+            None => self.warm_compiler(),
+        }
+    }
+
+    /// Put a compiler back how it was warmed and make it available again. One that can't be
+    /// put back is dropped and the error returned.
+    pub fn check_in(&mut self, mut compiler: PooledCompiler) -> Result<(), ForthError> {
+        compiler.fc.restore_checkpoint(&compiler.checkpoint)?;
+        if self.idle.len() < self.capacity {
+            self.idle.push(compiler);
+        }
+        Ok(())
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.len()
+    }
+
+    fn warm_compiler(&self) -> Result<PooledCompiler, ForthError> {
+        let fc = (self.warm)()?;
+        let checkpoint = fc.checkpoint();
+        Ok(PooledCompiler { fc, checkpoint })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_machine::{ArithmeticMode, GasLimit};
+
+    fn pool() -> ForthPool {
+        ForthPool::new(1, || {
+            let mut fc = ForthCompiler::new();
+            fc.add_alias("+", "ADD");
+            fc.execute_string(
+                ": Double DUP + ; VARIABLE Total 2 ARRAY Pair",
+                GasLimit::Limited(100),
+            )?;
+            Ok(fc)
+        })
+        .unwrap()
+    }
+
+    // Run a script in a compiler checked out of the pool, check it in and check the same
+    // compiler out again
+    fn after(pool: &mut ForthPool, script: &str) -> PooledCompiler {
+        let mut fc = pool.check_out().unwrap();
+        fc.execute_string(script, GasLimit::Limited(1000)).unwrap();
+        pool.check_in(fc).unwrap();
+        assert_eq!(pool.idle(), 1);
+        pool.check_out().unwrap()
+    }
+
+    #[test]
+    fn test_check_out_and_in() {
+        let mut pool = pool();
+        assert_eq!(pool.idle(), 1);
+
+        // A second compiler is warmed when the first is out, but only one is kept
+        let first = pool.check_out().unwrap();
+        let second = pool.check_out().unwrap();
+        assert_eq!(pool.idle(), 0);
+        pool.check_in(first).unwrap();
+        pool.check_in(second).unwrap();
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_reset_words() {
+        let mut pool = pool();
+        let mut fc = after(
+            &mut pool,
+            ": Double 3 MUL ; : Extra 1 ; ON-EVENT tick Extra Extra",
+        );
+
+        match fc.execute_string("Extra", GasLimit::Limited(100)) {
+            Err(ForthError::UnknownToken(word)) => assert_eq!(word, "Extra"),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        fc.execute_string("5 Double 1 +", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![11_i64]);
+
+        fc.post_event("tick", &[]);
+        assert_eq!(fc.process_events(GasLimit::Limited(100)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reset_stacks_and_data() {
+        let mut pool = pool();
+        let fc = after(
+            &mut pool,
+            "1 2 3 7 Total ! 9 0 Pair []! 4 ARRAY More CREATE Blob 64 ALLOT",
+        );
+
+        assert!(fc.sm.st.number_stack.is_empty());
+        assert!(fc.sm.st.return_stack().is_empty());
+        assert_eq!(fc.sm.st.data(), &[0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_reset_big_numbers() {
+        let mut pool = pool();
+        let mut fc = pool.check_out().unwrap();
+        fc.sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+        fc.execute_string("9223372036854775807 Double", GasLimit::Limited(100))
+            .unwrap();
+        let handle = fc.sm.st.number_stack[0];
+        assert!(fc.sm.st.big_number(handle).is_some());
+        pool.check_in(fc).unwrap();
+
+        let fc = pool.check_out().unwrap();
+        assert!(fc.sm.st.big_number(handle).is_none());
+    }
+
+    #[test]
+    fn test_reset_suspended_runs_events_and_timers() {
+        let mut pool = pool();
+        let mut fc = pool.check_out().unwrap();
+        fc.execute_string(
+            ": Ping 1 Total ! ; 10 ' Ping AFTER 0 1 REQUEST",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        fc.post_event("tick", &[1]);
+        assert!(fc.is_suspended());
+        pool.check_in(fc).unwrap();
+
+        let mut fc = pool.check_out().unwrap();
+        assert!(!fc.is_suspended());
+        assert_eq!(fc.pending_request(), None);
+        assert_eq!(fc.pending_events(), 0);
+        assert_eq!(fc.tick(100, GasLimit::Limited(100)).unwrap(), 0);
+        assert_eq!(fc.quota_usage().executions, 0);
+        assert_eq!(fc.sm.st.data(), &[0, 2, 0, 0]);
+    }
+}
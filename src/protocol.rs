@@ -0,0 +1,116 @@
+use super::forth_compiler::ForthCompiler;
+use super::stack_machine::GasLimit;
+use std::cell::RefCell;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::rc::Rc;
+
+// Collects what a command prints so it can go in the command's result line
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Drives a ForthCompiler one command per line, answering each with exactly one result line so
+/// test harnesses and other languages can talk to it over pipes. The result lines are
+///
+/// `ok [STACK] "OUTPUT"` or `err CODE [STACK] "OUTPUT" ERROR`
+///
+/// where STACK is the number stack after the command, bottom first and space separated, OUTPUT
+/// is everything the command printed as an escaped string, CODE is the error's exit code and
+/// ERROR is the error written out in Rust's Debug form.
+pub struct LineProtocol {
+    fc: ForthCompiler,
+    gas_limit: GasLimit,
+    output: SharedOutput,
+}
+
+impl LineProtocol {
+    pub fn new(fc: ForthCompiler, gas_limit: GasLimit) -> LineProtocol {
+        let mut fc = fc;
+        let output = SharedOutput::default();
+        fc.sm.st.output = Box::new(output.clone());
+        LineProtocol {
+            fc,
+            gas_limit,
+            output,
+        }
+    }
+
+    /// Run one command and return its result line, without the newline
+    pub fn respond(&mut self, command: &str) -> String {
+        let result = self.fc.execute_string(command, self.gas_limit);
+
+        let stack = self
+            .fc
+            .sm
+            .st
+            .number_stack
+            .iter()
+            .map(|x| self.fc.sm.st.format_cell(*x))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let printed = String::from_utf8_lossy(&self.output.0.borrow()).into_owned();
+        self.output.0.borrow_mut().clear();
+
+        match result {
+            Ok(()) => format!("ok [{}] {:?}", stack, printed),
+            Err(e) => {
+                let message = format!("{:?}", e);
+                format!("err {} [{}] {:?} {}", i32::from(e), stack, printed, message)
+            }
+        }
+    }
+
+    /// Answer every line of the input until it runs out
+    pub fn serve(&mut self, input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            writeln!(output, "{}", self.respond(&line?))?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn compiler(&mut self) -> &mut ForthCompiler {
+        &mut self.fc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serve() {
+        let mut protocol = LineProtocol::new(ForthCompiler::new(), GasLimit::Limited(100));
+        let input = "1 2\n: Double DUP ADD ; Double 5 U.\nPOP POP POP\n\n";
+        let mut output = Vec::new();
+
+        protocol.serve(input.as_bytes(), &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "ok [1 2] \"\"\n\
+             ok [1 4] \"5 \"\n\
+             err 4 [] \"\" PopOfEmptyStack\n\
+             ok [] \"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_gas_limit() {
+        let mut protocol = LineProtocol::new(ForthCompiler::new(), GasLimit::Limited(2));
+
+        assert_eq!(protocol.respond("1 2 3"), "err 9 [1 2 3] \"\" RanOutOfGas");
+        assert_eq!(protocol.respond("POP"), "ok [1 2] \"\"");
+    }
+}
@@ -0,0 +1,170 @@
+use super::stack_machine::GasLimit;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+/// How long the gas_per_hour quota counts gas for before starting again
+pub const QUOTA_WINDOW_MILLIS: u64 = 60 * 60 * 1000;
+
+/// Limits on how much a ForthCompiler may do across all its runs, for servers running scripts
+/// for many users. A run is one execute_string or include_file, or one event handler or timer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quotas {
+    // Gas used by all the runs in each hour, by the compiler's clock
+    pub gas_per_hour: Option<u64>,
+    pub max_executions: Option<u64>,
+    // Bytes written to the output by all the runs
+    pub max_output_bytes: Option<u64>,
+}
+
+impl Quotas {
+    /// No limits, which is what a new ForthCompiler uses
+    pub fn unlimited() -> Quotas {
+        Quotas::default()
+    }
+}
+
+/// Which quota ran out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quota {
+    GasPerHour,
+    Executions,
+    OutputBytes,
+}
+
+/// What a ForthCompiler has used of its quotas so far, kept whether or not there are any limits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuotaUsage {
+    // When the current hour started, None before the first run
+    pub window_start: Option<u64>,
+    pub gas_this_hour: u64,
+    pub executions: u64,
+    pub output_bytes: u64,
+}
+
+impl QuotaUsage {
+    // Account for a run starting at now, giving the gas limit to run it with and whether that is
+    // less than asked for because of the hourly quota
+    pub(crate) fn start_run(
+        &mut self,
+        quotas: &Quotas,
+        now: u64,
+        gas_limit: GasLimit,
+    ) -> Result<(GasLimit, bool), Quota> {
+        if self
+            .window_start
+            .is_none_or(|start| now >= start + QUOTA_WINDOW_MILLIS)
+        {
+            self.window_start = Some(now);
+            self.gas_this_hour = 0;
+        }
+        if quotas.max_executions.is_some_and(|m| self.executions >= m) {
+            return Err(Quota::Executions);
+        }
+        if quotas
+            .max_output_bytes
+            .is_some_and(|m| self.output_bytes >= m)
+        {
+            return Err(Quota::OutputBytes);
+        }
+        self.executions += 1;
+
+        match quotas.gas_per_hour {
+            Some(quota) => {
+                let left = quota.saturating_sub(self.gas_this_hour);
+                if left == 0 {
+                    return Err(Quota::GasPerHour);
+                }
+                match gas_limit {
+                    GasLimit::Limited(gas) if gas <= left => Ok((gas_limit, false)),
+                    _ => Ok((GasLimit::Limited(left), true)),
+                }
+            }
+            None => Ok((gas_limit, false)),
+        }
+    }
+}
+
+// The error MeteredOutput gives once the output quota is used up, so it can be told apart from
+// the output's own errors
+#[derive(Debug)]
+pub(crate) struct OutputQuotaExceeded;
+
+impl fmt::Display for OutputQuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "output quota exceeded")
+    }
+}
+
+impl Error for OutputQuotaExceeded {}
+
+pub(crate) fn is_output_quota_error(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|e| e.downcast_ref::<OutputQuotaExceeded>().is_some())
+}
+
+// Stands in for the StackMachine's output during a run, counting what is written and refusing
+// anything past the limit. The counter and the real output are shared so the compiler can take
+// them back afterwards.
+pub(crate) struct MeteredOutput {
+    pub(crate) inner: Rc<RefCell<Box<dyn Write>>>,
+    pub(crate) written: Rc<Cell<u64>>,
+    pub(crate) limit: Option<u64>,
+}
+
+impl Write for MeteredOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = self
+            .limit
+            .map_or(buf.len() as u64, |l| l.saturating_sub(self.written.get()));
+        if room == 0 && !buf.is_empty() {
+            return Err(io::Error::other(OutputQuotaExceeded));
+        }
+        let buf = &buf[..buf.len().min(room as usize)];
+        let n = self.inner.borrow_mut().write(buf)?;
+        self.written.set(self.written.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_run() {
+        let quotas = Quotas {
+            gas_per_hour: Some(100),
+            max_executions: Some(3),
+            ..Quotas::unlimited()
+        };
+        let mut usage = QuotaUsage::default();
+
+        assert_eq!(
+            usage.start_run(&quotas, 5, GasLimit::Limited(10)),
+            Ok((GasLimit::Limited(10), false))
+        );
+        usage.gas_this_hour = 95;
+        assert_eq!(
+            usage.start_run(&quotas, 6, GasLimit::Unlimited),
+            Ok((GasLimit::Limited(5), true))
+        );
+        usage.gas_this_hour = 100;
+        // A new hour starts the gas count again, but not the executions
+        assert_eq!(
+            usage.start_run(&quotas, 5 + QUOTA_WINDOW_MILLIS, GasLimit::Limited(10)),
+            Ok((GasLimit::Limited(10), false))
+        );
+        assert_eq!(
+            usage.start_run(&quotas, 5 + QUOTA_WINDOW_MILLIS, GasLimit::Limited(10)),
+            Err(Quota::Executions)
+        );
+    }
+}
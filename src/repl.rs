@@ -0,0 +1,237 @@
+//! Meta commands for interactive sessions, lines such as `:words` or `:see Double` that talk to
+//! the REPL rather than being Forth. They are kept in a registry so hosts shipping their own REPL
+//! can add commands of their own alongside the standard ones.
+
+use super::error::ForthError;
+use super::forth_compiler::{ForthCompiler, ResetLevel};
+use super::stack_machine::GasLimit;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Runs a meta command given the compiler, the session's history and whatever followed the
+/// command's name on the line. Commands print to the compiler's output.
+pub type MetaCommandFn =
+    Box<dyn FnMut(&mut ForthCompiler, &mut Vec<String>, &str) -> Result<(), ForthError>>;
+
+struct MetaCommand {
+    help: String,
+    run: MetaCommandFn,
+}
+
+/// The meta commands a REPL knows, by name without the leading colon
+pub struct MetaCommands {
+    commands: BTreeMap<String, MetaCommand>,
+}
+
+impl Default for MetaCommands {
+    fn default() -> MetaCommands {
+        MetaCommands::new()
+    }
+}
+
+impl MetaCommands {
+    /// A registry with no commands in it
+    pub fn empty() -> MetaCommands {
+        MetaCommands {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// A registry with the standard commands, :help, :words, :see, :stack, :gas, :load, :reset,
+    /// :save-session and :load-session
+    pub fn new() -> MetaCommands {
+        let mut commands = MetaCommands::empty();
+        commands.add("words", "list every word the compiler knows", |fc, _, _| {
+            writeln!(fc.sm.st.output, "{}", fc.word_names().join(" "))?;
+            Ok(())
+        });
+        commands.add(
+            "see",
+            "NAME  show the opcodes a word compiles to",
+            |fc, _, name| {
+                let listing = fc
+                    .word_listing(name)
+                    .ok_or_else(|| ForthError::UnknownToken(name.to_owned()))?;
+                write!(fc.sm.st.output, "{}", listing)?;
+                Ok(())
+            },
+        );
+        commands.add("stack", "show the number stack", |fc, _, _| {
+            let stack = fc.format_stack();
+            write!(fc.sm.st.output, "{}", stack)?;
+            Ok(())
+        });
+        commands.add(
+            "gas",
+            "show the gas used by the last run and by the session",
+            |fc, _, _| {
+                let (last, total) = (fc.sm.st.gas_used(), fc.metrics().gas_used);
+                writeln!(fc.sm.st.output, "last run {} session {}", last, total)?;
+                Ok(())
+            },
+        );
+        commands.add("load", "FILE  run a source file", |fc, _, file| {
+            fc.include_file(file, GasLimit::Unlimited)
+        });
+        commands.add(
+            "reset",
+            "[stacks|everything]  clear the stacks, or the words since the prelude or everything",
+            |fc, history, level| {
+                let level = match level {
+                    "stacks" => ResetLevel::Stacks,
+                    "" => ResetLevel::UserWords,
+                    "everything" => ResetLevel::Everything,
+                    _ => return Err(ForthError::InvalidSyntax(format!(":reset {}", level))),
+                };
+                fc.reset(level)?;
+                // The history is what a saved session replays, so it goes along with the words
+                if level != ResetLevel::Stacks {
+                    history.clear();
+                }
+                Ok(())
+            },
+        );
+        commands.add(
+            "save-session",
+            "FILE  save the words, stack, data and history",
+            |fc, history, file| fc.save_session(file, history),
+        );
+        commands.add(
+            "load-session",
+            "FILE  pick up a session saved by :save-session",
+            |fc, history, file| {
+                *history = fc.load_session(file)?;
+                Ok(())
+            },
+        );
+        commands
+    }
+
+    /// Add a command, replacing any command of the same name. The help text starts with the
+    /// command's arguments, if it takes any, and is shown by :help.
+    pub fn add<F>(&mut self, name: &str, help: &str, run: F)
+    where
+        F: FnMut(&mut ForthCompiler, &mut Vec<String>, &str) -> Result<(), ForthError> + 'static,
+    {
+        self.commands.insert(
+            name.to_owned(),
+            MetaCommand {
+                help: help.to_owned(),
+                run: Box::new(run),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.commands.remove(name).is_some()
+    }
+
+    /// The names of the commands, sorted and without their colons
+    pub fn names(&self) -> Vec<&str> {
+        self.commands.keys().map(|n| n.as_str()).collect()
+    }
+
+    /// True when the line is a meta command rather than Forth. A colon followed by a space
+    /// starts a colon definition, so meta commands have their name straight after the colon.
+    pub fn is_meta_command(line: &str) -> bool {
+        let line = line.trim_start();
+        line.starts_with(':') && line[1..].starts_with(|c: char| !c.is_whitespace())
+    }
+
+    /// Run the line if it is a meta command, giving back None for lines that are Forth. :help
+    /// is always there and lists the commands.
+    pub fn dispatch(
+        &mut self,
+        fc: &mut ForthCompiler,
+        history: &mut Vec<String>,
+        line: &str,
+    ) -> Option<Result<(), ForthError>> {
+        if !MetaCommands::is_meta_command(line) {
+            return None;
+        }
+        let line = line.trim();
+        let (name, argument) = match line[1..].split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (&line[1..], ""),
+        };
+
+        if name == "help" && !self.commands.contains_key(name) {
+            return Some(self.help(fc));
+        }
+        Some(match self.commands.get_mut(name) {
+            Some(command) => (command.run)(fc, history, argument),
+            None => Err(ForthError::UnknownToken(line.to_owned())),
+        })
+    }
+
+    fn help(&self, fc: &mut ForthCompiler) -> Result<(), ForthError> {
+        for (name, command) in self.commands.iter() {
+            writeln!(fc.sm.st.output, ":{:<14} {}", name, command.help)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_meta_command() {
+        assert!(MetaCommands::is_meta_command(":words"));
+        assert!(MetaCommands::is_meta_command("  :see Double"));
+        assert!(!MetaCommands::is_meta_command(": Double DUP ADD ;"));
+        assert!(!MetaCommands::is_meta_command(":"));
+        assert!(!MetaCommands::is_meta_command("1 2 ADD"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_dispatch() {
+        use crate::testing::hermetic_compiler;
+
+        let (mut fc, _, _, file_system, output) = hermetic_compiler();
+        let mut commands = MetaCommands::new();
+        let mut history = vec!["1 2".to_owned()];
+        file_system.add_file("double.fs", ": Double DUP ADD ;");
+
+        assert!(commands.dispatch(&mut fc, &mut history, "1 2").is_none());
+        commands
+            .dispatch(&mut fc, &mut history, ":load double.fs")
+            .unwrap()
+            .unwrap();
+        commands
+            .dispatch(&mut fc, &mut history, ":see Double")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            output.contents(),
+            "Double:\n     0  DUP\n     1  ADD\n     2  RET\n"
+        );
+
+        match commands.dispatch(&mut fc, &mut history, ":frob") {
+            Some(Err(ForthError::UnknownToken(_))) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        // Hosts add their own commands, and can replace the standard ones
+        commands.add("history", "show the history", |fc, history, _| {
+            writeln!(fc.sm.st.output, "{}", history.join(" | "))?;
+            Ok(())
+        });
+        output.clear();
+        commands
+            .dispatch(&mut fc, &mut history, ":history")
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.contents(), "1 2\n");
+        assert!(commands.names().contains(&"history"));
+
+        commands
+            .dispatch(&mut fc, &mut history, ":reset")
+            .unwrap()
+            .unwrap();
+        assert!(!fc.knows_word("Double"));
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+/// What scripts running in a ForthCompiler are allowed to do
+#[derive(Debug, Clone, Default)]
+pub struct SandboxPolicy {
+    // When true a TRAP only reaches the host from inside words exposed to it with
+    // ForthCompiler::expose_trap, anywhere else it fails with TrapNotPermitted
+    pub guard_traps: bool,
+    // The hosts the network words may connect to, any host when None
+    pub network_hosts: Option<Vec<String>>,
+}
+
+impl SandboxPolicy {
+    /// A policy that allows everything, which is what a new ForthCompiler uses
+    pub fn unrestricted() -> SandboxPolicy {
+        SandboxPolicy::default()
+    }
+
+    /// A policy where scripts can only reach the host through exposed words, and can't use the
+    /// network
+    pub fn restricted() -> SandboxPolicy {
+        SandboxPolicy {
+            guard_traps: true,
+            network_hosts: Some(Vec::new()),
+        }
+    }
+
+    /// Let the network words connect to the host, as well as any already allowed
+    pub fn allow_host(mut self, host: &str) -> SandboxPolicy {
+        if let Some(hosts) = &mut self.network_hosts {
+            hosts.push(host.to_owned());
+        }
+        self
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        match &self.network_hosts {
+            Some(hosts) => hosts.iter().any(|h| h.eq_ignore_ascii_case(host)),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//! Interactive sessions saved to a file and loaded again later, so the words, stack and data
+//! built up at the REPL aren't lost when it exits. The file is text, one item per line, with
+//! opcodes written the way Debug writes them. With the compression feature a session can also
+//! be written deflated, behind a short header so loading can tell the two apart.
+//!
+//! Numbers are written in decimal and the data space is kept as whole cells, whose bytes the
+//! stack machine always lays out little endian, so a session saved on one host loads the same on
+//! any other whatever its byte order or pointer width.
+
+use super::artifact::WordInfo;
+use super::checksum::Checksum;
+use super::error::ForthError;
+use super::forth_compiler::{SourceLocation, EXTERN_STUB_LENGTH};
+use super::stack_machine::Opcode;
+use std::fmt;
+use std::io;
+
+// The first line of every session file, the number goes up if the format changes
+const SESSION_HEADER: &str = "rust_forth session 1";
+
+// What a compressed session starts with, the deflated text follows it
+const COMPRESSED_HEADER: &[u8] = b"rust_forth deflate\n";
+
+// Inflating stops here, so a corrupt or hostile file can't use up all the memory
+#[cfg(feature = "compression")]
+const MAX_INFLATED_SIZE: usize = 256 * 1024 * 1024;
+
+/// The state of a ForthCompiler worth keeping between runs of an interactive session. Big
+/// integers, mapped buffers, timers and pending events belong to the run that made them and
+/// aren't kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SavedSession {
+    // The dictionary's opcodes, without any immediate mode code after them
+    pub opcodes: Vec<Opcode>,
+    // Sorted by address
+    pub words: Vec<WordInfo>,
+    // The address of the word each ARRAY defined
+    pub arrays: Vec<(String, usize)>,
+    pub aliases: Vec<(String, String)>,
+    // Words whose definitions use CREATE
+    pub defining_words: Vec<String>,
+    // The stack effects TRAP: declared, as the cells each word takes and leaves
+    pub declared_effects: Vec<(String, (usize, usize))>,
+    pub event_handlers: Vec<(String, String)>,
+    // The stub each EXTERN word was given, left unbound so loading binds it to the host's word
+    pub externs: Vec<(String, usize)>,
+    // Where each LDI that pushes a data space address is, such as at the start of a word made by
+    // VARIABLE or CREATE
+    pub data_words: Vec<usize>,
+    // How much of the data space holds the text of the dictionary's string literals
+    pub string_space_bytes: usize,
+    // Where each ASSERT( the dictionary's words use came from
+    pub assertion_locations: Vec<SourceLocation>,
+    pub number_stack: Vec<i64>,
+    pub data: Vec<i64>,
+    // The lines the user typed, oldest first
+    pub history: Vec<String>,
+}
+
+impl SavedSession {
+    /// Read a session from the text Display writes
+    pub fn parse(text: &str) -> Result<SavedSession, ForthError> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, l)| l) != Some(SESSION_HEADER) {
+            return Err(invalid_session(1, "not a rust_forth session"));
+        }
+
+        let mut session = SavedSession::default();
+        for (n, line) in lines {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            match (kind, fields.as_slice()) {
+                ("opcode", [op]) => session
+                    .opcodes
+                    .push(parse_opcode(op).ok_or_else(|| invalid_session(n + 1, "bad opcode"))?),
+                ("word", [name, address, length]) => session.words.push(WordInfo {
+                    name: name.to_string(),
+                    address: parse_number(n, address)?,
+                    length: parse_number(n, length)?,
+                }),
+                ("array", [name, address]) => session
+                    .arrays
+                    .push((name.to_string(), parse_number(n, address)?)),
+                ("alias", [alias, word]) => {
+                    session.aliases.push((alias.to_string(), word.to_string()))
+                }
+                ("defining", [word]) => session.defining_words.push(word.to_string()),
+                ("effect", [word, inputs, outputs]) => session.declared_effects.push((
+                    word.to_string(),
+                    (parse_number(n, inputs)?, parse_number(n, outputs)?),
+                )),
+                ("event", [event, word]) => session
+                    .event_handlers
+                    .push((event.to_string(), word.to_string())),
+                ("extern", [name, address]) => session
+                    .externs
+                    .push((name.to_string(), parse_number(n, address)?)),
+                ("dataword", [address]) => session.data_words.push(parse_number(n, address)?),
+                ("strings", [bytes]) => session.string_space_bytes = parse_number(n, bytes)?,
+                ("assertion", [line, column]) => session.assertion_locations.push(SourceLocation {
+                    line: parse_number(n, line)?,
+                    column: parse_number(n, column)?,
+                }),
+                ("stack", cells) => {
+                    for cell in cells {
+                        session.number_stack.push(parse_number(n, cell)?);
+                    }
+                }
+                ("data", cells) => {
+                    for cell in cells {
+                        session.data.push(parse_number(n, cell)?);
+                    }
+                }
+                // History lines are kept as they were typed, spaces and all
+                ("history", _) => session.history.push(rest.to_owned()),
+                _ => return Err(invalid_session(n + 1, "unknown line")),
+            }
+        }
+
+        let end = session.opcodes.len();
+        // An end that overflows is outside the dictionary as well
+        let outside =
+            |address: usize, length: usize| address.checked_add(length).is_none_or(|e| e > end);
+        if session.words.iter().any(|w| outside(w.address, w.length))
+            || session.arrays.iter().any(|(_, address)| *address >= end)
+            || session
+                .externs
+                .iter()
+                .any(|(_, address)| outside(*address, EXTERN_STUB_LENGTH))
+            || session.data_words.iter().any(|address| *address >= end)
+        {
+            return Err(invalid_session(1, "a word is outside the dictionary"));
+        }
+        Ok(session)
+    }
+
+    /// Read a session from a file's contents, compressed or not
+    pub fn from_bytes(bytes: &[u8]) -> Result<SavedSession, ForthError> {
+        match bytes.strip_prefix(COMPRESSED_HEADER) {
+            Some(deflated) => SavedSession::parse(&inflate(deflated)?),
+            None => match std::str::from_utf8(bytes) {
+                Ok(text) => SavedSession::parse(text),
+                Err(_) => Err(invalid_session(1, "not a rust_forth session")),
+            },
+        }
+    }
+
+    /// The text Display writes, deflated, in the form from_bytes reads
+    #[cfg(feature = "compression")]
+    pub fn compressed(&self) -> Vec<u8> {
+        let mut bytes = COMPRESSED_HEADER.to_vec();
+        bytes.extend(miniz_oxide::deflate::compress_to_vec(
+            self.to_string().as_bytes(),
+            9,
+        ));
+        bytes
+    }
+}
+
+#[cfg(feature = "compression")]
+fn inflate(deflated: &[u8]) -> Result<String, ForthError> {
+    let bytes = miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, MAX_INFLATED_SIZE)
+        .map_err(|_| invalid_session(1, "the compressed session is corrupt"))?;
+    String::from_utf8(bytes).map_err(|_| invalid_session(1, "not a rust_forth session"))
+}
+
+#[cfg(not(feature = "compression"))]
+fn inflate(_deflated: &[u8]) -> Result<String, ForthError> {
+    Err(invalid_session(
+        1,
+        "the session is compressed, which needs the compression feature",
+    ))
+}
+
+/// Written in the form parse reads
+impl fmt::Display for SavedSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", SESSION_HEADER)?;
+        for op in self.opcodes.iter() {
+            writeln!(f, "opcode {:?}", op)?;
+        }
+        for w in self.words.iter() {
+            writeln!(f, "word {} {} {}", w.name, w.address, w.length)?;
+        }
+        for (name, address) in self.arrays.iter() {
+            writeln!(f, "array {} {}", name, address)?;
+        }
+        for (alias, word) in self.aliases.iter() {
+            writeln!(f, "alias {} {}", alias, word)?;
+        }
+        for word in self.defining_words.iter() {
+            writeln!(f, "defining {}", word)?;
+        }
+        for (word, (inputs, outputs)) in self.declared_effects.iter() {
+            writeln!(f, "effect {} {} {}", word, inputs, outputs)?;
+        }
+        for (event, word) in self.event_handlers.iter() {
+            writeln!(f, "event {} {}", event, word)?;
+        }
+        for (name, address) in self.externs.iter() {
+            writeln!(f, "extern {} {}", name, address)?;
+        }
+        for address in self.data_words.iter() {
+            writeln!(f, "dataword {}", address)?;
+        }
+        if self.string_space_bytes > 0 {
+            writeln!(f, "strings {}", self.string_space_bytes)?;
+        }
+        for location in self.assertion_locations.iter() {
+            writeln!(f, "assertion {} {}", location.line, location.column)?;
+        }
+        write_cells(f, "stack", &self.number_stack)?;
+        // Long data spaces are split over several lines
+        for chunk in self.data.chunks(16) {
+            write_cells(f, "data", chunk)?;
+        }
+        for line in self.history.iter() {
+            writeln!(f, "history {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_cells(f: &mut fmt::Formatter, kind: &str, cells: &[i64]) -> fmt::Result {
+    write!(f, "{}", kind)?;
+    for cell in cells {
+        write!(f, " {}", cell)?;
+    }
+    writeln!(f)
+}
+
+fn invalid_session(line: usize, message: &str) -> ForthError {
+    ForthError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {} of the session: {}", line, message),
+    ))
+}
+
+fn parse_number<T: std::str::FromStr>(n: usize, text: &str) -> Result<T, ForthError> {
+    text.parse()
+        .map_err(|_| invalid_session(n + 1, "bad number"))
+}
+
+// Read an opcode written in its Debug form, such as ADD, LDI(-5) or CHECKSUM(Crc16)
+fn parse_opcode(text: &str) -> Option<Opcode> {
+    let (name, argument) = match text.split_once('(') {
+        Some((name, rest)) => (name, Some(rest.strip_suffix(')')?)),
+        None => (text, None),
+    };
+
+    let op = match (name, argument) {
+        ("LDI", Some(x)) => Opcode::LDI(x.parse().ok()?),
+        ("SYSCALL", Some(x)) => Opcode::SYSCALL(x.parse().ok()?),
+        ("FETCHLE", Some(x)) => Opcode::FETCHLE(x.parse().ok()?),
+        ("FETCHBE", Some(x)) => Opcode::FETCHBE(x.parse().ok()?),
+        ("STORELE", Some(x)) => Opcode::STORELE(x.parse().ok()?),
+        ("STOREBE", Some(x)) => Opcode::STOREBE(x.parse().ok()?),
+        ("INDEX", Some(x)) => Opcode::INDEX(x.parse().ok()?),
+        ("PICK", Some(x)) => Opcode::PICK(x.parse().ok()?),
+        ("ROLL", Some(x)) => Opcode::ROLL(x.parse().ok()?),
+        ("EXT", Some(x)) => Opcode::EXT(x.parse().ok()?),
+        ("CHECKSUM", Some(x)) => Opcode::CHECKSUM(match x {
+            "Crc16" => Checksum::Crc16,
+            "Crc32" => Checksum::Crc32,
+            "Sum8" => Checksum::Sum8,
+            "Xor8" => Checksum::Xor8,
+            _ => return None,
+        }),
+        (_, Some(_)) => return None,
+        ("JMP", None) => Opcode::JMP,
+        ("JR", None) => Opcode::JR,
+        ("JRZ", None) => Opcode::JRZ,
+        ("JRNZ", None) => Opcode::JRNZ,
+        ("CALL", None) => Opcode::CALL,
+        ("CMPZ", None) => Opcode::CMPZ,
+        ("CMPNZ", None) => Opcode::CMPNZ,
+        ("POP", None) => Opcode::POP,
+        ("SWAP", None) => Opcode::SWAP,
+        ("OVER", None) => Opcode::OVER,
+        ("RET", None) => Opcode::RET,
+        ("ADD", None) => Opcode::ADD,
+        ("SUB", None) => Opcode::SUB,
+        ("MUL", None) => Opcode::MUL,
+        ("DIV", None) => Opcode::DIV,
+        ("NEGATE", None) => Opcode::NEGATE,
+        ("ABS", None) => Opcode::ABS,
+        ("MIN", None) => Opcode::MIN,
+        ("MAX", None) => Opcode::MAX,
+        ("NOT", None) => Opcode::NOT,
+        ("AND", None) => Opcode::AND,
+        ("OR", None) => Opcode::OR,
+        ("XOR", None) => Opcode::XOR,
+        ("LSHIFT", None) => Opcode::LSHIFT,
+        ("RSHIFT", None) => Opcode::RSHIFT,
+        ("DUP", None) => Opcode::DUP,
+        ("TRAP", None) => Opcode::TRAP,
+        ("NOP", None) => Opcode::NOP,
+        ("ASSERT", None) => Opcode::ASSERT,
+        ("UDOT", None) => Opcode::UDOT,
+        ("DOT", None) => Opcode::DOT,
+        ("DOTS", None) => Opcode::DOTS,
+        ("TYPE", None) => Opcode::TYPE,
+        ("ULT", None) => Opcode::ULT,
+        ("UGT", None) => Opcode::UGT,
+        ("EQ", None) => Opcode::EQ,
+        ("LT", None) => Opcode::LT,
+        ("GT", None) => Opcode::GT,
+        ("UMMUL", None) => Opcode::UMMUL,
+        ("SMREM", None) => Opcode::SMREM,
+        ("FMMOD", None) => Opcode::FMMOD,
+        ("DIVMOD", None) => Opcode::DIVMOD,
+        ("DOTVS", None) => Opcode::DOTVS,
+        ("SQRT", None) => Opcode::SQRT,
+        ("GCD", None) => Opcode::GCD,
+        ("LCM", None) => Opcode::LCM,
+        ("POW", None) => Opcode::POW,
+        ("LOG2", None) => Opcode::LOG2,
+        ("ADDSAT", None) => Opcode::ADDSAT,
+        ("MULSAT", None) => Opcode::MULSAT,
+        ("ADDOV", None) => Opcode::ADDOV,
+        ("ARRAYFETCH", None) => Opcode::ARRAYFETCH,
+        ("ARRAYSTORE", None) => Opcode::ARRAYSTORE,
+        ("FETCH", None) => Opcode::FETCH,
+        ("STORE", None) => Opcode::STORE,
+        ("CFETCH", None) => Opcode::CFETCH,
+        ("CSTORE", None) => Opcode::CSTORE,
+        ("CMOVE", None) => Opcode::CMOVE,
+        ("DO", None) => Opcode::DO,
+        ("QDO", None) => Opcode::QDO,
+        ("PLUSLOOP", None) => Opcode::PLUSLOOP,
+        ("UNLOOP", None) => Opcode::UNLOOP,
+        ("HERE", None) => Opcode::HERE,
+        ("ALLOT", None) => Opcode::ALLOT,
+        ("RDEPTH", None) => Opcode::RDEPTH,
+        ("GASUSED", None) => Opcode::GASUSED,
+        ("GASLEFT", None) => Opcode::GASLEFT,
+        ("YIELD", None) => Opcode::YIELD,
+        ("REQUEST", None) => Opcode::REQUEST,
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forth_compiler::ForthCompiler;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_round_trip() {
+        let mut fc = ForthCompiler::new();
+        fc.add_alias("+", "ADD");
+        fc.execute_string(
+            ": Double DUP + ; 3 ARRAY Table 7 1 Table []! \
+             : Sum 0 3 0 DO I Table []@ + LOOP ; : Marker CREATE ; EXTERN DUP 5 Double",
+            GasLimit::Limited(500),
+        )
+        .unwrap();
+        let history = vec!["5 Double".to_owned(), " two  spaces ".to_owned()];
+
+        let text = fc.saved_session(&history).to_string();
+        let session = SavedSession::parse(&text).unwrap();
+        assert_eq!(session.to_string(), text);
+        assert_eq!(session.history, history);
+
+        let mut restored = ForthCompiler::new();
+        assert_eq!(restored.restore_session(session).unwrap(), history);
+        assert!(restored.is_defining_word("Marker"));
+        restored
+            .execute_string("Sum 2 +", GasLimit::Limited(500))
+            .unwrap();
+        assert_eq!(&restored.sm.st.number_stack, &vec![10_i64, 9]);
+    }
+
+    #[test]
+    fn test_byte_order() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            "2 ARRAY Bytes 1 Bytes C! 2 Bytes INC C! 305419896 Bytes 8 ADD BE32!",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+
+        // The file holds the cells as numbers, not the host's bytes for them
+        let text = fc.saved_session(&[]).to_string();
+        assert!(text.contains("data 2 513 2018915346\n"));
+
+        let mut restored = ForthCompiler::new();
+        restored
+            .restore_session(SavedSession::parse(&text).unwrap())
+            .unwrap();
+        restored
+            .execute_string(
+                "Bytes C@ Bytes INC C@ Bytes 8 ADD BE32@",
+                GasLimit::Limited(100),
+            )
+            .unwrap();
+        assert_eq!(&restored.sm.st.number_stack, &vec![1_i64, 2, 0x12345678]);
+    }
+
+    #[test]
+    fn test_parse_opcodes() {
+        for op in [
+            Opcode::LDI(-5),
+            Opcode::SYSCALL(12),
+            Opcode::STOREBE(4),
+            Opcode::INDEX(1),
+            Opcode::ROLL(3),
+            Opcode::EXT(3),
+            Opcode::CHECKSUM(Checksum::Sum8),
+            Opcode::PLUSLOOP,
+        ] {
+            assert_eq!(parse_opcode(&format!("{:?}", op)), Some(op));
+        }
+        assert_eq!(parse_opcode("LDI(x)"), None);
+        assert_eq!(parse_opcode("ADD(1)"), None);
+        assert_eq!(parse_opcode("FROB"), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed() {
+        let mut fc = ForthCompiler::new();
+        fc.execute_string(
+            ": Double DUP ADD ; 4 ARRAY Table 5 Double",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        let session = fc.saved_session(&["5 Double".to_owned()]);
+
+        let bytes = session.compressed();
+        assert!(bytes.starts_with(COMPRESSED_HEADER));
+        assert_eq!(SavedSession::from_bytes(&bytes).unwrap(), session);
+        assert_eq!(
+            SavedSession::from_bytes(session.to_string().as_bytes()).unwrap(),
+            session
+        );
+
+        let corrupt = &bytes[..bytes.len() / 2];
+        match SavedSession::from_bytes(corrupt) {
+            Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_invalid_sessions() {
+        for text in [
+            "",
+            "rust_forth session 1\nopcode FROB\n",
+            "rust_forth session 1\nopcode RET\nword Foo 0 2\n",
+            "rust_forth session 1\nopcode RET\nword X 18446744073709551615 1\n",
+            "rust_forth session 1\nopcode RET\nextern X 18446744073709551615\n",
+            "rust_forth session 1\nstack 1 x\n",
+        ] {
+            match SavedSession::parse(text) {
+                Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+                r => panic!("Incorrect result: {:?}", r),
+            }
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use super::error::ForthError;
+use super::forth_compiler::{tokenize_string, ForthCompiler, Token};
+use std::collections::HashMap;
+
+/// What happened to a definition when its chunk was updated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefinitionChange {
+    // The definition is the same as last time, nothing was compiled
+    Unchanged,
+    // The word wasn't in the dictionary before
+    Added,
+    // The new body was written over the old one, so existing callers use it too
+    Patched,
+    // The new body didn't fit where the old one was, so a new version went on the end of the
+    // dictionary, existing callers keep the old version
+    Appended,
+}
+
+// A definition as it was last compiled from a chunk
+#[derive(Debug, Clone)]
+pub(crate) struct Definition {
+    name: String,
+    // Compared to decide whether the definition changed, so layout changes don't count
+    tokens: Vec<Token>,
+}
+
+/// Compiles source that is split into chunks, such as the files or cells open in an editor, and
+/// keeps track of which definitions came from which chunk so an update only recompiles the
+/// definitions that changed. Only colon definitions are compiled, anything outside them is
+/// ignored.
+pub struct Session {
+    fc: ForthCompiler,
+    chunks: HashMap<String, Vec<Definition>>,
+}
+
+impl Session {
+    pub fn new(fc: ForthCompiler) -> Session {
+        Session {
+            fc,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn compiler(&self) -> &ForthCompiler {
+        &self.fc
+    }
+
+    pub fn compiler_mut(&mut self) -> &mut ForthCompiler {
+        &mut self.fc
+    }
+
+    /// Give the session the latest source of a chunk, compiling the definitions that changed
+    /// since the last update. Returns every definition in the chunk with what happened to it.
+    pub fn update_chunk(
+        &mut self,
+        chunk: &str,
+        source: &str,
+    ) -> Result<Vec<DefinitionUpdate>, ForthError> {
+        let previous = self.chunks.get(chunk).map(|d| d.as_slice()).unwrap_or(&[]);
+        let (compiled, changes) = update_definitions(&mut self.fc, previous, source)?;
+        self.chunks.insert(chunk.to_owned(), compiled);
+
+        Ok(changes)
+    }
+
+    /// The words defined by a chunk, in the order they appear in it
+    pub fn chunk_definitions(&self, chunk: &str) -> Vec<&str> {
+        self.chunks
+            .get(chunk)
+            .map(|d| d.iter().map(|d| d.name.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The chunk a word was last defined in
+    pub fn chunk_of(&self, word: &str) -> Option<&str> {
+        self.chunks
+            .iter()
+            .find(|(_, definitions)| definitions.iter().any(|d| d.name == word))
+            .map(|(chunk, _)| chunk.as_str())
+    }
+}
+
+/// A word from an updated chunk or file, and what happened to it
+pub type DefinitionUpdate = (String, DefinitionChange);
+
+// Compile the colon definitions in the source that aren't the same as in previous, patching
+// them in place where they fit. Gives the definitions as they now are, and what happened to each.
+pub(crate) fn update_definitions(
+    fc: &mut ForthCompiler,
+    previous: &[Definition],
+    source: &str,
+) -> Result<(Vec<Definition>, Vec<DefinitionUpdate>), ForthError> {
+    let tokens = tokenize_string(source)?;
+
+    // Pick out the source text and tokens of each definition
+    let mut definitions = Vec::new();
+    let mut start = None;
+    for (i, t) in tokens.iter().enumerate() {
+        match (&t.token, start) {
+            (Token::Colon(_), None) => start = Some(i),
+            (Token::SemiColon, Some(s)) => {
+                definitions.push(&tokens[s..=i]);
+                start = None;
+            }
+            (Token::Colon(_), Some(_)) => {
+                return Err(ForthError::InvalidSyntax(
+                    "Second colon before semicolon".to_string(),
+                ))
+            }
+            (Token::SemiColon, None) => {
+                return Err(ForthError::InvalidSyntax(
+                    "Semicolon before colon".to_string(),
+                ))
+            }
+            _ => (),
+        }
+    }
+    if start.is_some() {
+        return Err(ForthError::MissingSemicolonAfterColon);
+    }
+
+    let mut compiled = Vec::new();
+    let mut changes = Vec::new();
+    for d in definitions {
+        let name = match &d[0].token {
+            Token::Colon(name) => name.clone(),
+            _ => unreachable!(),
+        };
+        let tokens: Vec<Token> = d.iter().map(|t| t.token.clone()).collect();
+        let text = &source[d[0].span.start..d[d.len() - 1].span.end];
+
+        let unchanged = previous
+            .iter()
+            .any(|p| p.name == name && p.tokens == tokens);
+        let change = if unchanged {
+            DefinitionChange::Unchanged
+        } else if !fc.knows_word(&name) {
+            fc.compile_string(text)?;
+            DefinitionChange::Added
+        } else if fc.patch_word(text)? {
+            DefinitionChange::Patched
+        } else {
+            fc.compile_string(text)?;
+            DefinitionChange::Appended
+        };
+
+        changes.push((name.clone(), change));
+        compiled.push(Definition { name, tokens });
+    }
+
+    Ok((compiled, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_update_chunk() {
+        let mut session = Session::new(ForthCompiler::new());
+
+        assert_eq!(
+            session
+                .update_chunk("a", ": Double DUP ADD ;\n: Quad Double Double ;")
+                .unwrap(),
+            vec![
+                ("Double".to_owned(), DefinitionChange::Added),
+                ("Quad".to_owned(), DefinitionChange::Added)
+            ]
+        );
+        let opcodes = session.compiler().sm.st.opcodes.len();
+
+        // Same code laid out differently, and a smaller Double
+        assert_eq!(
+            session
+                .update_chunk("a", ": Double 2 MUL ;\n\n: Quad   Double Double ;")
+                .unwrap(),
+            vec![
+                ("Double".to_owned(), DefinitionChange::Patched),
+                ("Quad".to_owned(), DefinitionChange::Unchanged)
+            ]
+        );
+        // Nothing was added to the dictionary, and Quad uses the patched Double
+        assert_eq!(session.compiler().sm.st.opcodes.len(), opcodes);
+        session
+            .compiler_mut()
+            .execute_string("3 Quad", GasLimit::Limited(100))
+            .unwrap();
+        assert_eq!(&session.compiler().sm.st.number_stack, &vec![12_i64]);
+
+        // A Double too big to patch in goes on the end
+        assert_eq!(
+            session
+                .update_chunk("a", ": Double 1 2 POP POP 2 MUL ;\n: Quad Double Double ;")
+                .unwrap(),
+            vec![
+                ("Double".to_owned(), DefinitionChange::Appended),
+                ("Quad".to_owned(), DefinitionChange::Unchanged)
+            ]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_reload_file() {
+        use crate::testing::MemoryFileSystem;
+
+        let file_system = MemoryFileSystem::new();
+        let mut fc = ForthCompiler::new();
+        fc.set_file_system(Box::new(file_system.clone()));
+        file_system.add_file("app.fs", ": Double DUP ADD ; : Quad Double Double ; 5");
+        fc.include_file("app.fs", GasLimit::Limited(100)).unwrap();
+        fc.execute_string(": Main 3 Quad ;", GasLimit::Limited(100))
+            .unwrap();
+
+        // Main keeps working with the new Double, and the 5 isn't pushed again
+        file_system.add_file("app.fs", ": Double 3 MUL ; : Quad Double Double ; 5");
+        assert_eq!(
+            fc.reload_file("app.fs").unwrap(),
+            vec![
+                ("Double".to_owned(), DefinitionChange::Patched),
+                ("Quad".to_owned(), DefinitionChange::Patched)
+            ]
+        );
+        assert_eq!(
+            fc.reload_file("app.fs").unwrap(),
+            vec![
+                ("Double".to_owned(), DefinitionChange::Unchanged),
+                ("Quad".to_owned(), DefinitionChange::Unchanged)
+            ]
+        );
+        fc.execute_string("Main", GasLimit::Limited(100)).unwrap();
+        assert_eq!(&fc.sm.st.number_stack, &vec![5_i64, 27]);
+
+        file_system.add_file("app.fs", ": Double 3 MUL");
+        match fc.reload_file("app.fs") {
+            Err(ForthError::MissingSemicolonAfterColon) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut session = Session::new(ForthCompiler::new());
+
+        session
+            .update_chunk("a", ": Double DUP ADD ; : Triple DUP DUP ADD ADD ;")
+            .unwrap();
+        session
+            .update_chunk("b", "1 : Quad Double Double ; 2")
+            .unwrap();
+
+        assert_eq!(session.chunk_definitions("a"), vec!["Double", "Triple"]);
+        assert_eq!(session.chunk_definitions("b"), vec!["Quad"]);
+        assert_eq!(session.chunk_of("Quad"), Some("b"));
+        assert_eq!(session.chunk_of("Missing"), None);
+        // Code outside definitions isn't run
+        assert!(session.compiler().sm.st.number_stack.is_empty());
+
+        match session.update_chunk("c", ": Broken 1 2") {
+            Err(ForthError::MissingSemicolonAfterColon) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
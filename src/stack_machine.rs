@@ -1,16 +1,282 @@
+use super::bignum::BigInt;
+use super::checksum::Checksum;
+use std::cell::{Ref, RefCell, RefMut};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GasLimit {
     Unlimited,
     Limited(u64),
 }
 
+/// What ADD, SUB, MUL and DIV do when the result doesn't fit in a cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticMode {
+    /// Results wrap around, two's complement style
+    Wrapping,
+    /// Results that don't fit are kept as big integers on a heap, with the stack holding a
+    /// handle to them. Handles live in the range starting at BIG_NUMBER_HANDLE_BASE, so in this
+    /// mode ordinary results that land in that range are promoted as well.
+    Promoting,
+}
+
+/// Which cells the opcodes that test a flag (JRZ, JRNZ, CMPZ, CMPNZ and ASSERT, so IF as well)
+/// count as true
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truthiness {
+    /// Any cell but 0 is true, as it always has been
+    NonZero,
+    /// Only -1, the all bits set flag the comparison words leave, is true. Anything else is
+    /// false, so a number left on the stack by mistake isn't taken for a flag.
+    AllBitsSet,
+}
+
+impl Truthiness {
+    pub fn is_true(&self, x: i64) -> bool {
+        match self {
+            Truthiness::NonZero => x != 0,
+            Truthiness::AllBitsSet => x == -1,
+        }
+    }
+}
+
+/// Which way DIV, /MOD and MOD round a quotient that isn't whole. SM/REM and FM/MOD always round
+/// their own way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DivisionMode {
+    /// Towards zero, so the remainder has the sign of the dividend, as Rust's division does
+    Symmetric,
+    /// Towards negative infinity, so the remainder has the sign of the divisor
+    Floored,
+}
+
+impl DivisionMode {
+    /// The remainder and quotient of n / d, or None when d is 0. i64::MIN / -1 wraps around.
+    pub fn divide(&self, n: i64, d: i64) -> Option<(i64, i64)> {
+        if d == 0 {
+            return None;
+        }
+        let (quotient, remainder) = (n.wrapping_div(d), n.wrapping_rem(d));
+        if *self == DivisionMode::Floored && remainder != 0 && (remainder < 0) != (d < 0) {
+            Some((remainder + d, quotient - 1))
+        } else {
+            Some((remainder, quotient))
+        }
+    }
+}
+
+/// Cells from here up to BIG_NUMBER_HANDLE_BASE + MAX_BIG_NUMBERS are handles to big integers
+/// when running in ArithmeticMode::Promoting
+pub const BIG_NUMBER_HANDLE_BASE: i64 = i64::MIN;
+pub const MAX_BIG_NUMBERS: usize = 1 << 20;
+/// The most 32 bit digits POW makes a big integer with, so a small program can't ask for one
+/// too big to hold
+pub const MAX_BIG_NUMBER_DIGITS: usize = 1 << 12;
+
+/// How many bytes a cell takes up, data space addresses count bytes
+pub const CELL_SIZE: i64 = 8;
+/// The most cells the data space can hold
+pub const MAX_DATA_CELLS: usize = 1 << 20;
+/// Buffers the host maps in start at this address, each in its own MAPPED_BUFFER_SPAN bytes
+pub const MAPPED_BUFFER_BASE: i64 = 1 << 48;
+pub const MAPPED_BUFFER_SPAN: i64 = 1 << 32;
+pub const MAX_MAPPED_BUFFERS: usize = 1 << 14;
+
+/// Cells shared between the host and running programs. Clones refer to the same cells, so the
+/// host keeps one and maps another into the data space with StackMachineState::map_buffer.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<i64>>>);
+
+impl SharedBuffer {
+    /// A buffer of cells set to 0
+    pub fn new(cells: usize) -> SharedBuffer {
+        SharedBuffer::from_vec(vec![0; cells])
+    }
+
+    pub fn from_vec(cells: Vec<i64>) -> SharedBuffer {
+        SharedBuffer(Rc::new(RefCell::new(cells)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn borrow(&self) -> Ref<'_, Vec<i64>> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, Vec<i64>> {
+        self.0.borrow_mut()
+    }
+}
+
+/// What a program that ran REQUEST wants the host to do, the meaning of the code and arguments
+/// is up to the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostRequest {
+    pub code: i64,
+    // In the order they were pushed
+    pub arguments: Vec<i64>,
+}
+
+// Where the cell at an address is kept
+#[derive(Debug, Clone, Copy)]
+enum CellLocation {
+    Data(usize),
+    // The mapped buffer and the cell in it
+    Mapped(usize, usize),
+}
+
+/// Why the StackMachine stopped running
+#[derive(Debug, PartialEq)]
+pub enum ExecutionStatus {
+    /// The outermost RET was reached
+    Finished,
+    /// A SYSCALL opcode asked the host for a service, call resume() to carry on after it
+    SystemCall(u16),
+    /// A YIELD opcode gave control back to the host, call resume() or resume_yielded() to carry
+    /// on after it
+    Suspended,
+}
+
+/// Square root rounded down, None for negative numbers
+pub(crate) fn isqrt(n: i64) -> Option<i64> {
+    if n < 0 {
+        return None;
+    }
+    // The float answer is close, fix up the rounding
+    let mut r = (n as f64).sqrt() as i64;
+    while r.checked_mul(r).is_none_or(|s| s > n) {
+        r -= 1;
+    }
+    while (r + 1).checked_mul(r + 1).is_some_and(|s| s <= n) {
+        r += 1;
+    }
+    Some(r)
+}
+
+/// Greatest common divisor, never negative. None when the answer is 2^63, which doesn't fit in a
+/// cell.
+pub(crate) fn gcd(a: i64, b: i64) -> Option<i64> {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    i64::try_from(a).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArithmeticOperation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
 #[derive(Debug)]
 pub enum StackMachineError {
     UnkownError,
     NumberStackUnderflow,
     UnhandledTrap,
     RanOutOfGas,
+    AssertionFailed(i64),
+    Io(std::io::Error),
+    DivisionByZero,
+    TooManyBigNumbers,
+    TrapNotPermitted(i64),
+    IntegrityViolation(Box<IntegrityViolation>),
+    // A math word was given a number it has no answer for, such as the square root of -1
+    OutOfDomain(i64),
+    // A data space access outside what has been reserved, or not on a cell boundary
+    InvalidAddress(i64),
+    DataSpaceFull,
+    IndexOutOfBounds(i64),
+    // The sandbox policy doesn't let scripts reach this host
+    HostNotPermitted(String),
+    // The machine's state grew past StackMachine::memory_limit, this many bytes
+    MemoryLimitExceeded(usize),
+    // A loop word with no loop running
+    LoopStackUnderflow,
+    // A jump or call to somewhere outside opcode memory
+    InvalidCodeAddress(i64),
+    // An EXT opcode with no handler registered for it
+    UnknownExtOpcode(u16),
+}
+
+/// Bytes of the host's memory a StackMachine's state is using, counting what is in use rather
+/// than what has been reserved for it to grow into
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemoryUsage {
+    // The number and return stacks
+    pub stack_bytes: usize,
+    pub data_space_bytes: usize,
+    // Numbers that outgrew a cell in ArithmeticMode::Promoting
+    pub big_number_bytes: usize,
+    // Opcodes, for the words and any immediate mode code
+    pub dictionary_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.stack_bytes + self.data_space_bytes + self.big_number_bytes + self.dictionary_bytes
+    }
+}
+
+/// The limits integrity checking holds the StackMachine to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntegrityChecks {
+    pub max_number_stack: usize,
+    pub max_return_stack: usize,
+}
+
+impl Default for IntegrityChecks {
+    fn default() -> Self {
+        IntegrityChecks {
+            max_number_stack: 1024,
+            max_return_stack: 256,
+        }
+    }
+}
+
+/// The first broken invariant integrity checking found, with the machine as it was just after
+/// the opcode that broke it
+#[derive(Debug, Clone)]
+pub struct IntegrityViolation {
+    pub description: String,
+    // Where the opcode that broke the invariant is
+    pub pc: usize,
+    pub opcode: Opcode,
+    // The word the opcode is in, the StackMachine doesn't know about words so the compiler fills
+    // this in
+    pub word: Option<String>,
+    pub number_stack: Vec<i64>,
+    pub return_stack: Vec<usize>,
+}
+
+impl fmt::Display for IntegrityViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "integrity violation: {}", self.description)?;
+        write!(f, "  after {:?} at {}", self.opcode, self.pc)?;
+        match &self.word {
+            Some(word) => writeln!(f, " in {}", word)?,
+            None => writeln!(f)?,
+        }
+        writeln!(f, "  number stack: {:?}", self.number_stack)?;
+        writeln!(f, "  return stack: {:?}", self.return_stack)
+    }
 }
 
 pub enum TrapHandled {
@@ -27,9 +293,13 @@ pub trait HandleTrap {
     ) -> Result<TrapHandled, StackMachineError>;
 }
 
+// The closure type that a TrapHandler runs when its trap is hit
+type TrapFunction<'a> =
+    dyn Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a;
+
 pub struct TrapHandler<'a> {
     handled_trap: i64,
-    to_run: Box<dyn Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a>,
+    to_run: Box<TrapFunction<'a>>,
 }
 
 impl<'a> TrapHandler<'a> {
@@ -38,7 +308,7 @@ impl<'a> TrapHandler<'a> {
         C: Fn(i64, &mut StackMachineState) -> Result<TrapHandled, StackMachineError> + 'a,
     {
         TrapHandler {
-            handled_trap: handled_trap,
+            handled_trap,
             to_run: Box::new(f),
         }
     }
@@ -57,7 +327,23 @@ impl<'a> HandleTrap for TrapHandler<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Runs the host's own opcodes, the EXT opcodes registered with it in
+/// StackMachine::ext_opcodes. Unlike a TRAP there is no id to pop and no chain of handlers to
+/// ask, so a primitive costs about as much as a built in opcode.
+pub trait ExtOpcodeHandler {
+    fn execute(&mut self, id: u16, st: &mut StackMachineState) -> Result<(), StackMachineError>;
+}
+
+impl<F> ExtOpcodeHandler for F
+where
+    F: FnMut(u16, &mut StackMachineState) -> Result<(), StackMachineError>,
+{
+    fn execute(&mut self, id: u16, st: &mut StackMachineState) -> Result<(), StackMachineError> {
+        self(id, st)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Opcode {
     JMP,
     JR,
@@ -69,23 +355,197 @@ pub enum Opcode {
     LDI(i64),
     POP,
     SWAP,
+    // ( a b -- a b a ) copies the second cell to the top
+    OVER,
+    // Copy or move the cell this far below the top to the top, PICK(1) is OVER and ROLL(1) SWAP
+    PICK(u8),
+    ROLL(u8),
     RET,
     ADD,
     SUB,
     MUL,
     DIV,
+    // ( n -- -n ) and ( n -- |n| ), following the arithmetic mode, so in Wrapping mode both leave
+    // i64::MIN as it is
+    NEGATE,
+    ABS,
+    // ( a b -- x ) the smaller or larger of two signed cells
+    MIN,
+    MAX,
     NOT,
+    AND,
+    OR,
+    XOR,
+    // ( x n -- x' ) logical shifts by n bits, 64 or more shifts every bit out leaving 0 and a
+    // negative n is out of the domain
+    LSHIFT,
+    RSHIFT,
     DUP,
     TRAP,
     NOP,
+    ASSERT,
+    UDOT,
+    // ( x -- ) print a signed cell and a space
+    DOT,
+    // ( -- ) print the depth of the number stack in angle brackets then each cell, leaving the
+    // stack as it is
+    DOTS,
+    // ( addr len -- ) print len bytes of the data space starting at addr
+    TYPE,
+    ULT,
+    UGT,
+    // ( a b -- flag ) signed comparisons, -1 when true and 0 when false
+    EQ,
+    LT,
+    GT,
+    UMMUL,
+    // ( n d -- rem quot ) division rounding towards zero, floored division and division rounding
+    // the way StackMachineState::division_mode says
+    SMREM,
+    FMMOD,
+    DIVMOD,
+    SYSCALL(u16),
+    DOTVS,
+    SQRT,
+    GCD,
+    LCM,
+    POW,
+    LOG2,
+    ADDSAT,
+    MULSAT,
+    ADDOV,
+    ARRAYFETCH,
+    ARRAYSTORE,
+    // ( address -- x ) and ( x address -- ) on a whole cell, the address must be cell aligned
+    FETCH,
+    STORE,
+    CFETCH,
+    CSTORE,
+    CMOVE,
+    // Fetch and store integers of the given number of bytes, little or big endian
+    FETCHLE(u8),
+    FETCHBE(u8),
+    STORELE(u8),
+    STOREBE(u8),
+    // ( address length -- checksum ) over a range of bytes
+    CHECKSUM(Checksum),
+    // ( limit start -- ) starts a counted loop
+    DO,
+    // ( limit start -- flag ) like DO, but true without starting the loop when limit and start
+    // are equal
+    QDO,
+    // ( n -- flag ) adds n to the innermost loop's index, true and the loop is finished once
+    // the index crosses the boundary between limit - 1 and limit
+    PLUSLOOP,
+    // ( -- index ) the index of a loop, 0 for the innermost
+    INDEX(u8),
+    // Drops the innermost loop, for leaving it early
+    UNLOOP,
+    // An opcode the host provides, run by the ExtOpcodeHandler registered for the id
+    EXT(u16),
+    // ( -- address ) where the next cell reserved in the data space will be
+    HERE,
+    // ( n -- ) reserves n bytes at the end of the data space, rounded up to whole cells
+    ALLOT,
+    // ( -- n ) how many return addresses are on the return stack
+    RDEPTH,
+    // ( -- n ) the gas the run has used before this opcode, and how much it has left, i64::MAX
+    // when the gas is unlimited
+    GASUSED,
+    GASLEFT,
+    // Stops the run so the host can do something else, it carries on when the host resumes it
+    YIELD,
+    // ( arguments... n code -- ) like YIELD, but leaves a HostRequest for the host to answer,
+    // the answer is pushed when the host resumes the run
+    REQUEST,
+}
+
+impl Opcode {
+    /// How many cells the opcode takes off the number stack and how many it leaves there, for
+    /// opcodes that always do the same thing. Jumps, calls, TRAP and SYSCALL depend on more than
+    /// the opcode itself.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            Opcode::LDI(_) => Some((0, 1)),
+            Opcode::POP | Opcode::UDOT | Opcode::DOT => Some((1, 0)),
+            Opcode::DOTS => Some((0, 0)),
+            Opcode::TYPE => Some((2, 0)),
+            Opcode::ASSERT => Some((2, 0)),
+            Opcode::SWAP | Opcode::UMMUL => Some((2, 2)),
+            Opcode::SMREM | Opcode::FMMOD | Opcode::DIVMOD => Some((2, 2)),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => Some((2, 1)),
+            Opcode::NEGATE | Opcode::ABS => Some((1, 1)),
+            Opcode::MIN | Opcode::MAX => Some((2, 1)),
+            Opcode::ULT | Opcode::UGT => Some((2, 1)),
+            Opcode::EQ | Opcode::LT | Opcode::GT => Some((2, 1)),
+            Opcode::AND | Opcode::OR | Opcode::XOR => Some((2, 1)),
+            Opcode::LSHIFT | Opcode::RSHIFT => Some((2, 1)),
+            Opcode::GCD | Opcode::LCM | Opcode::POW => Some((2, 1)),
+            Opcode::ADDSAT | Opcode::MULSAT | Opcode::CHECKSUM(_) => Some((2, 1)),
+            Opcode::ADDOV => Some((2, 2)),
+            Opcode::ARRAYFETCH => Some((2, 1)),
+            Opcode::ARRAYSTORE | Opcode::CMOVE => Some((3, 0)),
+            Opcode::FETCH | Opcode::CFETCH | Opcode::FETCHLE(_) | Opcode::FETCHBE(_) => {
+                Some((1, 1))
+            }
+            Opcode::STORE | Opcode::CSTORE | Opcode::STORELE(_) | Opcode::STOREBE(_) => {
+                Some((2, 0))
+            }
+            Opcode::NOT | Opcode::CMPZ | Opcode::CMPNZ => Some((1, 1)),
+            Opcode::SQRT | Opcode::LOG2 => Some((1, 1)),
+            Opcode::DUP => Some((1, 2)),
+            Opcode::OVER => Some((2, 3)),
+            Opcode::PICK(n) => Some((*n as usize + 1, *n as usize + 2)),
+            Opcode::ROLL(n) => Some((*n as usize + 1, *n as usize + 1)),
+            Opcode::NOP | Opcode::DOTVS | Opcode::YIELD => Some((0, 0)),
+            Opcode::DO => Some((2, 0)),
+            Opcode::QDO => Some((2, 1)),
+            Opcode::PLUSLOOP => Some((1, 1)),
+            Opcode::INDEX(_) => Some((0, 1)),
+            Opcode::UNLOOP => Some((0, 0)),
+            Opcode::HERE | Opcode::RDEPTH | Opcode::GASUSED | Opcode::GASLEFT => Some((0, 1)),
+            Opcode::ALLOT => Some((1, 0)),
+            Opcode::JMP
+            | Opcode::JR
+            | Opcode::JRZ
+            | Opcode::JRNZ
+            | Opcode::CALL
+            | Opcode::RET
+            | Opcode::TRAP
+            | Opcode::SYSCALL(_)
+            | Opcode::EXT(_)
+            | Opcode::REQUEST => None,
+        }
+    }
 }
 
 pub struct StackMachineState {
     pub number_stack: Vec<i64>,
     return_stack: Vec<usize>,
+    // The index and limit of each running DO loop, the innermost last
+    loop_stack: Vec<(i64, i64)>,
     pub opcodes: Vec<Opcode>,
     pc: usize,
     gas_used: u64,
+    // Anything the running program prints goes here, stdout unless the host says otherwise
+    pub output: Box<dyn Write>,
+    pub arithmetic_mode: ArithmeticMode,
+    pub truthiness: Truthiness,
+    pub division_mode: DivisionMode,
+    // Values that outgrew a cell in ArithmeticMode::Promoting, cells refer to them by handle
+    big_numbers: Vec<BigInt>,
+    // What the big integers take up, kept as they are made so memory_usage doesn't add them up
+    // after every opcode when there is a memory limit
+    big_number_bytes: usize,
+    // Cells reserved by the program, cell n is at address n * CELL_SIZE
+    data: Vec<i64>,
+    // Buffers the host has mapped in, from MAPPED_BUFFER_BASE up
+    mapped: Vec<SharedBuffer>,
+    // What the run stopped by REQUEST is waiting for
+    request: Option<HostRequest>,
+    // The most cells the number stack and return addresses the return stack have held
+    number_stack_high_water: usize,
+    return_stack_high_water: usize,
 }
 
 impl StackMachineState {
@@ -93,71 +553,1050 @@ impl StackMachineState {
         StackMachineState {
             number_stack: Vec::new(),
             return_stack: Vec::new(),
+            loop_stack: Vec::new(),
             opcodes: Vec::new(),
             pc: 0,
             gas_used: 0,
+            output: Box::new(std::io::stdout()),
+            arithmetic_mode: ArithmeticMode::Wrapping,
+            truthiness: Truthiness::NonZero,
+            division_mode: DivisionMode::Symmetric,
+            big_numbers: Vec::new(),
+            big_number_bytes: 0,
+            data: Vec::new(),
+            mapped: Vec::new(),
+            request: None,
+            number_stack_high_water: 0,
+            return_stack_high_water: 0,
+        }
+    }
+}
+
+/// Where a run had got to, kept aside while the machine runs something else in the middle of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunContext {
+    pc: usize,
+    return_stack: Vec<usize>,
+    loop_stack: Vec<(i64, i64)>,
+    gas_used: u64,
+}
+
+impl RunContext {
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    /// Point the pc and return addresses that are in the range at the same opcodes starting at
+    /// `to` instead, for when the code they are in has been moved
+    pub fn relocate(&mut self, from: Range<usize>, to: usize) {
+        for address in std::iter::once(&mut self.pc).chain(self.return_stack.iter_mut()) {
+            if from.contains(address) {
+                *address = *address - from.start + to;
+            }
         }
     }
 }
 
+impl Default for StackMachineState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StackMachineState {
     pub fn gas_used(&self) -> u64 {
         self.gas_used
     }
+
+    /// What REQUEST asked for, until the run is resumed
+    pub fn request(&self) -> Option<&HostRequest> {
+        self.request.as_ref()
+    }
+
+    /// The deepest the number stack and the return stack have been while code was running,
+    /// since the machine was made or reset_high_water was last called
+    pub fn high_water(&self) -> (usize, usize) {
+        (self.number_stack_high_water, self.return_stack_high_water)
+    }
+
+    pub fn reset_high_water(&mut self) {
+        self.number_stack_high_water = 0;
+        self.return_stack_high_water = 0;
+    }
+
+    /// Where the machine is, after an error the opcode that failed
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The addresses RETs will go back to, the most recent call last
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    /// Push cells onto the number stack in order, so the last one ends up on top
+    pub fn push_slice(&mut self, cells: &[i64]) {
+        self.number_stack.extend_from_slice(cells);
+    }
+
+    /// Pop as many cells as the buffer holds into it, in stack order so what was on top is
+    /// last. The stack is left alone when it doesn't hold enough.
+    pub fn pop_n_into(&mut self, buffer: &mut [i64]) -> Result<(), StackMachineError> {
+        let start = self
+            .number_stack
+            .len()
+            .checked_sub(buffer.len())
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+        buffer.copy_from_slice(&self.number_stack[start..]);
+        self.number_stack.truncate(start);
+        Ok(())
+    }
+
+    /// Take everything on the number stack, bottom first, leaving it empty
+    pub fn drain_stack(&mut self) -> Vec<i64> {
+        self.number_stack.drain(..).collect()
+    }
+
+    /// Take the run in progress aside, leaving the machine free to run something else
+    pub fn save_context(&mut self) -> RunContext {
+        RunContext {
+            pc: self.pc,
+            return_stack: mem::take(&mut self.return_stack),
+            loop_stack: mem::take(&mut self.loop_stack),
+            gas_used: self.gas_used,
+        }
+    }
+
+    /// Go back to a run taken aside by save_context, the gas used since still counts against it
+    pub fn restore_context(&mut self, context: RunContext) {
+        self.pc = context.pc;
+        self.return_stack = context.return_stack;
+        self.loop_stack = context.loop_stack;
+        self.gas_used += context.gas_used;
+    }
+
+    /// Forget the calls and loops a failed run was inside of, so the next run's last RET
+    /// finishes it
+    pub fn clear_return_stack(&mut self) {
+        self.return_stack.clear();
+        self.loop_stack.clear();
+    }
+
+    /// Forget what runs have left behind outside the opcodes and data space: the stacks, big
+    /// integers, gas used and any request for the host
+    pub fn clear_run_state(&mut self) {
+        self.number_stack.clear();
+        self.clear_return_stack();
+        self.big_numbers.clear();
+        self.big_number_bytes = 0;
+        self.gas_used = 0;
+        self.request = None;
+    }
+
+    /// If the cell is a handle to a promoted big integer then return the big integer
+    pub fn big_number(&self, cell: i64) -> Option<&BigInt> {
+        if self.arithmetic_mode != ArithmeticMode::Promoting {
+            return None;
+        }
+        self.big_numbers
+            .get(cell.wrapping_sub(BIG_NUMBER_HANDLE_BASE) as u64 as usize)
+    }
+
+    /// Format a cell for people to read, following big integer handles
+    pub fn format_cell(&self, cell: i64) -> String {
+        match self.big_number(cell) {
+            Some(b) => b.to_string(),
+            None => cell.to_string(),
+        }
+    }
+
+    /// Render the number stack as an aligned table, bottom of the stack first, giving each
+    /// cell's index from the top along with its decimal, hex and character forms
+    pub fn format_stack(&self) -> String {
+        if self.number_stack.is_empty() {
+            return "Stack empty\n".to_owned();
+        }
+
+        let decimals: Vec<String> = self
+            .number_stack
+            .iter()
+            .map(|x| self.format_cell(*x))
+            .collect();
+        let width = decimals.iter().map(|d| d.len()).max().unwrap_or(0);
+        let index_width = (self.number_stack.len() - 1).to_string().len();
+
+        let mut text = format!("Stack depth {}\n", self.number_stack.len());
+        for (i, (x, decimal)) in self.number_stack.iter().zip(decimals.iter()).enumerate() {
+            let character = match u8::try_from(*x) {
+                Ok(c) if (0x20..0x7f).contains(&c) => c as char,
+                _ => '.',
+            };
+            text.push_str(&format!(
+                "  [{:>iw$}] {:>w$}  0x{:016X}  '{}'\n",
+                self.number_stack.len() - 1 - i,
+                decimal,
+                x,
+                character,
+                iw = index_width,
+                w = width
+            ));
+        }
+        text
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            stack_bytes: self.number_stack.len() * mem::size_of::<i64>()
+                + self.return_stack.len() * mem::size_of::<usize>()
+                + self.loop_stack.len() * mem::size_of::<(i64, i64)>(),
+            data_space_bytes: self.data.len() * CELL_SIZE as usize,
+            big_number_bytes: self.big_number_bytes,
+            dictionary_bytes: self.opcodes.len() * mem::size_of::<Opcode>(),
+        }
+    }
+
+    /// Everything reserved in the data space so far
+    pub fn data(&self) -> &[i64] {
+        &self.data
+    }
+
+    /// Replace everything reserved in the data space, such as with a saved session's
+    pub fn restore_data(&mut self, data: Vec<i64>) -> Result<(), StackMachineError> {
+        if data.len() > MAX_DATA_CELLS {
+            return Err(StackMachineError::DataSpaceFull);
+        }
+        self.data = data;
+        Ok(())
+    }
+
+    /// The address the next cell reserved in the data space will have
+    pub fn here(&self) -> i64 {
+        self.data.len() as i64 * CELL_SIZE
+    }
+
+    /// Reserve cells at the end of the data space, set to 0, returning the address of the first
+    pub fn allocate(&mut self, cells: usize) -> Result<i64, StackMachineError> {
+        if cells > MAX_DATA_CELLS - self.data.len() {
+            return Err(StackMachineError::DataSpaceFull);
+        }
+        let address = self.data.len() as i64 * CELL_SIZE;
+        self.data.resize(self.data.len() + cells, 0);
+        Ok(address)
+    }
+
+    /// Give back everything reserved in the data space from the address on, so it is where the
+    /// next cell reserved goes
+    pub fn release_data(&mut self, address: i64) {
+        let cells = usize::try_from(address / CELL_SIZE).unwrap_or(0);
+        self.data.truncate(cells);
+    }
+
+    /// The opcode a cell refers to as an address, for jumps and calls. Anything outside opcode
+    /// memory is an error rather than a negative cell becoming a huge address.
+    pub fn code_address(&self, address: i64) -> Result<usize, StackMachineError> {
+        usize::try_from(address)
+            .ok()
+            .filter(|a| *a < self.opcodes.len())
+            .ok_or(StackMachineError::InvalidCodeAddress(address))
+    }
+
+    // The opcode a relative jump from pc goes to
+    fn relative_code_address(&self, offset: i64) -> Result<usize, StackMachineError> {
+        self.code_address((self.pc as i64).saturating_add(offset))
+    }
+
+    // Which cell of the data space an address refers to
+    fn cell_index(&self, address: i64) -> Result<usize, StackMachineError> {
+        if address < 0 || address % CELL_SIZE != 0 {
+            return Err(StackMachineError::InvalidAddress(address));
+        }
+        let index = (address / CELL_SIZE) as usize;
+        if index >= self.data.len() {
+            return Err(StackMachineError::InvalidAddress(address));
+        }
+        Ok(index)
+    }
+
+    /// Make a host buffer part of the data space, returning the address of its first cell.
+    /// Programs index it with []@ and []! like an array.
+    pub fn map_buffer(&mut self, buffer: SharedBuffer) -> Result<i64, StackMachineError> {
+        if self.mapped.len() >= MAX_MAPPED_BUFFERS {
+            return Err(StackMachineError::DataSpaceFull);
+        }
+        self.mapped.push(buffer);
+        Ok(MAPPED_BUFFER_BASE + (self.mapped.len() - 1) as i64 * MAPPED_BUFFER_SPAN)
+    }
+
+    // The mapped buffer an address is in, and the cell of it the address refers to
+    fn mapped_cell(&self, address: i64) -> Option<(usize, usize)> {
+        let offset = address.checked_sub(MAPPED_BUFFER_BASE)?;
+        if offset < 0 || offset % CELL_SIZE != 0 {
+            return None;
+        }
+        let buffer = (offset / MAPPED_BUFFER_SPAN) as usize;
+        let index = (offset % MAPPED_BUFFER_SPAN / CELL_SIZE) as usize;
+        self.mapped.get(buffer)?;
+        Some((buffer, index))
+    }
+
+    fn locate(&self, address: i64) -> Result<CellLocation, StackMachineError> {
+        match self.mapped_cell(address) {
+            Some((buffer, index)) if index < self.mapped[buffer].len() => {
+                Ok(CellLocation::Mapped(buffer, index))
+            }
+            Some(_) => Err(StackMachineError::InvalidAddress(address)),
+            None => Ok(CellLocation::Data(self.cell_index(address)?)),
+        }
+    }
+
+    fn read(&self, location: CellLocation) -> i64 {
+        match location {
+            CellLocation::Data(index) => self.data[index],
+            CellLocation::Mapped(buffer, index) => self.mapped[buffer].borrow()[index],
+        }
+    }
+
+    fn write(&mut self, location: CellLocation, x: i64) {
+        match location {
+            CellLocation::Data(index) => self.data[index] = x,
+            CellLocation::Mapped(buffer, index) => self.mapped[buffer].borrow_mut()[index] = x,
+        }
+    }
+
+    pub fn fetch(&self, address: i64) -> Result<i64, StackMachineError> {
+        Ok(self.read(self.locate(address)?))
+    }
+
+    pub fn store(&mut self, address: i64, x: i64) -> Result<(), StackMachineError> {
+        let location = self.locate(address)?;
+        self.write(location, x);
+        Ok(())
+    }
+
+    // The cell a byte is in and how far up the cell it is, cells are little endian
+    fn locate_byte(&self, address: i64) -> Result<(CellLocation, u32), StackMachineError> {
+        let location = self
+            .locate(address.div_euclid(CELL_SIZE) * CELL_SIZE)
+            .map_err(|_| StackMachineError::InvalidAddress(address))?;
+        Ok((location, (address.rem_euclid(CELL_SIZE) * 8) as u32))
+    }
+
+    pub fn fetch_byte(&self, address: i64) -> Result<u8, StackMachineError> {
+        let (location, shift) = self.locate_byte(address)?;
+        Ok((self.read(location) >> shift) as u8)
+    }
+
+    pub fn store_byte(&mut self, address: i64, byte: u8) -> Result<(), StackMachineError> {
+        let (location, shift) = self.locate_byte(address)?;
+        let cell = self.read(location) & !(0xff << shift) | i64::from(byte) << shift;
+        self.write(location, cell);
+        Ok(())
+    }
+
+    /// Copy a range of bytes out of the data space, a negative length is an empty range
+    pub fn fetch_bytes(&self, address: i64, length: i64) -> Result<Vec<u8>, StackMachineError> {
+        (0..length.max(0))
+            .map(|i| self.fetch_byte(address.wrapping_add(i)))
+            .collect()
+    }
+
+    pub fn store_bytes(&mut self, address: i64, bytes: &[u8]) -> Result<(), StackMachineError> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.store_byte(address.wrapping_add(i as i64), *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Read an integer from `width` bytes, zero extending it to a cell
+    pub fn fetch_integer(
+        &self,
+        address: i64,
+        width: u8,
+        big_endian: bool,
+    ) -> Result<i64, StackMachineError> {
+        let mut x: u64 = 0;
+        for i in 0..i64::from(width) {
+            let byte = self.fetch_byte(address.wrapping_add(i))?;
+            let shift = if big_endian {
+                (i64::from(width) - 1 - i) * 8
+            } else {
+                i * 8
+            };
+            x |= u64::from(byte) << shift;
+        }
+        Ok(x as i64)
+    }
+
+    /// Write the low `width` bytes of a cell
+    pub fn store_integer(
+        &mut self,
+        address: i64,
+        x: i64,
+        width: u8,
+        big_endian: bool,
+    ) -> Result<(), StackMachineError> {
+        for i in 0..i64::from(width) {
+            let shift = if big_endian {
+                (i64::from(width) - 1 - i) * 8
+            } else {
+                i * 8
+            };
+            self.store_byte(address.wrapping_add(i), (x >> shift) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Reserve an array of cells, set to 0, returning the address of its first element. The
+    /// length is kept in the cell before it so that indexing can be checked.
+    pub fn allocate_array(&mut self, length: usize) -> Result<i64, StackMachineError> {
+        let address = self.allocate(length + 1)?;
+        self.data[(address / CELL_SIZE) as usize] = length as i64;
+        Ok(address + CELL_SIZE)
+    }
+
+    // Where an array's elements are in the data space
+    fn array_range(&self, address: i64) -> Result<Range<usize>, StackMachineError> {
+        let start = self.cell_index(address)?;
+        let length = usize::try_from(self.fetch(address - CELL_SIZE)?)
+            .map_err(|_| StackMachineError::InvalidAddress(address))?;
+        if length > self.data.len() - start {
+            return Err(StackMachineError::InvalidAddress(address));
+        }
+        Ok(start..start + length)
+    }
+
+    /// The elements of the array at the address
+    pub fn array(&self, address: i64) -> Option<&[i64]> {
+        let range = self.array_range(address).ok()?;
+        Some(&self.data[range])
+    }
+
+    pub fn array_mut(&mut self, address: i64) -> Option<&mut [i64]> {
+        let range = self.array_range(address).ok()?;
+        Some(&mut self.data[range])
+    }
+
+    // Where an array element is, checking it is inside the array. A mapped buffer is an array
+    // of all its cells.
+    fn array_element(&self, address: i64, index: i64) -> Result<CellLocation, StackMachineError> {
+        let (range, mapped) = match self.mapped_cell(address) {
+            Some((buffer, 0)) => (0..self.mapped[buffer].len(), Some(buffer)),
+            Some(_) => return Err(StackMachineError::InvalidAddress(address)),
+            None => (self.array_range(address)?, None),
+        };
+        let element = usize::try_from(index)
+            .ok()
+            .filter(|i| *i < range.len())
+            .map(|i| range.start + i)
+            .ok_or(StackMachineError::IndexOutOfBounds(index))?;
+        Ok(match mapped {
+            Some(buffer) => CellLocation::Mapped(buffer, element),
+            None => CellLocation::Data(element),
+        })
+    }
+
+    fn cell_to_big_number(&self, cell: i64) -> BigInt {
+        match self.big_number(cell) {
+            Some(b) => b.clone(),
+            None => BigInt::from_i64(cell),
+        }
+    }
+
+    // Turn a big integer back into a cell, allocating a handle if it can't be represented directly
+    fn store_big_number(&mut self, b: BigInt) -> Result<i64, StackMachineError> {
+        if let Some(n) = b.to_i64() {
+            if (n.wrapping_sub(BIG_NUMBER_HANDLE_BASE) as u64) >= MAX_BIG_NUMBERS as u64 {
+                return Ok(n);
+            }
+        }
+        if self.big_numbers.len() >= MAX_BIG_NUMBERS {
+            return Err(StackMachineError::TooManyBigNumbers);
+        }
+        self.big_number_bytes += mem::size_of::<BigInt>() + b.digit_count() * mem::size_of::<u32>();
+        self.big_numbers.push(b);
+        Ok(BIG_NUMBER_HANDLE_BASE + (self.big_numbers.len() - 1) as i64)
+    }
 }
 
-pub struct StackMachine {
-    pub st: StackMachineState,
-    pub trap_handlers: Vec<Box<dyn HandleTrap>>,
+/// Limits which code is allowed to execute each TRAP, so that scripts can only reach the host
+/// through the words that were written to expose it
+#[derive(Debug, Default, Clone)]
+pub struct TrapGuard {
+    // When false every TRAP is allowed, as if there were no guard
+    pub enabled: bool,
+    allowed: HashMap<i64, Vec<Range<usize>>>,
 }
 
-impl StackMachine {
-    pub fn new() -> StackMachine {
-        StackMachine {
-            st: StackMachineState::new(),
-            trap_handlers: Vec::new(),
+impl TrapGuard {
+    /// Allow the opcodes in the address range to execute the TRAP
+    pub fn allow(&mut self, trap_id: i64, code: Range<usize>) {
+        self.allowed.entry(trap_id).or_default().push(code);
+    }
+
+    /// Take the permissions away from the code at and after the address, for when the opcodes
+    /// there are thrown away and the address may be reused by other code
+    pub fn forget_from(&mut self, address: usize) {
+        for ranges in self.allowed.values_mut() {
+            ranges.retain(|r| r.end <= address);
         }
     }
 
-    pub fn execute(
+    pub fn permits(&self, trap_id: i64, pc: usize) -> bool {
+        !self.enabled
+            || self
+                .allowed
+                .get(&trap_id)
+                .is_some_and(|ranges| ranges.iter().any(|r| r.contains(&pc)))
+    }
+}
+
+/// Prints a line to the output each time a word is entered or left, with the top few cells of
+/// the stack, indented by how deeply the word is nested. The StackMachine doesn't know about
+/// words, so it is given their names by address.
+///
+/// ```text
+/// > Square 3
+///   > Double 3
+///   < Double 6
+/// < Square 9
+/// ```
+pub struct WordTrace {
+    names: HashMap<usize, String>,
+    cells: usize,
+    // The word each CALL still on the return stack went into, None when it wasn't a known word
+    frames: Vec<Option<String>>,
+}
+
+impl WordTrace {
+    pub fn new(names: HashMap<usize, String>, cells: usize) -> WordTrace {
+        WordTrace {
+            names,
+            cells,
+            frames: Vec::new(),
+        }
+    }
+
+    fn enter(
         &mut self,
-        starting_point: usize,
-        gas_limit: GasLimit,
+        address: usize,
+        st: &mut StackMachineState,
     ) -> Result<(), StackMachineError> {
-        self.st.gas_used = 0;
-        self.st.pc = starting_point;
-        loop {
-            let mut pc_reset = false;
-            match self.st.opcodes[self.st.pc] {
-                Opcode::JMP => {
-                    self.st.pc = self
-                        .st
-                        .number_stack
-                        .pop()
-                        .map(|x| x as usize)
-                        .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    pc_reset = true;
-                }
-                Opcode::JR => {
-                    let new_offset = self.st.pc as i128
-                        + self
-                            .st
-                            .number_stack
-                            .pop()
-                            .ok_or(StackMachineError::NumberStackUnderflow)?
-                            as i128;
-                    self.st.pc = usize::try_from(new_offset).unwrap();
-                    pc_reset = true;
-                }
-                Opcode::CALL => {
-                    self.st.return_stack.push(self.st.pc + 1);
-                    self.st.pc = self
+        let name = self.names.get(&address).cloned();
+        if let Some(name) = &name {
+            self.write_line('>', name, st)?;
+        }
+        self.frames.push(name);
+        Ok(())
+    }
+
+    fn exit(&mut self, st: &mut StackMachineState) -> Result<(), StackMachineError> {
+        if let Some(Some(name)) = self.frames.pop() {
+            self.write_line('<', &name, st)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(
+        &self,
+        direction: char,
+        name: &str,
+        st: &mut StackMachineState,
+    ) -> Result<(), StackMachineError> {
+        let depth = self.frames.iter().filter(|f| f.is_some()).count();
+        let start = st.number_stack.len().saturating_sub(self.cells);
+        let mut line = format!("{}{} {}", "  ".repeat(depth), direction, name);
+        for cell in &st.number_stack[start..] {
+            line.push(' ');
+            line.push_str(&st.format_cell(*cell));
+        }
+        writeln!(st.output, "{}", line).map_err(StackMachineError::Io)
+    }
+}
+
+/// How often a word was called and the gas used while it ran, counted by a Profiler
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WordProfile {
+    pub calls: u64,
+    // Gas used by the word's own opcodes
+    pub self_gas: u64,
+    // Gas used by the word and everything it called, a recursive word counts its inner calls
+    // again for each call they are inside
+    pub total_gas: u64,
+}
+
+/// Counts the calls into each address and the gas used until they return, for finding the
+/// words a program spends its time in. Like WordTrace it knows words only by their addresses.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    words: HashMap<usize, WordProfile>,
+    // The gas used since the profiler was made
+    gas: u64,
+    // For each CALL still on the return stack, the address it went to, the gas used when it did
+    // and the gas used by the words it has called that have returned
+    frames: Vec<(usize, u64, u64)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// What has been counted for each address that was called
+    pub fn words(&self) -> &HashMap<usize, WordProfile> {
+        &self.words
+    }
+
+    /// Forget the calls that haven't returned, for when the run they were in was abandoned
+    pub fn abandon_calls(&mut self) {
+        self.frames.clear();
+    }
+
+    fn enter(&mut self, address: usize) {
+        self.frames.push((address, self.gas, 0));
+    }
+
+    // A return from a call made before the profiler was started has no frame and isn't counted
+    fn exit(&mut self) {
+        if let Some((address, start, inner)) = self.frames.pop() {
+            let total = self.gas - start;
+            let word = self.words.entry(address).or_default();
+            word.calls += 1;
+            word.self_gas += total - inner;
+            word.total_gas += total;
+            if let Some(caller) = self.frames.last_mut() {
+                caller.2 += total;
+            }
+        }
+    }
+}
+
+/// Runs a host function every so many gas units while code is executing, so that embedded hosts
+/// can pet a hardware watchdog or yield to an RTOS during a long script. The function can't stop
+/// or change the running code.
+pub struct Watchdog {
+    interval: u64,
+    since_last: u64,
+    to_run: Box<dyn FnMut()>,
+}
+
+impl Watchdog {
+    pub fn new<C>(interval: u64, f: C) -> Watchdog
+    where
+        C: FnMut() + 'static,
+    {
+        Watchdog {
+            interval: interval.max(1),
+            since_last: 0,
+            to_run: Box::new(f),
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    // Count one gas unit, running the function if the interval is up
+    fn consume_gas(&mut self) {
+        self.since_last += 1;
+        if self.since_last >= self.interval {
+            self.since_last = 0;
+            (self.to_run)();
+        }
+    }
+}
+
+/// Emulates the timing of a target system, for retrocomputing and teaching. Before each opcode
+/// runs the host's function is given it and says how many cycles it takes on the target, and may
+/// sleep to keep to the target's speed. Unlike gas, which limits how much a run can do, the model
+/// only watches and never stops the code.
+pub struct CycleModel {
+    cycles: u64,
+    cost: Box<dyn FnMut(&Opcode) -> u64>,
+}
+
+impl CycleModel {
+    pub fn new<C>(cost: C) -> CycleModel
+    where
+        C: FnMut(&Opcode) -> u64 + 'static,
+    {
+        CycleModel {
+            cycles: 0,
+            cost: Box::new(cost),
+        }
+    }
+
+    /// The cycles every opcode run so far has taken, across runs until reset
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+    }
+
+    fn charge(&mut self, opcode: &Opcode) {
+        self.cycles = self.cycles.saturating_add((self.cost)(opcode));
+    }
+}
+
+pub struct StackMachine {
+    pub st: StackMachineState,
+    pub trap_handlers: Vec<Box<dyn HandleTrap>>,
+    // The handler for each EXT opcode id
+    pub ext_opcodes: HashMap<u16, Box<dyn ExtOpcodeHandler>>,
+    pub trap_guard: TrapGuard,
+    pub watchdog: Option<Watchdog>,
+    // When set, the machine's invariants are checked after every opcode, which is slow but finds
+    // miscompiled code at the point it goes wrong
+    pub integrity_checks: Option<IntegrityChecks>,
+    pub word_trace: Option<WordTrace>,
+    pub profiler: Option<Profiler>,
+    pub cycle_model: Option<CycleModel>,
+    // When set, running fails with MemoryLimitExceeded once the state uses more bytes than this,
+    // which gas alone doesn't prevent
+    pub memory_limit: Option<usize>,
+}
+
+impl StackMachine {
+    pub fn new() -> StackMachine {
+        StackMachine {
+            st: StackMachineState::new(),
+            trap_handlers: Vec::new(),
+            ext_opcodes: HashMap::new(),
+            trap_guard: TrapGuard::default(),
+            watchdog: None,
+            integrity_checks: None,
+            word_trace: None,
+            profiler: None,
+            cycle_model: None,
+            memory_limit: None,
+        }
+    }
+}
+
+impl Default for StackMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StackMachine {
+    // ADD, SUB, MUL and DIV all take the top of stack as the left hand side and the next
+    // element as the right hand side
+    fn binary_arithmetic(&mut self, op: ArithmeticOperation) -> Result<(), StackMachineError> {
+        let x = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+        let y = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+
+        let result = match self.st.arithmetic_mode {
+            ArithmeticMode::Wrapping => match op {
+                ArithmeticOperation::Add => x.wrapping_add(y),
+                ArithmeticOperation::Sub => x.wrapping_sub(y),
+                ArithmeticOperation::Mul => x.wrapping_mul(y),
+                ArithmeticOperation::Div => {
+                    let (_, quotient) = self
+                        .st
+                        .division_mode
+                        .divide(x, y)
+                        .ok_or(StackMachineError::DivisionByZero)?;
+                    quotient
+                }
+            },
+            ArithmeticMode::Promoting => {
+                let (bx, by) = (self.st.cell_to_big_number(x), self.st.cell_to_big_number(y));
+                let b = match op {
+                    ArithmeticOperation::Add => bx.add(&by),
+                    ArithmeticOperation::Sub => bx.sub(&by),
+                    ArithmeticOperation::Mul => bx.mul(&by),
+                    ArithmeticOperation::Div => {
+                        let quotient = bx.div(&by).ok_or(StackMachineError::DivisionByZero)?;
+                        let remainder = bx.sub(&quotient.mul(&by));
+                        let floored = self.st.division_mode == DivisionMode::Floored;
+                        if floored
+                            && !remainder.is_zero()
+                            && remainder.is_negative() != by.is_negative()
+                        {
+                            quotient.sub(&BigInt::from_i64(1))
+                        } else {
+                            quotient
+                        }
+                    }
+                };
+                self.st.store_big_number(b)?
+            }
+        };
+        self.st.number_stack.push(result);
+        Ok(())
+    }
+
+    // Negate the top of the stack, only when it is negative for ABS
+    fn negate(&mut self, only_negative: bool) -> Result<(), StackMachineError> {
+        let x = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+
+        let result = match self.st.arithmetic_mode {
+            ArithmeticMode::Wrapping if only_negative => x.wrapping_abs(),
+            ArithmeticMode::Wrapping => x.wrapping_neg(),
+            ArithmeticMode::Promoting => {
+                let b = self.st.cell_to_big_number(x);
+                if only_negative && !b.is_negative() {
+                    x
+                } else {
+                    self.st.store_big_number(b.negate())?
+                }
+            }
+        };
+        self.st.number_stack.push(result);
+        Ok(())
+    }
+
+    fn min_max(&mut self, max: bool) -> Result<(), StackMachineError> {
+        let (a, b) = self.pop_pair()?;
+        let a_less = self.compare(a, b) == Ordering::Less;
+        self.st.number_stack.push(if a_less != max { a } else { b });
+        Ok(())
+    }
+
+    // Compare two cells as numbers, following big integer handles in ArithmeticMode::Promoting
+    fn compare(&self, a: i64, b: i64) -> Ordering {
+        match self.st.arithmetic_mode {
+            ArithmeticMode::Wrapping => a.cmp(&b),
+            ArithmeticMode::Promoting => {
+                let difference = self
+                    .st
+                    .cell_to_big_number(a)
+                    .sub(&self.st.cell_to_big_number(b));
+                if difference.is_zero() {
+                    Ordering::Equal
+                } else if difference.is_negative() {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            }
+        }
+    }
+
+    // Pop two cells and push true when they compare as wanted
+    fn comparison(&mut self, wanted: Ordering) -> Result<(), StackMachineError> {
+        let (a, b) = self.pop_pair()?;
+        let result = self.compare(a, b) == wanted;
+        self.st.number_stack.push(if result { -1 } else { 0 });
+        Ok(())
+    }
+
+    // Answers that don't fit in a cell are big integers in ArithmeticMode::Promoting and
+    // OutOfDomain otherwise, rather than wrapping
+    fn lcm(&mut self) -> Result<(), StackMachineError> {
+        let (a, b) = self.pop_pair()?;
+        let l = match self.st.arithmetic_mode {
+            ArithmeticMode::Wrapping => match gcd(a, b) {
+                Some(0) => 0,
+                Some(g) => (a / g)
+                    .checked_mul(b)
+                    .and_then(|l| l.checked_abs())
+                    .ok_or(StackMachineError::OutOfDomain(a))?,
+                None => return Err(StackMachineError::OutOfDomain(a)),
+            },
+            ArithmeticMode::Promoting => {
+                let (ba, bb) = (self.st.cell_to_big_number(a), self.st.cell_to_big_number(b));
+                let l = match ba.div(&ba.gcd(&bb)) {
+                    Some(q) => q.mul(&bb).abs(),
+                    None => BigInt::zero(),
+                };
+                self.st.store_big_number(l)?
+            }
+        };
+        self.st.number_stack.push(l);
+        Ok(())
+    }
+
+    // The exponent is on top, see lcm for answers that don't fit in a cell
+    fn pow(&mut self) -> Result<(), StackMachineError> {
+        let (base, exponent) = self.pop_pair()?;
+        let e = u32::try_from(exponent).map_err(|_| StackMachineError::OutOfDomain(exponent))?;
+        let p = match self.st.arithmetic_mode {
+            ArithmeticMode::Wrapping => base
+                .checked_pow(e)
+                .ok_or(StackMachineError::OutOfDomain(exponent))?,
+            ArithmeticMode::Promoting => {
+                let p = self
+                    .st
+                    .cell_to_big_number(base)
+                    .pow(e, MAX_BIG_NUMBER_DIGITS)
+                    .ok_or(StackMachineError::OutOfDomain(exponent))?;
+                self.st.store_big_number(p)?
+            }
+        };
+        self.st.number_stack.push(p);
+        Ok(())
+    }
+
+    // Pop b and then a for the math words that take ( a b -- x )
+    fn pop_pair(&mut self) -> Result<(i64, i64), StackMachineError> {
+        let b = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+        let a = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+        Ok((a, b))
+    }
+
+    fn fetch_integer(&mut self, width: u8, big_endian: bool) -> Result<(), StackMachineError> {
+        let address = self
+            .st
+            .number_stack
+            .pop()
+            .ok_or(StackMachineError::NumberStackUnderflow)?;
+        let x = self.st.fetch_integer(address, width, big_endian)?;
+        self.st.number_stack.push(x);
+        Ok(())
+    }
+
+    pub fn execute(
+        &mut self,
+        starting_point: usize,
+        gas_limit: GasLimit,
+    ) -> Result<ExecutionStatus, StackMachineError> {
+        self.st.gas_used = 0;
+        self.st.request = None;
+        // There is nothing to run, not even a RET
+        if self.st.opcodes.is_empty() {
+            return Ok(ExecutionStatus::Finished);
+        }
+        self.st.pc = starting_point;
+        self.run(gas_limit)
+    }
+
+    /// Carry on from where a SYSCALL stopped execution, the gas used so far still counts
+    pub fn resume(&mut self, gas_limit: GasLimit) -> Result<ExecutionStatus, StackMachineError> {
+        self.run(gas_limit)
+    }
+
+    /// Carry on from where a YIELD stopped execution with a gas limit of its own, so a long run
+    /// can be given a slice of gas each time it is resumed
+    pub fn resume_yielded(
+        &mut self,
+        gas_limit: GasLimit,
+    ) -> Result<ExecutionStatus, StackMachineError> {
+        self.resume_with(&[], gas_limit)
+    }
+
+    /// Answer a REQUEST by pushing the values, last on top, and carry on like resume_yielded
+    pub fn resume_with(
+        &mut self,
+        values: &[i64],
+        gas_limit: GasLimit,
+    ) -> Result<ExecutionStatus, StackMachineError> {
+        self.st.request = None;
+        self.st.number_stack.extend_from_slice(values);
+        self.st.gas_used = 0;
+        self.run(gas_limit)
+    }
+
+    // Check the machine is still in a state it can carry on from, after running the opcode at pc
+    fn check_integrity(
+        &self,
+        checks: &IntegrityChecks,
+        pc: usize,
+    ) -> Result<(), StackMachineError> {
+        let opcode_count = self.st.opcodes.len();
+        let description = if self.st.number_stack.len() > checks.max_number_stack {
+            format!(
+                "number stack depth {} is over the limit of {}",
+                self.st.number_stack.len(),
+                checks.max_number_stack
+            )
+        } else if self.st.return_stack.len() > checks.max_return_stack {
+            format!(
+                "return stack depth {} is over the limit of {}",
+                self.st.return_stack.len(),
+                checks.max_return_stack
+            )
+        } else if self.st.pc >= opcode_count {
+            format!(
+                "pc {} is outside the {} opcodes in memory",
+                self.st.pc, opcode_count
+            )
+        } else if let Some(address) = self.st.return_stack.iter().find(|a| {
+            **a == 0 || **a > opcode_count || !matches!(self.st.opcodes[**a - 1], Opcode::CALL)
+        }) {
+            format!("return address {} doesn't follow a CALL", address)
+        } else {
+            return Ok(());
+        };
+
+        Err(StackMachineError::IntegrityViolation(Box::new(
+            IntegrityViolation {
+                description,
+                pc,
+                opcode: self.st.opcodes[pc].clone(),
+                word: None,
+                number_stack: self.st.number_stack.clone(),
+                return_stack: self.st.return_stack.clone(),
+            },
+        )))
+    }
+
+    fn run(&mut self, gas_limit: GasLimit) -> Result<ExecutionStatus, StackMachineError> {
+        loop {
+            let pc = self.st.pc;
+            let mut pc_reset = false;
+            let mut system_call = None;
+            let mut yielded = false;
+            if let Some(model) = self.cycle_model.as_mut() {
+                model.charge(&self.st.opcodes[pc]);
+            }
+            match self.st.opcodes[self.st.pc] {
+                Opcode::JMP => {
+                    let address = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    self.st.pc = self.st.code_address(address)?;
+                    pc_reset = true;
+                }
+                Opcode::JR => {
+                    let offset = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    self.st.pc = self.st.relative_code_address(offset)?;
+                    pc_reset = true;
+                }
+                Opcode::CALL => {
+                    let address = self
                         .st
                         .number_stack
                         .pop()
-                        .map(|x| x as usize)
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let address = self.st.code_address(address)?;
+                    self.st.return_stack.push(self.st.pc + 1);
+                    self.st.pc = address;
                     pc_reset = true;
+                    if let Some(trace) = self.word_trace.as_mut() {
+                        trace.enter(self.st.pc, &mut self.st)?;
+                    }
+                    if let Some(profiler) = self.profiler.as_mut() {
+                        profiler.enter(address);
+                    }
                 }
                 Opcode::CMPZ => {
                     let x = self
@@ -165,10 +1604,10 @@ impl StackMachine {
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    if x == 0 {
-                        self.st.number_stack.push(0);
-                    } else {
+                    if self.st.truthiness.is_true(x) {
                         self.st.number_stack.push(-1);
+                    } else {
+                        self.st.number_stack.push(0);
                     }
                 }
                 Opcode::CMPNZ => {
@@ -177,45 +1616,41 @@ impl StackMachine {
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    if x == 0 {
-                        self.st.number_stack.push(-1);
-                    } else {
+                    if self.st.truthiness.is_true(x) {
                         self.st.number_stack.push(0);
+                    } else {
+                        self.st.number_stack.push(-1);
                     }
                 }
                 Opcode::JRZ => {
-                    let new_offset = self.st.pc as i128
-                        + self
-                            .st
-                            .number_stack
-                            .pop()
-                            .ok_or(StackMachineError::NumberStackUnderflow)?
-                            as i128;
+                    let offset = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
                     let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    if x == 0 {
-                        self.st.pc = usize::try_from(new_offset).unwrap();
+                    if !self.st.truthiness.is_true(x) {
+                        self.st.pc = self.st.relative_code_address(offset)?;
                         pc_reset = true;
                     }
                 }
                 Opcode::JRNZ => {
-                    let new_offset = self.st.pc as i128
-                        + self
-                            .st
-                            .number_stack
-                            .pop()
-                            .ok_or(StackMachineError::NumberStackUnderflow)?
-                            as i128;
+                    let offset = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
                     let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    if x != 0 {
-                        self.st.pc = usize::try_from(new_offset).unwrap();
+                    if self.st.truthiness.is_true(x) {
+                        self.st.pc = self.st.relative_code_address(offset)?;
                         pc_reset = true;
                     }
                 }
@@ -229,25 +1664,71 @@ impl StackMachine {
                 }
                 Opcode::RET => {
                     match self.st.return_stack.pop() {
-                        None => return Ok(()),
+                        None => return Ok(ExecutionStatus::Finished),
                         Some(oldpc) => self.st.pc = oldpc,
                     };
                     pc_reset = true;
+                    if let Some(trace) = self.word_trace.as_mut() {
+                        trace.exit(&mut self.st)?;
+                    }
+                    if let Some(profiler) = self.profiler.as_mut() {
+                        profiler.exit();
+                    }
                 }
-                Opcode::ADD => {
+                Opcode::ADD => self.binary_arithmetic(ArithmeticOperation::Add)?,
+                Opcode::SUB => self.binary_arithmetic(ArithmeticOperation::Sub)?,
+                Opcode::MUL => self.binary_arithmetic(ArithmeticOperation::Mul)?,
+                Opcode::DIV => self.binary_arithmetic(ArithmeticOperation::Div)?,
+                Opcode::NEGATE => self.negate(false)?,
+                Opcode::ABS => self.negate(true)?,
+                Opcode::MIN => self.min_max(false)?,
+                Opcode::MAX => self.min_max(true)?,
+                Opcode::NOT => {
                     let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    let y = self
+                    self.st.number_stack.push(!x);
+                }
+                Opcode::DUP => {
+                    let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x + y);
+                    self.st.number_stack.push(x);
+                    self.st.number_stack.push(x);
+                }
+                Opcode::OVER => {
+                    let depth = self.st.number_stack.len();
+                    if depth < 2 {
+                        return Err(StackMachineError::NumberStackUnderflow);
+                    }
+                    let x = self.st.number_stack[depth - 2];
+                    self.st.number_stack.push(x);
+                }
+                Opcode::PICK(n) => {
+                    let x = self
+                        .st
+                        .number_stack
+                        .len()
+                        .checked_sub(1 + n as usize)
+                        .map(|i| self.st.number_stack[i])
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    self.st.number_stack.push(x);
+                }
+                Opcode::ROLL(n) => {
+                    let i = self
+                        .st
+                        .number_stack
+                        .len()
+                        .checked_sub(1 + n as usize)
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let x = self.st.number_stack.remove(i);
+                    self.st.number_stack.push(x);
                 }
-                Opcode::SUB => {
+                Opcode::SWAP => {
                     let x = self
                         .st
                         .number_stack
@@ -258,22 +1739,65 @@ impl StackMachine {
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x - y);
+                    self.st.number_stack.push(x);
+                    self.st.number_stack.push(y);
+                }
+                Opcode::TRAP => {
+                    // We are going to say that TRAPs always have a numeric code on the number stack to define which TRAP is being called
+                    let trap_id = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    if !self.trap_guard.permits(trap_id, self.st.pc) {
+                        return Err(StackMachineError::TrapNotPermitted(trap_id));
+                    }
+                    let mut handled = false;
+                    for h in self.trap_handlers.iter_mut() {
+                        if let TrapHandled::Handled = h.handle_trap(trap_id, &mut self.st)? {
+                            handled = true;
+                            break;
+                        }
+                    }
+                    if !handled {
+                        return Err(StackMachineError::UnhandledTrap);
+                    }
                 }
-                Opcode::MUL => {
+                Opcode::NOP => {}
+                Opcode::UDOT => {
                     let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    let y = self
+                    write!(self.st.output, "{} ", x as u64).map_err(StackMachineError::Io)?;
+                }
+                Opcode::DOT => {
+                    let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x * y);
+                    let text = self.st.format_cell(x);
+                    write!(self.st.output, "{} ", text).map_err(StackMachineError::Io)?;
+                }
+                Opcode::DOTS => {
+                    let mut text = format!("<{}> ", self.st.number_stack.len());
+                    for x in self.st.number_stack.iter() {
+                        text.push_str(&self.st.format_cell(*x));
+                        text.push(' ');
+                    }
+                    write!(self.st.output, "{}", text).map_err(StackMachineError::Io)?;
                 }
-                Opcode::DIV => {
+                Opcode::TYPE => {
+                    let (address, length) = self.pop_pair()?;
+                    let bytes = self.st.fetch_bytes(address, length)?;
+                    self.st
+                        .output
+                        .write_all(&bytes)
+                        .map_err(StackMachineError::Io)?;
+                }
+                Opcode::ULT => {
                     let x = self
                         .st
                         .number_stack
@@ -284,26 +1808,76 @@ impl StackMachine {
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x / y);
+                    self.st
+                        .number_stack
+                        .push(if (y as u64) < (x as u64) { -1 } else { 0 });
                 }
-                Opcode::NOT => {
+                Opcode::UGT => {
                     let x = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(!x);
-                }
-                Opcode::DUP => {
-                    let x = self
+                    let y = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x);
-                    self.st.number_stack.push(x);
+                    self.st
+                        .number_stack
+                        .push(if (y as u64) > (x as u64) { -1 } else { 0 });
                 }
-                Opcode::SWAP => {
+                Opcode::AND => {
+                    let (a, b) = self.pop_pair()?;
+                    self.st.number_stack.push(a & b);
+                }
+                Opcode::OR => {
+                    let (a, b) = self.pop_pair()?;
+                    self.st.number_stack.push(a | b);
+                }
+                Opcode::XOR => {
+                    let (a, b) = self.pop_pair()?;
+                    self.st.number_stack.push(a ^ b);
+                }
+                Opcode::LSHIFT => {
+                    let (x, n) = self.pop_pair()?;
+                    let n = u32::try_from(n).map_err(|_| StackMachineError::OutOfDomain(n))?;
+                    self.st.number_stack.push(x.checked_shl(n).unwrap_or(0));
+                }
+                Opcode::RSHIFT => {
+                    let (x, n) = self.pop_pair()?;
+                    let n = u32::try_from(n).map_err(|_| StackMachineError::OutOfDomain(n))?;
+                    self.st
+                        .number_stack
+                        .push((x as u64).checked_shr(n).unwrap_or(0) as i64);
+                }
+                Opcode::EQ => self.comparison(Ordering::Equal)?,
+                Opcode::LT => self.comparison(Ordering::Less)?,
+                Opcode::GT => self.comparison(Ordering::Greater)?,
+                Opcode::SYSCALL(id) => system_call = Some(id),
+                Opcode::YIELD => yielded = true,
+                Opcode::REQUEST => {
+                    let (n, code) = self.pop_pair()?;
+                    let n = usize::try_from(n).map_err(|_| StackMachineError::OutOfDomain(n))?;
+                    let start = self
+                        .st
+                        .number_stack
+                        .len()
+                        .checked_sub(n)
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let arguments = self.st.number_stack.split_off(start);
+                    self.st.request = Some(HostRequest { code, arguments });
+                    yielded = true;
+                }
+                Opcode::DOTVS => {
+                    let text = self.st.format_stack();
+                    self.st
+                        .output
+                        .write_all(text.as_bytes())
+                        .map_err(StackMachineError::Io)?;
+                }
+                Opcode::UMMUL => {
+                    // Unsigned multiply giving a double cell result, the high cell ends up on top
                     let x = self
                         .st
                         .number_stack
@@ -314,635 +1888,2210 @@ impl StackMachine {
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    self.st.number_stack.push(x);
-                    self.st.number_stack.push(y);
+                    let product = (x as u64 as u128) * (y as u64 as u128);
+                    self.st.number_stack.push(product as u64 as i64);
+                    self.st.number_stack.push((product >> 64) as u64 as i64);
                 }
-                Opcode::TRAP => {
-                    // We are going to say that TRAPs always have a numeric code on the number stack to define which TRAP is being called
-                    let trap_id = self
+                Opcode::SMREM | Opcode::FMMOD | Opcode::DIVMOD => {
+                    let mode = match self.st.opcodes[pc] {
+                        Opcode::SMREM => DivisionMode::Symmetric,
+                        Opcode::FMMOD => DivisionMode::Floored,
+                        _ => self.st.division_mode,
+                    };
+                    let (n, d) = self.pop_pair()?;
+                    let (remainder, quotient) =
+                        mode.divide(n, d).ok_or(StackMachineError::DivisionByZero)?;
+                    self.st.number_stack.push(remainder);
+                    self.st.number_stack.push(quotient);
+                }
+                Opcode::SQRT => {
+                    let n = self
                         .st
                         .number_stack
                         .pop()
                         .ok_or(StackMachineError::NumberStackUnderflow)?;
-                    for h in self.trap_handlers.iter_mut() {
-                        if let TrapHandled::Handled = h.handle_trap(trap_id, &mut self.st)? {
-                            return Ok(());
-                        }
+                    let r = isqrt(n).ok_or(StackMachineError::OutOfDomain(n))?;
+                    self.st.number_stack.push(r);
+                }
+                Opcode::GCD => {
+                    let (a, b) = self.pop_pair()?;
+                    let g = gcd(a, b).ok_or(StackMachineError::OutOfDomain(a))?;
+                    self.st.number_stack.push(g);
+                }
+                Opcode::LCM => self.lcm()?,
+                Opcode::POW => self.pow()?,
+                Opcode::LOG2 => {
+                    // Rounded down
+                    let n = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    if n <= 0 {
+                        return Err(StackMachineError::OutOfDomain(n));
+                    }
+                    self.st.number_stack.push(i64::from(n.ilog2()));
+                }
+                Opcode::ARRAYFETCH => {
+                    let (index, address) = self.pop_pair()?;
+                    let element = self.st.array_element(address, index)?;
+                    self.st.number_stack.push(self.st.read(element));
+                }
+                Opcode::ARRAYSTORE => {
+                    let (index, address) = self.pop_pair()?;
+                    let x = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let element = self.st.array_element(address, index)?;
+                    self.st.write(element, x);
+                }
+                Opcode::FETCH => {
+                    let address = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let x = self.st.fetch(address)?;
+                    self.st.number_stack.push(x);
+                }
+                Opcode::STORE => {
+                    let (x, address) = self.pop_pair()?;
+                    self.st.store(address, x)?;
+                }
+                Opcode::CFETCH => {
+                    let address = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let byte = self.st.fetch_byte(address)?;
+                    self.st.number_stack.push(i64::from(byte));
+                }
+                Opcode::CSTORE => {
+                    // Only the low byte of the value is stored
+                    let (x, address) = self.pop_pair()?;
+                    self.st.store_byte(address, x as u8)?;
+                }
+                Opcode::CMOVE => {
+                    // ( from to count -- ) one byte at a time from the lowest address up, so an
+                    // overlapping move to a higher address repeats the start
+                    let (to, count) = self.pop_pair()?;
+                    let from = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    for i in 0..count.max(0) {
+                        let byte = self.st.fetch_byte(from.wrapping_add(i))?;
+                        self.st.store_byte(to.wrapping_add(i), byte)?;
+                    }
+                }
+                Opcode::CHECKSUM(checksum) => {
+                    let (address, length) = self.pop_pair()?;
+                    let bytes = self.st.fetch_bytes(address, length)?;
+                    self.st
+                        .number_stack
+                        .push(i64::from(checksum.compute(&bytes)));
+                }
+                Opcode::DO => {
+                    let (limit, start) = self.pop_pair()?;
+                    self.st.loop_stack.push((start, limit));
+                }
+                Opcode::QDO => {
+                    let (limit, start) = self.pop_pair()?;
+                    if limit == start {
+                        self.st.number_stack.push(-1);
+                    } else {
+                        self.st.loop_stack.push((start, limit));
+                        self.st.number_stack.push(0);
+                    }
+                }
+                Opcode::PLUSLOOP => {
+                    let n = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let (index, limit) = self
+                        .st
+                        .loop_stack
+                        .last_mut()
+                        .ok_or(StackMachineError::LoopStackUnderflow)?;
+                    // Finished when index - limit changes sign going from limit - 1 to limit,
+                    // which works for steps either way and for loops that wrap around
+                    let before = index.wrapping_sub(*limit);
+                    let after = before.wrapping_add(n);
+                    *index = index.wrapping_add(n);
+                    if (before ^ after) & (before ^ n) < 0 {
+                        self.st.loop_stack.pop();
+                        self.st.number_stack.push(-1);
+                    } else {
+                        self.st.number_stack.push(0);
+                    }
+                }
+                Opcode::INDEX(depth) => {
+                    let loops = &self.st.loop_stack;
+                    let (index, _) = loops
+                        .len()
+                        .checked_sub(1 + depth as usize)
+                        .map(|i| loops[i])
+                        .ok_or(StackMachineError::LoopStackUnderflow)?;
+                    self.st.number_stack.push(index);
+                }
+                Opcode::UNLOOP => {
+                    self.st
+                        .loop_stack
+                        .pop()
+                        .ok_or(StackMachineError::LoopStackUnderflow)?;
+                }
+                Opcode::HERE => {
+                    let here = self.st.here();
+                    self.st.number_stack.push(here);
+                }
+                Opcode::RDEPTH => {
+                    let depth = self.st.return_stack.len() as i64;
+                    self.st.number_stack.push(depth);
+                }
+                Opcode::GASUSED => {
+                    let used = i64::try_from(self.st.gas_used).unwrap_or(i64::MAX);
+                    self.st.number_stack.push(used);
+                }
+                Opcode::GASLEFT => {
+                    let left = match gas_limit {
+                        GasLimit::Limited(x) => {
+                            i64::try_from(x.saturating_sub(self.st.gas_used)).unwrap_or(i64::MAX)
+                        }
+                        GasLimit::Unlimited => i64::MAX,
+                    };
+                    self.st.number_stack.push(left);
+                }
+                Opcode::ALLOT => {
+                    let n = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let bytes =
+                        usize::try_from(n).map_err(|_| StackMachineError::OutOfDomain(n))?;
+                    self.st.allocate(bytes.div_ceil(CELL_SIZE as usize))?;
+                }
+                Opcode::EXT(id) => match self.ext_opcodes.get_mut(&id) {
+                    Some(handler) => handler.execute(id, &mut self.st)?,
+                    None => return Err(StackMachineError::UnknownExtOpcode(id)),
+                },
+                Opcode::FETCHLE(width) => self.fetch_integer(width, false)?,
+                Opcode::FETCHBE(width) => self.fetch_integer(width, true)?,
+                Opcode::STORELE(width) => {
+                    let (x, address) = self.pop_pair()?;
+                    self.st.store_integer(address, x, width, false)?;
+                }
+                Opcode::STOREBE(width) => {
+                    let (x, address) = self.pop_pair()?;
+                    self.st.store_integer(address, x, width, true)?;
+                }
+                // These ignore the arithmetic mode, so scripts can pick what happens on overflow
+                // one operation at a time
+                Opcode::ADDSAT => {
+                    let (a, b) = self.pop_pair()?;
+                    self.st.number_stack.push(a.saturating_add(b));
+                }
+                Opcode::MULSAT => {
+                    let (a, b) = self.pop_pair()?;
+                    self.st.number_stack.push(a.saturating_mul(b));
+                }
+                Opcode::ADDOV => {
+                    // The wrapped sum, with a flag on top that is true when it overflowed
+                    let (a, b) = self.pop_pair()?;
+                    let (sum, overflowed) = a.overflowing_add(b);
+                    self.st.number_stack.push(sum);
+                    self.st.number_stack.push(if overflowed { -1 } else { 0 });
+                }
+                Opcode::ASSERT => {
+                    // The assertion id is on top, with the condition being checked underneath it
+                    let assertion_id = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    let x = self
+                        .st
+                        .number_stack
+                        .pop()
+                        .ok_or(StackMachineError::NumberStackUnderflow)?;
+                    if !self.st.truthiness.is_true(x) {
+                        return Err(StackMachineError::AssertionFailed(assertion_id));
                     }
-                    return Err(StackMachineError::UnhandledTrap);
                 }
-                Opcode::NOP => {}
             };
-            if pc_reset == false {
+            if !pc_reset {
                 self.st.pc += 1;
             }
+            if let Some(checks) = &self.integrity_checks {
+                self.check_integrity(checks, pc)?;
+            }
+
+            self.st.gas_used += 1;
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.gas += 1;
+            }
+            self.st.number_stack_high_water = self
+                .st
+                .number_stack_high_water
+                .max(self.st.number_stack.len());
+            self.st.return_stack_high_water = self
+                .st
+                .return_stack_high_water
+                .max(self.st.return_stack.len());
+            if let Some(w) = self.watchdog.as_mut() {
+                w.consume_gas();
+            }
+
+            if let GasLimit::Limited(x) = gas_limit {
+                if self.st.gas_used > x {
+                    return Err(StackMachineError::RanOutOfGas);
+                }
+            }
+
+            if let Some(limit) = self.memory_limit {
+                let used = self.st.memory_usage().total();
+                if used > limit {
+                    return Err(StackMachineError::MemoryLimitExceeded(used));
+                }
+            }
+
+            if let Some(id) = system_call {
+                return Ok(ExecutionStatus::SystemCall(id));
+            }
+            if yielded {
+                return Ok(ExecutionStatus::Suspended);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute_jr_forward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(2), // Jump to location 6 with the JR statement, relative jump of 1
+            Opcode::JR,
+            Opcode::LDI(3),
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn test_execute_jr_backward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::RET,
+            Opcode::LDI(2),
+            Opcode::LDI(-5), // Jump to the LDI(0)
+            Opcode::JR,
+        ]);
+
+        // Execute the instructions
+        sm.execute(3, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_execute_jrz_forward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(1), // This won't happen because TOS won't be zero...
+            Opcode::LDI(2), // TOS for JRZ
+            Opcode::JRZ,
+            Opcode::LDI(3),
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::LDI(0),
+            Opcode::LDI(2), // Relative Jump of 1
+            Opcode::JRZ,    // Jump over the LDI(6)
+            Opcode::LDI(6),
+            Opcode::LDI(7),
+            Opcode::LDI(8),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 3, 4, 5, 7, 8]);
+    }
+
+    #[test]
+    fn test_execute_jrz_backward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::RET,
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(1),  // This won't happen because TOS won't be zero...
+            Opcode::LDI(-2), // TOS for JRZ
+            Opcode::JRZ,
+            Opcode::LDI(3),
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::LDI(0),
+            Opcode::LDI(-12), // Relative Jump to start of code
+            Opcode::JRZ,      // Jump over the LDI(6)
+            Opcode::LDI(6),
+            Opcode::LDI(7),
+            Opcode::LDI(8),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(2, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn test_execute_jrnz_forward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(0), // This won't happen because TOS is zero...
+            Opcode::LDI(2), // TOS for JRZ
+            Opcode::JRNZ,
+            Opcode::LDI(3),
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::LDI(1),
+            Opcode::LDI(2), // Relative Jump of 1
+            Opcode::JRNZ,   // Jump over the LDI(6)
+            Opcode::LDI(6),
+            Opcode::LDI(7),
+            Opcode::LDI(8),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 3, 4, 5, 7, 8]);
+    }
+
+    #[test]
+    fn test_execute_jrnz_backward() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::RET,
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(0),  // This won't happen because TOS is zero...
+            Opcode::LDI(-2), // TOS for JRZ
+            Opcode::JRNZ,
+            Opcode::LDI(3),
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::LDI(1),
+            Opcode::LDI(-12), // Relative Jump to start of code
+            Opcode::JRNZ,     // Jump over the LDI(6)
+            Opcode::LDI(6),
+            Opcode::LDI(7),
+            Opcode::LDI(8),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(2, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn test_execute_cmpz_1() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 321, 0]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::CMPZ, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123_i64, 321, 0]);
+    }
+
+    #[test]
+    fn test_execute_cmpz_2() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 321, 1]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::CMPZ, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123_i64, 321, -1]);
+    }
+
+    #[test]
+    fn test_execute_cmpz_truthiness() {
+        for (truthiness, flags) in [
+            (Truthiness::NonZero, vec![0_i64, -1, -1]),
+            (Truthiness::AllBitsSet, vec![0_i64, 0, -1]),
+        ] {
+            let mut sm = StackMachine::new();
+            sm.st.truthiness = truthiness;
+
+            sm.st.opcodes.extend_from_slice(&[
+                Opcode::LDI(0),
+                Opcode::CMPZ,
+                Opcode::LDI(1),
+                Opcode::CMPZ,
+                Opcode::LDI(-1),
+                Opcode::CMPZ,
+                Opcode::RET,
+            ]);
+            sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+            assert_eq!(sm.st.number_stack, flags);
+        }
+    }
+
+    #[test]
+    fn test_execute_cmpnz_1() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 321, 0]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::CMPNZ, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123_i64, 321, -1]);
+    }
+
+    #[test]
+    fn test_execute_cmpnz_2() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 321, 1]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::CMPNZ, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123_i64, 321, 0]);
+    }
+
+    #[test]
+    fn test_execute_call() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(5),
+            Opcode::CALL,
+            Opcode::LDI(1),
+            Opcode::RET,
+            Opcode::LDI(2),
+            Opcode::LDI(10),
+            Opcode::CALL,
+            Opcode::LDI(3),
+            Opcode::RET,
+            Opcode::LDI(4),
+            Opcode::LDI(15),
+            Opcode::CALL,
+            Opcode::LDI(5),
+            Opcode::RET,
+            Opcode::LDI(6),
+            Opcode::LDI(20),
+            Opcode::CALL,
+            Opcode::LDI(7),
+            Opcode::RET,
+            Opcode::LDI(8),
+            Opcode::LDI(25),
+            Opcode::CALL,
+            Opcode::LDI(9),
+            Opcode::RET,
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(
+            sm.st.number_stack,
+            vec![321, 39483, 0, 2, 4, 6, 8, 9, 7, 5, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_execute_ldi() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_execute_pop() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::POP,
+            Opcode::LDI(2),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_execute_pop_error() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::POP,
+            Opcode::POP,
+            Opcode::POP,
+            Opcode::POP,
+            Opcode::POP,
+            Opcode::LDI(2),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+    }
+
+    #[test]
+    fn test_execute_swap() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(1),
+            Opcode::SWAP,
+            Opcode::LDI(2),
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_execute_add() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 321]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::ADD, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![444]);
+    }
+
+    #[test]
+    fn test_execute_sub() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 444]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::SUB, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123]);
+    }
+
+    #[test]
+    fn test_execute_mul() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 123]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::MUL, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![39483]);
+    }
+
+    #[test]
+    fn test_execute_div() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::DIV, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123]);
+    }
+
+    #[test]
+    fn test_execute_division_modes() {
+        let mut sm = StackMachine::new();
+
+        // -7 divided by 2, both ways, then by the machine's policy
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(-7),
+            Opcode::LDI(2),
+            Opcode::SMREM,
+            Opcode::LDI(-7),
+            Opcode::LDI(2),
+            Opcode::FMMOD,
+            Opcode::LDI(-7),
+            Opcode::LDI(2),
+            Opcode::DIVMOD,
+            Opcode::LDI(-2),
+            Opcode::LDI(7),
+            Opcode::DIV,
+            Opcode::RET,
+        ]);
+
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![-1, -3, 1, -4, -1, -3, -3]);
+
+        sm.st.number_stack.clear();
+        sm.st.division_mode = DivisionMode::Floored;
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![-1, -3, 1, -4, 1, -4, -4]);
+
+        // The remainder takes the sign of the divisor when floored
+        assert_eq!(DivisionMode::Floored.divide(7, -2), Some((-1, -4)));
+        assert_eq!(DivisionMode::Symmetric.divide(7, -2), Some((1, -3)));
+        assert_eq!(
+            DivisionMode::Floored.divide(i64::MIN, -1),
+            Some((0, i64::MIN))
+        );
+        assert_eq!(DivisionMode::Floored.divide(1, 0), None);
+    }
+
+    #[test]
+    fn test_execute_not() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 0]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::NOT, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![321_i64, -1]);
+    }
+
+    #[test]
+    fn test_execute_dup() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[123, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[Opcode::DUP, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![123, 39483, 39483]);
+    }
+
+    #[test]
+    fn test_execute_pick_roll() {
+        let mut sm = StackMachine::new();
+
+        sm.st.number_stack.extend_from_slice(&[1, 2, 3, 4]);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::PICK(3),
+            Opcode::ROLL(2),
+            Opcode::PICK(0),
+            Opcode::RET,
+            Opcode::ROLL(9),
+        ]);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![1, 2, 4, 1, 3, 3]);
+
+        match sm.execute(4, GasLimit::Limited(100)) {
+            Err(StackMachineError::NumberStackUnderflow) => (),
+            r => panic!("expected an underflow, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_high_water() {
+        let mut sm = StackMachine::new();
+
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::LDI(6),
+            Opcode::CALL,
+            Opcode::POP,
+            Opcode::RET,
+            Opcode::LDI(3),
+            Opcode::ADD,
+            Opcode::RET,
+        ]);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![1]);
+        assert_eq!(sm.st.high_water(), (3, 1));
+
+        sm.st.reset_high_water();
+        assert_eq!(sm.st.high_water(), (0, 0));
+    }
+
+    #[test]
+    fn test_execute_over() {
+        let mut sm = StackMachine::new();
+
+        sm.st.number_stack.extend_from_slice(&[123, 39483]);
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::OVER, Opcode::RET, Opcode::POP, Opcode::OVER]);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![123, 39483, 123]);
+
+        // With one cell there is no second one to copy
+        sm.st.number_stack.truncate(1);
+        match sm.execute(3, GasLimit::Limited(100)) {
+            Err(StackMachineError::NumberStackUnderflow) => (),
+            r => panic!("expected an underflow, got {:?}", r),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_execute_run_out_of_gas() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0),
+            Opcode::LDI(5),
+            Opcode::CALL,
+            Opcode::LDI(1),
+            Opcode::RET,
+            Opcode::LDI(2),
+            Opcode::LDI(10),
+            Opcode::CALL,
+            Opcode::LDI(3),
+            Opcode::RET,
+            Opcode::LDI(4),
+            Opcode::LDI(15),
+            Opcode::CALL,
+            Opcode::LDI(5),
+            Opcode::RET,
+            Opcode::LDI(6),
+            Opcode::LDI(20),
+            Opcode::CALL,
+            Opcode::LDI(7),
+            Opcode::RET,
+            Opcode::LDI(8),
+            Opcode::LDI(25),
+            Opcode::CALL,
+            Opcode::LDI(9),
+            Opcode::RET,
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(10)).unwrap();
+    }
+
+    #[test]
+    fn test_handle_trap_1() {
+        let mut sm = StackMachine::new();
+
+        sm.trap_handlers
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                st.number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                st.number_stack.push(200);
+                Ok(TrapHandled::Handled)
+            })));
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![200]);
+    }
+
+    #[test]
+    fn test_handle_trap_2() {
+        let mut sm = StackMachine::new();
+
+        sm.trap_handlers
+            .push(Box::from(TrapHandler::new(-100, |_trap_id, st| {
+                st.number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                st.number_stack.push(-100);
+                Ok(TrapHandled::Handled)
+            })));
+        sm.trap_handlers
+            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
+                st.number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                st.number_stack.push(200);
+                Ok(TrapHandled::Handled)
+            })));
+        sm.trap_handlers
+            .push(Box::from(TrapHandler::new(-200, |_trap_id, st| {
+                st.number_stack
+                    .pop()
+                    .ok_or(StackMachineError::NumberStackUnderflow)?;
+                st.number_stack.push(-200);
+                Ok(TrapHandled::Handled)
+            })));
+
+        // Populate the number stack, with a value (50), and the trap number (100)
+        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(sm.st.number_stack, vec![200]);
+    }
+
+    #[test]
+    fn test_unhandled_trap_1() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack, with a value (50), and the trap number (100)
+        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
+
+        // Put the opcodes into the *memory*
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+
+        // Execute the instructions
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::UnhandledTrap) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_execute_assert() {
+        let mut sm = StackMachine::new();
+
+        // Populate the number stack with a passing condition and a failing one
+        sm.st.number_stack.extend_from_slice(&[0, 5]);
+        // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::ASSERT,
+            Opcode::LDI(2),
+            Opcode::ASSERT,
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::AssertionFailed(2)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+
+        // A condition that isn't -1 fails when only all bits set counts as true
+        let mut sm = StackMachine::new();
+        sm.st.truthiness = Truthiness::AllBitsSet;
+        sm.st.number_stack.extend_from_slice(&[5, -1]);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::ASSERT,
+            Opcode::LDI(2),
+            Opcode::ASSERT,
+            Opcode::RET,
+        ]);
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::AssertionFailed(2)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_execute_empty() {
+        let mut sm = StackMachine::new();
+
+        assert!(matches!(
+            sm.execute(0, GasLimit::Limited(100)),
+            Ok(ExecutionStatus::Finished)
+        ));
+        assert_eq!(sm.st.gas_used(), 0);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut sm = StackMachine::new();
+        sm.memory_limit = Some(4096);
+
+        // DUP forever, gas alone would let the stack grow to a million cells
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::DUP,
+            Opcode::LDI(1),
+            Opcode::JMP,
+        ]);
+
+        match sm.execute(0, GasLimit::Limited(1_000_000)) {
+            Err(StackMachineError::MemoryLimitExceeded(n)) => assert!(n > 4096),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert!(sm.st.number_stack.len() < 512);
+        assert_eq!(
+            sm.st.memory_usage().total(),
+            sm.st.memory_usage().stack_bytes + 4 * mem::size_of::<Opcode>()
+        );
+
+        // Squaring forever, the big integers it makes count towards the limit
+        let mut sm = StackMachine::new();
+        sm.memory_limit = Some(4096);
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(i64::MAX),
+            Opcode::DUP,
+            Opcode::MUL,
+            Opcode::LDI(1),
+            Opcode::JMP,
+        ]);
+
+        match sm.execute(0, GasLimit::Limited(1_000_000)) {
+            Err(StackMachineError::MemoryLimitExceeded(n)) => assert!(n > 4096),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert!(sm.st.memory_usage().big_number_bytes > 2048);
+        sm.st.clear_run_state();
+        assert_eq!(sm.st.memory_usage().big_number_bytes, 0);
+    }
+
+    #[test]
+    fn test_plus_loop_boundary() {
+        // ( limit start step ) and the indexes the body should see
+        for (limit, start, step, indexes) in [
+            (3, 0, 1, vec![0, 1, 2]),
+            (10, 0, 4, vec![0, 4, 8]),
+            (0, 3, -1, vec![3, 2, 1, 0]),
+            (0, 10, -4, vec![10, 6, 2]),
+            // Counting up to i64::MIN wraps round through i64::MAX
+            (i64::MIN, 0, i64::MAX, vec![0, i64::MAX]),
+            // A start past the limit goes all the way round, so this one runs out of gas
+            (0, 1, 1, vec![]),
+        ] {
+            let mut sm = StackMachine::new();
+            sm.st.opcodes.extend_from_slice(&[
+                Opcode::LDI(limit),
+                Opcode::LDI(start),
+                Opcode::DO,
+                Opcode::INDEX(0),
+                Opcode::LDI(step),
+                Opcode::PLUSLOOP,
+                Opcode::LDI(-4),
+                Opcode::JRZ,
+                Opcode::RET,
+            ]);
+
+            match sm.execute(0, GasLimit::Limited(1000)) {
+                Ok(_) => assert_eq!(sm.st.number_stack, indexes),
+                Err(StackMachineError::RanOutOfGas) => assert!(indexes.is_empty()),
+                r => panic!("Incorrect result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_code_address() {
+        for (target, jump, address) in [
+            (-1, Opcode::JMP, -1),
+            (1000, Opcode::CALL, 1000),
+            (-5, Opcode::JR, -4),
+            (i64::MAX, Opcode::JR, i64::MAX),
+        ] {
+            let mut sm = StackMachine::new();
+            sm.st
+                .opcodes
+                .extend_from_slice(&[Opcode::LDI(target), jump, Opcode::RET]);
+
+            match sm.execute(0, GasLimit::Limited(10)) {
+                Err(StackMachineError::InvalidCodeAddress(a)) => assert_eq!(a, address),
+                r => panic!("Incorrect error type returned {:?}", r),
+            }
+            // A failed CALL doesn't leave a return address behind
+            assert!(sm.st.return_stack().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_execute_ext() {
+        let mut sm = StackMachine::new();
+        // Q16.16 fixed point multiply
+        let fixed_mul = |_, st: &mut StackMachineState| {
+            let x = st
+                .number_stack
+                .pop()
+                .ok_or(StackMachineError::NumberStackUnderflow)?;
+            let y = st
+                .number_stack
+                .pop()
+                .ok_or(StackMachineError::NumberStackUnderflow)?;
+            st.number_stack.push(x.wrapping_mul(y) >> 16);
+            Ok(())
+        };
+        sm.ext_opcodes.insert(7, Box::new(fixed_mul));
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(3 << 16),
+            Opcode::LDI(1 << 15),
+            Opcode::EXT(7),
+            Opcode::EXT(8),
+            Opcode::RET,
+        ]);
+
+        match sm.execute(0, GasLimit::Limited(10)) {
+            Err(StackMachineError::UnknownExtOpcode(8)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![3 << 15]);
+    }
+
+    #[test]
+    fn test_bulk_stack_operations() {
+        let mut st = StackMachineState::new();
+        st.push_slice(&[1, 2, 3, 4]);
+
+        let mut buffer = [0; 3];
+        st.pop_n_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [2, 3, 4]);
+        match st.pop_n_into(&mut buffer) {
+            Err(StackMachineError::NumberStackUnderflow) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(st.number_stack, vec![1]);
+
+        st.push_slice(&[5]);
+        assert_eq!(st.drain_stack(), vec![1, 5]);
+        assert!(st.number_stack.is_empty());
+    }
+
+    #[test]
+    fn test_execute_allot() {
+        let mut sm = StackMachine::new();
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::HERE,
+            Opcode::LDI(12),
+            Opcode::ALLOT,
+            Opcode::HERE,
+            Opcode::LDI(-1),
+            Opcode::ALLOT,
+            Opcode::RET,
+        ]);
+
+        // 12 bytes take two cells
+        match sm.execute(0, GasLimit::Limited(10)) {
+            Err(StackMachineError::OutOfDomain(-1)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![0, 16]);
+        assert_eq!(sm.st.data(), &[0, 0]);
+    }
+
+    #[test]
+    fn test_execute_fetch_store() {
+        let mut sm = StackMachine::new();
+        sm.st.allocate(2).unwrap();
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(-3),
+            Opcode::LDI(8),
+            Opcode::STORE,
+            Opcode::LDI(8),
+            Opcode::FETCH,
+            Opcode::LDI(4),
+            Opcode::FETCH,
+            Opcode::RET,
+        ]);
+
+        // Only whole, aligned cells can be fetched and stored
+        match sm.execute(0, GasLimit::Limited(10)) {
+            Err(StackMachineError::InvalidAddress(4)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![-3]);
+        assert_eq!(sm.st.data(), &[0, -3]);
+    }
+
+    #[test]
+    fn test_execute_introspection() {
+        let mut sm = StackMachine::new();
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(3),
+            Opcode::CALL,
+            Opcode::RET,
+            Opcode::RDEPTH,
+            Opcode::GASUSED,
+            Opcode::GASLEFT,
+            Opcode::RET,
+        ]);
+
+        // Gas is counted once an opcode has run, so GASUSED doesn't count itself
+        sm.execute(0, GasLimit::Limited(10)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![1, 3, 6]);
+
+        sm.st.number_stack.clear();
+        sm.execute(5, GasLimit::Unlimited).unwrap();
+        assert_eq!(sm.st.number_stack, vec![i64::MAX]);
+    }
+
+    #[test]
+    fn test_execute_yield() {
+        let mut sm = StackMachine::new();
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::YIELD,
+            Opcode::LDI(2),
+            Opcode::LDI(3),
+            Opcode::RET,
+        ]);
+
+        assert_eq!(
+            sm.execute(0, GasLimit::Limited(2)).unwrap(),
+            ExecutionStatus::Suspended
+        );
+        assert_eq!(sm.st.number_stack, vec![1]);
+
+        // Each resumption gets the gas limit to itself
+        assert_eq!(
+            sm.resume_yielded(GasLimit::Limited(3)).unwrap(),
+            ExecutionStatus::Finished
+        );
+        assert_eq!(sm.st.number_stack, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_execute_request() {
+        let mut sm = StackMachine::new();
+        sm.st.number_stack.push(9);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(4),
+            Opcode::LDI(5),
+            Opcode::LDI(2),
+            Opcode::LDI(70),
+            Opcode::REQUEST,
+            Opcode::ADD,
+            Opcode::RET,
+        ]);
+
+        assert_eq!(
+            sm.execute(0, GasLimit::Limited(10)).unwrap(),
+            ExecutionStatus::Suspended
+        );
+        assert_eq!(
+            sm.st.request(),
+            Some(&HostRequest {
+                code: 70,
+                arguments: vec![4, 5]
+            })
+        );
+        assert_eq!(sm.st.number_stack, vec![9]);
+
+        sm.resume_with(&[20], GasLimit::Limited(10)).unwrap();
+        assert_eq!(sm.st.request(), None);
+        assert_eq!(sm.st.number_stack, vec![29]);
+    }
+
+    #[test]
+    fn test_loop_stack_underflow() {
+        let mut sm = StackMachine::new();
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::INDEX(0), Opcode::RET]);
 
-            self.st.gas_used += 1;
+        match sm.execute(0, GasLimit::Limited(10)) {
+            Err(StackMachineError::LoopStackUnderflow) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
 
-            if let GasLimit::Limited(x) = gas_limit {
-                if self.st.gas_used > x {
-                    return Err(StackMachineError::RanOutOfGas);
-                }
-            }
+    // Lets tests look at what a program printed after the StackMachine has taken ownership of the output
+    #[derive(Clone, Default)]
+    struct CapturedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for CapturedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    impl CapturedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
 
     #[test]
-    fn test_execute_jr_forward() {
+    fn test_execute_udot() {
         let mut sm = StackMachine::new();
+        let captured = CapturedOutput::default();
+        sm.st.output = Box::new(captured.clone());
 
         // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        sm.st.number_stack.extend_from_slice(&[-1, 42]);
         // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(1),
-            Opcode::LDI(2),
-            Opcode::LDI(2), // Jump to location 6 with the JR statement, relative jump of 1
-            Opcode::JR,
-            Opcode::LDI(3),
-            Opcode::LDI(4),
-            Opcode::LDI(5),
-            Opcode::RET,
-        ]);
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::UDOT, Opcode::UDOT, Opcode::RET]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 4, 5]);
+        assert_eq!(captured.contents(), "42 18446744073709551615 ");
+        assert_eq!(sm.st.number_stack, Vec::<i64>::new());
     }
 
     #[test]
-    fn test_execute_jr_backward() {
+    fn test_execute_dot_dots() {
         let mut sm = StackMachine::new();
+        let captured = CapturedOutput::default();
+        sm.st.output = Box::new(captured.clone());
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(1),
-            Opcode::RET,
-            Opcode::LDI(2),
-            Opcode::LDI(-5), // Jump to the LDI(0)
-            Opcode::JR,
-        ]);
+        sm.st.number_stack.extend_from_slice(&[-1, 42]);
+        sm.st
+            .opcodes
+            .extend_from_slice(&[Opcode::DOTS, Opcode::DOT, Opcode::DOTS, Opcode::RET]);
 
-        // Execute the instructions
-        sm.execute(3, GasLimit::Limited(100)).unwrap();
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 2, 0, 1]);
+        assert_eq!(captured.contents(), "<2> -1 42 42 <1> -1 ");
+        assert_eq!(sm.st.number_stack, vec![-1]);
     }
 
     #[test]
-    fn test_execute_jrz_forward() {
+    fn test_execute_ult_ugt() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // -1 is the largest unsigned number, so it is never less than 1
+        sm.st.number_stack.extend_from_slice(&[-1, 1]);
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
+            Opcode::ULT,
+            Opcode::LDI(-1),
+            Opcode::LDI(1),
+            Opcode::UGT,
             Opcode::LDI(1),
             Opcode::LDI(2),
-            Opcode::LDI(1), // This won't happen because TOS won't be zero...
-            Opcode::LDI(2), // TOS for JRZ
-            Opcode::JRZ,
-            Opcode::LDI(3),
-            Opcode::LDI(4),
-            Opcode::LDI(5),
-            Opcode::LDI(0),
-            Opcode::LDI(2), // Relative Jump of 1
-            Opcode::JRZ,    // Jump over the LDI(6)
-            Opcode::LDI(6),
-            Opcode::LDI(7),
-            Opcode::LDI(8),
+            Opcode::ULT,
             Opcode::RET,
         ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 3, 4, 5, 7, 8]);
+        assert_eq!(sm.st.number_stack, vec![0, -1, -1]);
     }
 
     #[test]
-    fn test_execute_jrz_backward() {
+    fn test_execute_comparisons() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
+        // Unlike ULT, -1 is less than 1
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::RET,
+            Opcode::LDI(-1),
             Opcode::LDI(1),
-            Opcode::LDI(2),
-            Opcode::LDI(1),  // This won't happen because TOS won't be zero...
-            Opcode::LDI(-2), // TOS for JRZ
-            Opcode::JRZ,
+            Opcode::LT,
+            Opcode::LDI(-1),
+            Opcode::LDI(1),
+            Opcode::GT,
             Opcode::LDI(3),
-            Opcode::LDI(4),
-            Opcode::LDI(5),
-            Opcode::LDI(0),
-            Opcode::LDI(-12), // Relative Jump to start of code
-            Opcode::JRZ,      // Jump over the LDI(6)
-            Opcode::LDI(6),
-            Opcode::LDI(7),
-            Opcode::LDI(8),
+            Opcode::LDI(3),
+            Opcode::EQ,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(2, GasLimit::Limited(100)).unwrap();
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 2, 3, 4, 5, 0]);
+        assert_eq!(sm.st.number_stack, vec![-1, 0, -1]);
     }
 
     #[test]
-    fn test_execute_jrnz_forward() {
+    fn test_execute_bitwise() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(1),
-            Opcode::LDI(2),
-            Opcode::LDI(0), // This won't happen because TOS is zero...
-            Opcode::LDI(2), // TOS for JRZ
-            Opcode::JRNZ,
-            Opcode::LDI(3),
-            Opcode::LDI(4),
-            Opcode::LDI(5),
-            Opcode::LDI(1),
-            Opcode::LDI(2), // Relative Jump of 1
-            Opcode::JRNZ,   // Jump over the LDI(6)
-            Opcode::LDI(6),
-            Opcode::LDI(7),
-            Opcode::LDI(8),
+            Opcode::LDI(0b1100),
+            Opcode::LDI(0b1010),
+            Opcode::AND,
+            Opcode::LDI(0b1100),
+            Opcode::LDI(0b1010),
+            Opcode::OR,
+            Opcode::LDI(0b1100),
+            Opcode::LDI(0b1010),
+            Opcode::XOR,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2, 3, 4, 5, 7, 8]);
+        assert_eq!(sm.st.number_stack, vec![0b1000, 0b1110, 0b0110]);
     }
 
     #[test]
-    fn test_execute_jrnz_backward() {
+    fn test_execute_shifts() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
+        // Right shifts bring in zeros even for negative numbers
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::RET,
-            Opcode::LDI(1),
-            Opcode::LDI(2),
-            Opcode::LDI(0),  // This won't happen because TOS is zero...
-            Opcode::LDI(-2), // TOS for JRZ
-            Opcode::JRNZ,
             Opcode::LDI(3),
             Opcode::LDI(4),
-            Opcode::LDI(5),
+            Opcode::LSHIFT,
+            Opcode::LDI(-1),
+            Opcode::LDI(60),
+            Opcode::RSHIFT,
             Opcode::LDI(1),
-            Opcode::LDI(-12), // Relative Jump to start of code
-            Opcode::JRNZ,     // Jump over the LDI(6)
-            Opcode::LDI(6),
-            Opcode::LDI(7),
-            Opcode::LDI(8),
+            Opcode::LDI(63),
+            Opcode::LSHIFT,
+            Opcode::LDI(-1),
+            Opcode::LDI(64),
+            Opcode::LSHIFT,
+            Opcode::LDI(-1),
+            Opcode::LDI(100),
+            Opcode::RSHIFT,
+            Opcode::LDI(1),
+            Opcode::LDI(-1),
+            Opcode::LSHIFT,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(2, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 2, 3, 4, 5, 0]);
-    }
-
-    #[test]
-    fn test_execute_cmpz_1() {
-        let mut sm = StackMachine::new();
-
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 321, 0]);
-        // Put the opcodes into the *memory*
-        sm.st
-            .opcodes
-            .extend_from_slice(&[Opcode::CMPZ, Opcode::RET]);
-
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![123_i64, 321, 0]);
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::OutOfDomain(-1)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![48, 15, i64::MIN, 0, 0]);
     }
 
     #[test]
-    fn test_execute_cmpz_2() {
+    fn test_execute_ummul() {
         let mut sm = StackMachine::new();
 
         // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 321, 1]);
-        // Put the opcodes into the *memory*
-        sm.st
-            .opcodes
-            .extend_from_slice(&[Opcode::CMPZ, Opcode::RET]);
+        sm.st.number_stack.extend_from_slice(&[3, 4]);
+        // Put the opcodes into the *memory*, the second multiply overflows into the high cell
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::UMMUL,
+            Opcode::LDI(-1),
+            Opcode::LDI(2),
+            Opcode::UMMUL,
+            Opcode::RET,
+        ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![123_i64, 321, -1]);
+        assert_eq!(sm.st.number_stack, vec![12, 0, -2, 1]);
     }
 
     #[test]
-    fn test_execute_cmpnz_1() {
+    fn test_execute_integer_math() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 321, 0]);
         // Put the opcodes into the *memory*
-        sm.st
-            .opcodes
-            .extend_from_slice(&[Opcode::CMPNZ, Opcode::RET]);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(17),
+            Opcode::SQRT,
+            Opcode::LDI(-12),
+            Opcode::LDI(18),
+            Opcode::GCD,
+            Opcode::LDI(4),
+            Opcode::LDI(-6),
+            Opcode::LCM,
+            Opcode::LDI(0),
+            Opcode::LDI(5),
+            Opcode::LCM,
+            Opcode::LDI(-3),
+            Opcode::LDI(3),
+            Opcode::POW,
+            Opcode::LDI(7),
+            Opcode::LDI(0),
+            Opcode::POW,
+            Opcode::LDI(1025),
+            Opcode::LOG2,
+            Opcode::RET,
+        ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![123_i64, 321, -1]);
+        assert_eq!(sm.st.number_stack, vec![4, 6, 12, 0, -27, 1, 10]);
     }
 
     #[test]
-    fn test_execute_cmpnz_2() {
-        let mut sm = StackMachine::new();
-
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 321, 1]);
-        // Put the opcodes into the *memory*
-        sm.st
-            .opcodes
-            .extend_from_slice(&[Opcode::CMPNZ, Opcode::RET]);
+    fn test_execute_integer_math_out_of_domain() {
+        for (opcodes, bad) in [
+            (vec![Opcode::LDI(-4), Opcode::SQRT], -4),
+            (vec![Opcode::LDI(2), Opcode::LDI(-1), Opcode::POW], -1),
+            // Too big for a cell, which only promoting mode has room for
+            (vec![Opcode::LDI(2), Opcode::LDI(63), Opcode::POW], 63),
+            (
+                vec![Opcode::LDI(i64::MAX), Opcode::LDI(2), Opcode::LCM],
+                i64::MAX,
+            ),
+            (vec![Opcode::LDI(0), Opcode::LOG2], 0),
+            (
+                vec![Opcode::LDI(i64::MIN), Opcode::LDI(0), Opcode::GCD],
+                i64::MIN,
+            ),
+        ] {
+            let mut sm = StackMachine::new();
+            sm.st.opcodes.extend_from_slice(&opcodes);
+            sm.st.opcodes.push(Opcode::RET);
 
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![123_i64, 321, 0]);
+            match sm.execute(0, GasLimit::Limited(100)) {
+                Err(StackMachineError::OutOfDomain(n)) => assert_eq!(n, bad),
+                r => panic!("Incorrect result: {:?}", r),
+            }
+        }
     }
 
     #[test]
-    fn test_execute_call() {
+    fn test_execute_promoting_lcm_pow() {
         let mut sm = StackMachine::new();
-
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(5),
-            Opcode::CALL,
-            Opcode::LDI(1),
-            Opcode::RET,
             Opcode::LDI(2),
-            Opcode::LDI(10),
-            Opcode::CALL,
+            Opcode::LDI(64),
+            Opcode::POW,
+            Opcode::LDI(i64::MAX),
+            Opcode::LDI(-2),
+            Opcode::LCM,
+            // The LCM of two big integers, 2^64 and 3 * 2^64
+            Opcode::LDI(2),
+            Opcode::LDI(64),
+            Opcode::POW,
+            Opcode::DUP,
             Opcode::LDI(3),
-            Opcode::RET,
+            Opcode::MUL,
+            Opcode::LCM,
+            Opcode::LDI(-3),
+            Opcode::LDI(3),
+            Opcode::POW,
             Opcode::LDI(4),
-            Opcode::LDI(15),
-            Opcode::CALL,
-            Opcode::LDI(5),
-            Opcode::RET,
             Opcode::LDI(6),
-            Opcode::LDI(20),
-            Opcode::CALL,
-            Opcode::LDI(7),
-            Opcode::RET,
-            Opcode::LDI(8),
-            Opcode::LDI(25),
-            Opcode::CALL,
-            Opcode::LDI(9),
-            Opcode::RET,
+            Opcode::LCM,
             Opcode::RET,
         ]);
-
-        // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
-
+        let stack: Vec<String> = sm
+            .st
+            .number_stack
+            .iter()
+            .map(|cell| sm.st.format_cell(*cell))
+            .collect();
         assert_eq!(
-            sm.st.number_stack,
-            vec![321, 39483, 0, 2, 4, 6, 8, 9, 7, 5, 3, 1]
+            stack,
+            vec![
+                "18446744073709551616",
+                "18446744073709551614",
+                "55340232221128654848",
+                "-27",
+                "12"
+            ]
         );
+
+        // Answers past MAX_BIG_NUMBER_DIGITS are refused rather than built
+        let mut sm = StackMachine::new();
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(3),
+            Opcode::LDI(1 << 30),
+            Opcode::POW,
+            Opcode::RET,
+        ]);
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::OutOfDomain(n)) => assert_eq!(n, 1 << 30),
+            r => panic!("Incorrect result: {:?}", r),
+        }
     }
 
     #[test]
-    fn test_execute_ldi() {
+    fn test_execute_saturating_and_overflow() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        // Even in promoting mode these stay in a cell
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
+            Opcode::LDI(i64::MAX),
+            Opcode::LDI(1),
+            Opcode::ADDSAT,
+            Opcode::LDI(i64::MIN + 1),
+            Opcode::LDI(3),
+            Opcode::MULSAT,
+            Opcode::LDI(2),
+            Opcode::LDI(3),
+            Opcode::MULSAT,
+            Opcode::LDI(i64::MAX),
             Opcode::LDI(1),
+            Opcode::ADDOV,
             Opcode::LDI(2),
+            Opcode::LDI(3),
+            Opcode::ADDOV,
             Opcode::RET,
         ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 1, 2]);
+        assert_eq!(
+            sm.st.number_stack,
+            vec![i64::MAX, i64::MIN, 6, i64::MIN, -1, 5, 0]
+        );
     }
 
     #[test]
-    fn test_execute_pop() {
+    fn test_execute_array() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        let array = sm.st.allocate_array(3).unwrap();
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(1),
-            Opcode::POP,
+            Opcode::LDI(7),
+            Opcode::LDI(2),
+            Opcode::LDI(array),
+            Opcode::ARRAYSTORE,
             Opcode::LDI(2),
+            Opcode::LDI(array),
+            Opcode::ARRAYFETCH,
+            Opcode::LDI(3),
+            Opcode::LDI(array),
+            Opcode::ARRAYFETCH,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 0, 2]);
+        // Execute the instructions, the last fetch is past the end
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::IndexOutOfBounds(3)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![7]);
+        assert_eq!(sm.st.array(array), Some(&[0_i64, 0, 7][..]));
+        assert_eq!(sm.st.fetch(array - CELL_SIZE).unwrap(), 3);
+        assert!(sm.st.array(array + 1).is_none());
+        match sm.st.fetch(array + 3 * CELL_SIZE) {
+            Err(StackMachineError::InvalidAddress(_)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn test_execute_pop_error() {
+    fn test_execute_mapped_buffer() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        let buffer = SharedBuffer::from_vec(vec![1, 2]);
+        let address = sm.st.map_buffer(buffer.clone()).unwrap();
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
             Opcode::LDI(1),
-            Opcode::POP,
-            Opcode::POP,
-            Opcode::POP,
-            Opcode::POP,
-            Opcode::POP,
+            Opcode::LDI(address),
+            Opcode::ARRAYFETCH,
+            Opcode::LDI(0),
+            Opcode::LDI(address),
+            Opcode::ARRAYSTORE,
             Opcode::LDI(2),
+            Opcode::LDI(address),
+            Opcode::ARRAYFETCH,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        // Execute the instructions, the last fetch is past the end
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::IndexOutOfBounds(2)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(*buffer.borrow(), vec![2, 2]);
+        assert_eq!(sm.st.fetch(address + CELL_SIZE).unwrap(), 2);
+        sm.st.store(address, 5).unwrap();
+        assert_eq!(buffer.borrow()[0], 5);
+        match sm.st.fetch(address + MAPPED_BUFFER_SPAN) {
+            Err(StackMachineError::InvalidAddress(_)) => (),
+            r => panic!("Incorrect result: {:?}", r),
+        }
     }
 
     #[test]
-    fn test_execute_swap() {
+    fn test_execute_bytes() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
+        let address = sm.st.allocate(2).unwrap();
+        let buffer = SharedBuffer::new(1);
+        let mapped = sm.st.map_buffer(buffer.clone()).unwrap();
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(1),
-            Opcode::SWAP,
+            Opcode::LDI(0x1ff),
+            Opcode::LDI(address + 1),
+            Opcode::CSTORE,
+            Opcode::LDI(0x42),
+            Opcode::LDI(address + 2),
+            Opcode::CSTORE,
+            Opcode::LDI(address + 1),
+            Opcode::CFETCH,
+            // Overlapping copy up by one byte repeats the first byte
+            Opcode::LDI(address + 1),
+            Opcode::LDI(address + 2),
+            Opcode::LDI(9),
+            Opcode::CMOVE,
+            Opcode::LDI(address + 1),
+            Opcode::LDI(mapped + 3),
             Opcode::LDI(2),
+            Opcode::CMOVE,
+            Opcode::LDI(address + 16),
+            Opcode::CFETCH,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![321, 39483, 1, 0, 2]);
+        // Execute the instructions, the last fetch is past the end of the data space
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::InvalidAddress(a)) => assert_eq!(a, address + 16),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+        assert_eq!(sm.st.number_stack, vec![0xff]);
+        assert_eq!(sm.st.data(), &[-256_i64, 0xff_ffff]);
+        assert_eq!(*buffer.borrow(), vec![0xffff_i64 << 24]);
     }
 
     #[test]
-    fn test_execute_add() {
+    fn test_execute_integer_packing() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 321]);
+        let address = sm.st.allocate(2).unwrap();
         // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::ADD, Opcode::RET]);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(0x11223344),
+            Opcode::LDI(address + 1),
+            Opcode::STOREBE(4),
+            Opcode::LDI(-2),
+            Opcode::LDI(address + 5),
+            Opcode::STORELE(2),
+            Opcode::LDI(address + 1),
+            Opcode::FETCHLE(4),
+            Opcode::LDI(address + 1),
+            Opcode::FETCHBE(2),
+            Opcode::LDI(address + 5),
+            Opcode::FETCHLE(2),
+            Opcode::LDI(address),
+            Opcode::FETCHBE(8),
+            Opcode::RET,
+        ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![444]);
+        assert_eq!(
+            sm.st.number_stack,
+            vec![0x44332211, 0x1122, 0xfffe, 0x0011223344feff00]
+        );
     }
 
     #[test]
-    fn test_execute_sub() {
+    fn test_execute_div_by_zero() {
         let mut sm = StackMachine::new();
 
         // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 444]);
+        sm.st.number_stack.extend_from_slice(&[0, 39483]);
         // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::SUB, Opcode::RET]);
+        sm.st.opcodes.extend_from_slice(&[Opcode::DIV, Opcode::RET]);
 
         // Execute the instructions
-        sm.execute(0, GasLimit::Limited(100)).unwrap();
-
-        assert_eq!(sm.st.number_stack, vec![123]);
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::DivisionByZero) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
     }
 
     #[test]
-    fn test_execute_mul() {
+    fn test_execute_wrapping_overflow() {
         let mut sm = StackMachine::new();
 
         // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 123]);
+        sm.st.number_stack.extend_from_slice(&[1, i64::MAX]);
         // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::MUL, Opcode::RET]);
+        sm.st.opcodes.extend_from_slice(&[Opcode::ADD, Opcode::RET]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![39483]);
+        assert_eq!(sm.st.number_stack, vec![i64::MIN]);
     }
 
     #[test]
-    fn test_execute_div() {
+    fn test_execute_promoting_overflow() {
         let mut sm = StackMachine::new();
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
 
         // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
-        // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::DIV, Opcode::RET]);
+        sm.st.number_stack.extend_from_slice(&[i64::MAX, i64::MAX]);
+        // Square i64::MAX, then divide it back down again
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::DUP,
+            Opcode::MUL,
+            Opcode::DUP,
+            Opcode::LDI(i64::MAX),
+            Opcode::SWAP,
+            Opcode::DIV,
+            Opcode::RET,
+        ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![123]);
+        let square = sm.st.number_stack[1];
+        assert_eq!(
+            sm.st.format_cell(square),
+            "85070591730234615847396907784232501249"
+        );
+        assert_eq!(sm.st.number_stack[2], i64::MAX);
+        assert_eq!(
+            sm.st.format_cell(sm.st.number_stack[2]),
+            i64::MAX.to_string()
+        );
     }
 
     #[test]
-    fn test_execute_not() {
+    fn test_execute_promoting_comparisons() {
+        // 2^63 compared with 5, with a copy of itself in another handle and with i64::MAX
+        let big = [Opcode::LDI(i64::MAX), Opcode::LDI(1), Opcode::ADD];
+        let mut program = big.to_vec();
+        program.extend_from_slice(&[Opcode::LDI(5), Opcode::GT]);
+        program.extend_from_slice(&big);
+        program.extend_from_slice(&big);
+        program.push(Opcode::EQ);
+        program.extend_from_slice(&big);
+        program.extend_from_slice(&[Opcode::LDI(i64::MAX), Opcode::LT]);
+        program.extend_from_slice(&[Opcode::LDI(10), Opcode::LDI(5), Opcode::GT, Opcode::RET]);
+
         let mut sm = StackMachine::new();
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+        sm.st.opcodes.extend_from_slice(&program);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![-1, -1, 0, -1]);
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 0]);
-        // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::NOT, Opcode::RET]);
+        // Wrapping mode compares the wrapped cells
+        let mut sm = StackMachine::new();
+        sm.st.opcodes.extend_from_slice(&program);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(sm.st.number_stack, vec![0, -1, -1, -1]);
+    }
 
-        // Execute the instructions
+    #[test]
+    fn test_execute_negate_abs_min_max() {
+        let program = [
+            Opcode::LDI(i64::MIN),
+            Opcode::NEGATE,
+            Opcode::LDI(i64::MIN),
+            Opcode::ABS,
+            Opcode::LDI(-5),
+            Opcode::ABS,
+            Opcode::LDI(i64::MIN),
+            Opcode::LDI(i64::MAX),
+            Opcode::MIN,
+            Opcode::LDI(i64::MIN),
+            Opcode::LDI(i64::MAX),
+            Opcode::MAX,
+            Opcode::RET,
+        ];
+
+        // Wrapping mode has nowhere to put 2^63, so i64::MIN negates to itself
+        let mut sm = StackMachine::new();
+        sm.st.opcodes.extend_from_slice(&program);
         sm.execute(0, GasLimit::Limited(100)).unwrap();
+        assert_eq!(
+            sm.st.number_stack,
+            vec![i64::MIN, i64::MIN, 5, i64::MIN, i64::MAX]
+        );
 
-        assert_eq!(sm.st.number_stack, vec![321_i64, -1]);
+        // Promoting mode gives 2^63 a handle, after which a literal i64::MIN refers to that
+        let mut sm = StackMachine::new();
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(i64::MIN),
+            Opcode::ABS,
+            Opcode::LDI(7),
+            Opcode::MAX,
+            Opcode::LDI(i64::MIN),
+            Opcode::NEGATE,
+            Opcode::LDI(-1),
+            Opcode::MIN,
+            Opcode::RET,
+        ]);
+        sm.execute(0, GasLimit::Limited(100)).unwrap();
+        let stack: Vec<String> = sm
+            .st
+            .number_stack
+            .iter()
+            .map(|cell| sm.st.format_cell(*cell))
+            .collect();
+        assert_eq!(stack, vec!["9223372036854775808", "-9223372036854775808"]);
     }
 
     #[test]
-    fn test_execute_dup() {
+    fn test_execute_promoting_reserved_range() {
         let mut sm = StackMachine::new();
+        sm.st.arithmetic_mode = ArithmeticMode::Promoting;
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[123, 39483]);
-        // Put the opcodes into the *memory*
-        sm.st.opcodes.extend_from_slice(&[Opcode::DUP, Opcode::RET]);
+        // i64::MIN is an ordinary result, but collides with the handle range so gets promoted too
+        sm.st.number_stack.extend_from_slice(&[-1, i64::MIN + 1]);
+        sm.st.opcodes.extend_from_slice(&[Opcode::ADD, Opcode::RET]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![123, 39483, 39483]);
+        let handle = sm.st.number_stack[0];
+        assert_eq!(sm.st.big_number(handle), Some(&BigInt::from_i64(i64::MIN)));
+        assert_eq!(sm.st.format_cell(handle), i64::MIN.to_string());
     }
 
     #[test]
-    #[should_panic]
-    fn test_execute_run_out_of_gas() {
+    fn test_execute_syscall_and_resume() {
         let mut sm = StackMachine::new();
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[321, 39483]);
         // Put the opcodes into the *memory*
         sm.st.opcodes.extend_from_slice(&[
-            Opcode::LDI(0),
-            Opcode::LDI(5),
-            Opcode::CALL,
             Opcode::LDI(1),
-            Opcode::RET,
+            Opcode::SYSCALL(7),
             Opcode::LDI(2),
-            Opcode::LDI(10),
-            Opcode::CALL,
-            Opcode::LDI(3),
-            Opcode::RET,
-            Opcode::LDI(4),
-            Opcode::LDI(15),
-            Opcode::CALL,
-            Opcode::LDI(5),
-            Opcode::RET,
-            Opcode::LDI(6),
-            Opcode::LDI(20),
-            Opcode::CALL,
-            Opcode::LDI(7),
-            Opcode::RET,
-            Opcode::LDI(8),
-            Opcode::LDI(25),
-            Opcode::CALL,
-            Opcode::LDI(9),
-            Opcode::RET,
             Opcode::RET,
         ]);
 
-        // Execute the instructions
-        sm.execute(0, GasLimit::Limited(10)).unwrap();
+        // Execute the instructions, the host gets control back at the SYSCALL
+        assert_eq!(
+            sm.execute(0, GasLimit::Limited(100)).unwrap(),
+            ExecutionStatus::SystemCall(7)
+        );
+        assert_eq!(sm.st.number_stack, vec![1]);
+
+        sm.st.number_stack.push(100);
+        assert_eq!(
+            sm.resume(GasLimit::Limited(100)).unwrap(),
+            ExecutionStatus::Finished
+        );
+        assert_eq!(sm.st.number_stack, vec![1, 100, 2]);
+        assert_eq!(sm.st.gas_used(), 3);
     }
 
     #[test]
-    fn test_handle_trap_1() {
+    fn test_execution_continues_after_trap() {
         let mut sm = StackMachine::new();
 
         sm.trap_handlers
             .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
-                st.number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
                 st.number_stack.push(200);
                 Ok(TrapHandled::Handled)
             })));
 
-        // Populate the number stack
-        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
         // Put the opcodes into the *memory*
-        sm.st
-            .opcodes
-            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(100),
+            Opcode::TRAP,
+            Opcode::LDI(300),
+            Opcode::RET,
+        ]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![200]);
+        assert_eq!(sm.st.number_stack, vec![200, 300]);
     }
 
     #[test]
-    fn test_handle_trap_2() {
+    fn test_trap_guard() {
         let mut sm = StackMachine::new();
 
         sm.trap_handlers
-            .push(Box::from(TrapHandler::new(-100, |_trap_id, st| {
-                st.number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                st.number_stack.push(-100);
-                Ok(TrapHandled::Handled)
-            })));
-        sm.trap_handlers
-            .push(Box::from(TrapHandler::new(100, |_trap_id, st| {
-                st.number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                st.number_stack.push(200);
-                Ok(TrapHandled::Handled)
-            })));
-        sm.trap_handlers
-            .push(Box::from(TrapHandler::new(-200, |_trap_id, st| {
-                st.number_stack
-                    .pop()
-                    .ok_or(StackMachineError::NumberStackUnderflow)?;
-                st.number_stack.push(-200);
+            .push(Box::from(TrapHandler::new(100, |_trap_id, _st| {
                 Ok(TrapHandled::Handled)
             })));
+        sm.trap_guard.enabled = true;
+        sm.trap_guard.allow(100, 3..5);
 
-        // Populate the number stack, with a value (50), and the trap number (100)
-        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
+        // Put the opcodes into the *memory*, only the TRAP at address 4 is allowed
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(100),
+            Opcode::TRAP,
+            Opcode::RET,
+            Opcode::LDI(100),
+            Opcode::TRAP,
+            Opcode::RET,
+        ]);
+
+        sm.execute(3, GasLimit::Limited(100)).unwrap();
+
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::TrapNotPermitted(100)) => (),
+            r => panic!("Incorrect error type returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_format_stack() {
+        let mut sm = StackMachine::new();
+
+        assert_eq!(sm.st.format_stack(), "Stack empty\n");
+
+        sm.st
+            .number_stack
+            .extend_from_slice(&[65, -1, 1234, 0, 7, 8, 9, 10, 11, 12, 13]);
+
+        assert_eq!(
+            sm.st.format_stack(),
+            "Stack depth 11\n\
+            \x20 [10]   65  0x0000000000000041  'A'\n\
+            \x20 [ 9]   -1  0xFFFFFFFFFFFFFFFF  '.'\n\
+            \x20 [ 8] 1234  0x00000000000004D2  '.'\n\
+            \x20 [ 7]    0  0x0000000000000000  '.'\n\
+            \x20 [ 6]    7  0x0000000000000007  '.'\n\
+            \x20 [ 5]    8  0x0000000000000008  '.'\n\
+            \x20 [ 4]    9  0x0000000000000009  '.'\n\
+            \x20 [ 3]   10  0x000000000000000A  '.'\n\
+            \x20 [ 2]   11  0x000000000000000B  '.'\n\
+            \x20 [ 1]   12  0x000000000000000C  '.'\n\
+            \x20 [ 0]   13  0x000000000000000D  '.'\n"
+        );
+    }
+
+    #[test]
+    fn test_execute_dotvs() {
+        let mut sm = StackMachine::new();
+        let captured = CapturedOutput::default();
+        sm.st.output = Box::new(captured.clone());
+
+        // Populate the number stack
+        sm.st.number_stack.extend_from_slice(&[72, 105]);
         // Put the opcodes into the *memory*
         sm.st
             .opcodes
-            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+            .extend_from_slice(&[Opcode::DOTVS, Opcode::RET]);
 
         // Execute the instructions
         sm.execute(0, GasLimit::Limited(100)).unwrap();
 
-        assert_eq!(sm.st.number_stack, vec![200]);
+        assert_eq!(
+            captured.contents(),
+            "Stack depth 2\n  [1]  72  0x0000000000000048  'H'\n  [0] 105  0x0000000000000069  'i'\n"
+        );
+        // The stack is left alone
+        assert_eq!(sm.st.number_stack, vec![72, 105]);
     }
 
     #[test]
-    fn test_unhandled_trap_1() {
+    fn test_watchdog() {
         let mut sm = StackMachine::new();
-
-        // Populate the number stack, with a value (50), and the trap number (100)
-        sm.st.number_stack.extend_from_slice(&[50_i64, 100]);
+        let petted = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = petted.clone();
+        sm.watchdog = Some(Watchdog::new(3, move || counter.set(counter.get() + 1)));
 
         // Put the opcodes into the *memory*
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(1),
+            Opcode::LDI(2),
+            Opcode::ADD,
+            Opcode::LDI(3),
+            Opcode::ADD,
+            Opcode::LDI(4),
+            Opcode::ADD,
+            Opcode::RET,
+        ]);
+
+        // Execute the instructions
+        sm.execute(0, GasLimit::Unlimited).unwrap();
+        assert_eq!(petted.get(), 2);
+        // The count carries on across runs rather than starting again
+        sm.execute(5, GasLimit::Unlimited).unwrap();
+        assert_eq!(petted.get(), 3);
+        assert_eq!(sm.st.number_stack, vec![14]);
+    }
+
+    #[test]
+    fn test_cycle_model() {
+        let mut sm = StackMachine::new();
+        // Loads take 2 cycles, arithmetic 3 and everything else 1
+        sm.cycle_model = Some(CycleModel::new(|opcode| match opcode {
+            Opcode::LDI(_) => 2,
+            Opcode::ADD | Opcode::MUL => 3,
+            _ => 1,
+        }));
+
+        sm.st.opcodes.extend_from_slice(&[
+            Opcode::LDI(6),
+            Opcode::LDI(7),
+            Opcode::MUL,
+            Opcode::RET,
+        ]);
+
+        sm.execute(0, GasLimit::Unlimited).unwrap();
+        assert_eq!(sm.cycle_model.as_ref().unwrap().cycles(), 8);
+        assert_eq!(sm.st.gas_used(), 3);
+        assert_eq!(sm.st.number_stack, vec![42]);
+
+        // Cycles add up across runs until the host resets them
+        sm.execute(0, GasLimit::Unlimited).unwrap();
+        assert_eq!(sm.cycle_model.as_ref().unwrap().cycles(), 16);
+        sm.cycle_model.as_mut().unwrap().reset();
+        assert_eq!(sm.cycle_model.as_ref().unwrap().cycles(), 0);
+    }
+
+    #[test]
+    fn test_integrity_checks() {
+        let mut sm = StackMachine::new();
+        sm.integrity_checks = Some(IntegrityChecks::default());
+
+        // Running off the end of memory, jumps there are already refused as InvalidCodeAddress
         sm.st
             .opcodes
-            .extend_from_slice(&[Opcode::TRAP, Opcode::RET]);
+            .extend_from_slice(&[Opcode::LDI(7), Opcode::LDI(5), Opcode::ADD]);
 
-        // Execute the instructions
         match sm.execute(0, GasLimit::Limited(100)) {
-            Err(StackMachineError::UnhandledTrap) => (),
-            r => panic!("Incorrect error type returned {:?}", r),
+            Err(StackMachineError::IntegrityViolation(v)) => {
+                assert_eq!(v.description, "pc 3 is outside the 3 opcodes in memory");
+                assert_eq!(v.pc, 2);
+                assert_eq!(
+                    v.to_string(),
+                    "integrity violation: pc 3 is outside the 3 opcodes in memory\n  after ADD at 2\n  number stack: [12]\n  return stack: []\n"
+                );
+            }
+            r => panic!("Incorrect result: {:?}", r),
+        }
+
+        // A return address that no CALL could have pushed
+        sm.st.number_stack.clear();
+        sm.st.return_stack.push(2);
+        match sm.execute(0, GasLimit::Limited(100)) {
+            Err(StackMachineError::IntegrityViolation(v)) => {
+                assert_eq!(v.description, "return address 2 doesn't follow a CALL")
+            }
+            r => panic!("Incorrect result: {:?}", r),
         }
     }
 }
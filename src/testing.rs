@@ -0,0 +1,114 @@
+//! Helpers for testing Forth code from Rust, such as golden tests of what a library compiles to.
+//! With the testing feature there are also in-memory stand-ins for everything the compiler
+//! reaches outside itself, so scripts can be tested hermetically. Each stand-in is a handle to
+//! shared state, give the compiler a clone and keep one to look at or change from the test.
+
+use super::error::ForthError;
+use super::forth_compiler::ForthCompiler;
+
+#[cfg(feature = "testing")]
+mod doubles;
+#[cfg(feature = "testing")]
+pub use doubles::*;
+
+/// Compile the source and return the opcode listing of everything the compiler now holds, see
+/// CompilationArtifact::opcode_listing. Nothing is run or printed.
+pub fn compile_to_text(fc: &mut ForthCompiler, source: &str) -> Result<String, ForthError> {
+    let artifacts_enabled = fc.artifacts_enabled();
+    fc.set_artifacts_enabled(true);
+    let listing = fc
+        .compile_string(source)
+        .map(|_| fc.last_artifact().map(|a| a.opcode_listing()));
+    fc.set_artifacts_enabled(artifacts_enabled);
+
+    Ok(listing?.unwrap_or_default())
+}
+
+/// Assert that Forth source compiles to the expected opcode listing, using a new ForthCompiler
+/// or the one given. Trailing whitespace on the end of either listing is ignored.
+///
+/// ```
+/// rust_forth::assert_compiles_to!(
+///     ": Double DUP ADD ; 2 Double",
+///     "Double:\n     0  DUP\n     1  ADD\n     2  RET\n<immediate>:\n     3  LDI(2)\n     4  LDI(0)\n     5  CALL\n     6  RET\n"
+/// );
+/// ```
+#[macro_export]
+macro_rules! assert_compiles_to {
+    ($source: expr, $expected: expr) => {
+        $crate::assert_compiles_to!(
+            &mut $crate::forth_compiler::ForthCompiler::new(),
+            $source,
+            $expected
+        )
+    };
+    ($fc: expr, $source: expr, $expected: expr) => {{
+        let listing = match $crate::testing::compile_to_text($fc, $source) {
+            Ok(listing) => listing,
+            Err(e) => panic!("{:?} failed to compile: {:?}", $source, e),
+        };
+        let expected: &str = $expected.as_ref();
+        if listing.trim_end() != expected.trim_end() {
+            panic!(
+                "{:?} compiled to\n{}\nbut expected\n{}",
+                $source, listing, expected
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_to_text() {
+        let mut fc = ForthCompiler::new();
+
+        assert_compiles_to!(
+            &mut fc,
+            ": Double DUP ADD ;\n\n: Quad Double Double ;",
+            "Double:
+     0  DUP
+     1  ADD
+     2  RET
+Quad:
+     3  LDI(0)
+     4  CALL
+     5  LDI(0)
+     6  CALL
+     7  RET
+<immediate>:
+     8  RET"
+        );
+        // The compiler keeps what it compiled, and doesn't keep artifacts it wasn't asked for
+        assert_compiles_to!(
+            &mut fc,
+            "1 IF Quad THEN",
+            "Double:
+     0  DUP
+     1  ADD
+     2  RET
+Quad:
+     3  LDI(0)
+     4  CALL
+     5  LDI(0)
+     6  CALL
+     7  RET
+<immediate>:
+     8  LDI(1)
+     9  LDI(3)
+    10  JRNZ
+    11  LDI(3)
+    12  CALL
+    13  RET"
+        );
+        assert!(fc.last_artifact().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "but expected")]
+    fn test_mismatch() {
+        assert_compiles_to!("1 2 ADD", "<immediate>:\n     0  LDI(3)\n");
+    }
+}
@@ -0,0 +1,217 @@
+//! The in-memory stand-ins the testing feature adds, re-exported from testing
+
+use crate::forth_compiler::ForthCompiler;
+use crate::host::{Clock, FileSystem, IoBus, MemoryKeyValueStore};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+
+/// A clock that only moves when the test moves it
+#[derive(Debug, Clone, Default)]
+pub struct MockClock(Rc<Cell<u64>>);
+
+impl MockClock {
+    pub fn new(now: u64) -> MockClock {
+        MockClock(Rc::new(Cell::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.set(self.0.get() + ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockIoBusState {
+    // Values waiting to be read from each port, reading an empty port gives 0
+    reads: HashMap<i64, VecDeque<i64>>,
+    // Every write in order, as (port, value)
+    writes: Vec<(i64, i64)>,
+}
+
+/// An I/O bus that reads values the test queued up and records what is written
+#[derive(Debug, Clone, Default)]
+pub struct MockIoBus(Rc<RefCell<MockIoBusState>>);
+
+impl MockIoBus {
+    pub fn new() -> MockIoBus {
+        MockIoBus::default()
+    }
+
+    /// Queue values for IN to read from the port, in order
+    pub fn queue_reads(&self, port: i64, values: &[i64]) {
+        self.0
+            .borrow_mut()
+            .reads
+            .entry(port)
+            .or_default()
+            .extend(values);
+    }
+
+    /// Every OUT so far, as (port, value)
+    pub fn writes(&self) -> Vec<(i64, i64)> {
+        self.0.borrow().writes.clone()
+    }
+}
+
+impl IoBus for MockIoBus {
+    fn read_port(&mut self, port: i64) -> io::Result<i64> {
+        Ok(self
+            .0
+            .borrow_mut()
+            .reads
+            .get_mut(&port)
+            .and_then(|r| r.pop_front())
+            .unwrap_or(0))
+    }
+
+    fn write_port(&mut self, port: i64, value: i64) -> io::Result<()> {
+        self.0.borrow_mut().writes.push((port, value));
+        Ok(())
+    }
+}
+
+/// Files that only exist in memory
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem(Rc<RefCell<HashMap<String, Vec<u8>>>>);
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    pub fn add_file(&self, path: &str, contents: &str) {
+        self.add_bytes(path, contents.as_bytes());
+    }
+
+    pub fn add_bytes(&self, path: &str, contents: &[u8]) {
+        self.0
+            .borrow_mut()
+            .insert(path.to_owned(), contents.to_vec());
+    }
+
+    /// The file's contents, None when there is no such file or it isn't text
+    pub fn file(&self, path: &str) -> Option<String> {
+        String::from_utf8(self.bytes(path)?).ok()
+    }
+
+    pub fn bytes(&self, path: &str) -> Option<Vec<u8>> {
+        self.0.borrow().get(path).cloned()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.add_file(path, contents);
+        Ok(())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.bytes(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_owned()))
+    }
+
+    fn write_bytes(&mut self, path: &str, contents: &[u8]) -> io::Result<()> {
+        self.add_bytes(path, contents);
+        Ok(())
+    }
+}
+
+/// An output sink that keeps everything written to it
+#[derive(Debug, Clone, Default)]
+pub struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl CapturedOutput {
+    pub fn new() -> CapturedOutput {
+        CapturedOutput::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl io::Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A ForthCompiler wired up to new stand-ins, which are returned alongside it. KV! and KV@ use
+/// a MemoryKeyValueStore.
+pub fn hermetic_compiler() -> (
+    ForthCompiler,
+    MockClock,
+    MockIoBus,
+    MemoryFileSystem,
+    CapturedOutput,
+) {
+    let clock = MockClock::new(0);
+    let io_bus = MockIoBus::new();
+    let file_system = MemoryFileSystem::new();
+    let output = CapturedOutput::new();
+
+    let mut fc = ForthCompiler::new();
+    fc.set_clock(Box::new(clock.clone()));
+    fc.set_io_bus(Box::new(io_bus.clone()));
+    fc.set_file_system(Box::new(file_system.clone()));
+    fc.set_key_value_store(Box::new(MemoryKeyValueStore::new()));
+    fc.sm.st.output = Box::new(output.clone());
+
+    (fc, clock, io_bus, file_system, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ForthError;
+    use crate::stack_machine::GasLimit;
+
+    #[test]
+    fn test_hermetic_compiler() {
+        let (mut fc, clock, io_bus, file_system, output) = hermetic_compiler();
+        clock.set(1000);
+        io_bus.queue_reads(7, &[42, 43]);
+        file_system.add_file("lib.fs", ": Double DUP ADD ;");
+
+        fc.include_file("lib.fs", GasLimit::Limited(100)).unwrap();
+        fc.execute_string(
+            "MS@ 7 IN 7 IN Double 3 OUT 5 U. 7 IN",
+            GasLimit::Limited(100),
+        )
+        .unwrap();
+        clock.advance(5);
+        fc.execute_string("MS@", GasLimit::Limited(100)).unwrap();
+
+        assert_eq!(&fc.sm.st.number_stack, &vec![1000_i64, 42, 0, 1005]);
+        assert_eq!(io_bus.writes(), vec![(3, 86)]);
+        assert_eq!(output.contents(), "5 ");
+        match fc.include_file("missing.fs", GasLimit::Limited(100)) {
+            Err(ForthError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            r => panic!("Incorrect result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crossterm::cursor::MoveTo;
+use crossterm::event;
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::QueueableCommand;
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+/// Move the cursor to column x, row y, both counting from 0 at the top left
+pub fn at_xy(output: &mut dyn Write, x: i64, y: i64) -> io::Result<()> {
+    // Positions off the screen are pinned to its edge, terminals count from 1 so leave room for that
+    let clamp = |n: i64| u16::try_from(n.clamp(0, i64::from(u16::MAX - 1))).unwrap_or(0);
+    output.queue(MoveTo(clamp(x), clamp(y)))?;
+    output.flush()
+}
+
+/// Clear the screen and home the cursor
+pub fn page(output: &mut dyn Write) -> io::Result<()> {
+    output.queue(Clear(ClearType::All))?.queue(MoveTo(0, 0))?;
+    output.flush()
+}
+
+/// Whether a key press is waiting to be read, without blocking
+pub fn key_available() -> io::Result<bool> {
+    event::poll(Duration::from_secs(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut output = Vec::new();
+
+        at_xy(&mut output, 4, 2).unwrap();
+        page(&mut output).unwrap();
+        at_xy(&mut output, -1, 70_000).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b[3;5H\x1b[2J\x1b[1;1H\x1b[65535;1H"
+        );
+    }
+}